@@ -0,0 +1,180 @@
+//! Shared secret handshake run at the start of every connection between the
+//! distributer and a processor, on the control port as well as the data port.
+//!
+//! ```text
+//! listener -> dialer   16 byte nonce_l
+//! dialer -> listener   16 byte nonce_d, HMAC-SHA256(secret, "dialer" nonce_l nonce_d)
+//! listener -> dialer   HMAC-SHA256(secret, "listener" nonce_d nonce_l)
+//! ```
+//!
+//! Each side proves it knows the secret without sending it, and the fresh
+//! nonces keep a recorded handshake from being replayed. A listener that
+//! fails to verify the dialer closes the connection without answering.
+
+use std::env;
+use std::io::{Error, ErrorKind};
+use std::sync::Arc;
+use std::time::Duration;
+
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+const NONCE_LEN: usize = 16;
+const MAC_LEN: usize = 32;
+/// A peer that has not finished the handshake by then is dropped.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone)]
+pub struct Secret(Arc<[u8]>);
+
+impl Secret {
+    pub fn new(secret: &[u8]) -> Self {
+        Self(secret.into())
+    }
+
+    /// `CLUSTER_SECRET`, which must be the same on the distributer and every
+    /// processor. Unset means connections are not authenticated.
+    pub fn from_env() -> Option<Self> {
+        env::var("CLUSTER_SECRET")
+            .ok()
+            .filter(|s| !s.is_empty())
+            .map(|s| Self::new(s.as_bytes()))
+    }
+
+    /// Whether `token` is the secret itself, as admin API clients send it.
+    /// Compared through a MAC so the time taken gives nothing away.
+    pub fn matches(&self, token: &[u8]) -> bool {
+        let expected = self.mac(b"token", &self.0, &[]).finalize().into_bytes();
+        self.mac(b"token", token, &[])
+            .verify_slice(&expected)
+            .is_ok()
+    }
+
+    fn mac(&self, role: &[u8], theirs: &[u8], ours: &[u8]) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.0).expect("hmac takes any key length");
+        mac.update(role);
+        mac.update(theirs);
+        mac.update(ours);
+        mac
+    }
+}
+
+/// Runs the listener side of the handshake on a freshly accepted connection.
+pub async fn accept<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    secret: &Secret,
+) -> Result<(), Error> {
+    timeout(async {
+        let nonce = nonce();
+        stream.write_all(&nonce).await?;
+
+        let mut response = [0u8; NONCE_LEN + MAC_LEN];
+        stream.read_exact(&mut response).await?;
+        let (theirs, tag) = response.split_at(NONCE_LEN);
+        secret
+            .mac(b"dialer", &nonce, theirs)
+            .verify_slice(tag)
+            .map_err(|_| denied("peer does not know the cluster secret"))?;
+
+        let proof = secret.mac(b"listener", theirs, &nonce).finalize();
+        stream.write_all(&proof.into_bytes()).await
+    })
+    .await
+}
+
+/// Runs the dialer side of the handshake right after connecting.
+pub async fn connect<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    secret: &Secret,
+) -> Result<(), Error> {
+    timeout(async {
+        let mut theirs = [0u8; NONCE_LEN];
+        stream.read_exact(&mut theirs).await?;
+
+        let nonce = nonce();
+        let tag = secret.mac(b"dialer", &theirs, &nonce).finalize();
+        let mut response = nonce.to_vec();
+        response.extend_from_slice(&tag.into_bytes());
+        stream.write_all(&response).await?;
+
+        let mut proof = [0u8; MAC_LEN];
+        stream
+            .read_exact(&mut proof)
+            .await
+            .map_err(|_| denied("listener closed the handshake, check CLUSTER_SECRET"))?;
+        secret
+            .mac(b"listener", &nonce, &theirs)
+            .verify_slice(&proof)
+            .map_err(|_| denied("listener does not know the cluster secret"))
+    })
+    .await
+}
+
+fn nonce() -> [u8; NONCE_LEN] {
+    let mut nonce = [0u8; NONCE_LEN];
+    rand::rng().fill_bytes(&mut nonce);
+    nonce
+}
+
+fn denied(msg: &str) -> Error {
+    Error::new(ErrorKind::PermissionDenied, msg)
+}
+
+async fn timeout(handshake: impl Future<Output = Result<(), Error>>) -> Result<(), Error> {
+    tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake)
+        .await
+        .map_err(|_| Error::new(ErrorKind::TimedOut, "handshake timed out"))?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn handshake(
+        listener: &Secret,
+        dialer: &Secret,
+    ) -> (Result<(), Error>, Result<(), Error>) {
+        let (mut a, mut b) = tokio::io::duplex(256);
+        let listener = listener.clone();
+        let accepted = tokio::spawn(async move {
+            let result = accept(&mut a, &listener).await;
+            drop(a);
+            result
+        });
+        let connected = connect(&mut b, dialer).await;
+        (accepted.await.unwrap(), connected)
+    }
+
+    #[tokio::test]
+    async fn test_handshake() {
+        let secret = Secret::new(b"correct horse");
+        let (accepted, connected) = handshake(&secret, &secret).await;
+        assert!(accepted.is_ok() && connected.is_ok());
+
+        let (accepted, connected) = handshake(&secret, &Secret::new(b"battery staple")).await;
+        assert_eq!(accepted.unwrap_err().kind(), ErrorKind::PermissionDenied);
+        assert_eq!(connected.unwrap_err().kind(), ErrorKind::PermissionDenied);
+    }
+
+    #[tokio::test]
+    async fn test_handshake_rejects_replay() {
+        let secret = Secret::new(b"correct horse");
+        let (mut a, mut b) = tokio::io::duplex(256);
+        let listener = secret.clone();
+        tokio::spawn(async move { accept(&mut a, &listener).await });
+        let mut nonce = [0u8; NONCE_LEN];
+        b.read_exact(&mut nonce).await.unwrap();
+        let recorded = secret.mac(b"dialer", &nonce, &[7; NONCE_LEN]).finalize();
+
+        // the same response against a fresh nonce no longer verifies
+        let (mut a, mut b) = tokio::io::duplex(256);
+        let accepted = tokio::spawn(async move { accept(&mut a, &secret).await });
+        b.read_exact(&mut nonce).await.unwrap();
+        let mut response = vec![7; NONCE_LEN];
+        response.extend_from_slice(&recorded.into_bytes());
+        b.write_all(&response).await.unwrap();
+        assert!(accepted.await.unwrap().is_err());
+    }
+}
@@ -0,0 +1,516 @@
+//! Column oriented encoding of a netflow chunk, an alternative to bincode of
+//! `Vec<Netflow>` for processors that advertise it at registration.
+//!
+//! Layout, all integers little endian or LEB128 varints:
+//!
+//! ```text
+//! u32 rows
+//! flow_id      zigzag varint of the first id, then of each delta
+//! ip dictionary  varint count, then per entry a 4/6 tag and the octets
+//! src_ip       null bitmap, varint dictionary index per present value
+//! dst_ip       null bitmap, varint dictionary index per present value
+//! src_port     null bitmap, zigzag varint per present value
+//! dst_port     null bitmap, zigzag varint per present value
+//! protocol     null bitmap, one byte per present value
+//! bytes        null bitmap, zigzag varint per present value
+//! packets      null bitmap, zigzag varint per present value
+//! start_ts     null bitmap, zigzag varint micros delta to the previous present value
+//! end_ts       null bitmap, zigzag varint micros delta to the previous present value
+//! src_asn      null bitmap, zigzag varint per present value
+//! dst_asn      null bitmap, zigzag varint per present value
+//! ```
+//!
+//! A null bitmap is `ceil(rows / 8)` bytes with bit `i % 8` of byte `i / 8`
+//! set when row `i` has a value.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use chrono::DateTime;
+
+use crate::netflow::{Netflow, Protocol};
+
+#[derive(Debug)]
+pub struct ColumnarError(String);
+
+impl fmt::Display for ColumnarError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid columnar chunk: {}", self.0)
+    }
+}
+
+impl std::error::Error for ColumnarError {}
+
+pub fn encode(items: &[Netflow]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(items.len() * 24);
+    out.extend_from_slice(&(items.len() as u32).to_le_bytes());
+
+    let mut prev = 0i64;
+    for item in items {
+        put_varint(&mut out, zigzag(item.flow_id.wrapping_sub(prev)));
+        prev = item.flow_id;
+    }
+
+    let mut dictionary: HashMap<IpAddr, u64> = HashMap::new();
+    let mut entries = Vec::new();
+    let mut index = |ip: IpAddr| {
+        *dictionary.entry(ip).or_insert_with(|| {
+            entries.push(ip);
+            entries.len() as u64 - 1
+        })
+    };
+    let src: Vec<Option<u64>> = items.iter().map(|n| n.src_ip.map(&mut index)).collect();
+    let dst: Vec<Option<u64>> = items.iter().map(|n| n.dst_ip.map(&mut index)).collect();
+    put_varint(&mut out, entries.len() as u64);
+    for ip in &entries {
+        match ip {
+            IpAddr::V4(v4) => {
+                out.push(4);
+                out.extend_from_slice(&v4.octets());
+            }
+            IpAddr::V6(v6) => {
+                out.push(6);
+                out.extend_from_slice(&v6.octets());
+            }
+        }
+    }
+    put_column(&mut out, &src, |out, i| put_varint(out, *i));
+    put_column(&mut out, &dst, |out, i| put_varint(out, *i));
+
+    let int = |out: &mut Vec<u8>, v: &i64| put_varint(out, zigzag(*v));
+    let widen = |f: fn(&Netflow) -> Option<i32>| -> Vec<Option<i64>> {
+        items.iter().map(|n| f(n).map(i64::from)).collect()
+    };
+    put_column(&mut out, &widen(|n| n.src_port), int);
+    put_column(&mut out, &widen(|n| n.dst_port), int);
+    let protocols: Vec<Option<u8>> = items.iter().map(|n| n.protocol.map(u8::from)).collect();
+    put_column(&mut out, &protocols, |out, p| out.push(*p));
+    put_column(
+        &mut out,
+        &items.iter().map(|n| n.bytes).collect::<Vec<_>>(),
+        int,
+    );
+    put_column(
+        &mut out,
+        &items.iter().map(|n| n.packets).collect::<Vec<_>>(),
+        int,
+    );
+
+    for ts in [
+        items.iter().map(|n| n.start_ts).collect::<Vec<_>>(),
+        items.iter().map(|n| n.end_ts).collect::<Vec<_>>(),
+    ] {
+        let mut prev = 0i64;
+        let micros: Vec<Option<i64>> = ts
+            .iter()
+            .map(|ts| {
+                ts.map(|ts| {
+                    let micros = ts.timestamp_micros();
+                    let delta = micros.wrapping_sub(prev);
+                    prev = micros;
+                    delta
+                })
+            })
+            .collect();
+        put_column(&mut out, &micros, int);
+    }
+
+    put_column(&mut out, &widen(|n| n.src_asn), int);
+    put_column(&mut out, &widen(|n| n.dst_asn), int);
+    out
+}
+
+pub fn decode(buf: &[u8]) -> Result<Vec<Netflow>, ColumnarError> {
+    let mut r = Reader { buf, pos: 0 };
+    let rows = u32::from_le_bytes(r.take(4)?.try_into().unwrap()) as usize;
+    // every row costs at least one byte of flow_id, so this bounds the allocation
+    if rows > buf.len() {
+        return Err(ColumnarError(format!(
+            "{} rows in {} bytes",
+            rows,
+            buf.len()
+        )));
+    }
+
+    let mut flow_ids = Vec::with_capacity(rows);
+    let mut prev = 0i64;
+    for _ in 0..rows {
+        prev = prev.wrapping_add(unzigzag(r.varint()?));
+        flow_ids.push(prev);
+    }
+
+    let entries = r.varint()? as usize;
+    if entries > buf.len() {
+        return Err(ColumnarError(format!("{} dictionary entries", entries)));
+    }
+    let mut dictionary = Vec::with_capacity(entries);
+    for _ in 0..entries {
+        dictionary.push(match r.byte()? {
+            4 => IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(r.take(4)?).unwrap())),
+            6 => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(r.take(16)?).unwrap())),
+            tag => return Err(ColumnarError(format!("unknown address tag {}", tag))),
+        });
+    }
+    let mut ip = |r: &mut Reader| -> Result<IpAddr, ColumnarError> {
+        let i = r.varint()? as usize;
+        dictionary
+            .get(i)
+            .copied()
+            .ok_or_else(|| ColumnarError(format!("dictionary index {} out of range", i)))
+    };
+    let src_ip = r.column(rows, &mut ip)?;
+    let dst_ip = r.column(rows, &mut ip)?;
+
+    let int = |r: &mut Reader| r.varint().map(unzigzag);
+    let int32 = |r: &mut Reader| -> Result<i32, ColumnarError> {
+        i32::try_from(unzigzag(r.varint()?)).map_err(|e| ColumnarError(e.to_string()))
+    };
+    let src_port = r.column(rows, int32)?;
+    let dst_port = r.column(rows, int32)?;
+    let protocol = r.column(rows, |r| r.byte().map(Protocol::from))?;
+    let bytes = r.column(rows, int)?;
+    let packets = r.column(rows, int)?;
+    let mut timestamps = || -> Result<Vec<_>, ColumnarError> {
+        let mut prev = 0i64;
+        r.column(rows, |r| {
+            prev = prev.wrapping_add(unzigzag(r.varint()?));
+            DateTime::from_timestamp_micros(prev)
+                .ok_or_else(|| ColumnarError(format!("timestamp {} out of range", prev)))
+        })
+    };
+    let start_ts = timestamps()?;
+    let end_ts = timestamps()?;
+    let src_asn = r.column(rows, int32)?;
+    let dst_asn = r.column(rows, int32)?;
+    if r.pos != buf.len() {
+        return Err(ColumnarError(format!(
+            "{} trailing bytes",
+            buf.len() - r.pos
+        )));
+    }
+
+    Ok((0..rows)
+        .map(|i| Netflow {
+            flow_id: flow_ids[i],
+            src_ip: src_ip[i],
+            dst_ip: dst_ip[i],
+            src_port: src_port[i],
+            dst_port: dst_port[i],
+            protocol: protocol[i],
+            bytes: bytes[i],
+            packets: packets[i],
+            start_ts: start_ts[i],
+            end_ts: end_ts[i],
+            src_asn: src_asn[i],
+            dst_asn: dst_asn[i],
+        })
+        .collect())
+}
+
+fn put_column<T>(out: &mut Vec<u8>, values: &[Option<T>], mut put: impl FnMut(&mut Vec<u8>, &T)) {
+    let mut bitmap = vec![0u8; values.len().div_ceil(8)];
+    for (i, v) in values.iter().enumerate() {
+        if v.is_some() {
+            bitmap[i / 8] |= 1 << (i % 8);
+        }
+    }
+    out.extend_from_slice(&bitmap);
+    for v in values.iter().flatten() {
+        put(out, v);
+    }
+}
+
+fn put_varint(out: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        out.push(v as u8 | 0x80);
+        v >>= 7;
+    }
+    out.push(v as u8);
+}
+
+fn zigzag(v: i64) -> u64 {
+    ((v << 1) ^ (v >> 63)) as u64
+}
+
+fn unzigzag(v: u64) -> i64 {
+    (v >> 1) as i64 ^ -((v & 1) as i64)
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], ColumnarError> {
+        let end = self
+            .pos
+            .checked_add(n)
+            .filter(|&end| end <= self.buf.len())
+            .ok_or_else(|| ColumnarError("unexpected end of chunk".to_string()))?;
+        let slice = &self.buf[self.pos..end];
+        self.pos = end;
+        Ok(slice)
+    }
+
+    fn byte(&mut self) -> Result<u8, ColumnarError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn varint(&mut self) -> Result<u64, ColumnarError> {
+        let mut v = 0u64;
+        for shift in (0..64).step_by(7) {
+            let b = self.byte()?;
+            v |= ((b & 0x7f) as u64) << shift;
+            if b & 0x80 == 0 {
+                return Ok(v);
+            }
+        }
+        Err(ColumnarError("varint longer than 10 bytes".to_string()))
+    }
+
+    fn column<T>(
+        &mut self,
+        rows: usize,
+        mut get: impl FnMut(&mut Self) -> Result<T, ColumnarError>,
+    ) -> Result<Vec<Option<T>>, ColumnarError> {
+        let bitmap = self.take(rows.div_ceil(8))?;
+        (0..rows)
+            .map(|i| {
+                if bitmap[i / 8] & (1 << (i % 8)) != 0 {
+                    get(self).map(Some)
+                } else {
+                    Ok(None)
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use chrono::Utc;
+    use rand::{Rng, SeedableRng, rngs::StdRng};
+
+    use super::*;
+    use crate::codec::Codec;
+
+    fn lz4_rows(items: &[Netflow]) -> Vec<u8> {
+        Codec::Lz4.compress(&bincode2::serialize(items).unwrap())
+    }
+
+    fn lz4_columnar(items: &[Netflow]) -> Vec<u8> {
+        Codec::Lz4.compress(&encode(items))
+    }
+
+    /// Rows shaped like a source partition: ascending ids and start times, a
+    /// few hundred distinct hosts and the generator's share of nulls.
+    fn sample(rows: usize, seed: u64) -> Vec<Netflow> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let base = Utc::now().timestamp_micros();
+        let maybe = |rng: &mut StdRng| rng.random_bool(0.8);
+        (0..rows as i64)
+            .map(|i| {
+                let start = base + i * 1_000 + rng.random_range(0..1_000);
+                let host = |rng: &mut StdRng| -> IpAddr {
+                    if rng.random_bool(0.1) {
+                        IpAddr::V6(Ipv6Addr::new(
+                            0x2001,
+                            0xdb8,
+                            0,
+                            0,
+                            0,
+                            0,
+                            0,
+                            rng.random_range(1..64),
+                        ))
+                    } else {
+                        IpAddr::V4(Ipv4Addr::new(
+                            10,
+                            0,
+                            rng.random_range(0..4),
+                            rng.random_range(1..255),
+                        ))
+                    }
+                };
+                Netflow {
+                    flow_id: 1_000_000 + i,
+                    src_ip: maybe(&mut rng).then(|| host(&mut rng)),
+                    dst_ip: maybe(&mut rng).then(|| host(&mut rng)),
+                    src_port: maybe(&mut rng).then(|| rng.random_range(1024..65535)),
+                    dst_port: maybe(&mut rng).then(|| [80, 443, 53, 22][rng.random_range(0..4)]),
+                    protocol: maybe(&mut rng)
+                        .then(|| Protocol::from([6, 17, 1][rng.random_range(0..3)])),
+                    bytes: maybe(&mut rng).then(|| rng.random_range(64..1_500_000)),
+                    packets: maybe(&mut rng).then(|| rng.random_range(1..1_000)),
+                    start_ts: maybe(&mut rng)
+                        .then(|| DateTime::from_timestamp_micros(start).unwrap()),
+                    end_ts: maybe(&mut rng).then(|| {
+                        DateTime::from_timestamp_micros(start + rng.random_range(0..60_000_000))
+                            .unwrap()
+                    }),
+                    src_asn: maybe(&mut rng).then(|| rng.random_range(1..65000)),
+                    dst_asn: maybe(&mut rng).then(|| rng.random_range(1..65000)),
+                }
+            })
+            .collect()
+    }
+
+    fn assert_same(a: &[Netflow], b: &[Netflow]) {
+        assert_eq!(a.len(), b.len());
+        for (a, b) in a.iter().zip(b) {
+            assert_eq!(format!("{:?}", a), format!("{:?}", b));
+        }
+    }
+
+    #[test]
+    fn test_columnar_round_trip() {
+        let items = sample(1000, 7);
+        assert_same(&decode(&encode(&items)).unwrap(), &items);
+        assert!(decode(&encode(&[])).unwrap().is_empty());
+
+        let mut extremes = sample(3, 8);
+        extremes[0].flow_id = i64::MIN;
+        extremes[1].flow_id = i64::MAX;
+        extremes[2].protocol = Some(Protocol::Other(255));
+        extremes[2].start_ts = DateTime::from_timestamp_micros(0);
+        assert_same(&decode(&encode(&extremes)).unwrap(), &extremes);
+    }
+
+    /// Two flows covering both address families, a repeated address, a
+    /// descending id, an unknown protocol and null columns.
+    fn golden() -> Vec<Netflow> {
+        vec![
+            Netflow {
+                flow_id: 42,
+                src_ip: Some("10.0.0.1".parse().unwrap()),
+                dst_ip: Some("2001:db8::1".parse().unwrap()),
+                src_port: Some(51000),
+                dst_port: Some(443),
+                protocol: Some(Protocol::Tcp),
+                bytes: Some(1500),
+                packets: Some(3),
+                start_ts: DateTime::from_timestamp_micros(1_700_000_000_000_000),
+                end_ts: DateTime::from_timestamp_micros(1_700_000_000_250_000),
+                src_asn: Some(64512),
+                dst_asn: Some(15169),
+            },
+            Netflow {
+                flow_id: 40,
+                src_ip: Some("10.0.0.1".parse().unwrap()),
+                dst_ip: None,
+                src_port: None,
+                dst_port: Some(53),
+                protocol: Some(Protocol::Other(99)),
+                bytes: Some(0),
+                packets: None,
+                start_ts: None,
+                end_ts: None,
+                src_asn: None,
+                dst_asn: None,
+            },
+        ]
+    }
+
+    /// `encode(&golden())`, pinned so a change to the layout is noticed.
+    #[rustfmt::skip]
+    const GOLDEN: &[u8] = &[
+        2, 0, 0, 0, 84, 3, 2, 4, 10, 0, 0, 1, 6, 32, 1, 13, 184, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        0, 1, 3, 0, 0, 1, 1, 1, 240, 156, 6, 3, 246, 6, 106, 3, 6, 99, 3, 184, 23, 0, 1, 6, 1,
+        128, 128, 242, 129, 131, 137, 133, 6, 1, 160, 194, 144, 130, 131, 137, 133, 6, 1, 128,
+        240, 7, 1, 130, 237, 1,
+    ];
+
+    #[test]
+    fn test_columnar_golden_bytes() {
+        assert_eq!(encode(&golden()), GOLDEN);
+        assert_same(&decode(GOLDEN).unwrap(), &golden());
+    }
+
+    #[test]
+    fn test_unknown_address_family_is_refused() {
+        let mut encoded = encode(&golden());
+        let octets = "2001:db8::1".parse::<Ipv6Addr>().unwrap().octets();
+        let at = encoded
+            .windows(17)
+            .position(|entry| entry[0] == 6 && entry[1..] == octets)
+            .expect("the v6 address entry");
+        encoded[at] = 5;
+        assert!(decode(&encoded).is_err());
+    }
+
+    #[test]
+    fn test_columnar_rejects_truncated() {
+        let encoded = encode(&sample(100, 9));
+        for len in [0, 3, 10, encoded.len() / 2, encoded.len() - 1] {
+            assert!(decode(&encoded[..len]).is_err(), "accepted {} bytes", len);
+        }
+        let mut trailing = encoded.clone();
+        trailing.push(0);
+        assert!(decode(&trailing).is_err());
+        assert!(decode(&u32::MAX.to_le_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_columnar_smaller_than_rows() {
+        let items = sample(1000, 10);
+        assert!(lz4_columnar(&items).len() < lz4_rows(&items).len());
+    }
+
+    /// `cargo test --release bench_columnar -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn bench_columnar_vs_bincode() {
+        let chunks: Vec<Vec<Netflow>> = (0..200).map(|seed| sample(1000, seed)).collect();
+        let rows = (chunks.len() * 1000) as f64;
+
+        let time = |f: &dyn Fn(&[Netflow]) -> Vec<u8>| {
+            let start = Instant::now();
+            let bytes: usize = chunks.iter().map(|c| f(c).len()).sum();
+            (bytes, start.elapsed())
+        };
+        let report = |name: &str, (bytes, elapsed): (usize, std::time::Duration)| {
+            println!(
+                "{:<22} {:>6.1} bytes/row {:>8.2} Mrows/s",
+                name,
+                bytes as f64 / rows,
+                rows / elapsed.as_secs_f64() / 1e6
+            );
+        };
+        report("bincode", time(&|c| bincode2::serialize(c).unwrap()));
+        report("bincode+lz4 encode", time(&|c| lz4_rows(c)));
+        report("columnar", time(&|c| encode(c)));
+        report("columnar+lz4 encode", time(&|c| lz4_columnar(c)));
+        report(
+            "columnar+zstd encode",
+            time(&|c| Codec::Zstd.compress(&encode(c))),
+        );
+        report(
+            "columnar+snappy encode",
+            time(&|c| Codec::Snappy.compress(&encode(c))),
+        );
+
+        let rows_frames: Vec<Vec<u8>> = chunks.iter().map(|c| lz4_rows(c)).collect();
+        let columnar_frames: Vec<Vec<u8>> = chunks.iter().map(|c| lz4_columnar(c)).collect();
+        let start = Instant::now();
+        for frame in &rows_frames {
+            let raw = lz4_flex::decompress_size_prepended(frame).unwrap();
+            bincode2::deserialize::<Vec<Netflow>>(&raw).unwrap();
+        }
+        report(
+            "bincode+lz4 decode",
+            (rows_frames.iter().map(Vec::len).sum(), start.elapsed()),
+        );
+        let start = Instant::now();
+        for frame in &columnar_frames {
+            decode(&lz4_flex::decompress_size_prepended(frame).unwrap()).unwrap();
+        }
+        report(
+            "columnar+lz4 decode",
+            (columnar_frames.iter().map(Vec::len).sum(), start.elapsed()),
+        );
+    }
+}
@@ -0,0 +1,237 @@
+//! Framing shared by the data port and the result channel. After the 5 byte
+//! prefix naming the payload:
+//!
+//! ```text
+//! codec    u8
+//! checksum u32 BE, CRC32C of job, chunk, partition and payload
+//! job      u64 BE
+//! chunk    u64 BE
+//! part     u32 BE
+//! len      u32 BE
+//! payload  len bytes, compressed with codec
+//! ```
+//!
+//! The receiver answers every frame with `ACK`, `NAK` (checksum mismatch,
+//! send it again) or `ERR` (unusable, do not resend). A chunk result can also
+//! be answered `DUP`: taken, but another processor's result for the same
+//! chunk came first.
+
+use std::env;
+use std::io::{Error, ErrorKind};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::warn;
+
+use crate::codec::Codec;
+use crate::metrics::{Counter, Family};
+
+/// Bytes following the prefix up to the payload.
+const HEADER_LEN: usize = 29;
+/// Times a frame is sent again after the receiver reports a checksum mismatch.
+const MAX_RESENDS: usize = 3;
+/// How long to wait for the receiver to answer a frame.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub static METRICS: FrameMetrics = FrameMetrics {
+    sent_raw: Family::new(),
+    sent_compressed: Family::new(),
+    received_raw: Family::new(),
+    received_compressed: Family::new(),
+    resent: Counter::new(),
+};
+
+/// What the framing counts, for each side to serve among its own metrics.
+pub struct FrameMetrics {
+    /// Payloads of the frames encoded, before and after compression, by codec.
+    pub sent_raw: Family,
+    pub sent_compressed: Family,
+    /// Payloads of the frames read, decompressed and as sent, by codec.
+    pub received_raw: Family,
+    pub received_compressed: Family,
+    /// Frames sent again after the receiver answered `NAK`.
+    pub resent: Counter,
+}
+
+/// Which chunk of which job a frame carries, assigned by the distributer and
+/// echoed back with the chunk's results. `partition` is the source partition
+/// the chunk was read from, carried along so its logs can be followed end to
+/// end; frames that carry no chunk leave it 0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ChunkId {
+    pub job: u64,
+    pub chunk: u64,
+    pub partition: u32,
+}
+
+impl ChunkId {
+    /// An id outside any partition, for frames that carry no chunk.
+    pub const fn new(job: u64, chunk: u64) -> Self {
+        Self {
+            job,
+            chunk,
+            partition: 0,
+        }
+    }
+}
+
+/// Bounds on what a single frame can make the receiver allocate, so a peer
+/// cannot claim a 4 GiB frame or decompression bomb.
+#[derive(Debug, Clone, Copy)]
+pub struct FrameLimits {
+    /// Largest compressed payload read off the socket.
+    pub max_frame: usize,
+    /// Largest size a payload may claim to decompress to.
+    pub max_decompressed: usize,
+}
+
+impl Default for FrameLimits {
+    fn default() -> Self {
+        Self {
+            max_frame: 16 << 20,
+            max_decompressed: 64 << 20,
+        }
+    }
+}
+
+impl FrameLimits {
+    /// Reads `MAX_FRAME_SIZE` and `MAX_DECOMPRESSED_SIZE`, in bytes.
+    pub fn from_env() -> Result<Self, String> {
+        let mut limits = Self::default();
+        for (name, limit) in [
+            ("MAX_FRAME_SIZE", &mut limits.max_frame),
+            ("MAX_DECOMPRESSED_SIZE", &mut limits.max_decompressed),
+        ] {
+            if let Ok(value) = env::var(name) {
+                *limit = value
+                    .parse()
+                    .map_err(|_| format!("{} must be a size in bytes, got {:?}", name, value))?;
+            }
+        }
+        Ok(limits)
+    }
+}
+
+/// Why a frame was answered with something other than `ACK`.
+pub enum FrameError {
+    /// The payload does not match its checksum; answered with `NAK` so the
+    /// sender sends it again.
+    Checksum,
+    /// The frame arrived intact but cannot be decoded; answered with `ERR`.
+    Invalid(String),
+    /// The header announces more than the connection may send; answered with
+    /// `ERR` before the connection is closed.
+    Protocol(String),
+}
+
+pub fn encode(prefix: &[u8; 5], codec: Codec, id: ChunkId, raw: &[u8]) -> Vec<u8> {
+    let payload = codec.compress(raw);
+    METRICS.sent_raw.add(codec.as_str(), raw.len() as u64);
+    METRICS
+        .sent_compressed
+        .add(codec.as_str(), payload.len() as u64);
+    let mut frame = Vec::with_capacity(prefix.len() + HEADER_LEN + payload.len());
+    frame.extend_from_slice(prefix);
+    frame.push(codec.id());
+    frame.extend_from_slice(&checksum(id, &payload).to_be_bytes());
+    frame.extend_from_slice(&id.job.to_be_bytes());
+    frame.extend_from_slice(&id.chunk.to_be_bytes());
+    frame.extend_from_slice(&id.partition.to_be_bytes());
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&payload);
+    frame
+}
+
+fn checksum(id: ChunkId, payload: &[u8]) -> u32 {
+    let crc = crc32c::crc32c(&id.job.to_be_bytes());
+    let crc = crc32c::crc32c_append(crc, &id.chunk.to_be_bytes());
+    let crc = crc32c::crc32c_append(crc, &id.partition.to_be_bytes());
+    crc32c::crc32c_append(crc, payload)
+}
+
+/// Reads the rest of a frame after its prefix and returns the decompressed
+/// payload. The checksum is verified before anything is decompressed. `None`
+/// means the connection is done.
+pub async fn read<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    limits: FrameLimits,
+) -> Option<Result<(ChunkId, Vec<u8>), FrameError>> {
+    let mut header = [0u8; HEADER_LEN];
+    reader.read_exact(&mut header).await.ok()?;
+    let expected = u32::from_be_bytes(header[1..5].try_into().unwrap());
+    let id = ChunkId {
+        job: u64::from_be_bytes(header[5..13].try_into().unwrap()),
+        chunk: u64::from_be_bytes(header[13..21].try_into().unwrap()),
+        partition: u32::from_be_bytes(header[21..25].try_into().unwrap()),
+    };
+    let len = u32::from_be_bytes(header[25..].try_into().unwrap()) as usize;
+    if len > limits.max_frame {
+        return Some(Err(FrameError::Protocol(format!(
+            "{} byte frame, limit is {}",
+            len, limits.max_frame
+        ))));
+    }
+    let mut compressed = vec![0u8; len];
+    reader.read_exact(&mut compressed).await.ok()?;
+
+    if checksum(id, &compressed) != expected {
+        return Some(Err(FrameError::Checksum));
+    }
+    let Some(codec) = Codec::from_id(header[0]) else {
+        return Some(Err(FrameError::Invalid(format!(
+            "unknown codec {}",
+            header[0]
+        ))));
+    };
+    let raw = match codec.decompress(&compressed, limits.max_decompressed) {
+        Ok(raw) => raw,
+        Err(err) => return Some(Err(FrameError::Invalid(err.to_string()))),
+    };
+    METRICS.received_raw.add(codec.as_str(), raw.len() as u64);
+    METRICS
+        .received_compressed
+        .add(codec.as_str(), compressed.len() as u64);
+    Some(Ok((id, raw)))
+}
+
+/// Writes `frame` and waits for it to be acknowledged, sending it again on
+/// `NAK` up to [`MAX_RESENDS`] times. An error of kind `InvalidInput` means
+/// the receiver gave up on the frame itself and sending it again won't help,
+/// one of kind `AlreadyExists` that it answered `DUP`; anything else is a
+/// problem with the connection.
+pub async fn send<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    frame: &[u8],
+) -> Result<(), Error> {
+    for attempt in 0..=MAX_RESENDS {
+        stream.write_all(frame).await?;
+        let mut reply = [0u8; 3];
+        tokio::time::timeout(REPLY_TIMEOUT, stream.read_exact(&mut reply))
+            .await
+            .map_err(|_| Error::new(ErrorKind::TimedOut, "no reply to frame"))??;
+        match &reply {
+            b"ACK" => return Ok(()),
+            b"NAK" => {
+                METRICS.resent.inc();
+                warn!(
+                    attempt = attempt + 1,
+                    max = MAX_RESENDS,
+                    "frame reported corrupted, resending"
+                );
+            }
+            b"ERR" => return Err(Error::new(ErrorKind::InvalidInput, "frame rejected")),
+            b"DUP" => {
+                return Err(Error::new(
+                    ErrorKind::AlreadyExists,
+                    "another processor's result came first",
+                ));
+            }
+            _ => return Err(Error::new(ErrorKind::InvalidData, "unexpected reply")),
+        }
+    }
+    Err(Error::new(
+        ErrorKind::InvalidInput,
+        "frame still corrupted after resending",
+    ))
+}
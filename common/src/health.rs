@@ -0,0 +1,56 @@
+//! What a processor tells the distributer about itself on every heartbeat.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+/// A processor's answer to a `health-check`: a `healt` frame holding this,
+/// bincode encoded.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HealthReport {
+    /// Chunks received but not picked up by a worker yet.
+    pub queue_depth: u64,
+    pub queue_capacity: u64,
+    pub busy_workers: u32,
+    pub workers: u32,
+    /// Rows processed since the processor started.
+    pub records_processed: u64,
+    /// Resident set size in bytes, where the platform tells.
+    pub memory_bytes: Option<u64>,
+    /// Shutting down: finishing the chunks it has and wanting no new ones.
+    pub draining: bool,
+}
+
+impl HealthReport {
+    /// Whether the chunk queue is at least three quarters full, so new
+    /// chunks are better sent elsewhere.
+    pub fn saturated(&self) -> bool {
+        self.queue_capacity > 0 && self.queue_depth * 4 >= self.queue_capacity * 3
+    }
+
+    /// Nothing queued and a worker free, so a chunk sent now starts at once.
+    pub fn idle(&self) -> bool {
+        self.queue_depth == 0 && self.busy_workers < self.workers
+    }
+}
+
+impl fmt::Display for HealthReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "queue {}/{}, {}/{} workers busy, {} records",
+            self.queue_depth,
+            self.queue_capacity,
+            self.busy_workers,
+            self.workers,
+            self.records_processed
+        )?;
+        if let Some(bytes) = self.memory_bytes {
+            write!(f, ", {} MiB", bytes / (1024 * 1024))?;
+        }
+        if self.draining {
+            write!(f, ", draining")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,224 @@
+//! Just enough HTTP/1.1 for local endpoints like `/metrics` and the admin
+//! API: one request per connection, answered and closed.
+
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::Serialize;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::warn;
+
+/// Request heads larger than this are refused.
+const MAX_HEAD: usize = 16 * 1024;
+/// Request bodies larger than this are refused.
+const MAX_BODY: usize = 1024 * 1024;
+/// A client that has not sent its whole request by then is dropped.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct Request {
+    pub method: String,
+    /// Without the query string.
+    pub path: String,
+    /// Header names lowercased, in the order sent.
+    pub headers: Vec<(String, String)>,
+    /// As long as `Content-Length` says, empty without one.
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    /// The first header called `name`, which has to be lowercase.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn text(body: String) -> Self {
+        Self {
+            status: 200,
+            content_type: "text/plain; version=0.0.4",
+            body: body.into_bytes(),
+        }
+    }
+
+    pub fn json<T: Serialize>(status: u16, value: &T) -> Self {
+        Self {
+            status,
+            content_type: "application/json",
+            body: serde_json::to_vec(value).expect("failed to encode response"),
+        }
+    }
+
+    pub fn not_found() -> Self {
+        Self::error(404, "not found")
+    }
+
+    pub fn error(status: u16, message: &str) -> Self {
+        Self {
+            status,
+            content_type: "text/plain",
+            body: format!("{}\n", message).into_bytes(),
+        }
+    }
+}
+
+/// Answers every connection on `listener` with `handle`.
+pub async fn serve<F>(listener: TcpListener, handle: F) -> io::Result<()>
+where
+    F: Fn(&Request) -> Response + Send + Sync + 'static,
+{
+    let handle = Arc::new(handle);
+    loop {
+        let (socket, addr) = listener.accept().await?;
+        let handle = Arc::clone(&handle);
+        tokio::spawn(async move {
+            if let Err(e) = answer(socket, handle.as_ref()).await {
+                warn!(%addr, error = %e, "HTTP request failed");
+            }
+        });
+    }
+}
+
+async fn answer<F>(socket: TcpStream, handle: &F) -> io::Result<()>
+where
+    F: Fn(&Request) -> Response,
+{
+    let mut reader = BufReader::new(socket);
+    let request = tokio::time::timeout(REQUEST_TIMEOUT, read_request(&mut reader))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "request not read in time"))?;
+    let response = match request? {
+        Ok(request) => handle(&request),
+        Err(message) => Response::error(400, message),
+    };
+    let reason = match response.status {
+        200 => "OK",
+        201 => "Created",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        409 => "Conflict",
+        _ => "Internal Server Error",
+    };
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        reason,
+        response.content_type,
+        response.body.len()
+    );
+    let socket = reader.get_mut();
+    socket.write_all(head.as_bytes()).await?;
+    socket.write_all(&response.body).await?;
+    socket.shutdown().await
+}
+
+/// Reads a request head and the body its `Content-Length` announces. The
+/// outer error is the connection failing, the inner one a request that
+/// cannot be answered.
+async fn read_request(
+    reader: &mut BufReader<TcpStream>,
+) -> io::Result<Result<Request, &'static str>> {
+    let mut head = Vec::new();
+    let mut request_line = None;
+    let mut headers = Vec::new();
+    let mut content_length = 0;
+    loop {
+        let mut line = String::new();
+        // one byte past the limit, so a line that reaches it is caught
+        let room = (MAX_HEAD + 1 - head.len()) as u64;
+        if (&mut *reader).take(room).read_line(&mut line).await? == 0 {
+            return Ok(Err("connection closed mid request"));
+        }
+        head.extend_from_slice(line.as_bytes());
+        if head.len() > MAX_HEAD {
+            return Ok(Err("request head too large"));
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if request_line.is_none() {
+            request_line = Some(line.to_string());
+        } else if let Some((name, value)) = line.split_once(':')
+            && name.eq_ignore_ascii_case("content-length")
+        {
+            let Ok(length) = value.trim().parse() else {
+                return Ok(Err("invalid content length"));
+            };
+            content_length = length;
+        } else if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.trim().to_ascii_lowercase(), value.trim().to_string()));
+        }
+    }
+    if content_length > MAX_BODY {
+        return Ok(Err("request body too large"));
+    }
+    let mut body = vec![0u8; content_length];
+    reader.read_exact(&mut body).await?;
+    let request_line = request_line.unwrap_or_default();
+    let mut parts = request_line.split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        return Ok(Err("invalid request line"));
+    };
+    let path = target.split('?').next().unwrap_or(target);
+    Ok(Ok(Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        headers,
+        body,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_serve() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, |request| match request.path.as_str() {
+            "/hello" => Response::text(format!("hello {}", request.method)),
+            "/agent" => Response::text(request.header("user-agent").unwrap_or("").to_string()),
+            "/echo" => Response::text(String::from_utf8_lossy(&request.body).into_owned()),
+            _ => Response::not_found(),
+        }));
+
+        let request = |raw: &'static str| async move {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream.write_all(raw.as_bytes()).await.unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            response
+        };
+        let hello = request("GET /hello?x=1 HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        assert!(hello.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(hello.ends_with("\r\n\r\nhello GET"));
+        let missing = request("GET /nothing HTTP/1.1\r\n\r\n").await;
+        assert!(missing.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        let echo = request("POST /echo HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello").await;
+        assert!(echo.ends_with("\r\n\r\nhello"));
+        let agent = request("GET /agent HTTP/1.1\r\nUser-Agent: curl\r\n\r\n").await;
+        assert!(agent.ends_with("\r\n\r\ncurl"));
+
+        // an endless line is cut off at the head limit
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"GET /").await.unwrap();
+        let _ = stream.write_all(&vec![b'a'; MAX_HEAD + 1]).await;
+        let mut response = String::new();
+        let _ = stream.read_to_string(&mut response).await;
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    }
+}
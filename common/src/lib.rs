@@ -0,0 +1,17 @@
+//! What the distributer and the processors share: the flows and record
+//! batches they exchange and the results sent back for them, their columnar
+//! encoding, the frames carrying them, the handshake opening every connection
+//! and the health reports sent on every heartbeat, the HTTP server behind
+//! their local endpoints and the metrics it serves.
+
+pub mod auth;
+pub mod codec;
+pub mod columnar;
+pub mod frame;
+pub mod health;
+pub mod http;
+pub mod metrics;
+pub mod netflow;
+pub mod record;
+pub mod result;
+pub mod spill;
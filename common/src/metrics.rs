@@ -0,0 +1,181 @@
+//! Counters, gauges and histograms kept for the whole process, and the
+//! Prometheus text format they are served in.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+#[derive(Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub const fn new() -> Self {
+        Self(AtomicU64::new(0))
+    }
+
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Values split by one label, counters or gauges depending on how they are
+/// updated.
+#[derive(Default)]
+pub struct Family(Mutex<BTreeMap<String, u64>>);
+
+impl Family {
+    pub const fn new() -> Self {
+        Self(Mutex::new(BTreeMap::new()))
+    }
+
+    pub fn add(&self, label: &str, n: u64) {
+        let mut values = self.0.lock().unwrap();
+        match values.get_mut(label) {
+            Some(value) => *value += n,
+            None => {
+                values.insert(label.to_string(), n);
+            }
+        }
+    }
+
+    pub fn set(&self, label: &str, value: u64) {
+        self.0.lock().unwrap().insert(label.to_string(), value);
+    }
+
+    pub fn remove(&self, label: &str) {
+        self.0.lock().unwrap().remove(label);
+    }
+}
+
+pub struct Histogram {
+    /// Upper bounds of the buckets, ascending; `+Inf` is implied.
+    bounds: &'static [f64],
+    state: Mutex<HistogramState>,
+}
+
+struct HistogramState {
+    /// Observations per bucket, not cumulative; empty until the first one.
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    pub const fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            state: Mutex::new(HistogramState {
+                buckets: Vec::new(),
+                sum: 0.0,
+                count: 0,
+            }),
+        }
+    }
+
+    pub fn observe(&self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        let bucket = self.bounds.partition_point(|bound| *bound < seconds);
+        let mut state = self.state.lock().unwrap();
+        state.buckets.resize(self.bounds.len() + 1, 0);
+        state.buckets[bucket] += 1;
+        state.sum += seconds;
+        state.count += 1;
+    }
+}
+
+/// The Prometheus text format being written.
+#[derive(Default)]
+pub struct Exposition(String);
+
+impl Exposition {
+    fn header(&mut self, name: &str, help: &str, kind: &str) {
+        let _ = writeln!(self.0, "# HELP {} {}", name, help);
+        let _ = writeln!(self.0, "# TYPE {} {}", name, kind);
+    }
+
+    pub fn counter(&mut self, name: &str, help: &str, counter: &Counter) {
+        self.value(name, help, "counter", counter.get());
+    }
+
+    /// A single value read at the time of the request.
+    pub fn value(&mut self, name: &str, help: &str, kind: &str, value: u64) {
+        self.header(name, help, kind);
+        let _ = writeln!(self.0, "{} {}", name, value);
+    }
+
+    pub fn family(&mut self, name: &str, help: &str, kind: &str, label: &str, family: &Family) {
+        self.header(name, help, kind);
+        for (value, n) in family.0.lock().unwrap().iter() {
+            let value = value.replace('\\', "\\\\").replace('"', "\\\"");
+            let _ = writeln!(self.0, "{}{{{}=\"{}\"}} {}", name, label, value, n);
+        }
+    }
+
+    pub fn histogram(&mut self, name: &str, help: &str, histogram: &Histogram) {
+        self.header(name, help, "histogram");
+        let state = histogram.state.lock().unwrap();
+        let mut cumulative = 0;
+        for (i, bound) in histogram.bounds.iter().enumerate() {
+            cumulative += state.buckets.get(i).copied().unwrap_or(0);
+            let _ = writeln!(self.0, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+        }
+        let _ = writeln!(self.0, "{}_bucket{{le=\"+Inf\"}} {}", name, state.count);
+        let _ = writeln!(self.0, "{}_sum {}", name, state.sum);
+        let _ = writeln!(self.0, "{}_count {}", name, state.count);
+    }
+
+    pub fn finish(self) -> String {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exposition() {
+        let requests = Counter::new();
+        requests.add(3);
+        let rows = Family::new();
+        rows.add("1", 10);
+        rows.add("0", 5);
+        rows.add("1", 2);
+        let latency = Histogram::new(&[0.1, 1.0]);
+        latency.observe(Duration::from_millis(50));
+        latency.observe(Duration::from_millis(500));
+        latency.observe(Duration::from_secs(3));
+
+        let mut out = Exposition::default();
+        out.counter("requests_total", "Requests.", &requests);
+        out.family("rows_total", "Rows.", "counter", "partition", &rows);
+        out.histogram("latency_seconds", "Latency.", &latency);
+        assert_eq!(
+            out.finish(),
+            "# HELP requests_total Requests.\n\
+             # TYPE requests_total counter\n\
+             requests_total 3\n\
+             # HELP rows_total Rows.\n\
+             # TYPE rows_total counter\n\
+             rows_total{partition=\"0\"} 5\n\
+             rows_total{partition=\"1\"} 12\n\
+             # HELP latency_seconds Latency.\n\
+             # TYPE latency_seconds histogram\n\
+             latency_seconds_bucket{le=\"0.1\"} 1\n\
+             latency_seconds_bucket{le=\"1\"} 2\n\
+             latency_seconds_bucket{le=\"+Inf\"} 3\n\
+             latency_seconds_sum 3.55\n\
+             latency_seconds_count 3\n"
+        );
+    }
+}
@@ -0,0 +1,115 @@
+//! The flow record both sides exchange, and the protocol numbers in it.
+
+use std::net::IpAddr;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "postgres", derive(sqlx::FromRow))]
+pub struct Netflow {
+    pub flow_id: i64,
+    pub src_ip: Option<IpAddr>,
+    pub dst_ip: Option<IpAddr>,
+    pub src_port: Option<i32>,
+    pub dst_port: Option<i32>,
+    pub protocol: Option<Protocol>,
+    pub bytes: Option<i64>,
+    pub packets: Option<i64>,
+    #[serde(with = "chrono::serde::ts_microseconds_option")]
+    pub start_ts: Option<DateTime<Utc>>,
+    #[serde(with = "chrono::serde::ts_microseconds_option")]
+    pub end_ts: Option<DateTime<Utc>>,
+    pub src_asn: Option<i32>,
+    pub dst_asn: Option<i32>,
+}
+
+impl Netflow {
+    /// Whether every column is set; processors drop flows that are not.
+    pub fn is_valid(&self) -> bool {
+        self.src_ip.is_some()
+            && self.dst_ip.is_some()
+            && self.protocol.is_some()
+            && self.bytes.is_some()
+            && self.packets.is_some()
+            && self.start_ts.is_some()
+            && self.end_ts.is_some()
+            && self.src_asn.is_some()
+            && self.dst_asn.is_some()
+    }
+}
+
+/// IANA protocol number, stored as `SMALLINT` and sent as a single byte.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(from = "u8", into = "u8")]
+pub enum Protocol {
+    Icmp,
+    Tcp,
+    Udp,
+    Gre,
+    Esp,
+    Icmpv6,
+    Sctp,
+    Other(u8),
+}
+
+impl From<u8> for Protocol {
+    fn from(number: u8) -> Self {
+        match number {
+            1 => Protocol::Icmp,
+            6 => Protocol::Tcp,
+            17 => Protocol::Udp,
+            47 => Protocol::Gre,
+            50 => Protocol::Esp,
+            58 => Protocol::Icmpv6,
+            132 => Protocol::Sctp,
+            n => Protocol::Other(n),
+        }
+    }
+}
+
+impl From<Protocol> for u8 {
+    fn from(protocol: Protocol) -> Self {
+        match protocol {
+            Protocol::Icmp => 1,
+            Protocol::Tcp => 6,
+            Protocol::Udp => 17,
+            Protocol::Gre => 47,
+            Protocol::Esp => 50,
+            Protocol::Icmpv6 => 58,
+            Protocol::Sctp => 132,
+            Protocol::Other(n) => n,
+        }
+    }
+}
+
+#[cfg(feature = "postgres")]
+mod postgres {
+    use sqlx::{
+        Decode, Encode, Postgres,
+        encode::IsNull,
+        error::BoxDynError,
+        postgres::{PgArgumentBuffer, PgTypeInfo, PgValueRef},
+    };
+
+    use super::Protocol;
+
+    impl sqlx::Type<Postgres> for Protocol {
+        fn type_info() -> PgTypeInfo {
+            <i16 as sqlx::Type<Postgres>>::type_info()
+        }
+    }
+
+    impl<'r> Decode<'r, Postgres> for Protocol {
+        fn decode(value: PgValueRef<'r>) -> Result<Self, BoxDynError> {
+            let number = <i16 as Decode<Postgres>>::decode(value)?;
+            Ok(Protocol::from(u8::try_from(number)?))
+        }
+    }
+
+    impl Encode<'_, Postgres> for Protocol {
+        fn encode_by_ref(&self, buf: &mut PgArgumentBuffer) -> IsNull {
+            <i16 as Encode<Postgres>>::encode(u8::from(*self) as i16, buf)
+        }
+    }
+}
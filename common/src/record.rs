@@ -0,0 +1,262 @@
+//! Self describing record batches, what sources other than netflow tables
+//! are shipped as, and the transform a job applies to them.
+
+use std::cmp::Ordering;
+use std::hash::{Hash, Hasher};
+use std::net::IpAddr;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::netflow::Netflow;
+
+/// Column names of the typed `Netflow` row. A source whose columns are exactly
+/// these is shipped on the `chunk` fast path instead of as generic records.
+pub const NETFLOW_COLUMNS: [&str; 12] = [
+    "flow_id", "src_ip", "dst_ip", "src_port", "dst_port", "protocol", "bytes", "packets",
+    "start_ts", "end_ts", "src_asn", "dst_asn",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ColumnType {
+    Bool,
+    Int,
+    Float,
+    Text,
+    Ip,
+    /// Microseconds since the unix epoch, UTC.
+    Timestamp,
+    Bytes,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Column {
+    pub name: String,
+    pub ty: ColumnType,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Schema {
+    pub columns: Vec<Column>,
+}
+
+impl Schema {
+    pub fn index_of(&self, name: &str) -> Option<usize> {
+        self.columns.iter().position(|c| c.name == name)
+    }
+
+    pub fn is_netflow(&self) -> bool {
+        self.columns.len() == NETFLOW_COLUMNS.len()
+            && NETFLOW_COLUMNS
+                .iter()
+                .all(|name| self.columns.iter().any(|c| c.name == *name))
+    }
+}
+
+/// A single typed cell. Floats compare and hash by bit pattern so values can
+/// be used as group keys.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Text(String),
+    Ip(IpAddr),
+    Timestamp(i64),
+    Bytes(Vec<u8>),
+}
+
+impl Value {
+    pub fn timestamp(ts: DateTime<Utc>) -> Self {
+        Value::Timestamp(ts.timestamp_micros())
+    }
+
+    /// Parses a literal (e.g. from a filter expression) as a value of type `ty`.
+    pub fn parse(literal: &str, ty: ColumnType) -> Option<Value> {
+        Some(match ty {
+            ColumnType::Bool => Value::Bool(literal.parse().ok()?),
+            ColumnType::Int => Value::Int(literal.parse().ok()?),
+            ColumnType::Float => Value::Float(literal.parse().ok()?),
+            ColumnType::Text => Value::Text(literal.to_string()),
+            ColumnType::Ip => Value::Ip(literal.parse().ok()?),
+            ColumnType::Timestamp => match literal.parse::<i64>() {
+                Ok(micros) => Value::Timestamp(micros),
+                Err(_) => Value::Timestamp(
+                    chrono::DateTime::parse_from_rfc3339(literal)
+                        .ok()?
+                        .timestamp_micros(),
+                ),
+            },
+            ColumnType::Bytes => Value::Bytes(literal.as_bytes().to_vec()),
+        })
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::Int(v) | Value::Timestamp(v) => Some(*v as f64),
+            Value::Float(v) => Some(*v),
+            _ => None,
+        }
+    }
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Value::Float(a), Value::Float(b)) => a.to_bits() == b.to_bits(),
+            (Value::Null, Value::Null) => true,
+            (Value::Bool(a), Value::Bool(b)) => a == b,
+            (Value::Int(a), Value::Int(b)) => a == b,
+            (Value::Text(a), Value::Text(b)) => a == b,
+            (Value::Ip(a), Value::Ip(b)) => a == b,
+            (Value::Timestamp(a), Value::Timestamp(b)) => a == b,
+            (Value::Bytes(a), Value::Bytes(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+impl Eq for Value {}
+
+impl Hash for Value {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        match self {
+            Value::Null => {}
+            Value::Bool(v) => v.hash(state),
+            Value::Int(v) | Value::Timestamp(v) => v.hash(state),
+            Value::Float(v) => v.to_bits().hash(state),
+            Value::Text(v) => v.hash(state),
+            Value::Ip(v) => v.hash(state),
+            Value::Bytes(v) => v.hash(state),
+        }
+    }
+}
+
+impl PartialOrd for Value {
+    /// Only values of the same variant are ordered; nulls and mixed types are not.
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        match (self, other) {
+            (Value::Bool(a), Value::Bool(b)) => a.partial_cmp(b),
+            (Value::Int(a), Value::Int(b)) => a.partial_cmp(b),
+            (Value::Float(a), Value::Float(b)) => a.partial_cmp(b),
+            (Value::Text(a), Value::Text(b)) => a.partial_cmp(b),
+            (Value::Ip(a), Value::Ip(b)) => a.partial_cmp(b),
+            (Value::Timestamp(a), Value::Timestamp(b)) => a.partial_cmp(b),
+            (Value::Bytes(a), Value::Bytes(b)) => a.partial_cmp(b),
+            _ => None,
+        }
+    }
+}
+
+/// Self describing rows: every value in `rows[i]` lines up with `schema.columns`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecordBatch {
+    pub schema: Schema,
+    pub rows: Vec<Vec<Value>>,
+}
+
+impl RecordBatch {
+    /// `netflows` as generic records, columns in [`NETFLOW_COLUMNS`] order.
+    pub fn from_netflows(netflows: &[Netflow]) -> Self {
+        let types = [
+            ColumnType::Int,
+            ColumnType::Ip,
+            ColumnType::Ip,
+            ColumnType::Int,
+            ColumnType::Int,
+            ColumnType::Int,
+            ColumnType::Int,
+            ColumnType::Int,
+            ColumnType::Timestamp,
+            ColumnType::Timestamp,
+            ColumnType::Int,
+            ColumnType::Int,
+        ];
+        let columns = NETFLOW_COLUMNS
+            .iter()
+            .zip(types)
+            .map(|(name, ty)| Column {
+                name: name.to_string(),
+                ty,
+            })
+            .collect();
+        let int = |v: Option<i64>| v.map_or(Value::Null, Value::Int);
+        let ip = |v: Option<IpAddr>| v.map_or(Value::Null, Value::Ip);
+        let ts = |v: Option<DateTime<Utc>>| v.map_or(Value::Null, Value::timestamp);
+        let rows = netflows
+            .iter()
+            .map(|n| {
+                vec![
+                    Value::Int(n.flow_id),
+                    ip(n.src_ip),
+                    ip(n.dst_ip),
+                    int(n.src_port.map(i64::from)),
+                    int(n.dst_port.map(i64::from)),
+                    int(n.protocol.map(|p| u8::from(p) as i64)),
+                    int(n.bytes),
+                    int(n.packets),
+                    ts(n.start_ts),
+                    ts(n.end_ts),
+                    int(n.src_asn.map(i64::from)),
+                    int(n.dst_asn.map(i64::from)),
+                ]
+            })
+            .collect();
+        Self {
+            schema: Schema { columns },
+            rows,
+        }
+    }
+
+    /// Fails unless every row has one value per column, which everything
+    /// indexing rows by column relies on.
+    pub fn check_width(&self) -> Result<(), String> {
+        let width = self.schema.columns.len();
+        match self.rows.iter().position(|row| row.len() != width) {
+            Some(i) => Err(format!(
+                "row {} has {} values for {} columns",
+                i,
+                self.rows[i].len(),
+                width
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A job's own filter and projection of its record batches, in the syntax
+/// of the processors' `FILTER` and `PROJECT`, which it replaces. Netflow
+/// chunks are aggregated as they are and take none.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TransformSpec {
+    pub filter: Option<String>,
+    pub project: Option<String>,
+}
+
+impl TransformSpec {
+    /// Checks the spec parses the way the processors parse it: `filter` is
+    /// comma separated `<column><op><literal>` with `op` one of `>=`, `<=`,
+    /// `!=`, `=`, `>` and `<`, `project` comma separated column names.
+    pub fn validate(&self) -> Result<(), String> {
+        const OPS: [&str; 6] = [">=", "<=", "!=", "=", ">", "<"];
+        if let Some(filter) = &self.filter {
+            for expr in filter.split(',') {
+                let valid = OPS.iter().find_map(|op| expr.split_once(op)).is_some_and(
+                    |(column, literal)| !column.trim().is_empty() && !literal.trim().is_empty(),
+                );
+                if !valid {
+                    return Err(format!("invalid filter {:?}", expr));
+                }
+            }
+        }
+        if let Some(project) = &self.project
+            && project.split(',').any(|column| column.trim().is_empty())
+        {
+            return Err(format!("invalid projection {:?}", project));
+        }
+        Ok(())
+    }
+}
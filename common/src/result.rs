@@ -0,0 +1,34 @@
+//! What processors send back about the chunks they were given.
+
+use serde::{Deserialize, Serialize};
+
+use crate::frame::ChunkId;
+use crate::netflow::Netflow;
+use crate::record::RecordBatch;
+
+/// How many rows of a chunk made it through validation or the filters.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkStats {
+    pub received: u64,
+    pub kept: u64,
+    pub rejected: u64,
+}
+
+/// Rows of a chunk's output, as carried by a `block` frame.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ChunkOutput {
+    Netflow(Vec<Netflow>),
+    Records(RecordBatch),
+}
+
+/// What a processor reports for one chunk as soon as it is processed, sent
+/// back to the distributer in a `reslt` frame carrying the chunk's id. The
+/// rows follow in `block` frames once the job is flushed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChunkResult {
+    pub id: ChunkId,
+    pub stats: ChunkStats,
+    /// This chunk's share of the aggregate: the group columns, `count`, then
+    /// `sum_<column>` columns, so partial aggregates add up row by row.
+    pub aggregates: RecordBatch,
+}
@@ -0,0 +1,128 @@
+//! Run files holding a job's output, sorted, until it is merged: written by
+//! processors as their output spills and by the distributer as uploads of
+//! them arrive.
+//!
+//! A run file is a sequence of blocks, each a u32 LE length followed by a
+//! bincode [`ChunkOutput`] of at most [`BLOCK_ROWS`] rows, sorted by the
+//! job's [`SortKey`] across the whole file.
+
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::netflow::Netflow;
+use crate::record::Value;
+use crate::result::ChunkOutput;
+
+/// Rows per block in a run file, and per `block` frame on upload.
+pub const BLOCK_ROWS: usize = 10_000;
+
+/// What runs are ordered by. Processors sort their runs by their own
+/// `SORT_BY`, which has to match the distributer's.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortKey {
+    FlowId,
+    StartTs,
+}
+
+impl SortKey {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "flow_id" => Some(SortKey::FlowId),
+            "start_ts" => Some(SortKey::StartTs),
+            _ => None,
+        }
+    }
+
+    /// The record column holding the key.
+    pub fn column(self) -> &'static str {
+        match self {
+            SortKey::FlowId => "flow_id",
+            SortKey::StartTs => "start_ts",
+        }
+    }
+
+    /// Rows without the key sort first.
+    pub fn netflow(self, netflow: &Netflow) -> Option<i64> {
+        match self {
+            SortKey::FlowId => Some(netflow.flow_id),
+            SortKey::StartTs => netflow.start_ts.map(|ts| ts.timestamp_micros()),
+        }
+    }
+
+    /// The key of a record whose key column holds `value`.
+    pub fn value(value: &Value) -> Option<i64> {
+        match value {
+            Value::Int(v) | Value::Timestamp(v) => Some(*v),
+            _ => None,
+        }
+    }
+
+    /// Records whose schema lacks the key column keep their arrival order.
+    pub fn sort(self, output: &mut ChunkOutput) {
+        match output {
+            ChunkOutput::Netflow(rows) => rows.sort_by_key(|n| self.netflow(n)),
+            ChunkOutput::Records(batch) => {
+                if let Some(i) = batch.schema.index_of(self.column()) {
+                    batch.rows.sort_by_key(|row| Self::value(&row[i]));
+                }
+            }
+        }
+    }
+}
+
+/// Writes a run file one block at a time.
+pub struct RunWriter {
+    path: PathBuf,
+    out: BufWriter<File>,
+}
+
+impl RunWriter {
+    pub fn create(path: PathBuf) -> io::Result<Self> {
+        let out = BufWriter::new(File::create(&path)?);
+        Ok(Self { path, out })
+    }
+
+    pub fn write(&mut self, block: &ChunkOutput) -> io::Result<()> {
+        let raw = bincode2::serialize(block).map_err(io::Error::other)?;
+        self.out.write_all(&(raw.len() as u32).to_le_bytes())?;
+        self.out.write_all(&raw)
+    }
+
+    pub fn finish(mut self) -> io::Result<PathBuf> {
+        self.out.flush()?;
+        Ok(self.path)
+    }
+}
+
+/// Reads a run file back one block at a time.
+pub struct RunReader {
+    file: BufReader<File>,
+}
+
+impl RunReader {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        Ok(Self {
+            file: BufReader::new(File::open(path)?),
+        })
+    }
+}
+
+impl Iterator for RunReader {
+    type Item = io::Result<ChunkOutput>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut len = [0u8; 4];
+        match self.file.read_exact(&mut len) {
+            Ok(()) => {}
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => return None,
+            Err(err) => return Some(Err(err)),
+        }
+        let mut raw = vec![0u8; u32::from_le_bytes(len) as usize];
+        Some(
+            self.file
+                .read_exact(&mut raw)
+                .and_then(|_| bincode2::deserialize(&raw).map_err(io::Error::other)),
+        )
+    }
+}
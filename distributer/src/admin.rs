@@ -0,0 +1,289 @@
+//! The admin API, JSON over HTTP on `ADMIN_ADDR`:
+//!
+//! ```text
+//! GET  /processors               registered processors and their health
+//! POST /processors/<addr>/drain  send a processor no new chunks
+//! POST /processors/<addr>/evict  drop a processor, its chunks go to the rest
+//! GET  /jobs                     every job submitted
+//! POST /jobs                     submit a job, the body a JobSpec
+//! GET  /jobs/<id>                one job and its progress per partition
+//! POST /jobs/<id>/cancel         cancel a job that has not finished
+//! POST /jobs/<id>/pause          stop a running job producing chunks
+//! POST /jobs/<id>/resume         carry on with a paused job
+//! GET  /errors                   the latest warnings and errors logged
+//! ```
+//!
+//! With `CLUSTER_SECRET` set every request has to carry it as
+//! `Authorization: Bearer <secret>`. Without it the API only listens on a
+//! loopback address.
+
+use std::sync::{Arc, Mutex};
+
+use common::auth::Secret;
+use serde_json::json;
+
+use crate::http::{Request, Response};
+use crate::jobs::{ControlError, JobSpec, JobState, JobStatus, Jobs};
+use crate::logging::RECENT_ERRORS;
+use crate::producer::Producer;
+use crate::result::ResultStore;
+
+pub struct Admin {
+    producer: Arc<Producer>,
+    results: Arc<Mutex<ResultStore>>,
+    jobs: Arc<Jobs>,
+    secret: Option<Secret>,
+}
+
+impl Admin {
+    pub fn new(
+        producer: Arc<Producer>,
+        results: Arc<Mutex<ResultStore>>,
+        jobs: Arc<Jobs>,
+        secret: Option<Secret>,
+    ) -> Self {
+        Self {
+            producer,
+            results,
+            jobs,
+            secret,
+        }
+    }
+
+    pub fn handle(&self, request: &Request) -> Response {
+        if let Some(secret) = &self.secret {
+            let token = request
+                .header("authorization")
+                .and_then(|value| value.strip_prefix("Bearer "));
+            if !token.is_some_and(|token| secret.matches(token.trim().as_bytes())) {
+                return error(401, "missing or wrong bearer token");
+            }
+        }
+        let path: Vec<&str> = request.path.trim_matches('/').split('/').collect();
+        match (request.method.as_str(), path.as_slice()) {
+            ("GET", ["processors"]) => Response::json(200, &self.producer.processors()),
+            ("POST", ["processors", addr, "drain"]) => {
+                if !self.producer.drain(addr) {
+                    return error(404, "unknown processor");
+                }
+                Response::json(200, &json!({ "drained": addr }))
+            }
+            ("POST", ["processors", addr, "evict"]) => {
+                if !self.producer.evict_processor(addr, &self.results) {
+                    return error(404, "unknown processor");
+                }
+                Response::json(200, &json!({ "evicted": addr }))
+            }
+            ("GET", ["jobs"]) => {
+                let jobs: Vec<JobStatus> = self
+                    .jobs
+                    .list()
+                    .into_iter()
+                    .map(|status| self.progress(status))
+                    .collect();
+                Response::json(200, &jobs)
+            }
+            ("POST", ["jobs"]) => {
+                let spec = if request.body.iter().all(u8::is_ascii_whitespace) {
+                    JobSpec::default()
+                } else {
+                    match serde_json::from_slice(&request.body) {
+                        Ok(spec) => spec,
+                        Err(e) => return error(400, &format!("invalid job: {}", e)),
+                    }
+                };
+                if let Err(e) = spec.validate() {
+                    return error(400, &format!("invalid job: {}", e));
+                }
+                let job = self.jobs.submit(spec);
+                Response::json(201, &json!({ "job": job }))
+            }
+            ("GET", ["jobs", job]) => {
+                let Ok(job) = job.parse() else {
+                    return error(400, "invalid job id");
+                };
+                match self.jobs.status(job) {
+                    Some(status) => Response::json(200, &self.progress(status)),
+                    None => error(404, "unknown job"),
+                }
+            }
+            ("POST", ["jobs", job, "cancel"]) => {
+                let Ok(job) = job.parse() else {
+                    return error(400, "invalid job id");
+                };
+                match self.jobs.cancel(job) {
+                    Ok(state) => {
+                        // the job loop cleans up after the jobs it is still running
+                        if state == JobState::Flushed {
+                            self.results.lock().unwrap().cancel(job);
+                            let producer = self.producer.clone();
+                            tokio::spawn(async move { producer.cancel(job).await });
+                        }
+                        Response::json(200, &json!({ "cancelled": job }))
+                    }
+                    Err(e) => control_error(e),
+                }
+            }
+            ("POST", ["jobs", job, "pause"]) => {
+                let Ok(job) = job.parse() else {
+                    return error(400, "invalid job id");
+                };
+                match self.jobs.pause(job) {
+                    Ok(()) => Response::json(200, &json!({ "paused": job })),
+                    Err(e) => control_error(e),
+                }
+            }
+            ("POST", ["jobs", job, "resume"]) => {
+                let Ok(job) = job.parse() else {
+                    return error(400, "invalid job id");
+                };
+                match self.jobs.resume(job) {
+                    Ok(()) => Response::json(200, &json!({ "resumed": job })),
+                    Err(e) => control_error(e),
+                }
+            }
+            ("GET", ["errors"]) => Response::json(200, &RECENT_ERRORS.snapshot()),
+            _ => Response::not_found(),
+        }
+    }
+
+    /// Fills in the job's share of the processors, and the chunks processed
+    /// so far for a job still waiting on its results.
+    fn progress(&self, mut status: JobStatus) -> JobStatus {
+        // a job that stopped sending keeps the share it had
+        if let Some(share) = self.producer.schedule.share(status.job) {
+            status.share = Some(share);
+        }
+        if matches!(
+            status.state,
+            JobState::Running | JobState::Paused | JobState::Flushed
+        ) {
+            let processed = self.results.lock().unwrap().processed(status.job);
+            status.processed = processed.into_iter().collect();
+        }
+        status
+    }
+}
+
+fn error(status: u16, message: &str) -> Response {
+    Response::json(status, &json!({ "error": message }))
+}
+
+fn control_error(e: ControlError) -> Response {
+    match e {
+        ControlError::Unknown => error(404, "unknown job"),
+        ControlError::Invalid(state) => error(409, &format!("job is {}", state.as_str())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::producer::HeartbeatConfig;
+
+    fn request(method: &str, path: &str, body: &str) -> Request {
+        Request {
+            method: method.to_string(),
+            path: path.to_string(),
+            headers: Vec::new(),
+            body: body.as_bytes().to_vec(),
+        }
+    }
+
+    fn body(response: &Response) -> serde_json::Value {
+        serde_json::from_slice(&response.body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_admin_jobs() {
+        let admin = Admin::new(
+            Arc::new(Producer::new(None, HeartbeatConfig::default())),
+            Arc::new(Mutex::new(ResultStore::new(
+                std::env::temp_dir().join("admin-test"),
+            ))),
+            Arc::new(Jobs::default()),
+            None,
+        );
+
+        let spec = r#"{"source": "csv:a.csv", "transform": {"filter": "bytes>0"}, "weight": 2}"#;
+        let submitted = admin.handle(&request("POST", "/jobs", spec));
+        assert_eq!(submitted.status, 201);
+        assert_eq!(body(&submitted)["job"], 0);
+        assert_eq!(admin.handle(&request("POST", "/jobs", "")).status, 201);
+        let invalid = admin.handle(&request("POST", "/jobs", r#"{"sauce": "x"}"#));
+        assert_eq!(invalid.status, 400);
+        let idle = admin.handle(&request("POST", "/jobs", r#"{"weight": 0}"#));
+        assert_eq!(idle.status, 400);
+        for spec in [
+            r#"{"query": "netflow; DROP TABLE netflow"}"#,
+            r#"{"partition_column": "1) OR (1"}"#,
+            r#"{"transform": {"filter": "bytes"}}"#,
+        ] {
+            assert_eq!(admin.handle(&request("POST", "/jobs", spec)).status, 400);
+        }
+
+        let (job, spec, _control) = admin.jobs.next().await;
+        assert_eq!((job, spec.source.as_deref()), (0, Some("csv:a.csv")));
+        admin.producer.start(job, spec.weight(), spec.transform);
+        admin.jobs.produced(0, 3);
+        let status = body(&admin.handle(&request("GET", "/jobs/0", "")));
+        assert_eq!(status["state"], "running");
+        assert_eq!(status["produced"]["3"], 1);
+        assert_eq!(status["spec"]["transform"]["filter"], "bytes>0");
+        assert_eq!(status["share"]["weight"], 2);
+
+        assert_eq!(
+            admin.handle(&request("POST", "/jobs/0/pause", "")).status,
+            200
+        );
+        assert_eq!(
+            admin.handle(&request("POST", "/jobs/0/pause", "")).status,
+            409
+        );
+        let status = body(&admin.handle(&request("GET", "/jobs/0", "")));
+        assert_eq!(status["state"], "paused");
+        assert_eq!(
+            admin.handle(&request("POST", "/jobs/0/resume", "")).status,
+            200
+        );
+        assert_eq!(
+            admin.handle(&request("POST", "/jobs/1/resume", "")).status,
+            409
+        );
+
+        assert_eq!(
+            admin.handle(&request("POST", "/jobs/1/cancel", "")).status,
+            200
+        );
+        assert_eq!(
+            admin.handle(&request("POST", "/jobs/1/cancel", "")).status,
+            409
+        );
+        assert_eq!(admin.handle(&request("GET", "/jobs/7", "")).status, 404);
+        let jobs = body(&admin.handle(&request("GET", "/jobs", "")));
+        assert_eq!(jobs[1]["state"], "cancelled");
+
+        let processors = admin.handle(&request("GET", "/processors", ""));
+        assert_eq!(body(&processors), json!([]));
+        let evict = admin.handle(&request("POST", "/processors/10.0.0.1:6000/evict", ""));
+        assert_eq!(evict.status, 404);
+    }
+
+    #[tokio::test]
+    async fn test_admin_requires_secret() {
+        let admin = Admin::new(
+            Arc::new(Producer::new(None, HeartbeatConfig::default())),
+            Arc::new(Mutex::new(ResultStore::new(
+                std::env::temp_dir().join("admin-auth-test"),
+            ))),
+            Arc::new(Jobs::default()),
+            Some(Secret::new(b"correct horse")),
+        );
+        let mut list = request("GET", "/processors", "");
+        assert_eq!(admin.handle(&list).status, 401);
+        list.headers = vec![("authorization".into(), "Bearer battery staple".into())];
+        assert_eq!(admin.handle(&list).status, 401);
+        list.headers = vec![("authorization".into(), "Bearer correct horse".into())];
+        assert_eq!(admin.handle(&list).status, 200);
+    }
+}
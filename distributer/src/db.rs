@@ -1,10 +1,94 @@
-use std::sync::Arc;
+use tokio::fs;
 
-use tokio::{fs, sync::mpsc::Sender};
+use sqlx::{Executor, Pool, Postgres, Transaction, postgres::PgPoolOptions};
+use tracing::info;
 
-use sqlx::{Pool, Postgres, postgres::PgPoolOptions};
+/// Schema version this binary reads and writes.
+pub const SCHEMA_VERSION: i64 = 4;
 
-use crate::Netflow;
+const MIGRATION_LOCK_ID: i64 = 0x006e_6574_666c_6f77;
+
+struct Migration {
+    version: i64,
+    name: &'static str,
+    sql: &'static str,
+}
+
+/// Applied in order, each in the same transaction as its `schema_migrations`
+/// row. Never edit an entry that has shipped, append a new one instead.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "create netflow",
+        sql: r#"
+        CREATE TABLE IF NOT EXISTS netflow (
+            flow_id BIGINT,
+            src_ip INET,
+            dst_ip INET,
+            src_port INT,
+            dst_port INT,
+            protocol SMALLINT,
+            bytes BIGINT,
+            packets BIGINT,
+            start_ts TIMESTAMPTZ,
+            end_ts TIMESTAMPTZ,
+            src_asn INT,
+            dst_asn INT
+        );
+        -- a table from before migrations kept addresses as text and
+        -- timestamps without a time zone
+        DO $$
+        BEGIN
+            IF (SELECT data_type FROM information_schema.columns
+                WHERE table_schema = current_schema() AND table_name = 'netflow'
+                    AND column_name = 'src_ip') = 'text' THEN
+                ALTER TABLE netflow
+                    ALTER COLUMN src_ip TYPE INET USING NULLIF(src_ip, '')::inet,
+                    ALTER COLUMN dst_ip TYPE INET USING NULLIF(dst_ip, '')::inet;
+            END IF;
+            IF (SELECT data_type FROM information_schema.columns
+                WHERE table_schema = current_schema() AND table_name = 'netflow'
+                    AND column_name = 'start_ts') = 'timestamp without time zone' THEN
+                ALTER TABLE netflow
+                    ALTER COLUMN start_ts TYPE TIMESTAMPTZ USING start_ts AT TIME ZONE 'UTC',
+                    ALTER COLUMN end_ts TYPE TIMESTAMPTZ USING end_ts AT TIME ZONE 'UTC';
+            END IF;
+        END
+        $$;
+        "#,
+    },
+    Migration {
+        version: 2,
+        name: "netflow primary key",
+        // an older table may hold rows the key refuses; the last copy of a
+        // flow written wins
+        sql: r#"
+        DELETE FROM netflow WHERE flow_id IS NULL;
+        DELETE FROM netflow a USING netflow b
+            WHERE a.flow_id = b.flow_id AND a.ctid < b.ctid;
+        ALTER TABLE netflow ADD PRIMARY KEY (flow_id);
+        "#,
+    },
+    Migration {
+        version: 3,
+        name: "netflow partitioning indexes",
+        sql: r#"
+        CREATE INDEX IF NOT EXISTS netflow_start_ts_idx ON netflow (start_ts);
+        CREATE INDEX IF NOT EXISTS netflow_src_ip_idx ON netflow (src_ip);
+        CREATE INDEX IF NOT EXISTS netflow_dst_ip_idx ON netflow (dst_ip);
+        "#,
+    },
+    Migration {
+        version: 4,
+        name: "job keys",
+        sql: r#"
+        CREATE TABLE IF NOT EXISTS job_keys (
+            job_id BIGSERIAL PRIMARY KEY,
+            key TEXT NOT NULL UNIQUE
+        )
+        "#,
+    },
+];
 
 pub struct DB {
     pool: Pool<Postgres>,
@@ -19,79 +103,282 @@ impl DB {
 
         Ok(Self { pool })
     }
-    pub async fn create_table(&self) -> Result<(), sqlx::Error> {
-        match sqlx::query(
-            r#"
-        CREATE TABLE IF NOT EXISTS netflow (
-            flow_id BIGINT,
-            src_ip TEXT,
-            dst_ip TEXT,
-            src_port INT,
-            dst_port INT,
-            protocol SMALLINT,
-            bytes BIGINT,
-            packets BIGINT,
-            start_ts TIMESTAMP,
-            end_ts TIMESTAMP,
-            src_asn INT,
-            dst_asn INT
+
+    /// Applies every migration newer than the recorded schema version and
+    /// returns the resulting version. Safe to call from several distributers
+    /// at once: the whole run holds a transaction scoped advisory lock.
+    pub async fn migrate(&self) -> Result<i64, sqlx::Error> {
+        self.pool
+            .execute(
+                r#"
+        CREATE TABLE IF NOT EXISTS schema_migrations (
+            version BIGINT PRIMARY KEY,
+            name TEXT NOT NULL,
+            applied_at TIMESTAMPTZ NOT NULL DEFAULT now()
         )
         "#,
-        )
-        .execute(&self.pool)
-        .await
-        {
-            Ok(_) => Ok(()),
-            Err(e) => Err(e),
+            )
+            .await?;
+
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("SELECT pg_advisory_xact_lock($1)")
+            .bind(MIGRATION_LOCK_ID)
+            .execute(&mut *tx)
+            .await?;
+        let current: i64 =
+            sqlx::query_scalar("SELECT COALESCE(MAX(version), 0) FROM schema_migrations")
+                .fetch_one(&mut *tx)
+                .await?;
+
+        let mut version = current;
+        for migration in MIGRATIONS.iter().filter(|m| m.version > current) {
+            tx.execute(migration.sql).await?;
+            sqlx::query("INSERT INTO schema_migrations (version, name) VALUES ($1, $2)")
+                .bind(migration.version)
+                .bind(migration.name)
+                .execute(&mut *tx)
+                .await?;
+            info!(
+                version = migration.version,
+                name = migration.name,
+                "applied migration"
+            );
+            version = migration.version;
         }
+
+        tx.commit().await?;
+        Ok(version)
     }
+
+    pub async fn schema_version(&self) -> Result<i64, sqlx::Error> {
+        let exists: bool =
+            sqlx::query_scalar("SELECT to_regclass('schema_migrations') IS NOT NULL")
+                .fetch_one(&self.pool)
+                .await?;
+        if !exists {
+            return Ok(0);
+        }
+        sqlx::query_scalar("SELECT COALESCE(MAX(version), 0) FROM schema_migrations")
+            .fetch_one(&self.pool)
+            .await
+    }
+
+    /// Fails unless the database is at exactly the version this binary was built for.
+    pub async fn check_schema(&self) -> Result<(), sqlx::Error> {
+        let version = self.schema_version().await?;
+        if version != SCHEMA_VERSION {
+            return Err(sqlx::Error::Configuration(
+                format!(
+                    "database schema is at version {}, this binary expects {}",
+                    version, SCHEMA_VERSION
+                )
+                .into(),
+            ));
+        }
+        Ok(())
+    }
+
     pub async fn insert_data(&self, sql_path: &str) -> Result<(), sqlx::Error> {
         let sql = fs::read_to_string(sql_path).await?;
-        sqlx::query(&sql).execute(&self.pool).await?;
+        // the generator writes several batched statements per file, which the
+        // extended protocol used by `sqlx::query` refuses to run
+        self.pool.execute(sql.as_str()).await?;
         Ok(())
     }
 
-    pub async fn read_chunk(
-        self: Arc<Self>,
-        chunk_size: usize,
-        page: usize,
-        tx: Sender<Vec<Netflow>>,
-    ) -> Result<(), sqlx::Error> {
-        let offset = page * chunk_size;
-        let batch_size = 1000;
-        let mut remaining = chunk_size;
+    pub async fn copy_csv(&self, csv_path: &str) -> Result<u64, sqlx::Error> {
+        let file = fs::File::open(csv_path).await?;
+        let mut conn = self.pool.acquire().await?;
+        let mut copy = conn
+            .copy_in_raw("COPY netflow FROM STDIN WITH (FORMAT csv, HEADER true)")
+            .await?;
+        copy.read_from(file).await?;
+        copy.finish().await
+    }
 
-        while remaining > 0 {
-            let current_batch = batch_size.min(remaining);
+    pub fn pool(&self) -> &Pool<Postgres> {
+        &self.pool
+    }
+}
 
-            let rows = match sqlx::query_as::<_, Netflow>(
-                r#"
-    SELECT flow_id, src_ip, dst_ip, src_port, dst_port,
-           protocol, bytes, packets,
-           EXTRACT(EPOCH FROM start_ts)::BIGINT AS start_ts,
-           EXTRACT(EPOCH FROM end_ts)::BIGINT AS end_ts,
-           src_asn, dst_asn
-    FROM netflow
-    ORDER BY flow_id
-    LIMIT $1 OFFSET $2
-    "#,
+/// Writes one job's rows into a result table so that rerunning the job,
+/// after a crash or on purpose, replaces what earlier runs wrote instead of
+/// adding to it. A run is known by its job key, which `job_keys` maps to the
+/// `job_id` its rows are tagged with, so reruns find them across restarts.
+/// Rows are copied into a temporary staging table; `commit` then deletes the
+/// rows an earlier run of the job left behind and moves the staged rows
+/// over, upserting on `flow_id` when the rows have one, all in one
+/// transaction.
+pub struct JobWriter {
+    tx: Transaction<'static, Postgres>,
+    table: String,
+    job: i64,
+    columns: Vec<String>,
+    key: Option<&'static str>,
+    /// Whether `table` exists, so the job's earlier rows may be in it.
+    exists: bool,
+}
+
+impl JobWriter {
+    /// Starts writing `columns` of the job known by `key` into `table`. A
+    /// netflow table is created like `netflow`, primary key included, plus
+    /// the `job_id BIGINT` column recording which job wrote each row, when it
+    /// is missing; any other table has to exist with those columns, `job_id`
+    /// and, to upsert on `flow_id`, a unique index on it. With no `columns`
+    /// the job wrote nothing, and committing only clears the rows an earlier
+    /// run of it left, if the table is there at all.
+    pub async fn begin(
+        pool: &Pool<Postgres>,
+        table: &str,
+        key: &str,
+        columns: &[&str],
+        netflow: bool,
+    ) -> Result<Self, sqlx::Error> {
+        let mut tx = pool.begin().await?;
+        if netflow {
+            tx.execute(
+                format!(
+                    "CREATE TABLE IF NOT EXISTS {} (LIKE netflow INCLUDING ALL, job_id BIGINT)",
+                    table
+                )
+                .as_str(),
             )
-            .bind(current_batch as i64)
-            .bind(offset as i64)
-            .fetch_all(&self.pool)
-            .await
-            {
-                Ok(rows) => rows,
-                Err(e) => return Err(e),
-            };
+            .await?;
+        }
+        let (exists, tagged): (bool, bool) = sqlx::query_as(
+            "SELECT to_regclass($1) IS NOT NULL, EXISTS (SELECT 1 FROM pg_attribute \
+             WHERE attrelid = to_regclass($1) AND attname = 'job_id' AND NOT attisdropped)",
+        )
+        .bind(table)
+        .fetch_one(&mut *tx)
+        .await?;
+        if !tagged && (exists || !columns.is_empty()) {
+            return Err(sqlx::Error::Configuration(
+                format!("result table {} needs a job_id BIGINT column", table).into(),
+            ));
+        }
+        let job: i64 = sqlx::query_scalar(
+            "INSERT INTO job_keys (key) VALUES ($1) \
+             ON CONFLICT (key) DO UPDATE SET key = EXCLUDED.key RETURNING job_id",
+        )
+        .bind(key)
+        .fetch_one(&mut *tx)
+        .await?;
+        if !columns.is_empty() {
+            tx.execute(
+                format!(
+                    "CREATE TEMP TABLE job_stage (LIKE {} INCLUDING DEFAULTS) ON COMMIT DROP",
+                    table
+                )
+                .as_str(),
+            )
+            .await?;
+        }
+        Ok(Self {
+            tx,
+            table: table.to_string(),
+            job,
+            columns: columns.iter().map(|c| quote_ident(c)).collect(),
+            key: columns.contains(&"flow_id").then_some("flow_id"),
+            exists,
+        })
+    }
 
-            if let Err(e) = tx.send(rows).await {
-                eprintln!("Failed to send batch: {}", e);
-            }
+    /// Stages CSV rows holding the columns given to `begin`, in that order.
+    pub async fn copy_csv(&mut self, rows: Vec<u8>) -> Result<(), sqlx::Error> {
+        let statement = format!(
+            "COPY job_stage ({}) FROM STDIN WITH (FORMAT csv)",
+            self.columns.join(", ")
+        );
+        let mut copy = self.tx.copy_in_raw(&statement).await?;
+        copy.send(rows).await?;
+        copy.finish().await?;
+        Ok(())
+    }
 
-            remaining -= current_batch;
+    /// Replaces the job's rows with the staged ones and returns how many
+    /// were written.
+    pub async fn commit(mut self) -> Result<u64, sqlx::Error> {
+        if self.exists {
+            sqlx::query(&format!("DELETE FROM {} WHERE job_id = $1", self.table))
+                .bind(self.job)
+                .execute(&mut *self.tx)
+                .await?;
+        }
+        let mut written = 0;
+        if !self.columns.is_empty() {
+            written = sqlx::query(&upsert_statement(&self.table, &self.columns, self.key))
+                .bind(self.job)
+                .execute(&mut *self.tx)
+                .await?
+                .rows_affected();
         }
+        self.tx.commit().await?;
+        Ok(written)
+    }
+}
 
-        Ok(())
+fn quote_ident(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/// Moves staged rows into `table` tagged with the job id bound to `$1`. When
+/// upserting on `key`, only the last staged row of each key is kept, as one
+/// statement may not update a row twice.
+fn upsert_statement(table: &str, columns: &[String], key: Option<&str>) -> String {
+    let columns_list = columns.join(", ");
+    let mut sql = match key {
+        Some(key) => format!(
+            "INSERT INTO {table} ({columns_list}, job_id) \
+             SELECT DISTINCT ON ({key}) {columns_list}, $1 FROM job_stage \
+             ORDER BY {key}, ctid DESC",
+            key = quote_ident(key)
+        ),
+        None => format!(
+            "INSERT INTO {table} ({columns_list}, job_id) SELECT {columns_list}, $1 FROM job_stage"
+        ),
+    };
+    if let Some(key) = key {
+        let key = quote_ident(key);
+        let updates: Vec<String> = columns
+            .iter()
+            .filter(|c| **c != key)
+            .chain(std::iter::once(&"job_id".to_string()))
+            .map(|c| format!("{c} = EXCLUDED.{c}"))
+            .collect();
+        sql.push_str(&format!(
+            " ON CONFLICT ({}) DO UPDATE SET {}",
+            key,
+            updates.join(", ")
+        ));
+    }
+    sql
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_upsert_statement() {
+        let columns = vec![quote_ident("flow_id"), quote_ident("bytes")];
+        assert_eq!(
+            upsert_statement("netflow_validated", &columns, Some("flow_id")),
+            "INSERT INTO netflow_validated (\"flow_id\", \"bytes\", job_id) \
+             SELECT DISTINCT ON (\"flow_id\") \"flow_id\", \"bytes\", $1 FROM job_stage \
+             ORDER BY \"flow_id\", ctid DESC \
+             ON CONFLICT (\"flow_id\") DO UPDATE SET \"bytes\" = EXCLUDED.\"bytes\", \
+             job_id = EXCLUDED.job_id"
+        );
+        assert_eq!(
+            upsert_statement("totals", &columns[1..], None),
+            "INSERT INTO totals (\"bytes\", job_id) SELECT \"bytes\", $1 FROM job_stage"
+        );
+    }
+
+    #[test]
+    fn test_migrations_ordered() {
+        assert!(MIGRATIONS.windows(2).all(|w| w[0].version < w[1].version));
+        assert_eq!(MIGRATIONS.last().unwrap().version, SCHEMA_VERSION);
     }
 }
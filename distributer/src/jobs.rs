@@ -0,0 +1,514 @@
+//! Jobs submitted to the distributer and how far each has got. The loop in
+//! `main` takes them off the queue and runs up to `MAX_JOBS` at once, each
+//! reading its source and producing its chunks, pausing and stopping as its
+//! [`JobControl`] says.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::Mutex;
+
+use chrono::{DateTime, Utc};
+use common::record::TransformSpec;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{Notify, watch};
+use tracing::info;
+
+use crate::schedule::JobShare;
+use crate::sink::SinkSpec;
+use crate::source;
+
+/// What to run. Anything left out falls back to the distributer's own
+/// `SOURCE`, `SOURCE_QUERY`, `PARTITION_COLUMN` and `SINK`, and to the
+/// processors' own transform.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct JobSpec {
+    pub source: Option<String>,
+    pub query: Option<String>,
+    pub partition_column: Option<String>,
+    pub transform: Option<TransformSpec>,
+    /// Where the job's output goes, in the syntax of `SINK`.
+    pub sink: Option<String>,
+    /// The job's share of the processor pool against the other running
+    /// jobs', 1 unless set.
+    pub weight: Option<u32>,
+    /// Which earlier runs' rows this one's replace in a database sink; see
+    /// [`key`](Self::key).
+    pub key: Option<String>,
+}
+
+impl JobSpec {
+    pub fn weight(&self) -> u32 {
+        self.weight.unwrap_or(1)
+    }
+
+    /// What the job's rows are tagged with in a database sink: `key` if set,
+    /// else the rest of the spec less its weight, so the same job submitted
+    /// again, before or after a restart, replaces its earlier rows.
+    pub fn key(&self) -> String {
+        self.key.clone().unwrap_or_else(|| {
+            let spec = JobSpec {
+                weight: None,
+                ..self.clone()
+            };
+            serde_json::to_string(&spec).expect("failed to encode job spec")
+        })
+    }
+
+    /// Checks what a job submitted over the admin API may ask for. Only the
+    /// distributer's own `SOURCE_QUERY` can be a query; a job names a table.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.weight == Some(0) {
+            return Err("weight must be at least 1".to_string());
+        }
+        if let Some(query) = &self.query
+            && !source::is_identifier(query)
+        {
+            return Err(format!("query must be a table name, got {:?}", query));
+        }
+        if let Some(column) = &self.partition_column
+            && !source::is_identifier(column)
+        {
+            return Err(format!(
+                "partition_column must be a column name, got {:?}",
+                column
+            ));
+        }
+        if let Some(transform) = &self.transform {
+            transform.validate()?;
+        }
+        if let Some(sink) = &self.sink {
+            SinkSpec::parse(sink).map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Queued,
+    /// Reading the source and producing chunks.
+    Running,
+    /// Running but producing nothing until resumed.
+    Paused,
+    /// Every chunk produced, waiting for the processors' output.
+    Flushed,
+    /// Output merged into the sink.
+    Done,
+    Failed,
+    Cancelled,
+}
+
+impl JobState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            JobState::Queued => "queued",
+            JobState::Running => "running",
+            JobState::Paused => "paused",
+            JobState::Flushed => "flushed",
+            JobState::Done => "done",
+            JobState::Failed => "failed",
+            JobState::Cancelled => "cancelled",
+        }
+    }
+
+    fn is_finished(self) -> bool {
+        matches!(
+            self,
+            JobState::Done | JobState::Failed | JobState::Cancelled
+        )
+    }
+}
+
+/// A job as the admin API shows it.
+#[derive(Debug, Clone, Serialize)]
+pub struct JobStatus {
+    pub job: u64,
+    pub spec: JobSpec,
+    pub state: JobState,
+    pub submitted: DateTime<Utc>,
+    /// Chunks produced so far, by source partition.
+    pub produced: BTreeMap<u32, u64>,
+    /// Chunks processed, by source partition, once all of the job's results
+    /// are in.
+    pub processed: BTreeMap<u32, u64>,
+    /// Rows written to the sink once done.
+    pub rows: Option<u64>,
+    pub error: Option<String>,
+    /// What it has had of the processor pool, once it started sending.
+    pub share: Option<JobShare>,
+}
+
+/// Why a job could not be cancelled, paused or resumed.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ControlError {
+    Unknown,
+    /// Not in a state it can be taken out of that way.
+    Invalid(JobState),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Control {
+    Run,
+    Pause,
+    Cancel,
+}
+
+/// The job loop's end of a running job's pause, resume and cancel.
+pub struct JobControl(watch::Receiver<Control>);
+
+impl JobControl {
+    pub fn is_cancelled(&self) -> bool {
+        *self.0.borrow() == Control::Cancel
+    }
+
+    /// Waits out a pause. False once the job is cancelled.
+    pub async fn proceed(&mut self) -> bool {
+        let mut paused = false;
+        loop {
+            match *self.0.borrow_and_update() {
+                Control::Run => {
+                    if paused {
+                        info!("job resumed");
+                    }
+                    return true;
+                }
+                Control::Cancel => return false,
+                Control::Pause if !paused => {
+                    info!("job paused");
+                    paused = true;
+                }
+                Control::Pause => {}
+            }
+            if self.0.changed().await.is_err() {
+                return false;
+            }
+        }
+    }
+
+    /// Resolves once the job is cancelled.
+    pub async fn cancelled(&mut self) {
+        if self.0.wait_for(|c| *c == Control::Cancel).await.is_err() {
+            // the registry let go of a finished job, nothing will cancel it
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+#[derive(Default)]
+struct Registry {
+    next: u64,
+    queue: VecDeque<u64>,
+    jobs: BTreeMap<u64, JobStatus>,
+    /// The other end of every running job's [`JobControl`].
+    controls: HashMap<u64, watch::Sender<Control>>,
+}
+
+/// Every job submitted since the distributer started.
+#[derive(Default)]
+pub struct Jobs {
+    registry: Mutex<Registry>,
+    submitted: Notify,
+}
+
+impl Jobs {
+    /// Queues a job and returns its id.
+    pub fn submit(&self, spec: JobSpec) -> u64 {
+        let mut registry = self.registry.lock().unwrap();
+        let job = registry.next;
+        registry.next += 1;
+        registry.jobs.insert(
+            job,
+            JobStatus {
+                job,
+                spec,
+                state: JobState::Queued,
+                submitted: Utc::now(),
+                produced: BTreeMap::new(),
+                processed: BTreeMap::new(),
+                rows: None,
+                error: None,
+                share: None,
+            },
+        );
+        registry.queue.push_back(job);
+        drop(registry);
+        self.submitted.notify_one();
+        job
+    }
+
+    /// Waits for the next queued job and marks it running.
+    pub async fn next(&self) -> (u64, JobSpec, JobControl) {
+        loop {
+            let submitted = self.submitted.notified();
+            {
+                let mut registry = self.registry.lock().unwrap();
+                if let Some(job) = registry.queue.pop_front() {
+                    let status = registry.jobs.get_mut(&job).expect("queued jobs are kept");
+                    status.state = JobState::Running;
+                    let spec = status.spec.clone();
+                    let (control, rx) = watch::channel(Control::Run);
+                    registry.controls.insert(job, control);
+                    return (job, spec, JobControl(rx));
+                }
+            }
+            submitted.await;
+        }
+    }
+
+    /// Counts a chunk of `job` read from `partition` and produced.
+    pub fn produced(&self, job: u64, partition: u32) {
+        let mut registry = self.registry.lock().unwrap();
+        if let Some(status) = registry.jobs.get_mut(&job) {
+            *status.produced.entry(partition).or_default() += 1;
+        }
+    }
+
+    /// Records the chunks of `job` processed, by partition, once all of its
+    /// results are in.
+    pub fn processed(&self, job: u64, processed: impl IntoIterator<Item = (u32, u64)>) {
+        let mut registry = self.registry.lock().unwrap();
+        if let Some(status) = registry.jobs.get_mut(&job) {
+            status.processed = processed.into_iter().collect();
+        }
+    }
+
+    /// Marks a job done producing. It can no longer be paused.
+    pub fn flushed(&self, job: u64) {
+        let mut registry = self.registry.lock().unwrap();
+        registry.controls.remove(&job);
+        if let Some(status) = registry.jobs.get_mut(&job)
+            && !status.state.is_finished()
+        {
+            status.state = JobState::Flushed;
+        }
+    }
+
+    /// Records what a job that stopped sending had of the processor pool.
+    pub fn shared(&self, job: u64, share: Option<JobShare>) {
+        let mut registry = self.registry.lock().unwrap();
+        if let Some(status) = registry.jobs.get_mut(&job) {
+            status.share = share;
+        }
+    }
+
+    /// Records how a job ended: the rows it wrote or why it failed.
+    pub fn finish(&self, job: u64, outcome: Result<u64, String>) {
+        let mut registry = self.registry.lock().unwrap();
+        registry.controls.remove(&job);
+        let Some(status) = registry.jobs.get_mut(&job) else {
+            return;
+        };
+        if status.state == JobState::Cancelled {
+            return;
+        }
+        match outcome {
+            Ok(rows) => {
+                status.state = JobState::Done;
+                status.rows = Some(rows);
+            }
+            Err(e) => {
+                status.state = JobState::Failed;
+                status.error = Some(e);
+            }
+        }
+    }
+
+    /// Cancels a job, returning the state it was in. A queued one is dropped
+    /// from the queue, a running or paused one is stopped by the job loop
+    /// through its [`JobControl`]; the chunks and results of a flushed one
+    /// are the caller's to drop.
+    pub fn cancel(&self, job: u64) -> Result<JobState, ControlError> {
+        let mut registry = self.registry.lock().unwrap();
+        let state = registry.state(job)?;
+        if state.is_finished() {
+            return Err(ControlError::Invalid(state));
+        }
+        if state == JobState::Queued {
+            registry.queue.retain(|j| *j != job);
+        }
+        registry.control(job, Control::Cancel);
+        registry.jobs.get_mut(&job).expect("looked up above").state = JobState::Cancelled;
+        Ok(state)
+    }
+
+    /// Stops a running job producing. Its partition readers stall once the
+    /// chunks they have read fill the channel.
+    pub fn pause(&self, job: u64) -> Result<(), ControlError> {
+        self.switch(job, JobState::Running, JobState::Paused, Control::Pause)
+    }
+
+    pub fn resume(&self, job: u64) -> Result<(), ControlError> {
+        self.switch(job, JobState::Paused, JobState::Running, Control::Run)
+    }
+
+    pub fn status(&self, job: u64) -> Option<JobStatus> {
+        self.registry.lock().unwrap().jobs.get(&job).cloned()
+    }
+
+    /// Every job, oldest first.
+    pub fn list(&self) -> Vec<JobStatus> {
+        let registry = self.registry.lock().unwrap();
+        registry.jobs.values().cloned().collect()
+    }
+
+    fn switch(
+        &self,
+        job: u64,
+        from: JobState,
+        to: JobState,
+        control: Control,
+    ) -> Result<(), ControlError> {
+        let mut registry = self.registry.lock().unwrap();
+        let state = registry.state(job)?;
+        if state != from {
+            return Err(ControlError::Invalid(state));
+        }
+        registry.control(job, control);
+        registry.jobs.get_mut(&job).expect("looked up above").state = to;
+        Ok(())
+    }
+}
+
+impl Registry {
+    fn state(&self, job: u64) -> Result<JobState, ControlError> {
+        self.jobs
+            .get(&job)
+            .map(|status| status.state)
+            .ok_or(ControlError::Unknown)
+    }
+
+    fn control(&self, job: u64, control: Control) {
+        if let Some(sender) = self.controls.get(&job) {
+            sender.send_replace(control);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_jobs_run_in_order_and_cancel() {
+        let jobs = Jobs::default();
+        let first = jobs.submit(JobSpec::default());
+        let second = jobs.submit(JobSpec {
+            source: Some("csv:flows.csv".to_string()),
+            ..JobSpec::default()
+        });
+        let third = jobs.submit(JobSpec::default());
+        assert_eq!(jobs.cancel(second), Ok(JobState::Queued));
+
+        assert_eq!(jobs.next().await.0, first);
+        jobs.produced(first, 1);
+        jobs.produced(first, 1);
+        jobs.flushed(first);
+        assert_eq!(
+            jobs.pause(first),
+            Err(ControlError::Invalid(JobState::Flushed))
+        );
+        jobs.finish(first, Ok(10));
+        let status = jobs.status(first).unwrap();
+        assert_eq!(status.state, JobState::Done);
+        assert_eq!(status.produced, BTreeMap::from([(1, 2)]));
+        assert_eq!(
+            jobs.cancel(first),
+            Err(ControlError::Invalid(JobState::Done))
+        );
+
+        // the cancelled one was skipped
+        let (job, _, mut control) = jobs.next().await;
+        assert_eq!(job, third);
+        assert_eq!(jobs.cancel(third), Ok(JobState::Running));
+        assert!(control.is_cancelled());
+        assert!(!control.proceed().await);
+        control.cancelled().await;
+        jobs.finish(third, Ok(0));
+        assert_eq!(jobs.status(third).unwrap().state, JobState::Cancelled);
+        assert_eq!(jobs.cancel(99), Err(ControlError::Unknown));
+    }
+
+    #[test]
+    fn test_validate_transform() {
+        let spec = |filter: &str, project: Option<&str>| JobSpec {
+            transform: Some(TransformSpec {
+                filter: Some(filter.to_string()),
+                project: project.map(str::to_string),
+            }),
+            ..JobSpec::default()
+        };
+        assert!(
+            spec("bytes>=100, protocol=6", Some("flow_id,bytes"))
+                .validate()
+                .is_ok()
+        );
+        assert!(spec("bytes", None).validate().is_err());
+        assert!(spec("bytes>100,", None).validate().is_err());
+        assert!(spec(">100", None).validate().is_err());
+        assert!(spec("bytes>0", Some("flow_id,,bytes")).validate().is_err());
+        for (sink, valid) in [
+            ("csv:./out/{job}.csv", true),
+            ("postgres:results", true),
+            ("postgres:results; DROP TABLE netflow", false),
+            ("parquet:out", false),
+        ] {
+            let spec = JobSpec {
+                sink: Some(sink.to_string()),
+                ..JobSpec::default()
+            };
+            assert_eq!(spec.validate().is_ok(), valid, "{}", sink);
+        }
+    }
+
+    #[test]
+    fn test_job_key() {
+        let spec = JobSpec {
+            query: Some("flows".to_string()),
+            ..JobSpec::default()
+        };
+        let weighted = JobSpec {
+            weight: Some(3),
+            ..spec.clone()
+        };
+        assert_eq!(spec.key(), weighted.key());
+        assert_ne!(spec.key(), JobSpec::default().key());
+        let named = JobSpec {
+            key: Some("nightly".to_string()),
+            ..spec
+        };
+        assert_eq!(named.key(), "nightly");
+    }
+
+    #[tokio::test]
+    async fn test_pause_and_resume() {
+        let jobs = Jobs::default();
+        let job = jobs.submit(JobSpec::default());
+        assert_eq!(
+            jobs.resume(job),
+            Err(ControlError::Invalid(JobState::Queued))
+        );
+        let (_, _, mut control) = jobs.next().await;
+        assert!(control.proceed().await);
+
+        jobs.pause(job).unwrap();
+        assert_eq!(jobs.status(job).unwrap().state, JobState::Paused);
+        assert_eq!(
+            jobs.pause(job),
+            Err(ControlError::Invalid(JobState::Paused))
+        );
+        let waiting = tokio::spawn(async move { control.proceed().await });
+        tokio::task::yield_now().await;
+        assert!(!waiting.is_finished());
+        jobs.resume(job).unwrap();
+        assert!(waiting.await.unwrap());
+        assert_eq!(jobs.status(job).unwrap().state, JobState::Running);
+
+        // a paused job can still be cancelled
+        let job = jobs.submit(JobSpec::default());
+        let (_, _, mut control) = jobs.next().await;
+        jobs.pause(job).unwrap();
+        assert_eq!(jobs.cancel(job), Ok(JobState::Paused));
+        assert!(!control.proceed().await);
+    }
+}
@@ -0,0 +1,171 @@
+//! Log output. `RUST_LOG` picks what is logged, `info` and up unless set,
+//! and `LOG_FORMAT=json` turns every event into one JSON object carrying the
+//! fields of the spans it happened in, so a chunk can be followed by its
+//! `job`, `partition` and `chunk` from the source read to the merged output.
+//! The latest warnings and errors are also kept in [`RECENT_ERRORS`] for the
+//! admin API.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::env;
+use std::fmt::Debug;
+use std::sync::Mutex;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, fmt};
+
+/// How many warnings and errors [`RECENT_ERRORS`] holds on to.
+const KEPT_ERRORS: usize = 100;
+
+pub static RECENT_ERRORS: RecentErrors = RecentErrors::new();
+
+/// Installs the global subscriber from `RUST_LOG` and `LOG_FORMAT` (`text`,
+/// the default, or `json`).
+pub fn init() -> Result<(), String> {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let registry = tracing_subscriber::registry().with(filter).with(KeepErrors);
+    match env::var("LOG_FORMAT").as_deref() {
+        Ok("text") | Err(_) => registry.with(fmt::layer()).init(),
+        Ok("json") => registry
+            .with(fmt::layer().json().with_span_list(true))
+            .init(),
+        Ok(other) => {
+            return Err(format!(
+                "unknown LOG_FORMAT {:?}, expected text or json",
+                other
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// A warning or error as it was logged.
+#[derive(Debug, Clone, Serialize)]
+pub struct LoggedError {
+    pub time: DateTime<Utc>,
+    pub level: String,
+    pub target: String,
+    pub message: String,
+    /// The event's own fields over those of the spans it happened in.
+    pub fields: BTreeMap<String, String>,
+}
+
+/// The last [`KEPT_ERRORS`] warnings and errors, oldest first.
+pub struct RecentErrors(Mutex<VecDeque<LoggedError>>);
+
+impl RecentErrors {
+    const fn new() -> Self {
+        Self(Mutex::new(VecDeque::new()))
+    }
+
+    pub fn snapshot(&self) -> Vec<LoggedError> {
+        self.0.lock().unwrap().iter().cloned().collect()
+    }
+
+    fn push(&self, error: LoggedError) {
+        let mut errors = self.0.lock().unwrap();
+        if errors.len() == KEPT_ERRORS {
+            errors.pop_front();
+        }
+        errors.push_back(error);
+    }
+}
+
+/// Copies warnings and errors into [`RECENT_ERRORS`].
+struct KeepErrors;
+
+/// A span's fields, recorded when it is created.
+struct SpanFields(BTreeMap<String, String>);
+
+#[derive(Default)]
+struct Fields {
+    message: String,
+    fields: BTreeMap<String, String>,
+}
+
+impl Visit for Fields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.insert(field, value.to_string());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        self.insert(field, format!("{:?}", value));
+    }
+}
+
+impl Fields {
+    fn insert(&mut self, field: &Field, value: String) {
+        match field.name() {
+            "message" => self.message = value,
+            name => {
+                self.fields.insert(name.to_string(), value);
+            }
+        }
+    }
+}
+
+impl<S> Layer<S> for KeepErrors
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut fields = Fields::default();
+        attrs.record(&mut fields);
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(SpanFields(fields.fields));
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        // more verbose levels compare greater
+        if *metadata.level() > Level::WARN {
+            return;
+        }
+        let mut fields = Fields::default();
+        if let Some(scope) = ctx.event_scope(event) {
+            for span in scope.from_root() {
+                if let Some(SpanFields(span_fields)) = span.extensions().get::<SpanFields>() {
+                    fields.fields.extend(span_fields.clone());
+                }
+            }
+        }
+        event.record(&mut fields);
+        RECENT_ERRORS.push(LoggedError {
+            time: Utc::now(),
+            level: metadata.level().to_string(),
+            target: metadata.target().to_string(),
+            message: fields.message,
+            fields: fields.fields,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keeps_warnings_with_span_fields() {
+        let subscriber = tracing_subscriber::registry().with(KeepErrors);
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("job", job = 7);
+            let _entered = span.enter();
+            tracing::info!("not kept");
+            tracing::warn!(partition = 2, "kept");
+        });
+        let errors = RECENT_ERRORS.snapshot();
+        let kept: Vec<_> = errors.iter().filter(|e| e.message == "kept").collect();
+        assert_eq!(kept.len(), 1);
+        assert_eq!(kept[0].level, "WARN");
+        assert_eq!(kept[0].fields["job"], "7");
+        assert_eq!(kept[0].fields["partition"], "2");
+        assert!(errors.iter().all(|e| e.message != "not kept"));
+    }
+}
@@ -1,117 +1,501 @@
-use serde::{Deserialize, Serialize};
+use common::auth;
+use common::codec::Codec;
+use common::frame::{ChunkId, FrameLimits};
+use common::http;
+use common::spill::SortKey;
 use std::{
-    env, process,
-    sync::{Arc, atomic::Ordering},
+    env,
+    path::PathBuf,
+    process,
+    sync::{Arc, Mutex, atomic::Ordering},
 };
+use tokio::net::TcpListener;
 use tokio::time::{self, Duration};
+use tracing::{Instrument, debug, error, info, info_span, warn};
 
+use crate::jobs::{JobSpec, Jobs};
 use crate::producer::Producer;
+use crate::record::Chunk;
+use crate::result::{JobResults, ResultStore};
+use crate::source::{FileFormat, FileSource, PostgresSource, Source, SqliteSource};
 
+mod admin;
 mod db;
+mod jobs;
+mod logging;
+mod merge;
+mod metrics;
 mod netflow_gen;
 mod producer;
+mod record;
+mod result;
+mod schedule;
+mod sink;
+mod source;
 
-#[derive(Debug, sqlx::FromRow, Serialize, Deserialize, Clone)]
-
-pub struct Netflow {
-    pub flow_id: i64,
-    pub src_ip: Option<String>,
-    pub dst_ip: Option<String>,
-    pub src_port: Option<i32>,
-    pub dst_port: Option<i32>,
-    pub protocol: Option<i16>,
-    pub bytes: Option<i64>,
-    pub packets: Option<i64>,
-    pub start_ts: Option<i64>,
-    pub end_ts: Option<i64>,
-    pub src_asn: Option<i32>,
-    pub dst_asn: Option<i32>,
-}
 #[tokio::main]
 async fn main() -> Result<(), sqlx::Error> {
+    if let Err(e) = logging::init() {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
     const ROWS_COUNT: usize = 2_000_000;
     let create_sql = env::var("CREATE_SQL").unwrap_or_else(|_| "CREATE TABLE ...".to_string());
     let db = db::DB::new(String::from("postgres://postgres@localhost:5432/postgres")).await?;
 
-    let producer = Arc::new(Producer::new());
+    let secret = auth::Secret::from_env();
+    if secret.is_none() {
+        warn!("CLUSTER_SECRET not set, processors are not authenticated");
+    }
+    let heartbeat = producer::HeartbeatConfig::from_env().unwrap_or_else(|e| {
+        error!("{}", e);
+        process::exit(1);
+    });
+    let mut producer = Producer::new(secret.clone(), heartbeat);
+    if let Ok(value) = env::var("RETAIN_BYTES") {
+        producer.retain_bytes = value.parse().unwrap_or_else(|_| {
+            error!("RETAIN_BYTES must be a byte count, got {:?}", value);
+            process::exit(1);
+        });
+    }
+    let producer = Arc::new(producer);
+    let spill_dir = env::var("SPILL_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| env::temp_dir().join("distributer-spill"));
+    let results = Arc::new(Mutex::new(ResultStore::new(spill_dir)));
+    let jobs = Arc::new(Jobs::default());
+    let (done_tx, mut done_rx) = tokio::sync::mpsc::channel::<JobResults>(16);
+    // must match the processors' SORT_BY, their runs arrive sorted by it
+    let sort_key = env::var("SORT_BY")
+        .ok()
+        .map(|name| SortKey::from_name(&name).expect("unknown SORT_BY"))
+        .unwrap_or(SortKey::FlowId);
+    {
+        let limits = FrameLimits::from_env().unwrap_or_else(|e| {
+            error!("{}", e);
+            process::exit(1);
+        });
+        let secret = secret.clone();
+        let results = results.clone();
+        let done_tx = done_tx.clone();
+        tokio::spawn(async move {
+            if let Err(e) =
+                result::listen_results(8081, secret, limits, sort_key, results, done_tx).await
+            {
+                error!(error = %e, "listen_results failed");
+            }
+        });
+    }
+    let sink = match env::var("SINK") {
+        Ok(spec) => sink::SinkSpec::parse(&spec).unwrap_or_else(|e| {
+            error!(error = %e, "invalid SINK");
+            process::exit(1);
+        }),
+        Err(_) => sink::SinkSpec::default(),
+    };
+    {
+        let pool = db.pool().clone();
+        let producer = producer.clone();
+        let jobs = jobs.clone();
+        tokio::spawn(async move {
+            while let Some(mut job) = done_rx.recv().await {
+                let span = info_span!("merge", job = job.job);
+                async {
+                    jobs.shared(job.job, producer.schedule.share(job.job));
+                    if let Some(e) = job.failed.take() {
+                        error!(error = %e, "job failed");
+                        for path in job.take_runs() {
+                            let _ = std::fs::remove_file(path);
+                        }
+                        tokio::spawn({
+                            let producer = producer.clone();
+                            let job = job.job;
+                            async move { producer.cancel(job).await }
+                        });
+                        jobs.finish(job.job, Err(e));
+                        return;
+                    }
+                    // a processor that is slow to answer doesn't hold up the merge
+                    tokio::spawn({
+                        let producer = producer.clone();
+                        let job = job.job;
+                        async move { producer.complete(job).await }
+                    });
+                    jobs.processed(job.job, job.partitions().clone());
+                    info!(
+                        chunks = job.chunks(),
+                        received = job.stats.received,
+                        kept = job.stats.kept,
+                        rejected = job.stats.rejected,
+                        groups = job.aggregates().map_or(0, |a| a.rows.len()),
+                        "job done"
+                    );
+                    let runs = job.take_runs();
+                    let spec = jobs.status(job.job).map(|status| status.spec);
+                    let key = spec
+                        .as_ref()
+                        .map_or_else(|| job.job.to_string(), JobSpec::key);
+                    let merged = async {
+                        // checked when the job was submitted
+                        let sink = match spec.and_then(|spec| spec.sink) {
+                            Some(spec) => sink::SinkSpec::parse(&spec)?,
+                            None => sink.clone(),
+                        };
+                        let out = sink.open(job.job, &key, &pool).await?;
+                        let rows = merge::merge_into(runs.clone(), sort_key, out).await?;
+                        info!(
+                            rows,
+                            runs = runs.len(),
+                            sink = %sink.for_job(job.job),
+                            "job merged"
+                        );
+                        Ok::<_, sink::SinkError>(rows)
+                    }
+                    .await;
+                    if let Err(e) = &merged {
+                        error!(error = %e, "failed to merge job");
+                    }
+                    jobs.finish(
+                        job.job,
+                        merged.map(|rows| rows as u64).map_err(|e| e.to_string()),
+                    );
+                }
+                .instrument(span)
+                .await;
+            }
+        });
+    }
     {
         let producer = producer.clone();
         tokio::spawn(async move {
             if let Err(e) = producer.listen_processor().await {
-                eprintln!("Error in listen_processor: {}", e);
+                error!(error = %e, "listen_processor failed");
             }
         });
     }
     {
         let producer = producer.clone();
+        let results = results.clone();
         tokio::spawn(async move {
-            let mut interval = time::interval(Duration::from_secs(5));
+            let mut interval = time::interval(producer.heartbeat.interval);
             loop {
                 interval.tick().await;
-                if let Err(err) = producer.heartbeat_processors().await {
-                    println!("failed to run heartbeat {}", err)
-                };
+                producer.heartbeat_processors(&results).await;
+            }
+        });
+    }
+    let speculation = producer::SpeculationConfig::from_env().unwrap_or_else(|e| {
+        error!("{}", e);
+        process::exit(1);
+    });
+    if speculation.enabled() {
+        let producer = producer.clone();
+        let results = results.clone();
+        tokio::spawn(async move {
+            let mut interval = time::interval(speculation.interval);
+            loop {
+                interval.tick().await;
+                producer.speculate(&results, &speculation).await;
+            }
+        });
+    }
+    {
+        // Prometheus scrapes `/metrics` here
+        let addr = env::var("METRICS_ADDR").unwrap_or_else(|_| "127.0.0.1:9100".to_string());
+        tokio::spawn(async move {
+            let serve = async {
+                let listener = TcpListener::bind(&addr).await?;
+                info!("serving metrics on http://{}/metrics", addr);
+                http::serve(listener, metrics::handle).await
+            };
+            if let Err(e) = serve.await {
+                error!(error = %e, "failed to serve metrics on {}", addr);
+            }
+        });
+    }
+    {
+        let addr = env::var("ADMIN_ADDR").unwrap_or_else(|_| "127.0.0.1:9101".to_string());
+        let admin = admin::Admin::new(
+            producer.clone(),
+            results.clone(),
+            jobs.clone(),
+            secret.clone(),
+        );
+        let authenticated = secret.is_some();
+        tokio::spawn(async move {
+            let serve = async {
+                let listener = TcpListener::bind(&addr).await?;
+                // it runs queries and reads files on the caller's behalf
+                if !authenticated && !listener.local_addr()?.ip().is_loopback() {
+                    error!(
+                        "ADMIN_ADDR {} is not a loopback address, set CLUSTER_SECRET",
+                        addr
+                    );
+                    process::exit(1);
+                }
+                info!("serving the admin API on http://{}", addr);
+                http::serve(listener, move |request| admin.handle(request)).await
+            };
+            if let Err(e) = serve.await {
+                error!(error = %e, "failed to serve the admin API on {}", addr);
             }
         });
     }
 
+    if create_sql == "TRUE" || env::var("MIGRATE").as_deref() == Ok("TRUE") {
+        match db.migrate().await {
+            Ok(version) => info!(version, "schema migrated"),
+            Err(e) => {
+                error!(error = %e, "failed to migrate the schema");
+                process::exit(1);
+            }
+        }
+    }
+    if let Err(e) = db.check_schema().await {
+        error!("{}, run with MIGRATE=TRUE to upgrade", e);
+        process::exit(1);
+    }
+
     if create_sql == "TRUE" {
+        let format = env::var("NETFLOW_FORMAT")
+            .ok()
+            .map(|name| {
+                netflow_gen::OutputFormat::from_name(&name).expect("unknown NETFLOW_FORMAT")
+            })
+            .unwrap_or_default();
+        let output_path = format!("./netflow.{}", format.extension());
         let config = netflow_gen::NetflowGenConfig {
             rows: ROWS_COUNT,
             null_prob: 0.2,
+            ipv6_ratio: 0.1,
             seed: 1337,
-            output_path: "./netflow.sql".to_string(),
+            output_path: output_path.clone(),
+            format,
+            anomalies: (env::var("INJECT_ANOMALIES").as_deref() == Ok("TRUE"))
+                .then(netflow_gen::AnomalyConfig::default),
         };
 
         netflow_gen::run(config).expect("netflow generation failed");
-        match db.create_table().await {
-            Ok(_) => println!("table created;"),
-            Err(e) => {
-                println!("failed to create the table {}", e.to_string());
-                process::exit(1);
+        let inserted = match format {
+            netflow_gen::OutputFormat::Sql { .. } => db.insert_data(&output_path).await,
+            netflow_gen::OutputFormat::Csv => db.copy_csv(&output_path).await.map(|_| ()),
+            _ => {
+                warn!("{} written, not loadable into postgres", output_path);
+                Ok(())
             }
-        }
-        match db.insert_data("netflow.sql").await {
-            Ok(_) => println!("data inserted successfully;"),
+        };
+        match inserted {
+            Ok(_) => info!("data inserted successfully"),
             Err(e) => {
-                println!("failed to insert data {}", e.to_string());
+                error!(error = %e, "failed to insert data");
                 process::exit(1);
             }
         }
     }
-    let mut interval = time::interval(Duration::from_secs(5));
+    let cores = std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1);
+
+    // none or lz4 for processors close by, zstd when bandwidth is the bottleneck
+    let codec = env::var("CODEC")
+        .ok()
+        .map(|name| Codec::from_name(&name).expect("unknown CODEC"))
+        .unwrap_or(Codec::Lz4);
+
+    // jobs running at once, sharing the processors by their weights
+    let max_jobs = env::var("MAX_JOBS")
+        .ok()
+        .map(|n| n.parse().expect("MAX_JOBS must be a count"))
+        .unwrap_or(4);
+    let running = Arc::new(tokio::sync::Semaphore::new(max_jobs));
     let db = Arc::new(db);
+
+    // the configured source runs once, more jobs come in on the admin API
+    jobs.submit(JobSpec::default());
+    let mut interval = time::interval(Duration::from_secs(5));
     loop {
-        interval.tick().await;
-        if !producer.ready_to_produce.load(Ordering::Acquire) {
-            continue;
+        let permit = running
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("the semaphore is never closed");
+        let (job, spec, mut control) = jobs.next().await;
+        while !producer.ready_to_produce.load(Ordering::Acquire) {
+            interval.tick().await;
         }
 
-        let cores = std::thread::available_parallelism()
-            .map(|n| n.get())
-            .unwrap_or(1);
-        let chunk_size = ROWS_COUNT / cores;
-        let (tx, mut rx) = tokio::sync::mpsc::channel::<Vec<Netflow>>(chunk_size);
-        for i in 0..cores {
-            let db = db.clone();
-            let tx = tx.clone();
-
-            tokio::spawn(async move {
-                if let Err(e) = db.read_chunk(chunk_size, i, tx).await {
-                    eprintln!("failed to read chunk {}: {}", i, e);
+        let db = db.clone();
+        let producer = producer.clone();
+        let results = results.clone();
+        let jobs = jobs.clone();
+        let done_tx = done_tx.clone();
+        let run = async move {
+            let _permit = permit;
+            let source = match open_source(&db, &spec, cores).await {
+                Ok(source) => source,
+                Err(e) => {
+                    error!(error = %e, "failed to open the source");
+                    jobs.finish(job, Err(e.to_string()));
+                    return;
                 }
-            });
-        }
+            };
+            producer.start(job, spec.weight(), spec.transform.clone());
+            let partitions = source.partitions();
+            let (tx, mut rx) = tokio::sync::mpsc::channel::<(u32, Chunk)>(partitions * 4);
+            let mut readers = Vec::with_capacity(partitions);
+            for i in 0..partitions {
+                let source = source.clone();
+                let tx = tx.clone();
 
-        drop(tx);
-        while let Some(items) = rx.recv().await {
-            if !producer.ready_to_produce.load(Ordering::Acquire) {
-                continue;
+                readers.push(tokio::spawn(
+                    async move {
+                        // tags every chunk with its partition on the way out
+                        let (partition_tx, mut partition_rx) =
+                            tokio::sync::mpsc::channel::<Chunk>(4);
+                        let forward = async {
+                            while let Some(chunk) = partition_rx.recv().await {
+                                if tx.send((i as u32, chunk)).await.is_err() {
+                                    break;
+                                }
+                            }
+                        };
+                        let (read, _) =
+                            tokio::join!(source.read_partition(i, partition_tx), forward);
+                        if let Err(e) = read {
+                            error!(error = %e, "failed to read partition");
+                        }
+                    }
+                    .instrument(info_span!("read_partition", partition = i)),
+                ));
             }
-            match producer.produce(items).await {
-                Ok(_) => println!("chunk produced successfully"),
-                Err(err) => println!("failed to produce chunk {}", err),
+
+            drop(tx);
+            let mut chunks = 0;
+            let mut failed = None;
+            // a paused job stops here and its readers stall on the full channel
+            while control.proceed().await {
+                let next = tokio::select! {
+                    next = rx.recv() => next,
+                    _ = control.cancelled() => None,
+                };
+                let Some((partition, chunk)) = next else {
+                    break;
+                };
+                let id = ChunkId {
+                    job,
+                    chunk: chunks,
+                    partition,
+                };
+                let produced = tokio::select! {
+                    produced = produce_when_ready(&producer, id, &chunk, codec) => produced,
+                    _ = control.cancelled() => break,
+                };
+                match produced {
+                    Ok(_) => {
+                        debug!(chunk = id.chunk, partition, "chunk produced");
+                        jobs.produced(job, partition);
+                        chunks += 1;
+                    }
+                    Err(err) => {
+                        failed = Some(err.to_string());
+                        break;
+                    }
+                }
+            }
+            if control.is_cancelled() || failed.is_some() {
+                for reader in &readers {
+                    reader.abort();
+                }
+                match &failed {
+                    Some(e) => error!(chunks, error = %e, "job failed"),
+                    None => info!(chunks, "job cancelled"),
+                }
+                jobs.shared(job, producer.schedule.share(job));
+                producer.cancel(job).await;
+                results.lock().unwrap().cancel(job);
+                jobs.finish(job, Err(failed.unwrap_or_else(|| "cancelled".to_string())));
+                return;
+            }
+            info!(chunks, "job read in full, flushing");
+            jobs.flushed(job);
+            if let Some(job) = producer.flush(job, chunks, &results).await {
+                let _ = done_tx.send(job).await;
             }
+        };
+        tokio::spawn(run.instrument(info_span!("job", job)));
+    }
+}
+
+/// Produces a chunk, waiting for a processor to come back for as long as
+/// there is none to take it, so a chunk read from the source is never
+/// dropped.
+async fn produce_when_ready(
+    producer: &Producer,
+    id: ChunkId,
+    chunk: &Chunk,
+    codec: Codec,
+) -> std::io::Result<()> {
+    let mut interval = time::interval(Duration::from_secs(5));
+    loop {
+        while !producer.ready_to_produce.load(Ordering::Acquire) {
+            interval.tick().await;
+        }
+        match producer.produce(id, chunk, codec).await {
+            Err(e) if e.kind() == std::io::ErrorKind::NotConnected => {
+                warn!(
+                    partition = id.partition,
+                    "no processor took the chunk, waiting for one"
+                );
+            }
+            produced => return produced,
         }
     }
 }
+
+/// Opens the source of a job. Its `source` is `postgres` (the default),
+/// `sqlite:<path>`, `csv:<path>` or `ndjson:<path>`. Database sources read
+/// `query` (a table name or a query, `netflow` by default) partitioned on the
+/// integer `partition_column` (`flow_id` by default). Whatever the job leaves
+/// out comes from `SOURCE`, `SOURCE_QUERY` and `PARTITION_COLUMN`.
+async fn open_source(
+    db: &db::DB,
+    job: &JobSpec,
+    partitions: usize,
+) -> Result<Arc<dyn Source>, source::SourceError> {
+    let setting = |value: &Option<String>, name: &str, default: &str| {
+        value
+            .clone()
+            .or_else(|| env::var(name).ok())
+            .unwrap_or_else(|| default.to_string())
+    };
+    let spec = setting(&job.source, "SOURCE", "postgres");
+    let query = setting(&job.query, "SOURCE_QUERY", "netflow");
+    let column = setting(&job.partition_column, "PARTITION_COLUMN", "flow_id");
+    if !source::is_identifier(&column) {
+        return Err(source::SourceError::Parse(format!(
+            "PARTITION_COLUMN must be a column name, got {:?}",
+            column
+        )));
+    }
+
+    let source: Arc<dyn Source> = match spec.split_once(':') {
+        None if spec == "postgres" => Arc::new(PostgresSource::new(
+            db.pool().clone(),
+            &query,
+            &column,
+            partitions,
+        )),
+        Some(("sqlite", path)) => {
+            Arc::new(SqliteSource::open(path, &query, &column, partitions).await?)
+        }
+        Some(("csv", path)) => Arc::new(FileSource::new(path, FileFormat::Csv, partitions)),
+        Some(("ndjson", path)) => Arc::new(FileSource::new(path, FileFormat::Ndjson, partitions)),
+        _ => {
+            return Err(source::SourceError::Parse(format!(
+                "unknown SOURCE {:?}",
+                spec
+            )));
+        }
+    };
+    Ok(source)
+}
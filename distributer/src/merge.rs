@@ -0,0 +1,340 @@
+//! K-way merge of the sorted runs uploaded for a job into one sorted stream.
+//! Only the current block of every run is held in memory, so the size of the
+//! result is bounded by disk rather than RAM.
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use common::netflow::Netflow;
+use common::record::{RecordBatch, Schema, Value};
+use common::result::ChunkOutput;
+use common::spill::{BLOCK_ROWS, RunReader, SortKey};
+use tokio::sync::mpsc;
+use tokio::task;
+
+use crate::sink::{Sink, SinkError};
+
+enum Row {
+    Netflow(Netflow),
+    Record(Vec<Value>),
+}
+
+/// The next unmerged row of one run.
+struct Cursor {
+    reader: RunReader,
+    block: std::vec::IntoIter<Row>,
+    /// Schema of the current block, for record runs.
+    schema: Option<Schema>,
+    key_index: Option<usize>,
+    head: Option<Row>,
+}
+
+impl Cursor {
+    /// Moves to the next row, reading the next block when this one is used
+    /// up, and returns its key. `Ok(None)` once the run is exhausted.
+    fn advance(&mut self, key: SortKey) -> io::Result<Option<Option<i64>>> {
+        loop {
+            if let Some(row) = self.block.next() {
+                let row_key = match (&row, self.key_index) {
+                    (Row::Netflow(netflow), _) => key.netflow(netflow),
+                    (Row::Record(values), Some(i)) => {
+                        let value = values.get(i).ok_or_else(|| {
+                            io::Error::new(
+                                io::ErrorKind::InvalidData,
+                                format!("a row of {} values has no key column {}", values.len(), i),
+                            )
+                        })?;
+                        SortKey::value(value)
+                    }
+                    (Row::Record(_), None) => None,
+                };
+                self.head = Some(row);
+                return Ok(Some(row_key));
+            }
+            let Some(block) = self.reader.next().transpose()? else {
+                self.head = None;
+                return Ok(None);
+            };
+            let rows: Vec<Row> = match block {
+                ChunkOutput::Netflow(rows) => rows.into_iter().map(Row::Netflow).collect(),
+                ChunkOutput::Records(batch) => {
+                    self.key_index = batch.schema.index_of(key.column());
+                    self.schema = Some(batch.schema);
+                    batch.rows.into_iter().map(Row::Record).collect()
+                }
+            };
+            self.block = rows.into_iter();
+        }
+    }
+}
+
+/// Merged blocks of at most [`BLOCK_ROWS`] rows, in key order.
+pub struct Merge {
+    key: SortKey,
+    cursors: Vec<Cursor>,
+    /// Key of every cursor's head row; ties go to the earlier run.
+    heap: BinaryHeap<Reverse<(Option<i64>, usize)>>,
+}
+
+impl Merge {
+    pub fn open(runs: &[PathBuf], key: SortKey) -> io::Result<Self> {
+        let mut merge = Self {
+            key,
+            cursors: Vec::with_capacity(runs.len()),
+            heap: BinaryHeap::with_capacity(runs.len()),
+        };
+        for (i, run) in runs.iter().enumerate() {
+            let mut cursor = Cursor {
+                reader: RunReader::open(run)?,
+                block: Vec::new().into_iter(),
+                schema: None,
+                key_index: None,
+                head: None,
+            };
+            if let Some(row_key) = cursor.advance(key)? {
+                merge.heap.push(Reverse((row_key, i)));
+            }
+            merge.cursors.push(cursor);
+        }
+        Ok(merge)
+    }
+}
+
+impl Iterator for Merge {
+    type Item = io::Result<ChunkOutput>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut netflows = Vec::new();
+        let mut records = Vec::new();
+        let mut schema = None;
+        while netflows.len() + records.len() < BLOCK_ROWS {
+            let Some(Reverse((_, i))) = self.heap.pop() else {
+                break;
+            };
+            let cursor = &mut self.cursors[i];
+            match cursor.head.take() {
+                Some(Row::Netflow(netflow)) => netflows.push(netflow),
+                Some(Row::Record(values)) => {
+                    schema.get_or_insert_with(|| cursor.schema.clone());
+                    records.push(values);
+                }
+                None => continue,
+            }
+            match cursor.advance(self.key) {
+                Ok(Some(row_key)) => self.heap.push(Reverse((row_key, i))),
+                Ok(None) => {}
+                Err(err) => return Some(Err(err)),
+            }
+        }
+
+        if !netflows.is_empty() {
+            Some(Ok(ChunkOutput::Netflow(netflows)))
+        } else if let Some(Some(schema)) = schema {
+            Some(Ok(ChunkOutput::Records(RecordBatch {
+                schema,
+                rows: records,
+            })))
+        } else {
+            None
+        }
+    }
+}
+
+/// Merges `runs` into `sink`, removing them once merged, and returns how many
+/// rows were written. The merge reads files on a blocking thread and hands
+/// blocks over one at a time, so a slow sink holds back the merge instead of
+/// piling blocks up in memory.
+pub async fn merge_into(
+    runs: Vec<PathBuf>,
+    key: SortKey,
+    mut sink: Box<dyn Sink>,
+) -> Result<usize, SinkError> {
+    let (tx, mut rx) = mpsc::channel(1);
+    let merging = task::spawn_blocking(move || -> io::Result<Vec<PathBuf>> {
+        for block in Merge::open(&runs, key)? {
+            if tx.blocking_send(block?).is_err() {
+                break;
+            }
+        }
+        Ok(runs)
+    });
+
+    let mut rows = 0;
+    let mut written = Ok(());
+    while let Some(block) = rx.recv().await {
+        rows += match &block {
+            ChunkOutput::Netflow(netflows) => netflows.len(),
+            ChunkOutput::Records(batch) => batch.rows.len(),
+        };
+        written = sink.write(&block).await;
+        if written.is_err() {
+            break;
+        }
+    }
+    drop(rx);
+    let runs = merging.await.map_err(io::Error::other)??;
+    written?;
+    sink.finish().await?;
+    for run in runs {
+        fs::remove_file(run)?;
+    }
+    Ok(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::sync::{Arc, Mutex};
+
+    use async_trait::async_trait;
+    use chrono::DateTime;
+
+    use super::*;
+    use common::record::{Column, ColumnType};
+    use common::spill::RunWriter;
+
+    /// Keeps every block written to it.
+    struct Collect(Arc<Mutex<Vec<ChunkOutput>>>);
+
+    #[async_trait]
+    impl Sink for Collect {
+        async fn write(&mut self, block: &ChunkOutput) -> Result<(), SinkError> {
+            self.0.lock().unwrap().push(block.clone());
+            Ok(())
+        }
+
+        async fn finish(self: Box<Self>) -> Result<(), SinkError> {
+            Ok(())
+        }
+    }
+
+    fn netflow(flow_id: i64, start: Option<i64>) -> Netflow {
+        Netflow {
+            flow_id,
+            src_ip: None,
+            dst_ip: None,
+            src_port: None,
+            dst_port: None,
+            protocol: None,
+            bytes: None,
+            packets: None,
+            start_ts: start.and_then(DateTime::from_timestamp_micros),
+            end_ts: None,
+            src_asn: None,
+            dst_asn: None,
+        }
+    }
+
+    fn write_run(name: &str, blocks: &[ChunkOutput]) -> PathBuf {
+        let mut run = RunWriter::create(env::temp_dir().join(name)).unwrap();
+        for block in blocks {
+            run.write(block).unwrap();
+        }
+        run.finish().unwrap()
+    }
+
+    #[test]
+    fn test_merges_netflow_runs() {
+        // every third flow id per run, spread over several blocks each
+        let runs: Vec<PathBuf> = (0..3)
+            .map(|r| {
+                let blocks: Vec<ChunkOutput> = (0..4)
+                    .map(|b| {
+                        let ids = (0..5_000).map(|i| (b * 5_000 + i) * 3 + r);
+                        ChunkOutput::Netflow(ids.map(|id| netflow(id, Some(-id))).collect())
+                    })
+                    .collect();
+                write_run(&format!("merge-test-flow-{}.run", r), &blocks)
+            })
+            .collect();
+
+        let mut ids = Vec::new();
+        for block in Merge::open(&runs, SortKey::FlowId).unwrap() {
+            let ChunkOutput::Netflow(rows) = block.unwrap() else {
+                panic!("expected netflow blocks");
+            };
+            assert!(rows.len() <= BLOCK_ROWS);
+            ids.extend(rows.iter().map(|n| n.flow_id));
+        }
+        assert_eq!(ids, (0..60_000).collect::<Vec<_>>());
+
+        // runs sorted by start_ts, rows without one first
+        let late = write_run(
+            "merge-test-ts-0.run",
+            &[ChunkOutput::Netflow(vec![
+                netflow(1, None),
+                netflow(2, Some(20)),
+            ])],
+        );
+        let early = write_run(
+            "merge-test-ts-1.run",
+            &[ChunkOutput::Netflow(vec![
+                netflow(3, Some(10)),
+                netflow(4, Some(30)),
+            ])],
+        );
+        let merged: Vec<ChunkOutput> = Merge::open(&[late, early], SortKey::StartTs)
+            .unwrap()
+            .collect::<io::Result<_>>()
+            .unwrap();
+        let [ChunkOutput::Netflow(rows)] = merged.as_slice() else {
+            panic!("expected one netflow block");
+        };
+        let ids: Vec<i64> = rows.iter().map(|n| n.flow_id).collect();
+        assert_eq!(ids, vec![1, 3, 2, 4]);
+    }
+
+    #[tokio::test]
+    async fn test_merges_record_runs() {
+        let schema = Schema {
+            columns: vec![
+                Column {
+                    name: "flow_id".to_string(),
+                    ty: ColumnType::Int,
+                },
+                Column {
+                    name: "bytes".to_string(),
+                    ty: ColumnType::Int,
+                },
+            ],
+        };
+        let batch = |ids: &[i64]| {
+            ChunkOutput::Records(RecordBatch {
+                schema: schema.clone(),
+                rows: ids
+                    .iter()
+                    .map(|id| vec![Value::Int(*id), Value::Int(id * 100)])
+                    .collect(),
+            })
+        };
+        let runs = [
+            write_run("merge-test-rec-0.run", &[batch(&[1, 4]), batch(&[6])]),
+            write_run("merge-test-rec-1.run", &[batch(&[2, 3, 5])]),
+        ];
+        let merged = Arc::new(Mutex::new(Vec::new()));
+        let sink = Box::new(Collect(merged.clone()));
+        let rows = merge_into(runs.to_vec(), SortKey::FlowId, sink).await;
+        assert_eq!(rows.unwrap(), 6);
+        assert!(runs.iter().all(|run| !run.exists()));
+        let merged = merged.lock().unwrap();
+        let [ChunkOutput::Records(batch)] = merged.as_slice() else {
+            panic!("expected one record block");
+        };
+        assert_eq!(batch.schema, schema);
+        let ids: Vec<Value> = batch.rows.iter().map(|row| row[0].clone()).collect();
+        assert_eq!(ids, (1..=6).map(Value::Int).collect::<Vec<_>>());
+
+        // a row too short to hold the key column
+        let short = ChunkOutput::Records(RecordBatch {
+            schema: Schema {
+                columns: schema.columns.iter().rev().cloned().collect(),
+            },
+            rows: vec![vec![Value::Int(100)]],
+        });
+        let run = write_run("merge-test-rec-short.run", &[short]);
+        assert!(Merge::open(&[run], SortKey::FlowId).is_err());
+    }
+}
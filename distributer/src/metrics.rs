@@ -0,0 +1,129 @@
+//! Counters and histograms kept for the whole process and served in the
+//! Prometheus text format on `/metrics`.
+
+use common::frame;
+use common::metrics::{Counter, Exposition, Family, Histogram};
+
+use crate::http::{Request, Response};
+
+pub static METRICS: Metrics = Metrics {
+    rows_read: Family::new(),
+    chunks_sent: Counter::new(),
+    chunks_acked: Counter::new(),
+    chunks_retried: Counter::new(),
+    chunks_speculated: Counter::new(),
+    results_duplicate: Counter::new(),
+    processor_queue_depth: Family::new(),
+    rows_rejected: Counter::new(),
+    chunk_send_seconds: Histogram::new(LATENCY_BUCKETS),
+};
+
+/// Upper bounds in seconds, for anything from a local round trip to a slow
+/// processor.
+const LATENCY_BUCKETS: &[f64] = &[
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+pub struct Metrics {
+    /// Rows read from the source, by partition.
+    pub rows_read: Family,
+    /// Every attempt to hand a chunk to a processor, resends included.
+    pub chunks_sent: Counter,
+    pub chunks_acked: Counter,
+    /// Chunks sent again: to another processor after a failed send, or
+    /// reassigned after an eviction.
+    pub chunks_retried: Counter,
+    /// Straggling chunks sent to a second processor.
+    pub chunks_speculated: Counter,
+    /// Chunk results answered `DUP`, another copy's having come first.
+    pub results_duplicate: Counter,
+    /// Last reported chunk queue depth, by processor.
+    pub processor_queue_depth: Family,
+    /// Rows the processors rejected in validation.
+    pub rows_rejected: Counter,
+    /// From sending a chunk to its acknowledgement.
+    pub chunk_send_seconds: Histogram,
+}
+
+impl Metrics {
+    pub fn render(&self) -> String {
+        let mut out = Exposition::default();
+        out.family(
+            "distributer_rows_read_total",
+            "Rows read from the source.",
+            "counter",
+            "partition",
+            &self.rows_read,
+        );
+        out.counter(
+            "distributer_chunks_sent_total",
+            "Attempts to send a chunk to a processor.",
+            &self.chunks_sent,
+        );
+        out.counter(
+            "distributer_chunks_acked_total",
+            "Chunks acknowledged by a processor.",
+            &self.chunks_acked,
+        );
+        out.counter(
+            "distributer_chunks_retried_total",
+            "Chunks sent again after a failed send or an eviction.",
+            &self.chunks_retried,
+        );
+        out.counter(
+            "distributer_chunks_speculated_total",
+            "Straggling chunks sent to a second processor.",
+            &self.chunks_speculated,
+        );
+        out.counter(
+            "distributer_results_duplicate_total",
+            "Chunk results dropped as another copy's came first.",
+            &self.results_duplicate,
+        );
+        out.counter(
+            "distributer_frames_resent_total",
+            "Frames resent after a NAK.",
+            &frame::METRICS.resent,
+        );
+        out.family(
+            "distributer_bytes_raw_total",
+            "Frame payload bytes before compression.",
+            "counter",
+            "codec",
+            &frame::METRICS.sent_raw,
+        );
+        out.family(
+            "distributer_bytes_compressed_total",
+            "Frame payload bytes after compression.",
+            "counter",
+            "codec",
+            &frame::METRICS.sent_compressed,
+        );
+        out.family(
+            "distributer_processor_queue_depth",
+            "Chunks queued on a processor at its last heartbeat.",
+            "gauge",
+            "processor",
+            &self.processor_queue_depth,
+        );
+        out.counter(
+            "distributer_rows_rejected_total",
+            "Rows rejected by processor validation.",
+            &self.rows_rejected,
+        );
+        out.histogram(
+            "distributer_chunk_send_seconds",
+            "Time from sending a chunk to its acknowledgement.",
+            &self.chunk_send_seconds,
+        );
+        out.finish()
+    }
+}
+
+/// Answers `GET /metrics`.
+pub fn handle(request: &Request) -> Response {
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/metrics") => Response::text(METRICS.render()),
+        _ => Response::not_found(),
+    }
+}
@@ -1,16 +1,29 @@
-use chrono::{Duration, NaiveDateTime};
+use chrono::{DateTime, Duration, NaiveDateTime, Utc};
+use common::codec::Codec;
+use common::frame::{self, ChunkId};
+use common::netflow::{Netflow, Protocol};
 use rand::rngs::StdRng;
 use rand::{Rng, SeedableRng};
+use serde::Serialize;
 use std::fs::File;
 use std::io::{BufWriter, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::sync::mpsc;
 use std::thread;
 
+use crate::producer;
+
+const COLUMNS: &str = "flow_id, src_ip, dst_ip, src_port, dst_port, protocol, bytes, packets, start_ts, end_ts, src_asn, dst_asn";
+
 pub struct NetflowGenConfig {
     pub rows: usize,
     pub null_prob: f64,
+    /// Share of regular rows whose addresses are IPv6 instead of dotted IPv4.
+    pub ipv6_ratio: f64,
     pub seed: u64,
     pub output_path: String,
+    pub format: OutputFormat,
+    pub anomalies: Option<AnomalyConfig>,
 }
 
 impl Default for NetflowGenConfig {
@@ -18,36 +31,266 @@ impl Default for NetflowGenConfig {
         Self {
             rows: 1_000_000,
             null_prob: 0.15,
+            ipv6_ratio: 0.0,
             seed: 0xdeadbeef,
             output_path: "netflow.sql".to_string(),
+            format: OutputFormat::default(),
+            anomalies: None,
         }
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// `INSERT` statements of at most `batch_size` rows each.
+    Sql { batch_size: usize },
+    /// Header plus one row per line, loadable with `COPY ... WITH (FORMAT csv, HEADER true)`.
+    Csv,
+    /// One JSON object per line.
+    Ndjson,
+    /// `chunk` frames of `chunk_size` rows in the framing `Producer::produce` uses,
+    /// but always lz4 and row encoded, numbered `ChunkId::new(0, n)` rather than
+    /// by a real job, whatever a job's `CODEC` and `CHUNK_ENCODING` would pick.
+    Chunks { chunk_size: usize },
+}
 
-pub fn run(config: NetflowGenConfig) -> std::io::Result<()> {
-    let file = File::create(&config.output_path)?;
-    let mut out = BufWriter::new(file);
+impl Default for OutputFormat {
+    fn default() -> Self {
+        OutputFormat::Sql { batch_size: 10_000 }
+    }
+}
+
+impl OutputFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "sql" => Some(OutputFormat::default()),
+            "csv" => Some(OutputFormat::Csv),
+            "ndjson" => Some(OutputFormat::Ndjson),
+            "chunks" => Some(OutputFormat::Chunks { chunk_size: 1000 }),
+            _ => None,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Sql { .. } => "sql",
+            OutputFormat::Csv => "csv",
+            OutputFormat::Ndjson => "ndjson",
+            OutputFormat::Chunks { .. } => "chunks",
+        }
+    }
+}
+
+/// Labelled anomalies appended after the regular rows. Every injected flow is
+/// listed in `label_path` as `flow_id,scenario,event` so detection transforms
+/// can be scored against ground truth.
+pub struct AnomalyConfig {
+    pub scenarios: Vec<Scenario>,
+    pub events_per_scenario: usize,
+    pub label_path: String,
+}
+
+impl Default for AnomalyConfig {
+    fn default() -> Self {
+        Self {
+            scenarios: Scenario::ALL.to_vec(),
+            events_per_scenario: 5,
+            label_path: "netflow_labels.csv".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scenario {
+    /// One source probing a long run of ports on a single destination.
+    PortScan,
+    /// Many sources hitting one destination port inside a short window.
+    DDoS,
+    /// A few long lived flows moving an unusually large amount of bytes out.
+    Exfiltration,
+    /// Rows that can't be real: end before start or ports outside 0..=65535.
+    Malformed,
+}
+
+impl Scenario {
+    pub const ALL: [Scenario; 4] = [
+        Scenario::PortScan,
+        Scenario::DDoS,
+        Scenario::Exfiltration,
+        Scenario::Malformed,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scenario::PortScan => "port_scan",
+            Scenario::DDoS => "ddos",
+            Scenario::Exfiltration => "exfiltration",
+            Scenario::Malformed => "malformed",
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct FlowRow {
+    flow_id: usize,
+    src_ip: Option<IpAddr>,
+    dst_ip: Option<IpAddr>,
+    src_port: Option<i32>,
+    dst_port: Option<i32>,
+    protocol: Option<i32>,
+    bytes: Option<i64>,
+    packets: Option<i64>,
+    start_ts: Option<DateTime<Utc>>,
+    end_ts: Option<DateTime<Utc>>,
+    src_asn: Option<i32>,
+    dst_asn: Option<i32>,
+}
+
+impl FlowRow {
+    fn to_sql(&self) -> String {
+        format!(
+            "({}, {}, {}, {}, {}, {}, {}, {}, {}, {}, {}, {})",
+            self.flow_id,
+            sql_value(&self.src_ip),
+            sql_value(&self.dst_ip),
+            sql_value(&self.src_port),
+            sql_value(&self.dst_port),
+            sql_value(&self.protocol),
+            sql_value(&self.bytes),
+            sql_value(&self.packets),
+            sql_value(&self.start_ts),
+            sql_value(&self.end_ts),
+            sql_value(&self.src_asn),
+            sql_value(&self.dst_asn),
+        )
+    }
+
+    fn to_csv(&self) -> String {
+        format!(
+            "{},{},{},{},{},{},{},{},{},{},{},{}",
+            self.flow_id,
+            csv_value(&self.src_ip),
+            csv_value(&self.dst_ip),
+            csv_value(&self.src_port),
+            csv_value(&self.dst_port),
+            csv_value(&self.protocol),
+            csv_value(&self.bytes),
+            csv_value(&self.packets),
+            csv_value(&self.start_ts),
+            csv_value(&self.end_ts),
+            csv_value(&self.src_asn),
+            csv_value(&self.dst_asn),
+        )
+    }
+
+    fn to_netflow(&self) -> Netflow {
+        Netflow {
+            flow_id: self.flow_id as i64,
+            src_ip: self.src_ip,
+            dst_ip: self.dst_ip,
+            src_port: self.src_port,
+            dst_port: self.dst_port,
+            protocol: self.protocol.map(|p| Protocol::from(p as u8)),
+            bytes: self.bytes,
+            packets: self.packets,
+            start_ts: self.start_ts,
+            end_ts: self.end_ts,
+            src_asn: self.src_asn,
+            dst_asn: self.dst_asn,
+        }
+    }
+}
 
-    writeln!(
-        out,
-        "INSERT INTO netflow \
-        (flow_id, src_ip, dst_ip, src_port, dst_port, protocol, bytes, packets, start_ts, end_ts, src_asn, dst_asn) VALUES"
-    )?;
+struct RowWriter {
+    out: BufWriter<File>,
+    format: OutputFormat,
+    pending: usize,
+    chunk: Vec<Netflow>,
+    chunks_written: u64,
+}
 
-    let base_ts =
-        NaiveDateTime::parse_from_str("2024-01-01 00:00:00", "%Y-%m-%d %H:%M:%S").unwrap();
+impl RowWriter {
+    fn create(path: &str, format: OutputFormat) -> std::io::Result<Self> {
+        let mut out = BufWriter::new(File::create(path)?);
+        if format == OutputFormat::Csv {
+            writeln!(out, "{}", COLUMNS.replace(", ", ","))?;
+        }
+        Ok(Self {
+            out,
+            format,
+            pending: 0,
+            chunk: Vec::new(),
+            chunks_written: 0,
+        })
+    }
+
+    fn write(&mut self, row: &FlowRow) -> std::io::Result<()> {
+        match self.format {
+            OutputFormat::Sql { batch_size } => {
+                if self.pending == 0 {
+                    writeln!(self.out, "INSERT INTO netflow ({}) VALUES", COLUMNS)?;
+                } else {
+                    writeln!(self.out, ",")?;
+                }
+                write!(self.out, "{}", row.to_sql())?;
+                self.pending += 1;
+                if self.pending >= batch_size {
+                    writeln!(self.out, ";")?;
+                    self.pending = 0;
+                }
+            }
+            OutputFormat::Csv => writeln!(self.out, "{}", row.to_csv())?,
+            OutputFormat::Ndjson => {
+                serde_json::to_writer(&mut self.out, row)?;
+                writeln!(self.out)?;
+            }
+            OutputFormat::Chunks { chunk_size } => {
+                self.chunk.push(row.to_netflow());
+                if self.chunk.len() >= chunk_size {
+                    self.flush_chunk()?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn flush_chunk(&mut self) -> std::io::Result<()> {
+        let raw = producer::encode_chunk(&self.chunk);
+        let id = ChunkId::new(0, self.chunks_written);
+        self.out
+            .write_all(&frame::encode(b"chunk", Codec::Lz4, id, &raw))?;
+        self.chunk.clear();
+        self.chunks_written += 1;
+        Ok(())
+    }
+
+    fn finish(mut self) -> std::io::Result<()> {
+        match self.format {
+            OutputFormat::Sql { .. } if self.pending > 0 => writeln!(self.out, ";")?,
+            OutputFormat::Chunks { .. } if !self.chunk.is_empty() => self.flush_chunk()?,
+            _ => {}
+        }
+        self.out.flush()
+    }
+}
+
+pub fn run(config: NetflowGenConfig) -> std::io::Result<()> {
+    let mut out = RowWriter::create(&config.output_path, config.format)?;
+
+    let base_ts = NaiveDateTime::parse_from_str("2024-01-01 00:00:00", "%Y-%m-%d %H:%M:%S")
+        .unwrap()
+        .and_utc();
 
     let threads = 4;
     let rows_per_thread = config.rows / threads;
 
-    let (tx, rx) = mpsc::channel::<String>();
+    let (tx, rx) = mpsc::channel::<FlowRow>();
 
     for t in 0..threads {
         let tx = tx.clone();
         let mut rng = StdRng::seed_from_u64(config.seed + t as u64);
-        let base_ts = base_ts;
         let null_prob = config.null_prob;
+        let ipv6_ratio = config.ipv6_ratio;
 
         thread::spawn(move || {
             let start_id = t * rows_per_thread;
@@ -56,21 +299,20 @@ pub fn run(config: NetflowGenConfig) -> std::io::Result<()> {
                 let start = base_ts + Duration::seconds(rng.random_range(0..86_400));
                 let end = start + Duration::milliseconds(rng.random_range(1..5_000));
 
-                let row = format!(
-                    "({}, {}, {}, {}, {}, {}, {}, {}, {}, {}, {}, {}),",
-                    start_id + i + 1,
-                    maybe_null(rand_ip(&mut rng), &mut rng, null_prob),
-                    maybe_null(rand_ip(&mut rng), &mut rng, null_prob),
-                    maybe_null(rng.random_range(1024..65535), &mut rng, null_prob),
-                    maybe_null(rng.random_range(1..65535), &mut rng, null_prob),
-                    maybe_null(rng.random_range(1..=255), &mut rng, null_prob),
-                    maybe_null(rng.random_range(40..1_000_000), &mut rng, null_prob),
-                    maybe_null(rng.random_range(1..10_000), &mut rng, null_prob),
-                    maybe_null(start, &mut rng, null_prob),
-                    maybe_null(end, &mut rng, null_prob),
-                    maybe_null(rng.random_range(1..100_000), &mut rng, null_prob),
-                    maybe_null(rng.random_range(1..100_000), &mut rng, null_prob),
-                );
+                let row = FlowRow {
+                    flow_id: start_id + i + 1,
+                    src_ip: maybe_null(rand_addr(&mut rng, ipv6_ratio), &mut rng, null_prob),
+                    dst_ip: maybe_null(rand_addr(&mut rng, ipv6_ratio), &mut rng, null_prob),
+                    src_port: maybe_null(rng.random_range(1024..65535), &mut rng, null_prob),
+                    dst_port: maybe_null(rng.random_range(1..65535), &mut rng, null_prob),
+                    protocol: maybe_null(rng.random_range(1..=255), &mut rng, null_prob),
+                    bytes: maybe_null(rng.random_range(40..1_000_000), &mut rng, null_prob),
+                    packets: maybe_null(rng.random_range(1..10_000), &mut rng, null_prob),
+                    start_ts: maybe_null(start, &mut rng, null_prob),
+                    end_ts: maybe_null(end, &mut rng, null_prob),
+                    src_asn: maybe_null(rng.random_range(1..100_000), &mut rng, null_prob),
+                    dst_asn: maybe_null(rng.random_range(1..100_000), &mut rng, null_prob),
+                };
 
                 tx.send(row).expect("writer dropped");
             }
@@ -80,27 +322,275 @@ pub fn run(config: NetflowGenConfig) -> std::io::Result<()> {
     drop(tx);
 
     for row in rx {
-        writeln!(out, "{}", row)?;
+        out.write(&row)?;
+    }
+
+    if let Some(anomalies) = &config.anomalies {
+        let mut labels = BufWriter::new(File::create(&anomalies.label_path)?);
+        writeln!(labels, "flow_id,scenario,event")?;
+
+        let mut rng = StdRng::seed_from_u64(config.seed + threads as u64);
+        let mut next_id = rows_per_thread * threads + 1;
+        for scenario in &anomalies.scenarios {
+            for event in 0..anomalies.events_per_scenario {
+                for row in inject(*scenario, &mut rng, base_ts, &mut next_id) {
+                    writeln!(labels, "{},{},{}", row.flow_id, scenario.as_str(), event)?;
+                    out.write(&row)?;
+                }
+            }
+        }
+        labels.flush()?;
+    }
+
+    out.finish()
+}
+
+fn inject(
+    scenario: Scenario,
+    rng: &mut StdRng,
+    base_ts: DateTime<Utc>,
+    next_id: &mut usize,
+) -> Vec<FlowRow> {
+    let start = base_ts + Duration::seconds(rng.random_range(0..86_400));
+    let mut rows = Vec::new();
+
+    match scenario {
+        Scenario::PortScan => {
+            let src = rand_ip(rng);
+            let dst = rand_ip(rng);
+            let src_port = rng.random_range(1024..65535);
+            let first_port = rng.random_range(1..1024);
+            let ports = rng.random_range(100..500);
+            for i in 0..ports {
+                let ts = start + Duration::milliseconds(i as i64 * 5);
+                rows.push(FlowRow {
+                    src_ip: Some(src),
+                    dst_ip: Some(dst),
+                    src_port: Some(src_port),
+                    dst_port: Some(first_port + i),
+                    protocol: Some(6),
+                    bytes: Some(rng.random_range(40..80)),
+                    packets: Some(1),
+                    start_ts: Some(ts),
+                    end_ts: Some(ts + Duration::milliseconds(1)),
+                    ..regular(rng, base_ts)
+                });
+            }
+        }
+        Scenario::DDoS => {
+            let dst = rand_ip(rng);
+            let dst_port = if rng.random_bool(0.5) { 80 } else { 443 };
+            let sources = rng.random_range(500..2_000);
+            for _ in 0..sources {
+                let ts = start + Duration::milliseconds(rng.random_range(0..10_000));
+                rows.push(FlowRow {
+                    src_ip: Some(rand_ip(rng)),
+                    dst_ip: Some(dst),
+                    src_port: Some(rng.random_range(1024..65535)),
+                    dst_port: Some(dst_port),
+                    protocol: Some(if rng.random_bool(0.5) { 6 } else { 17 }),
+                    bytes: Some(rng.random_range(40..1_500)),
+                    packets: Some(rng.random_range(1..5)),
+                    start_ts: Some(ts),
+                    end_ts: Some(ts + Duration::milliseconds(rng.random_range(1..50))),
+                    ..regular(rng, base_ts)
+                });
+            }
+        }
+        Scenario::Exfiltration => {
+            let src = IpAddr::V4(Ipv4Addr::new(
+                10,
+                rng.random_range(0..=255),
+                rng.random_range(0..=255),
+                rng.random_range(1..=254),
+            ));
+            let dst = rand_ip(rng);
+            let flows = rng.random_range(1..=5);
+            for _ in 0..flows {
+                let ts = start + Duration::seconds(rng.random_range(0..600));
+                rows.push(FlowRow {
+                    src_ip: Some(src),
+                    dst_ip: Some(dst),
+                    src_port: Some(rng.random_range(1024..65535)),
+                    dst_port: Some(443),
+                    protocol: Some(6),
+                    bytes: Some(rng.random_range(500_000_000..5_000_000_000)),
+                    packets: Some(rng.random_range(400_000..4_000_000)),
+                    start_ts: Some(ts),
+                    end_ts: Some(ts + Duration::seconds(rng.random_range(300..3_600))),
+                    ..regular(rng, base_ts)
+                });
+            }
+        }
+        Scenario::Malformed => {
+            let mut row = regular(rng, base_ts);
+            match rng.random_range(0..3) {
+                0 => {
+                    row.start_ts = Some(start);
+                    row.end_ts = Some(start - Duration::seconds(rng.random_range(1..3_600)));
+                }
+                1 => row.src_port = Some(rng.random_range(65_536..1_000_000)),
+                _ => row.dst_port = Some(-rng.random_range(1..65_536)),
+            }
+            rows.push(row);
+        }
     }
 
-    out.flush()?;
-    Ok(())
+    for row in &mut rows {
+        row.flow_id = *next_id;
+        *next_id += 1;
+    }
+    rows
 }
 
-fn maybe_null<T: ToString>(v: T, rng: &mut StdRng, p: f64) -> String {
-    if rng.random_bool(p) {
-        "NULL".to_string()
+/// A fully populated, plausible flow used as the base for injected rows.
+fn regular(rng: &mut StdRng, base_ts: DateTime<Utc>) -> FlowRow {
+    let start = base_ts + Duration::seconds(rng.random_range(0..86_400));
+    FlowRow {
+        flow_id: 0,
+        src_ip: Some(rand_ip(rng)),
+        dst_ip: Some(rand_ip(rng)),
+        src_port: Some(rng.random_range(1024..65535)),
+        dst_port: Some(rng.random_range(1..65535)),
+        protocol: Some(6),
+        bytes: Some(rng.random_range(40..1_000_000)),
+        packets: Some(rng.random_range(1..10_000)),
+        start_ts: Some(start),
+        end_ts: Some(start + Duration::milliseconds(rng.random_range(1..5_000))),
+        src_asn: Some(rng.random_range(1..100_000)),
+        dst_asn: Some(rng.random_range(1..100_000)),
+    }
+}
+
+fn maybe_null<T>(v: T, rng: &mut StdRng, p: f64) -> Option<T> {
+    if rng.random_bool(p) { None } else { Some(v) }
+}
+
+fn sql_value<T: ToString>(v: &Option<T>) -> String {
+    match v {
+        Some(v) => format!("'{}'", v.to_string()),
+        None => "NULL".to_string(),
+    }
+}
+
+fn csv_value<T: ToString>(v: &Option<T>) -> String {
+    match v {
+        Some(v) => v.to_string(),
+        None => String::new(),
+    }
+}
+
+fn rand_addr(rng: &mut StdRng, ipv6_ratio: f64) -> IpAddr {
+    if ipv6_ratio > 0.0 && rng.random_bool(ipv6_ratio) {
+        rand_ipv6(rng)
     } else {
-        format!("'{}'", v.to_string())
+        rand_ip(rng)
     }
 }
 
-fn rand_ip(rng: &mut StdRng) -> String {
-    format!(
-        "{}.{}.{}.{}",
+/// A global unicast (`2000::/3`) address in canonical compressed form.
+fn rand_ipv6(rng: &mut StdRng) -> IpAddr {
+    IpAddr::V6(Ipv6Addr::new(
+        rng.random_range(0x2000..=0x3fff),
+        rng.random(),
+        rng.random(),
+        rng.random(),
+        rng.random(),
+        rng.random(),
+        rng.random(),
+        rng.random(),
+    ))
+}
+
+fn rand_ip(rng: &mut StdRng) -> IpAddr {
+    IpAddr::V4(Ipv4Addr::new(
         rng.random_range(1..=223),
         rng.random_range(0..=255),
         rng.random_range(0..=255),
         rng.random_range(1..=254),
-    )
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::frame::FrameLimits;
+
+    #[test]
+    fn test_anomaly_labels() {
+        let dir = std::env::temp_dir();
+        let output_path = dir.join("netflow_gen_test.sql");
+        let label_path = dir.join("netflow_gen_test_labels.csv");
+        run(NetflowGenConfig {
+            rows: 100,
+            null_prob: 0.2,
+            ipv6_ratio: 0.3,
+            seed: 7,
+            output_path: output_path.to_string_lossy().into_owned(),
+            format: OutputFormat::Sql { batch_size: 1000 },
+            anomalies: Some(AnomalyConfig {
+                label_path: label_path.to_string_lossy().into_owned(),
+                ..AnomalyConfig::default()
+            }),
+        })
+        .unwrap();
+
+        let labels = std::fs::read_to_string(&label_path).unwrap();
+        let ids: Vec<usize> = labels
+            .lines()
+            .skip(1)
+            .map(|l| l.split(',').next().unwrap().parse().unwrap())
+            .collect();
+        assert_eq!(ids[0], 101);
+        assert!(ids.windows(2).all(|w| w[1] == w[0] + 1));
+        for scenario in Scenario::ALL {
+            assert!(labels.contains(scenario.as_str()));
+        }
+
+        let sql = std::fs::read_to_string(&output_path).unwrap();
+        let rows = sql.lines().filter(|l| l.starts_with('(')).count();
+        assert_eq!(rows, 100 + ids.len());
+        assert_eq!(sql.matches("INSERT INTO").count(), rows.div_ceil(1000));
+        assert!(sql.trim_end().ends_with(';'));
+
+        let mut v6 = 0;
+        for line in sql.lines().filter(|l| l.starts_with('(')).take(100) {
+            for field in line.split(", ").skip(1).take(2) {
+                let addr = field.trim_matches('\'');
+                if addr != "NULL" {
+                    let addr: IpAddr = addr.parse().unwrap();
+                    v6 += addr.is_ipv6() as usize;
+                }
+            }
+        }
+        assert!(v6 > 0);
+    }
+
+    #[tokio::test]
+    async fn test_chunk_output() {
+        let output_path = std::env::temp_dir().join("netflow_gen_test.chunks");
+        run(NetflowGenConfig {
+            rows: 2500,
+            output_path: output_path.to_string_lossy().into_owned(),
+            format: OutputFormat::Chunks { chunk_size: 1000 },
+            ..NetflowGenConfig::default()
+        })
+        .unwrap();
+
+        let bytes = std::fs::read(&output_path).unwrap();
+        let mut reader = bytes.as_slice();
+        let mut sizes = Vec::new();
+        while let Some((prefix, rest)) = reader.split_first_chunk::<5>() {
+            assert_eq!(prefix, b"chunk");
+            assert_eq!(rest[0], Codec::Lz4.id());
+            reader = rest;
+            let Some(Ok((id, raw))) = frame::read(&mut reader, FrameLimits::default()).await else {
+                panic!("corrupted chunk frame");
+            };
+            assert_eq!(id.chunk, sizes.len() as u64);
+            let items: Vec<Netflow> = bincode2::deserialize(&raw).unwrap();
+            sizes.push(items.len());
+        }
+        assert_eq!(sizes, vec![1000, 1000, 500]);
+    }
 }
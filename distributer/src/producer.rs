@@ -1,38 +1,388 @@
-use crate::Netflow;
-use lz4_flex::compress_prepend_size;
-use std::io::Error;
+use crate::metrics::METRICS;
+use crate::record::Chunk;
+use crate::result::{JobResults, ResultStore};
+use crate::schedule::FairQueue;
+use common::auth::{self, Secret};
+use common::codec::Codec;
+use common::columnar;
+use common::frame::{self, ChunkId, FrameLimits};
+use common::health::HealthReport;
+use common::netflow::Netflow;
+use common::record::{RecordBatch, TransformSpec};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::io::{Error, ErrorKind};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{self, Duration, Instant};
+use tracing::{debug, error, info, warn};
+
+/// How processors are watched. Every heartbeat checks all of them at once,
+/// giving each `timeout` to answer. A processor that misses `suspect_after`
+/// checks in a row is suspect and gets no new chunks; one that misses
+/// `evict_after` is dropped and its chunks go to the others. A suspect has to
+/// pass `recover_after` checks in a row before it is trusted again, so a
+/// flapping processor doesn't bounce in and out of the rotation.
+#[derive(Debug, Clone, Copy)]
+pub struct HeartbeatConfig {
+    pub interval: Duration,
+    pub timeout: Duration,
+    pub suspect_after: u32,
+    pub evict_after: u32,
+    pub recover_after: u32,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(5),
+            timeout: Duration::from_secs(2),
+            suspect_after: 1,
+            evict_after: 3,
+            recover_after: 2,
+        }
+    }
+}
+
+impl HeartbeatConfig {
+    /// Reads `HEARTBEAT_INTERVAL_MS`, `HEARTBEAT_TIMEOUT_MS`,
+    /// `SUSPECT_AFTER`, `EVICT_AFTER` and `RECOVER_AFTER`.
+    pub fn from_env() -> Result<Self, String> {
+        let mut config = Self::default();
+        for (name, duration) in [
+            ("HEARTBEAT_INTERVAL_MS", &mut config.interval),
+            ("HEARTBEAT_TIMEOUT_MS", &mut config.timeout),
+        ] {
+            if let Ok(value) = env::var(name) {
+                *duration = value
+                    .parse()
+                    .map(Duration::from_millis)
+                    .map_err(|_| format!("{} must be in milliseconds, got {:?}", name, value))?;
+            }
+        }
+        for (name, count) in [
+            ("SUSPECT_AFTER", &mut config.suspect_after),
+            ("EVICT_AFTER", &mut config.evict_after),
+            ("RECOVER_AFTER", &mut config.recover_after),
+        ] {
+            if let Ok(value) = env::var(name) {
+                *count = value
+                    .parse()
+                    .map_err(|_| format!("{} must be a count, got {:?}", name, value))?;
+            }
+        }
+        if config.evict_after < config.suspect_after {
+            return Err("EVICT_AFTER must not be below SUSPECT_AFTER".to_string());
+        }
+        Ok(config)
+    }
+}
+
+/// When a chunk is a straggler: it has gone `factor` times the median time
+/// from acknowledgement to result of its job's chunks so far without one,
+/// and at least `min_samples` of them are in. It is then sent to an idle
+/// processor as well. Checked every `interval`; a `factor` of 0 turns it off.
+#[derive(Debug, Clone, Copy)]
+pub struct SpeculationConfig {
+    pub interval: Duration,
+    pub factor: f64,
+    pub min_samples: usize,
+}
+
+impl Default for SpeculationConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(1),
+            factor: 3.0,
+            min_samples: 10,
+        }
+    }
+}
+
+impl SpeculationConfig {
+    /// Reads `SPECULATE_INTERVAL_MS`, `SPECULATE_FACTOR` and
+    /// `SPECULATE_MIN_SAMPLES`.
+    pub fn from_env() -> Result<Self, String> {
+        let mut config = Self::default();
+        if let Ok(value) = env::var("SPECULATE_INTERVAL_MS") {
+            config.interval = value.parse().map(Duration::from_millis).map_err(|_| {
+                format!(
+                    "SPECULATE_INTERVAL_MS must be in milliseconds, got {:?}",
+                    value
+                )
+            })?;
+        }
+        if let Ok(value) = env::var("SPECULATE_FACTOR") {
+            config.factor = value
+                .parse()
+                .ok()
+                .filter(|factor: &f64| *factor == 0.0 || *factor >= 1.0)
+                .ok_or_else(|| {
+                    format!("SPECULATE_FACTOR must be 0 or at least 1, got {:?}", value)
+                })?;
+        }
+        if let Ok(value) = env::var("SPECULATE_MIN_SAMPLES") {
+            config.min_samples = value
+                .parse()
+                .map_err(|_| format!("SPECULATE_MIN_SAMPLES must be a count, got {:?}", value))?;
+        }
+        Ok(config)
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.factor > 0.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Health {
+    Healthy,
+    /// Missed a heartbeat or a send; gets no new chunks until it recovers.
+    Suspect,
+}
+
+/// A registered processor and the chunk encodings it offered in `connect`.
+#[derive(Debug, Clone)]
+struct ProcessorNode {
+    addr: String,
+    columnar: bool,
+    health: Health,
+    /// Heartbeats missed in a row.
+    misses: u32,
+    /// Heartbeats passed in a row since it became suspect.
+    passes: u32,
+    /// What it said in the last heartbeat it passed.
+    report: Option<HealthReport>,
+    /// Drained through the admin API: it keeps the chunks it has but gets
+    /// no new ones.
+    drained: bool,
+}
+
+/// A registered processor as the admin API shows it.
+#[derive(Debug, Clone, Serialize)]
+pub struct ProcessorStatus {
+    pub addr: String,
+    pub columnar: bool,
+    pub health: Health,
+    pub misses: u32,
+    pub drained: bool,
+    pub report: Option<HealthReport>,
+}
+
+impl ProcessorNode {
+    fn new(addr: String, columnar: bool) -> Self {
+        Self {
+            addr,
+            columnar,
+            health: Health::Healthy,
+            misses: 0,
+            passes: 0,
+            report: None,
+            drained: false,
+        }
+    }
+
+    fn draining(&self) -> bool {
+        self.drained || self.report.as_ref().is_some_and(|r| r.draining)
+    }
+
+    /// Healthy, taking chunks and with nothing to do at its last heartbeat.
+    fn idle(&self) -> bool {
+        self.health == Health::Healthy
+            && !self.draining()
+            && self.report.as_ref().is_some_and(HealthReport::idle)
+    }
+
+    /// How much it should be avoided for new chunks: 0 if it has room, 1 if
+    /// its queue is nearly full, 2 if it is draining.
+    fn load_tier(&self) -> u8 {
+        match &self.report {
+            _ if self.draining() => 2,
+            Some(report) if report.saturated() => 1,
+            _ => 0,
+        }
+    }
+
+    /// One line of cluster status.
+    fn status(&self) -> String {
+        let health = match self.health {
+            Health::Healthy => "healthy",
+            Health::Suspect => "suspect",
+        };
+        match &self.report {
+            Some(report) => format!("{} {}: {}", self.addr, health, report),
+            None => format!("{} {}: no report yet", self.addr, health),
+        }
+    }
+}
+
+/// The frames one processor was given for a job.
+#[derive(Default)]
+struct NodeFrames {
+    acked: Vec<(ChunkId, Vec<u8>)>,
+    /// Reassigned here but not yet acknowledged, resent every heartbeat.
+    unacked: Vec<(ChunkId, Vec<u8>)>,
+    /// The total the processor last acknowledged in a `flush`.
+    flushed: Option<u64>,
+}
+
+impl NodeFrames {
+    fn total(&self) -> u64 {
+        (self.acked.len() + self.unacked.len()) as u64
+    }
+
+    fn frame(&self, chunk: u64) -> Option<&(ChunkId, Vec<u8>)> {
+        self.acked
+            .iter()
+            .chain(&self.unacked)
+            .find(|(id, _)| id.chunk == chunk)
+    }
+}
+
+/// How many bytes of encoded frames a job may keep for resends by default.
+pub const DEFAULT_RETAIN_BYTES: usize = 1 << 30;
+
+/// Where a job's chunks went, kept until the job completes so those of an
+/// evicted processor can be sent to another one.
+#[derive(Default)]
+struct Assignment {
+    /// Sent along with every record batch of the job.
+    transform: Option<TransformSpec>,
+    /// Set once every chunk of the job has been produced.
+    flushed: bool,
+    nodes: HashMap<String, NodeFrames>,
+    /// Frames of evicted processors waiting for a healthy one to take them.
+    /// The results still expect them from the evicted processor until then.
+    orphaned: HashMap<String, Vec<(ChunkId, Vec<u8>)>>,
+    /// Which processors hold a frame of each chunk.
+    holders: HashMap<u64, (ChunkId, Vec<String>)>,
+    /// When each chunk still waiting for its result was first acknowledged.
+    sent: HashMap<u64, Instant>,
+    /// How long the chunks whose results are in took, sorted.
+    latencies: Vec<Duration>,
+    /// Chunks sent to a second processor as well: stragglers, and those
+    /// whose first send failed after it may have got through.
+    speculated: HashSet<u64>,
+    /// Bytes of the frames kept above, held to the producer's `retain_bytes`.
+    retained: usize,
+}
+
+impl Assignment {
+    /// How many chunks each processor has to upload output for.
+    fn totals(&self) -> HashMap<String, u64> {
+        let assigned = self.nodes.iter().map(|(addr, n)| (addr.clone(), n.total()));
+        let orphaned = self
+            .orphaned
+            .iter()
+            .map(|(addr, frames)| (addr.clone(), frames.len() as u64));
+        assigned.chain(orphaned).collect()
+    }
+
+    /// Records that `addr` holds a frame of chunk `id`.
+    fn hold(&mut self, addr: &str, id: ChunkId) {
+        let (_, addrs) = self.holders.entry(id.chunk).or_insert((id, Vec::new()));
+        addrs.push(addr.to_string());
+    }
+
+    /// Forgets that `addr` holds any frame of the job.
+    fn release(&mut self, addr: &str) {
+        for (_, addrs) in self.holders.values_mut() {
+            addrs.retain(|a| a != addr);
+        }
+    }
+}
+
+/// What `sync` has to send to one processor for one job.
+struct Pending {
+    job: u64,
+    addr: String,
+    columnar: bool,
+    frames: Vec<(ChunkId, Vec<u8>)>,
+    flush: Option<u64>,
+}
+
 pub struct Producer {
-    processors: Arc<Mutex<Vec<String>>>,
+    processors: Arc<Mutex<Vec<ProcessorNode>>>,
     curr_index: Arc<Mutex<usize>>,
+    /// Per job, which processor got which chunk, until the job completes.
+    jobs: Arc<Mutex<HashMap<u64, Assignment>>>,
     pub ready_to_produce: Arc<AtomicBool>,
+    /// When set, every connection in either direction starts with the
+    /// [`auth`] handshake.
+    secret: Option<Secret>,
+    pub heartbeat: HeartbeatConfig,
+    /// Shares the processors between the jobs running at once.
+    pub schedule: FairQueue,
+    /// How many bytes of encoded frames each job may keep for resends. A job
+    /// whose next chunk would go over it fails rather than run the
+    /// distributer out of memory.
+    pub retain_bytes: usize,
 }
 
 impl Producer {
-    pub fn new() -> Self {
+    pub fn new(secret: Option<Secret>, heartbeat: HeartbeatConfig) -> Self {
         Self {
             processors: Arc::new(Mutex::new(Vec::new())),
             curr_index: Arc::new(Mutex::new(0)),
+            jobs: Arc::new(Mutex::new(HashMap::new())),
             ready_to_produce: Arc::new(AtomicBool::new(false)),
+            secret,
+            heartbeat,
+            schedule: FairQueue::default(),
+            retain_bytes: DEFAULT_RETAIN_BYTES,
+        }
+    }
+
+    /// Opens a connection to a processor's data port, authenticated when a
+    /// secret is configured.
+    async fn connect(&self, addr: &str) -> Result<TcpStream, Error> {
+        let mut stream = TcpStream::connect(addr).await?;
+        if let Some(secret) = &self.secret {
+            auth::connect(&mut stream, secret).await?;
         }
+        Ok(stream)
+    }
+
+    /// Sends one frame and waits for it to be acknowledged.
+    async fn send_to(&self, addr: &str, frame: &[u8]) -> Result<(), Error> {
+        let mut stream = self.connect(addr).await?;
+        frame::send(&mut stream, frame).await
+    }
+
+    /// [`send_to`](Self::send_to) for a chunk frame, timed and counted.
+    async fn send_chunk(&self, addr: &str, frame: &[u8]) -> Result<(), Error> {
+        METRICS.chunks_sent.inc();
+        let started = Instant::now();
+        self.send_to(addr, frame).await?;
+        METRICS.chunk_send_seconds.observe(started.elapsed());
+        METRICS.chunks_acked.inc();
+        Ok(())
     }
 
     pub async fn listen_processor(&self) -> Result<(), Box<dyn std::error::Error>> {
         let listener: TcpListener = TcpListener::bind("0.0.0.0:8080").await?;
-        println!("Listening on port 8080...");
+        info!("listening for processors on port 8080");
 
-        let processors: Arc<Mutex<Vec<String>>> = Arc::clone(&self.processors);
+        let processors = Arc::clone(&self.processors);
 
         loop {
             let (mut socket, addr) = listener.accept().await?;
-            println!("New connection from {}", addr);
+            debug!(%addr, "new control connection");
 
             let processors = Arc::clone(&processors);
+            let secret = self.secret.clone();
 
             tokio::spawn(async move {
+                if let Some(secret) = secret
+                    && let Err(e) = auth::accept(&mut socket, &secret).await
+                {
+                    warn!(%addr, error = %e, "rejected control connection");
+                    return;
+                }
                 let mut buf = vec![0u8; 1024];
 
                 loop {
@@ -40,102 +390,1066 @@ impl Producer {
                         Ok(0) => break,
                         Ok(n) => {
                             let cmd = String::from_utf8_lossy(&buf[..n]).to_string();
-                            println!("received command: {}", cmd);
+                            debug!(%addr, command = %cmd, "received command");
                             if cmd.contains("connect") {
-                                if let Some(port) = cmd.strip_prefix("connect ") {
-                                    let node_addr = format!("{}:{}", addr.ip(), port);
-                                    println!("{} registered", node_addr);
+                                if let Some(args) = cmd.strip_prefix("connect ") {
+                                    // `connect <port> [encoding...]`
+                                    let mut args = args.split_whitespace();
+                                    let Some(port) = args.next() else { continue };
+                                    let node = ProcessorNode::new(
+                                        format!("{}:{}", addr.ip(), port),
+                                        args.any(|a| a == "columnar"),
+                                    );
+                                    info!(
+                                        processor = %node.addr,
+                                        columnar = node.columnar,
+                                        "processor registered"
+                                    );
                                     let mut procs = processors.lock().unwrap();
-                                    if !procs.contains(&node_addr) {
-                                        procs.push(node_addr);
+                                    match procs.iter_mut().find(|p| p.addr == node.addr) {
+                                        Some(existing) => *existing = node,
+                                        None => procs.push(node),
                                     }
                                 }
                             } else if cmd == "disconnect" {
                                 let mut procs = processors.lock().unwrap();
-                                procs.retain(|ip: &String| ip != &addr.ip().to_string());
+                                procs.retain(|p| p.addr != addr.ip().to_string());
                             } else {
                                 if let Err(e) = socket.write_all(b"invalid command").await {
-                                    eprintln!("Failed to write to socket: {}", e);
+                                    warn!(%addr, error = %e, "failed to write to socket");
                                     break;
                                 }
                             }
                         }
                         Err(e) => {
-                            eprintln!("Failed to read from socket: {}", e);
+                            warn!(%addr, error = %e, "failed to read from socket");
                             break;
                         }
                     }
                 }
 
-                println!("Connection from {} closed", addr);
+                debug!(%addr, "control connection closed");
             });
         }
     }
 
-    pub async fn heartbeat_processors(&self) -> Result<(), Error> {
+    /// Checks every processor at once, evicts the ones that missed too many
+    /// checks and hands their chunks to the rest, then resends whatever a
+    /// processor has not acknowledged yet.
+    pub async fn heartbeat_processors(&self, store: &Mutex<ResultStore>) {
         let processors_snapshot: Vec<String> = {
             let procs = self.processors.lock().unwrap();
-            procs.clone()
+            procs.iter().map(|p| p.addr.clone()).collect()
         };
 
-        let mut unhealthy = Vec::new();
-
-        for processor in &processors_snapshot {
-            match TcpStream::connect(processor).await {
-                Ok(mut stream) => {
-                    stream.write_all(b"health-check").await?;
-                    let mut buf = vec![0; 1024];
-                    let n = stream.read(&mut buf).await?;
-                    let resp = String::from_utf8_lossy(&buf[..n]);
+        let checks = processors_snapshot.iter().map(|addr| async move {
+            let check = async {
+                let mut stream = self.connect(addr).await?;
+                stream.write_all(b"health-check").await?;
+                let mut prefix = [0u8; 5];
+                stream.read_exact(&mut prefix).await?;
+                if &prefix != b"healt" {
+                    return Err(Error::other("unexpected health check reply"));
+                }
+                let Some(Ok((_, raw))) = frame::read(&mut stream, FrameLimits::default()).await
+                else {
+                    return Err(Error::other("corrupted health report"));
+                };
+                bincode2::deserialize::<HealthReport>(&raw).map_err(Error::other)
+            };
+            time::timeout(self.heartbeat.timeout, check)
+                .await
+                .ok()
+                .and_then(Result::ok)
+        });
+        let reports = futures::future::join_all(checks).await;
 
-                    if resp != "healthy" {
-                        unhealthy.push(processor.clone());
+        let mut evicted = Vec::new();
+        {
+            let mut procs = self.processors.lock().unwrap();
+            for (addr, report) in processors_snapshot.iter().zip(reports) {
+                let Some(node) = procs.iter_mut().find(|p| &p.addr == addr) else {
+                    continue;
+                };
+                if let Some(report) = report {
+                    if report.draining && !node.draining() {
+                        info!(processor = %addr, "processor draining, sending it no new chunks");
+                    }
+                    METRICS.processor_queue_depth.set(addr, report.queue_depth);
+                    node.report = Some(report);
+                    node.misses = 0;
+                    if node.health == Health::Suspect {
+                        node.passes += 1;
+                        if node.passes >= self.heartbeat.recover_after {
+                            info!(processor = %addr, "processor recovered");
+                            node.health = Health::Healthy;
+                        }
                     }
+                    continue;
                 }
-                Err(_) => {
-                    unhealthy.push(processor.clone());
+                node.misses += 1;
+                node.passes = 0;
+                if node.misses >= self.heartbeat.evict_after {
+                    warn!(processor = %addr, misses = node.misses, "evicting processor");
+                    evicted.push(addr.clone());
+                } else if node.misses >= self.heartbeat.suspect_after
+                    && node.health == Health::Healthy
+                {
+                    warn!(processor = %addr, misses = node.misses, "processor suspect");
+                    node.health = Health::Suspect;
                 }
             }
+            procs.retain(|p| !evicted.contains(&p.addr));
+            for node in procs.iter() {
+                debug!("{}", node.status());
+            }
         }
-
-        if !unhealthy.is_empty() {
-            let mut procs = self.processors.lock().unwrap();
-            procs.retain(|ip| !unhealthy.contains(ip));
+        for addr in &evicted {
+            METRICS.processor_queue_depth.remove(addr);
+            self.evict(addr, store);
         }
+        self.reassign(store);
+        self.sync().await;
         self.ready_to_produce.store(
-            !self.processors.lock().unwrap().is_empty(),
+            self.processors
+                .lock()
+                .unwrap()
+                .iter()
+                .any(|p| p.health == Health::Healthy),
             Ordering::Release,
         );
+    }
 
-        Ok(())
+    /// Every registered processor and how it is doing.
+    pub fn processors(&self) -> Vec<ProcessorStatus> {
+        let procs = self.processors.lock().unwrap();
+        procs
+            .iter()
+            .map(|p| ProcessorStatus {
+                addr: p.addr.clone(),
+                columnar: p.columnar,
+                health: p.health,
+                misses: p.misses,
+                drained: p.drained,
+                report: p.report.clone(),
+            })
+            .collect()
+    }
+
+    /// Stops sending new chunks to a processor, like it does once it reports
+    /// draining itself. Returns whether it is registered.
+    pub fn drain(&self, addr: &str) -> bool {
+        let mut procs = self.processors.lock().unwrap();
+        let Some(node) = procs.iter_mut().find(|p| p.addr == addr) else {
+            return false;
+        };
+        info!(processor = %addr, "draining processor on request");
+        node.drained = true;
+        true
+    }
+
+    /// Drops a processor as if it had missed too many heartbeats. Its chunks
+    /// go to the others on the next heartbeat. Returns whether it was
+    /// registered.
+    pub fn evict_processor(&self, addr: &str, store: &Mutex<ResultStore>) -> bool {
+        {
+            let mut procs = self.processors.lock().unwrap();
+            let Some(i) = procs.iter().position(|p| p.addr == addr) else {
+                return false;
+            };
+            procs.remove(i);
+            self.ready_to_produce.store(
+                procs.iter().any(|p| p.health == Health::Healthy),
+                Ordering::Release,
+            );
+        }
+        warn!(processor = %addr, "evicting processor on request");
+        METRICS.processor_queue_depth.remove(addr);
+        self.evict(addr, store);
+        true
+    }
+
+    /// Marks a processor that failed a send as suspect.
+    fn suspect(&self, addr: &str) {
+        let mut procs = self.processors.lock().unwrap();
+        if let Some(node) = procs.iter_mut().find(|p| p.addr == addr)
+            && node.health == Health::Healthy
+        {
+            warn!(processor = %addr, "processor failed a send, suspect");
+            node.health = Health::Suspect;
+            node.passes = 0;
+        }
     }
 
-    pub async fn produce(&self, items: Vec<Netflow>) -> Result<(), Error> {
-        let encoded: Vec<u8> = bincode2::serialize(&items).expect("failed to encode items");
-        let compressed: Vec<u8> = compress_prepend_size(&encoded);
-        let len = (compressed.len() as u32).to_be_bytes();
+    /// Orphans the chunks an evicted processor got for every running job,
+    /// unless it already uploaded all of their output.
+    fn evict(&self, addr: &str, store: &Mutex<ResultStore>) {
+        let mut jobs = self.jobs.lock().unwrap();
+        for (job, assignment) in jobs.iter_mut() {
+            let Some(frames) = assignment.nodes.remove(addr) else {
+                continue;
+            };
+            assignment.release(addr);
+            if store.lock().unwrap().evict(*job, addr) {
+                let orphaned = assignment.orphaned.entry(addr.to_string()).or_default();
+                orphaned.extend(frames.acked);
+                orphaned.extend(frames.unacked);
+            }
+        }
+    }
 
-        let processors_snapshot = {
+    /// Sends straggling chunks (see [`SpeculationConfig`]) to an idle
+    /// processor as well, and tells the processors that lost the race for a
+    /// chunk to drop their copy if it is still queued. Both copies count
+    /// towards their processor's total; the results take whichever arrives
+    /// first and answer the other `DUP`, so only its output is uploaded.
+    pub async fn speculate(&self, store: &Mutex<ResultStore>, config: &SpeculationConfig) {
+        let mut idle: Vec<String> = {
             let procs = self.processors.lock().unwrap();
-            procs.clone()
+            procs
+                .iter()
+                .filter(|p| p.idle())
+                .map(|p| p.addr.clone())
+                .collect()
         };
-        if processors_snapshot.is_empty() {
-            self.ready_to_produce.store(false, Ordering::Release);
+        let mut losers = Vec::new();
+        let mut reassigned = Vec::new();
+        {
+            let now = Instant::now();
+            let mut jobs = self.jobs.lock().unwrap();
+            // only to see which of the waiting chunks are in
+            {
+                let store = store.lock().unwrap();
+                for (job, assignment) in jobs.iter_mut() {
+                    let Assignment {
+                        holders,
+                        sent,
+                        latencies,
+                        speculated,
+                        ..
+                    } = assignment;
+                    sent.retain(|chunk, sent| {
+                        let Some((winner, at)) = store.taken(*job, *chunk) else {
+                            return true;
+                        };
+                        let took = at.saturating_duration_since(*sent);
+                        let i = latencies.partition_point(|t| *t < took);
+                        latencies.insert(i, took);
+                        if speculated.contains(chunk)
+                            && let Some((id, addrs)) = holders.get(chunk)
+                        {
+                            for addr in addrs.iter().filter(|addr| *addr != winner) {
+                                losers.push((addr.clone(), *id));
+                            }
+                        }
+                        false
+                    });
+                }
+            }
 
-            return Err(Error::new(
-                std::io::ErrorKind::Other,
-                "no processors available",
-            ));
+            for (job, assignment) in jobs.iter_mut() {
+                let times = &assignment.latencies;
+                if times.len() < config.min_samples.max(1) || idle.is_empty() {
+                    continue;
+                }
+                let median = times[times.len() / 2];
+                let threshold = median.mul_f64(config.factor);
+                let before = assignment.speculated.len();
+                let stragglers: Vec<(u64, Duration)> = assignment
+                    .sent
+                    .iter()
+                    .map(|(chunk, sent)| (*chunk, now.saturating_duration_since(*sent)))
+                    .filter(|(chunk, waited)| {
+                        *waited > threshold && !assignment.speculated.contains(chunk)
+                    })
+                    .collect();
+                for (chunk, waited) in stragglers {
+                    // orphaned, it goes to another processor anyway
+                    let Some((_, holders)) = assignment.holders.get(&chunk) else {
+                        continue;
+                    };
+                    let Some(frame) = holders
+                        .first()
+                        .and_then(|addr| assignment.nodes.get(addr))
+                        .and_then(|node| node.frame(chunk))
+                        .cloned()
+                    else {
+                        continue;
+                    };
+                    let Some(i) = idle.iter().position(|addr| !holders.contains(addr)) else {
+                        continue;
+                    };
+                    // a copy is kept for resends like any other frame
+                    if assignment.retained + frame.1.len() > self.retain_bytes {
+                        debug!(job, chunk, "straggler not copied, RETAIN_BYTES reached");
+                        continue;
+                    }
+                    let to = idle.swap_remove(i);
+                    info!(
+                        job,
+                        partition = frame.0.partition,
+                        chunk,
+                        waited_ms = waited.as_millis() as u64,
+                        median_ms = median.as_millis() as u64,
+                        processor = %to,
+                        "straggler, sending it to an idle processor as well"
+                    );
+                    METRICS.chunks_speculated.inc();
+                    assignment.retained += frame.1.len();
+                    assignment.hold(&to, frame.0);
+                    assignment.nodes.entry(to).or_default().unacked.push(frame);
+                    assignment.speculated.insert(chunk);
+                    if idle.is_empty() {
+                        break;
+                    }
+                }
+                if assignment.speculated.len() > before {
+                    reassigned.push((*job, assignment.flushed.then(|| assignment.totals())));
+                }
+            }
+        }
+        let copied = !reassigned.is_empty();
+        for (job, totals) in reassigned {
+            if let Some(totals) = totals {
+                store.lock().unwrap().reassign(job, totals);
+            }
         }
 
+        // one that misses this processes its copy and gets `DUP` for it
+        for (addr, id) in losers {
+            let frame = frame::encode(b"taken", Codec::None, id, &[]);
+            if let Err(e) = self.send_to(&addr, &frame).await {
+                warn!(
+                    job = id.job,
+                    chunk = id.chunk,
+                    processor = %addr,
+                    error = %e,
+                    "failed to tell a processor its copy lost"
+                );
+            }
+        }
+        if copied {
+            self.sync().await;
+        }
+    }
+
+    /// Spreads orphaned chunks over the healthy processors that are not
+    /// draining, telling the results to expect them there instead. They are
+    /// sent by `sync`.
+    fn reassign(&self, store: &Mutex<ResultStore>) {
+        let healthy: Vec<String> = {
+            let procs = self.processors.lock().unwrap();
+            let healthy = procs.iter().filter(|p| p.health == Health::Healthy);
+            // draining ones only if nothing else is left
+            let open = healthy.clone().any(|p| !p.draining());
+            healthy
+                .filter(|p| !open || !p.draining())
+                .map(|p| p.addr.clone())
+                .collect()
+        };
+        if healthy.is_empty() {
+            return;
+        }
+        let mut jobs = self.jobs.lock().unwrap();
+        for (job, assignment) in jobs.iter_mut() {
+            if assignment.orphaned.is_empty() {
+                continue;
+            }
+            for (from, frames) in std::mem::take(&mut assignment.orphaned) {
+                info!(
+                    job,
+                    from = %from,
+                    chunks = frames.len(),
+                    "reassigning chunks of evicted processor"
+                );
+                for (i, frame) in frames.into_iter().enumerate() {
+                    // one holding the chunk already would count it twice
+                    let holders = assignment.holders.get(&frame.0.chunk);
+                    let holds = |addr: &String| holders.is_some_and(|(_, a)| a.contains(addr));
+                    let Some(to) = (0..healthy.len())
+                        .map(|j| &healthy[(i + j) % healthy.len()])
+                        .find(|addr| !holds(addr))
+                    else {
+                        assignment.retained -= frame.1.len();
+                        continue;
+                    };
+                    assignment.hold(to, frame.0);
+                    let node = assignment.nodes.entry(to.clone()).or_default();
+                    node.unacked.push(frame);
+                }
+            }
+            if assignment.flushed {
+                store.lock().unwrap().reassign(*job, assignment.totals());
+            }
+        }
+    }
+
+    /// Sends every processor the frames it has not acknowledged yet and, once
+    /// a job is flushed, its total if it changed since the last `flush`.
+    async fn sync(&self) {
+        let columnar: HashMap<String, bool> = {
+            let procs = self.processors.lock().unwrap();
+            procs.iter().map(|p| (p.addr.clone(), p.columnar)).collect()
+        };
+        let pending: Vec<Pending> = {
+            let jobs = self.jobs.lock().unwrap();
+            jobs.iter()
+                .flat_map(|(job, assignment)| {
+                    let columnar = &columnar;
+                    assignment.nodes.iter().filter_map(move |(addr, node)| {
+                        let flush = (assignment.flushed && node.flushed != Some(node.total()))
+                            .then(|| node.total());
+                        if node.unacked.is_empty() && flush.is_none() {
+                            return None;
+                        }
+                        Some(Pending {
+                            job: *job,
+                            addr: addr.clone(),
+                            columnar: *columnar.get(addr)?,
+                            frames: node.unacked.clone(),
+                            flush,
+                        })
+                    })
+                })
+                .collect()
+        };
+
+        for pending in pending {
+            let mut failed = false;
+            for (id, frame) in pending.frames {
+                let frame = if !pending.columnar && frame.starts_with(b"colmn") {
+                    match to_rows(&frame).await {
+                        Ok(rows) => rows,
+                        Err(e) => {
+                            error!(
+                                job = id.job,
+                                partition = id.partition,
+                                chunk = id.chunk,
+                                error = %e,
+                                "failed to re-encode chunk"
+                            );
+                            continue;
+                        }
+                    }
+                } else {
+                    frame
+                };
+                METRICS.chunks_retried.inc();
+                if let Err(e) = self.send_chunk(&pending.addr, &frame).await {
+                    warn!(
+                        job = id.job,
+                        partition = id.partition,
+                        chunk = id.chunk,
+                        processor = %pending.addr,
+                        error = %e,
+                        "failed to resend chunk"
+                    );
+                    failed = true;
+                    break;
+                }
+                let mut jobs = self.jobs.lock().unwrap();
+                if let Some(node) = jobs
+                    .get_mut(&pending.job)
+                    .and_then(|a| a.nodes.get_mut(&pending.addr))
+                    && let Some(i) = node.unacked.iter().position(|(u, _)| *u == id)
+                {
+                    let frame = node.unacked.remove(i);
+                    node.acked.push(frame);
+                }
+            }
+            if failed {
+                self.suspect(&pending.addr);
+                continue;
+            }
+            if let Some(total) = pending.flush {
+                self.flush_node(pending.job, &pending.addr, total).await;
+            }
+        }
+    }
+
+    /// Tells a processor it got `total` chunks of `job`, so it uploads its
+    /// output once they are processed.
+    async fn flush_node(&self, job: u64, addr: &str, total: u64) {
+        let frame = frame::encode(b"flush", Codec::None, ChunkId::new(job, total), &[]);
+        match self.send_to(addr, &frame).await {
+            Ok(_) => {
+                let mut jobs = self.jobs.lock().unwrap();
+                if let Some(node) = jobs.get_mut(&job).and_then(|a| a.nodes.get_mut(addr)) {
+                    node.flushed = Some(total);
+                }
+            }
+            Err(e) => {
+                warn!(job, processor = %addr, error = %e, "failed to flush job");
+                self.suspect(addr);
+            }
+        }
+    }
+
+    /// The next healthy processor in turn among the least loaded ones, going
+    /// by their last heartbeat: those with room in their queue, else those
+    /// that are not draining, else any.
+    fn next_processor(&self) -> Option<ProcessorNode> {
+        let procs = self.processors.lock().unwrap();
+        let healthy = procs.iter().filter(|p| p.health == Health::Healthy);
+        let tier = healthy.clone().map(ProcessorNode::load_tier).min()?;
+        let candidates: Vec<&ProcessorNode> = healthy.filter(|p| p.load_tier() == tier).collect();
         let mut index_lock = self.curr_index.lock().unwrap();
-        let processor_addr = &processors_snapshot[*index_lock];
-        *index_lock = (*index_lock + 1) % processors_snapshot.len();
-        println!("producing to: {}", processor_addr);
-        let mut stream = TcpStream::connect(processor_addr).await?;
-        stream.write_all(b"chunk").await?;
-        stream.write_all(&len).await?;
-        stream.write_all(&compressed).await?;
-        Ok(())
+        let index = *index_lock % candidates.len();
+        *index_lock = (index + 1) % candidates.len();
+        Some(candidates[index].clone())
+    }
+
+    /// Registers a job about to produce chunks: its share of the processor
+    /// pool and the transform its record batches carry.
+    pub fn start(&self, job: u64, weight: u32, transform: Option<TransformSpec>) {
+        self.schedule.register(job, weight);
+        self.jobs.lock().unwrap().entry(job).or_default().transform = transform;
+    }
+
+    /// Sends chunk `id` to the next healthy processor in turn, compressed
+    /// with the job's `codec`, and waits for it to be acknowledged; see
+    /// [`frame::send`] for resends. A processor that fails to take it is
+    /// made suspect and the next one tried. The frame may have reached it all
+    /// the same, so it stays on that processor as unacknowledged, counts
+    /// towards its total and is resent until acknowledged or the processor
+    /// is evicted; the chunk is then produced once it went to any processor.
+    /// The processor echoes `id` back with the chunk's results. It first
+    /// waits its turn in [`schedule`](Self::schedule), there being one send
+    /// at a time per healthy processor. Fails with [`ErrorKind::NotConnected`]
+    /// if no processor was there to try, with [`ErrorKind::InvalidInput`] if
+    /// the frame was refused and with [`ErrorKind::OutOfMemory`] if the job
+    /// would keep more than [`retain_bytes`](Self::retain_bytes) of frames.
+    #[tracing::instrument(
+        name = "produce",
+        skip_all,
+        fields(job = id.job, partition = id.partition, chunk = id.chunk)
+    )]
+    pub async fn produce(&self, id: ChunkId, chunk: &Chunk, codec: Codec) -> Result<(), Error> {
+        let (attempts, healthy) = {
+            let procs = self.processors.lock().unwrap();
+            let healthy = procs.iter().filter(|p| p.health == Health::Healthy);
+            (procs.len(), healthy.count())
+        };
+        let slot = self.schedule.acquire(id.job, healthy).await;
+        let transform = match chunk {
+            Chunk::Records(_) => {
+                let jobs = self.jobs.lock().unwrap();
+                jobs.get(&id.job).and_then(|a| a.transform.clone())
+            }
+            Chunk::Netflow(_) => None,
+        };
+        let mut parked = false;
+        for attempt in 0..attempts {
+            let Some(processor) = self.next_processor() else {
+                break;
+            };
+            if attempt > 0 {
+                METRICS.chunks_retried.inc();
+            }
+            let frame = match chunk {
+                Chunk::Netflow(items) if processor.columnar => {
+                    frame::encode(b"colmn", codec, id, &columnar::encode(items))
+                }
+                Chunk::Netflow(items) => frame::encode(b"chunk", codec, id, &encode_chunk(items)),
+                Chunk::Records(batch) => frame::encode(
+                    b"batch",
+                    codec,
+                    id,
+                    &encode_batch(batch, transform.as_ref()),
+                ),
+            };
+
+            let retained = {
+                let jobs = self.jobs.lock().unwrap();
+                jobs.get(&id.job).map_or(0, |a| a.retained)
+            };
+            if retained + frame.len() > self.retain_bytes {
+                return Err(Error::new(
+                    ErrorKind::OutOfMemory,
+                    format!(
+                        "the job's chunks take more than RETAIN_BYTES ({} bytes) to keep for resends",
+                        self.retain_bytes
+                    ),
+                ));
+            }
+
+            debug!(processor = %processor.addr, attempt, "sending chunk");
+            match self.send_chunk(&processor.addr, &frame).await {
+                Ok(_) => {
+                    slot.sent(frame.len());
+                    let mut jobs = self.jobs.lock().unwrap();
+                    let assignment = jobs.entry(id.job).or_default();
+                    assignment.sent.insert(id.chunk, Instant::now());
+                    assignment.retained += frame.len();
+                    assignment.hold(&processor.addr, id);
+                    if parked {
+                        assignment.speculated.insert(id.chunk);
+                    }
+                    let node = assignment.nodes.entry(processor.addr).or_default();
+                    node.acked.push((id, frame));
+                    return Ok(());
+                }
+                // the frame itself was refused, another processor won't take it either
+                Err(e) if e.kind() == ErrorKind::InvalidInput => return Err(e),
+                Err(e) => {
+                    warn!(processor = %processor.addr, error = %e, "failed to send chunk");
+                    self.suspect(&processor.addr);
+                    let mut jobs = self.jobs.lock().unwrap();
+                    let assignment = jobs.entry(id.job).or_default();
+                    assignment.retained += frame.len();
+                    assignment.hold(&processor.addr, id);
+                    let node = assignment.nodes.entry(processor.addr).or_default();
+                    node.unacked.push((id, frame));
+                    parked = true;
+                }
+            }
+        }
+        self.ready_to_produce.store(false, Ordering::Release);
+        if parked {
+            return Ok(());
+        }
+        Err(Error::new(
+            ErrorKind::NotConnected,
+            "no processors available",
+        ))
+    }
+
+    /// Marks `job` as fully produced: the results learn which processor owes
+    /// how many chunks, and every processor that got chunks is told its
+    /// total so it uploads its output once they are processed. Returns the
+    /// job if its results are already complete.
+    pub async fn flush(
+        &self,
+        job: u64,
+        chunks: u64,
+        store: &Mutex<ResultStore>,
+    ) -> Option<JobResults> {
+        let finished = {
+            let mut jobs = self.jobs.lock().unwrap();
+            let assignment = jobs.entry(job).or_default();
+            assignment.flushed = true;
+            store
+                .lock()
+                .unwrap()
+                .expect(job, chunks, assignment.totals())
+        };
+        self.sync().await;
+        finished
+    }
+
+    /// Forgets a job whose output is merged, its share of the pool included,
+    /// and tells every processor to forget it too.
+    pub async fn complete(&self, job: u64) {
+        self.jobs.lock().unwrap().remove(&job);
+        self.schedule.unregister(job);
+        self.broadcast(b"close", job).await;
+    }
+
+    /// Forgets a cancelled job, its share of the pool included, and tells
+    /// every processor to drop the chunks of it still queued and the output
+    /// it has not uploaded. A processor that misses this uploads as usual
+    /// and the results are thrown away.
+    pub async fn cancel(&self, job: u64) {
+        self.jobs.lock().unwrap().remove(&job);
+        self.schedule.unregister(job);
+        self.broadcast(b"cancl", job).await;
+    }
+
+    /// Sends a `prefix` frame about `job` to every processor at once.
+    async fn broadcast(&self, prefix: &[u8; 5], job: u64) {
+        let addrs: Vec<String> = {
+            let procs = self.processors.lock().unwrap();
+            procs.iter().map(|p| p.addr.clone()).collect()
+        };
+        let frame = frame::encode(prefix, Codec::None, ChunkId::new(job, 0), &[]);
+        let frame = &frame;
+        let sends = addrs.into_iter().map(|addr| async move {
+            if let Err(e) = self.send_to(&addr, frame).await {
+                warn!(
+                    job,
+                    processor = %addr,
+                    frame = %String::from_utf8_lossy(prefix),
+                    error = %e,
+                    "failed to tell a processor about the job"
+                );
+            }
+        });
+        futures::future::join_all(sends).await;
+    }
+}
+
+/// Turns a `colmn` frame into a `chunk` frame with the same id and codec, for
+/// a processor that only reads rows.
+async fn to_rows(frame: &[u8]) -> Result<Vec<u8>, Error> {
+    let codec = Codec::from_id(frame[5]).ok_or_else(|| Error::other("unknown codec"))?;
+    let limits = FrameLimits {
+        max_frame: usize::MAX,
+        max_decompressed: usize::MAX,
+    };
+    let mut reader = &frame[5..];
+    let (id, raw) = match frame::read(&mut reader, limits).await {
+        Some(Ok(decoded)) => decoded,
+        _ => return Err(Error::other("corrupted frame")),
+    };
+    let items = columnar::decode(&raw).map_err(|e| Error::other(e.to_string()))?;
+    Ok(frame::encode(b"chunk", codec, id, &encode_chunk(&items)))
+}
+
+/// Body of a `chunk` frame: bincode encoded rows.
+pub fn encode_chunk(items: &[Netflow]) -> Vec<u8> {
+    bincode2::serialize(items).expect("failed to encode items")
+}
+
+/// A `batch` frame's payload: the job's transform, if it has one, then the
+/// rows.
+pub fn encode_batch(batch: &RecordBatch, transform: Option<&TransformSpec>) -> Vec<u8> {
+    bincode2::serialize(&(transform, batch)).expect("failed to encode batch")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_produce_resends_on_nak() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let processor = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut checksums = Vec::new();
+            for reply in [b"NAK", b"NAK", b"ACK"] {
+                let mut header = [0u8; 34];
+                socket.read_exact(&mut header).await.unwrap();
+                assert_eq!(&header[..5], b"colmn");
+                assert_eq!(u64::from_be_bytes(header[18..26].try_into().unwrap()), 42);
+                let len = u32::from_be_bytes(header[30..].try_into().unwrap()) as usize;
+                let mut payload = vec![0u8; len];
+                socket.read_exact(&mut payload).await.unwrap();
+                checksums.push(u32::from_be_bytes(header[6..10].try_into().unwrap()));
+                socket.write_all(reply).await.unwrap();
+            }
+            checksums
+        });
+
+        let producer = Producer::new(None, HeartbeatConfig::default());
+        producer
+            .processors
+            .lock()
+            .unwrap()
+            .push(ProcessorNode::new(addr, true));
+        producer
+            .produce(ChunkId::new(1, 42), &Chunk::Netflow(Vec::new()), Codec::Lz4)
+            .await
+            .unwrap();
+        let checksums = processor.await.unwrap();
+        assert!(checksums.windows(2).all(|w| w[0] == w[1]));
+    }
+
+    #[tokio::test]
+    async fn test_produce_fails_past_the_retention_bound() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let (tx, mut rx) = tokio::sync::mpsc::channel(10);
+        tokio::spawn(fake_processor(listener, tx));
+
+        let mut producer = Producer::new(None, HeartbeatConfig::default());
+        producer.retain_bytes = 100;
+        producer
+            .processors
+            .lock()
+            .unwrap()
+            .push(ProcessorNode::new(addr, true));
+        let chunk = Chunk::Netflow(Vec::new());
+        for chunk_id in 0..2 {
+            producer
+                .produce(ChunkId::new(1, chunk_id), &chunk, Codec::None)
+                .await
+                .unwrap();
+            rx.recv().await.unwrap();
+        }
+        let err = producer
+            .produce(ChunkId::new(1, 2), &chunk, Codec::None)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::OutOfMemory);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_failed_send_counts_towards_the_processor() {
+        // reads the frame and hangs up before acknowledging it
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let lost = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut prefix = [0u8; 5];
+            socket.read_exact(&mut prefix).await.unwrap();
+            frame::read(&mut socket, FrameLimits::default()).await;
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let alive = listener.local_addr().unwrap().to_string();
+        let (tx, mut rx) = tokio::sync::mpsc::channel(10);
+        tokio::spawn(fake_processor(listener, tx));
+
+        let producer = Producer::new(None, HeartbeatConfig::default());
+        producer.processors.lock().unwrap().extend([
+            ProcessorNode::new(lost.clone(), true),
+            ProcessorNode::new(alive.clone(), true),
+        ]);
+        let id = ChunkId::new(7, 0);
+        producer
+            .produce(id, &Chunk::Netflow(Vec::new()), Codec::None)
+            .await
+            .unwrap();
+        assert_eq!(rx.recv().await.unwrap(), (b"colmn".to_vec(), id));
+
+        let jobs = producer.jobs.lock().unwrap();
+        let totals = jobs[&7].totals();
+        assert_eq!((totals[&lost], totals[&alive]), (1, 1));
+        assert_eq!(jobs[&7].nodes[&lost].unacked.len(), 1);
+        assert!(jobs[&7].speculated.contains(&0));
+    }
+
+    #[tokio::test]
+    async fn test_produce_without_processors() {
+        let producer = Producer::new(None, HeartbeatConfig::default());
+        let err = producer
+            .produce(ChunkId::new(1, 0), &Chunk::Netflow(Vec::new()), Codec::None)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotConnected);
+        assert!(producer.jobs.lock().unwrap().is_empty());
+    }
+
+    /// A processor that passes health checks and acknowledges every frame,
+    /// reporting the prefix and id of each.
+    async fn fake_processor(
+        listener: TcpListener,
+        frames: tokio::sync::mpsc::Sender<(Vec<u8>, ChunkId)>,
+    ) {
+        loop {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let frames = frames.clone();
+            tokio::spawn(async move {
+                let mut prefix = [0u8; 5];
+                while socket.read_exact(&mut prefix).await.is_ok() {
+                    if &prefix == b"healt" {
+                        let mut rest = [0u8; 7];
+                        socket.read_exact(&mut rest).await.unwrap();
+                        let report = bincode2::serialize(&HealthReport::default()).unwrap();
+                        let id = ChunkId::new(0, 0);
+                        let reply = frame::encode(b"healt", Codec::None, id, &report);
+                        socket.write_all(&reply).await.unwrap();
+                        continue;
+                    }
+                    let Some(Ok((id, _))) = frame::read(&mut socket, FrameLimits::default()).await
+                    else {
+                        break;
+                    };
+                    socket.write_all(b"ACK").await.unwrap();
+                    frames.send((prefix.to_vec(), id)).await.unwrap();
+                }
+            });
+        }
+    }
+
+    #[tokio::test]
+    async fn test_heartbeat_evicts_and_reassigns() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let alive = listener.local_addr().unwrap().to_string();
+        let (tx, mut rx) = tokio::sync::mpsc::channel(10);
+        tokio::spawn(fake_processor(listener, tx));
+        // nothing listens here any more
+        let dead = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            listener.local_addr().unwrap().to_string()
+        };
+
+        let heartbeat = HeartbeatConfig {
+            suspect_after: 1,
+            evict_after: 2,
+            ..HeartbeatConfig::default()
+        };
+        let producer = Producer::new(None, heartbeat);
+        producer.processors.lock().unwrap().extend([
+            ProcessorNode::new(alive.clone(), false),
+            ProcessorNode::new(dead.clone(), true),
+        ]);
+        let id = ChunkId::new(3, 5);
+        let frame = frame::encode(b"colmn", Codec::Lz4, id, &columnar::encode(&[]));
+        producer
+            .jobs
+            .lock()
+            .unwrap()
+            .entry(3)
+            .or_default()
+            .nodes
+            .entry(dead.clone())
+            .or_default()
+            .acked
+            .push((id, frame));
+        let store = Mutex::new(ResultStore::new(
+            std::env::temp_dir().join("heartbeat-test"),
+        ));
+
+        producer.heartbeat_processors(&store).await;
+        let health = |addr: &str| {
+            let procs = producer.processors.lock().unwrap();
+            procs.iter().find(|p| p.addr == addr).map(|p| p.health)
+        };
+        assert_eq!(health(&dead), Some(Health::Suspect));
+        assert_eq!(health(&alive), Some(Health::Healthy));
+        assert!(producer.ready_to_produce.load(Ordering::Acquire));
+
+        producer.heartbeat_processors(&store).await;
+        assert_eq!(health(&dead), None);
+        // re-encoded as rows for the processor that doesn't read columns
+        assert_eq!(rx.recv().await.unwrap(), (b"chunk".to_vec(), id));
+        let jobs = producer.jobs.lock().unwrap();
+        let node = &jobs[&3].nodes[&alive];
+        assert_eq!((node.acked.len(), node.unacked.len()), (1, 0));
+    }
+
+    #[tokio::test]
+    async fn test_cancel_and_complete_tell_processors() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let (tx, mut rx) = tokio::sync::mpsc::channel(10);
+        tokio::spawn(fake_processor(listener, tx));
+
+        let producer = Producer::new(None, HeartbeatConfig::default());
+        producer
+            .processors
+            .lock()
+            .unwrap()
+            .push(ProcessorNode::new(addr, true));
+        producer.jobs.lock().unwrap().entry(4).or_default();
+        producer.schedule.register(4, 2);
+
+        producer.cancel(4).await;
+        assert_eq!(
+            rx.recv().await.unwrap(),
+            (b"cancl".to_vec(), ChunkId::new(4, 0))
+        );
+        assert!(producer.jobs.lock().unwrap().is_empty());
+        assert_eq!(producer.schedule.share(4), None);
+
+        producer.jobs.lock().unwrap().entry(5).or_default();
+        producer.complete(5).await;
+        assert_eq!(
+            rx.recv().await.unwrap(),
+            (b"close".to_vec(), ChunkId::new(5, 0))
+        );
+        assert!(producer.jobs.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_speculates_stragglers() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let idle = listener.local_addr().unwrap().to_string();
+        let (tx, mut rx) = tokio::sync::mpsc::channel(10);
+        tokio::spawn(fake_processor(listener, tx));
+
+        let mut producer = Producer::new(None, HeartbeatConfig::default());
+        let mut node = ProcessorNode::new(idle.clone(), true);
+        node.report = Some(HealthReport {
+            queue_capacity: 100,
+            workers: 4,
+            ..HealthReport::default()
+        });
+        producer.processors.lock().unwrap().push(node);
+        let ids: Vec<ChunkId> = (0..4).map(|chunk| ChunkId::new(6, chunk)).collect();
+        {
+            let mut jobs = producer.jobs.lock().unwrap();
+            let assignment = jobs.entry(6).or_default();
+            let now = Instant::now();
+            // chunks 0 and 1 took 100ms, 2 has waited 10s and 3 just went out
+            let sent = [100, 100, 10_000, 0].map(|ms| now - Duration::from_millis(ms));
+            for (id, sent) in ids.iter().zip(sent) {
+                let frame = frame::encode(b"colmn", Codec::Lz4, *id, &columnar::encode(&[]));
+                let slow = assignment.nodes.entry("slow".to_string()).or_default();
+                slow.acked.push((*id, frame));
+                assignment.hold("slow", *id);
+                assignment.sent.insert(id.chunk, sent);
+            }
+        }
+        let store = Mutex::new(ResultStore::new(
+            std::env::temp_dir().join("speculate-test"),
+        ));
+        let result = |id: ChunkId| common::result::ChunkResult {
+            id,
+            stats: Default::default(),
+            aggregates: RecordBatch {
+                schema: common::record::Schema {
+                    columns: Vec::new(),
+                },
+                rows: Vec::new(),
+            },
+        };
+        {
+            let mut store = store.lock().unwrap();
+            store.hello(1, "slow".to_string());
+            store.add(1, result(ids[0]));
+            store.add(1, result(ids[1]));
+        }
+
+        let config = SpeculationConfig {
+            min_samples: 2,
+            ..SpeculationConfig::default()
+        };
+        // no room to keep a copy
+        producer.retain_bytes = 1;
+        producer.speculate(&store, &config).await;
+        assert!(producer.jobs.lock().unwrap()[&6].speculated.is_empty());
+        assert!(rx.try_recv().is_err());
+
+        producer.retain_bytes = DEFAULT_RETAIN_BYTES;
+        producer.speculate(&store, &config).await;
+        assert_eq!(rx.recv().await.unwrap(), (b"colmn".to_vec(), ids[2]));
+        {
+            let jobs = producer.jobs.lock().unwrap();
+            assert_eq!(jobs[&6].nodes[&idle].acked.len(), 1);
+            assert_eq!(jobs[&6].speculated.len(), 1);
+        }
+
+        // the straggler came through after all, the idle processor's copy lost
+        store.lock().unwrap().add(1, result(ids[2]));
+        producer.speculate(&store, &config).await;
+        assert_eq!(rx.recv().await.unwrap(), (b"taken".to_vec(), ids[2]));
+        producer.speculate(&store, &config).await;
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_routes_by_load() {
+        let producer = Producer::new(None, HeartbeatConfig::default());
+        let node = |addr: &str, queue_depth: u64, draining: bool| {
+            let mut node = ProcessorNode::new(addr.to_string(), false);
+            node.report = Some(HealthReport {
+                queue_depth,
+                queue_capacity: 100,
+                draining,
+                ..HealthReport::default()
+            });
+            node
+        };
+        producer.processors.lock().unwrap().extend([
+            node("idle", 10, false),
+            node("full", 90, false),
+            node("draining", 0, true),
+        ]);
+        let next = || producer.next_processor().unwrap().addr;
+        assert_eq!([next(), next()], ["idle", "idle"]);
+
+        producer.processors.lock().unwrap()[0] = node("idle", 80, false);
+        assert_eq!([next(), next()], ["idle", "full"]);
+
+        producer.processors.lock().unwrap().truncate(0);
+        producer
+            .processors
+            .lock()
+            .unwrap()
+            .push(node("draining", 0, true));
+        assert_eq!(next(), "draining");
     }
 }
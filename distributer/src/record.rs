@@ -0,0 +1,9 @@
+use common::netflow::Netflow;
+use common::record::RecordBatch;
+
+/// What a source hands to the producer for one batch.
+#[derive(Debug, Clone)]
+pub enum Chunk {
+    Netflow(Vec<Netflow>),
+    Records(RecordBatch),
+}
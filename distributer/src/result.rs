@@ -0,0 +1,907 @@
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use common::auth::{self, Secret};
+use common::frame::{self, ChunkId, FrameError, FrameLimits};
+use common::record::{RecordBatch, Schema, Value};
+use common::result::{ChunkOutput, ChunkResult, ChunkStats};
+use common::spill::{RunWriter, SortKey};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc::Sender;
+use tokio::time::Instant;
+use tracing::{debug, error, info, warn};
+
+use crate::metrics::METRICS;
+
+/// The output of one job assembled from its chunk results.
+#[derive(Default)]
+pub struct JobResults {
+    pub job: u64,
+    dir: PathBuf,
+    /// Chunks produced for the job, known once the job has been read in full.
+    expected: Option<u64>,
+    /// Per chunk, the processor whose result was taken and when it came.
+    received: HashMap<u64, (String, Instant)>,
+    /// Chunks received so far, by the source partition they were read from.
+    partitions: HashMap<u32, u64>,
+    /// Per processor, how many chunks it owes output for, known once the job
+    /// is flushed, and how many its uploads so far covered.
+    assigned: Option<HashMap<String, u64>>,
+    delivered: HashMap<String, u64>,
+    /// Processors evicted during the job. Their chunks were sent elsewhere,
+    /// whatever they still upload is dropped.
+    evicted: HashSet<String>,
+    pub stats: ChunkStats,
+    /// Sorted runs uploaded in full, ready to merge, by uploading processor.
+    runs: Vec<(String, PathBuf)>,
+    /// Runs a result connection is still writing, by connection and run
+    /// number, with the uploading processor; the job waits for them. A run
+    /// split over two connections by a reconnect lands in two files, each
+    /// still sorted.
+    uploading: HashMap<(u64, u64), String>,
+    /// Run files created so far, to name the next one.
+    files: u64,
+    schema: Option<Schema>,
+    /// Group key to `count` and sums.
+    groups: HashMap<Vec<Value>, Vec<Value>>,
+    /// Why the job failed, if some of its output was lost.
+    pub failed: Option<String>,
+}
+
+impl JobResults {
+    fn new(job: u64, dir: PathBuf) -> Self {
+        Self {
+            job,
+            dir,
+            ..Self::default()
+        }
+    }
+
+    pub fn chunks(&self) -> usize {
+        self.received.len()
+    }
+
+    /// Chunks received, by source partition.
+    pub fn partitions(&self) -> &HashMap<u32, u64> {
+        &self.partitions
+    }
+
+    /// The runs to merge, handed over once.
+    pub fn take_runs(&mut self) -> Vec<PathBuf> {
+        std::mem::take(&mut self.runs)
+            .into_iter()
+            .map(|(_, path)| path)
+            .collect()
+    }
+
+    fn has_delivered(&self, node: &str) -> bool {
+        let owed = self.assigned.as_ref().and_then(|a| a.get(node));
+        owed.is_some_and(|owed| self.delivered.get(node).is_some_and(|d| d >= owed))
+    }
+
+    fn is_complete(&self) -> bool {
+        self.uploading.is_empty()
+            && self.expected == Some(self.received.len() as u64)
+            && self
+                .assigned
+                .as_ref()
+                .is_some_and(|a| a.keys().all(|node| self.has_delivered(node)))
+    }
+
+    /// Stops taking uploads from an evicted processor and drops what it
+    /// uploaded, unless it had already delivered all of its chunks. Returns
+    /// whether its chunks have to be processed again elsewhere.
+    fn evict(&mut self, node: &str) -> bool {
+        if self.has_delivered(node) {
+            return false;
+        }
+        self.evicted.insert(node.to_string());
+        self.delivered.remove(node);
+        // its connections drop the runs they are writing once done
+        self.uploading.retain(|_, owner| owner != node);
+        self.runs.retain(|(owner, path)| {
+            if owner == node {
+                let _ = fs::remove_file(path);
+            }
+            owner != node
+        });
+        true
+    }
+
+    /// Whether a result for `chunk` already came from a processor other than
+    /// `node` that still counts, its copy having been sent to both.
+    fn is_duplicate(&self, node: &str, chunk: u64) -> bool {
+        self.received
+            .get(&chunk)
+            .is_some_and(|(taken, _)| taken != node && !self.evicted.contains(taken))
+    }
+
+    /// Folds in a chunk's result from `node`. A chunk seen before (a resent
+    /// result whose `ACK` was lost, or the other copy of a straggler) is
+    /// ignored, unless the processor it was taken from has been evicted
+    /// since: the chunk is then `node`'s.
+    fn add(&mut self, node: &str, result: ChunkResult) {
+        if let Some((taken, _)) = self.received.get_mut(&result.id.chunk) {
+            if self.evicted.contains(taken) {
+                *taken = node.to_string();
+            }
+            return;
+        }
+        self.received
+            .insert(result.id.chunk, (node.to_string(), Instant::now()));
+        *self.partitions.entry(result.id.partition).or_default() += 1;
+        self.stats.received += result.stats.received;
+        self.stats.kept += result.stats.kept;
+        self.stats.rejected += result.stats.rejected;
+        METRICS.rows_rejected.add(result.stats.rejected);
+
+        let aggregates = result.aggregates;
+        let Some(keys) = aggregates.schema.index_of("count") else {
+            return;
+        };
+        for mut row in aggregates.rows {
+            let totals = row.split_off(keys);
+            match self.groups.get_mut(&row) {
+                Some(existing) => {
+                    for (total, value) in existing.iter_mut().zip(totals) {
+                        *total = add(total, value);
+                    }
+                }
+                None => {
+                    self.groups.insert(row, totals);
+                }
+            }
+        }
+        self.schema.get_or_insert(aggregates.schema);
+    }
+
+    /// The aggregates of every chunk merged, one row per group.
+    pub fn aggregates(&self) -> Option<RecordBatch> {
+        let schema = self.schema.clone()?;
+        let rows = self
+            .groups
+            .iter()
+            .map(|(key, totals)| key.iter().chain(totals).cloned().collect())
+            .collect();
+        Some(RecordBatch { schema, rows })
+    }
+}
+
+fn add(total: &Value, value: Value) -> Value {
+    match (total, value) {
+        (Value::Int(a), Value::Int(b)) => Value::Int(a + b),
+        (Value::Float(a), Value::Float(b)) => Value::Float(a + b),
+        (Value::Null, value) => value,
+        (total, _) => total.clone(),
+    }
+}
+
+/// How many of the last jobs handed out or cancelled are remembered to drop
+/// their late uploads.
+const FINISHED_KEPT: usize = 1024;
+
+/// Results of the jobs still running, keyed by job id.
+pub struct ResultStore {
+    /// Where uploaded runs are written until they are merged.
+    dir: PathBuf,
+    jobs: HashMap<u64, JobResults>,
+    /// The processor (its data port address) behind each result connection,
+    /// from the connection's `hello`.
+    nodes: HashMap<u64, String>,
+    /// Jobs already handed out, so late duplicates don't start them again;
+    /// the last [`FINISHED_KEPT`] of them, oldest first.
+    finished: HashSet<u64>,
+    finished_order: VecDeque<u64>,
+}
+
+impl ResultStore {
+    pub fn new(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            jobs: HashMap::new(),
+            nodes: HashMap::new(),
+            finished: HashSet::new(),
+            finished_order: VecDeque::new(),
+        }
+    }
+
+    /// Remembers that `job` is over, forgetting the oldest job that was.
+    fn finish(&mut self, job: u64) {
+        if !self.finished.insert(job) {
+            return;
+        }
+        self.finished_order.push_back(job);
+        if self.finished_order.len() > FINISHED_KEPT
+            && let Some(oldest) = self.finished_order.pop_front()
+        {
+            self.finished.remove(&oldest);
+        }
+    }
+
+    /// Records that `job` was split into `chunks` chunks, `assigned` giving
+    /// how many of them each processor got, returning the job if everything
+    /// is already in.
+    pub fn expect(
+        &mut self,
+        job: u64,
+        chunks: u64,
+        assigned: HashMap<String, u64>,
+    ) -> Option<JobResults> {
+        let results = self.job(job)?;
+        results.expected = Some(chunks);
+        results.assigned = Some(assigned);
+        self.take_if_complete(job)
+    }
+
+    /// Adds a chunk result from the processor on `conn`, returning its job
+    /// if that completed it.
+    pub fn add(&mut self, conn: u64, result: ChunkResult) -> Option<JobResults> {
+        let node = self.node(conn);
+        let job = result.id.job;
+        self.job(job)?.add(&node, result);
+        self.take_if_complete(job)
+    }
+
+    /// Whether the result for `id` from the processor on `conn` lost the race
+    /// to another copy's, so that processor has to drop its output.
+    pub fn is_duplicate(&self, conn: u64, id: ChunkId) -> bool {
+        let node = self.nodes.get(&conn).map_or("", String::as_str);
+        self.jobs
+            .get(&id.job)
+            .is_some_and(|results| results.is_duplicate(node, id.chunk))
+    }
+
+    /// The processor whose result for a chunk was taken, and when it came.
+    pub fn taken(&self, job: u64, chunk: u64) -> Option<(&str, Instant)> {
+        let (node, at) = self.jobs.get(&job)?.received.get(&chunk)?;
+        Some((node, *at))
+    }
+
+    /// Records which processor is on result connection `conn`.
+    pub fn hello(&mut self, conn: u64, node: String) {
+        self.nodes.insert(conn, node);
+    }
+
+    fn node(&self, conn: u64) -> String {
+        self.nodes.get(&conn).cloned().unwrap_or_default()
+    }
+
+    /// Where to write run `id.chunk` of job `id.job`, which result
+    /// connection `conn` starts uploading; the job waits for it until it is
+    /// handed back to [`add_runs`](Self::add_runs). `None` if the job takes
+    /// no more uploads from that processor.
+    pub fn run_path(&mut self, conn: u64, id: ChunkId) -> Option<PathBuf> {
+        let node = self.node(conn);
+        let results = self.job(id.job)?;
+        if results.evicted.contains(&node) {
+            return None;
+        }
+        results.uploading.insert((conn, id.chunk), node);
+        let path = results
+            .dir
+            .join(format!("job-{}-{}.run", results.job, results.files));
+        results.files += 1;
+        Some(path)
+    }
+
+    /// Takes the runs of `job` that result connection `conn` has written,
+    /// by run number. Runs of a job that is over, or of an evicted
+    /// processor, are removed; one that could not be written fails the job.
+    /// Returns the job if that completed or failed it.
+    pub fn add_runs(
+        &mut self,
+        conn: u64,
+        job: u64,
+        runs: Vec<(u64, io::Result<PathBuf>)>,
+    ) -> Option<JobResults> {
+        let node = self.node(conn);
+        let mut lost = None;
+        let mut written = Vec::new();
+        for (run, path) in runs {
+            match path {
+                Ok(path) => written.push((run, path)),
+                Err(e) => lost = Some(e),
+            }
+        }
+        let Some(results) = self
+            .jobs
+            .get_mut(&job)
+            .filter(|results| !results.evicted.contains(&node))
+        else {
+            for (_, path) in written {
+                let _ = fs::remove_file(path);
+            }
+            return None;
+        };
+        for (run, path) in written {
+            results.uploading.remove(&(conn, run));
+            results.runs.push((node.clone(), path));
+        }
+        if let Some(e) = lost {
+            error!(job, error = %e, "failed to write a run");
+            return self.fail(
+                conn,
+                job,
+                &format!("uploaded a run that could not be stored: {}", e),
+            );
+        }
+        self.take_if_complete(job)
+    }
+
+    /// Records that the processor on `conn` has uploaded the output of its
+    /// first `id.chunk` chunks of job `id.job`, taking the `runs` it wrote
+    /// for it, returning the job if that completed it.
+    pub fn uploaded(
+        &mut self,
+        conn: u64,
+        id: ChunkId,
+        runs: Vec<(u64, io::Result<PathBuf>)>,
+    ) -> Option<JobResults> {
+        if let Some(over) = self.add_runs(conn, id.job, runs) {
+            return Some(over);
+        }
+        let node = self.node(conn);
+        let results = self.job(id.job)?;
+        if results.evicted.contains(&node) {
+            return None;
+        }
+        let delivered = results.delivered.entry(node).or_default();
+        *delivered = (*delivered).max(id.chunk);
+        self.take_if_complete(id.job)
+    }
+
+    /// Fails `job` because output of it uploaded by the processor on `conn`
+    /// was lost, for `reason`, returning it to be ended. Ignored from an
+    /// evicted processor, whose chunks are processed again elsewhere.
+    pub fn fail(&mut self, conn: u64, job: u64, reason: &str) -> Option<JobResults> {
+        let node = self.node(conn);
+        let results = self.job(job)?;
+        if results.evicted.contains(&node) {
+            return None;
+        }
+        results.failed = Some(format!("processor {} {}", node, reason));
+        self.finish(job);
+        self.jobs.remove(&job)
+    }
+
+    /// Evicts processor `node` from `job`; see [`JobResults::evict`]. Until
+    /// [`reassign`](Self::reassign) the job keeps waiting for its chunks.
+    pub fn evict(&mut self, job: u64, node: &str) -> bool {
+        self.job(job).is_some_and(|results| results.evict(node))
+    }
+
+    /// Replaces the chunk counts owed per processor of a flushed job after
+    /// an evicted processor's chunks were handed to others.
+    pub fn reassign(&mut self, job: u64, assigned: HashMap<String, u64>) {
+        if let Some(results) = self.job(job)
+            && results.assigned.is_some()
+        {
+            results.assigned = Some(assigned);
+        }
+    }
+
+    /// Chunks of a running job processed so far, by source partition.
+    pub fn processed(&self, job: u64) -> HashMap<u32, u64> {
+        self.jobs
+            .get(&job)
+            .map(|results| results.partitions().clone())
+            .unwrap_or_default()
+    }
+
+    /// Drops a cancelled job and whatever was uploaded for it; anything
+    /// arriving for it later is ignored.
+    pub fn cancel(&mut self, job: u64) {
+        self.finish(job);
+        if let Some(mut results) = self.jobs.remove(&job) {
+            for path in results.take_runs() {
+                let _ = fs::remove_file(path);
+            }
+        }
+    }
+
+    /// The job's results so far, `None` once it has been handed out.
+    fn job(&mut self, job: u64) -> Option<&mut JobResults> {
+        if self.finished.contains(&job) {
+            return None;
+        }
+        let dir = &self.dir;
+        Some(
+            self.jobs
+                .entry(job)
+                .or_insert_with(|| JobResults::new(job, dir.clone())),
+        )
+    }
+
+    fn take_if_complete(&mut self, job: u64) -> Option<JobResults> {
+        if !self.jobs.get(&job)?.is_complete() {
+            return None;
+        }
+        self.finish(job);
+        self.jobs.remove(&job)
+    }
+}
+
+/// The runs one result connection is uploading, by job and run number,
+/// written to their files without holding the [`ResultStore`]'s lock.
+#[derive(Default)]
+struct Uploads {
+    open: HashMap<(u64, u64), RunWriter>,
+}
+
+impl Uploads {
+    /// Appends a block to run `id.chunk` of job `id.job`, creating the run's
+    /// file at `path()` for its first block. A block `path()` finds no
+    /// place for is dropped.
+    fn write(
+        &mut self,
+        id: ChunkId,
+        path: impl FnOnce() -> Option<PathBuf>,
+        output: &ChunkOutput,
+    ) -> io::Result<()> {
+        let writer = match self.open.entry((id.job, id.chunk)) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let Some(path) = path() else {
+                    return Ok(());
+                };
+                if let Some(dir) = path.parent() {
+                    fs::create_dir_all(dir)?;
+                }
+                entry.insert(RunWriter::create(path)?)
+            }
+        };
+        writer.write(output)
+    }
+
+    /// Finishes the runs of `job`, or of every job, for
+    /// [`ResultStore::add_runs`].
+    fn close(&mut self, job: Option<u64>) -> HashMap<u64, Vec<(u64, io::Result<PathBuf>)>> {
+        let keys: Vec<(u64, u64)> = self
+            .open
+            .keys()
+            .filter(|(j, _)| job.is_none_or(|job| *j == job))
+            .copied()
+            .collect();
+        let mut closed: HashMap<u64, Vec<_>> = HashMap::new();
+        for (job, run) in keys {
+            let writer = self.open.remove(&(job, run)).expect("key listed above");
+            closed.entry(job).or_default().push((run, writer.finish()));
+        }
+        closed
+    }
+}
+
+/// Accepts result connections from processors on `port`, answering every
+/// `reslt` frame like the processor's data port does, and sends each job to
+/// `done` once its last chunk is in. A processor whose `hello` says it sorts
+/// its runs by anything but `sort_key` is turned away.
+pub async fn listen_results(
+    port: u16,
+    secret: Option<Secret>,
+    limits: FrameLimits,
+    sort_key: SortKey,
+    store: Arc<Mutex<ResultStore>>,
+    done: Sender<JobResults>,
+) -> std::io::Result<()> {
+    let listener = TcpListener::bind(format!("0.0.0.0:{}", port)).await?;
+    info!("listening for results on port {}", port);
+    let mut conns = 0u64..;
+    loop {
+        let (mut socket, addr) = listener.accept().await?;
+        let conn = conns.next().expect("unbounded");
+        let secret = secret.clone();
+        let store = Arc::clone(&store);
+        let done = done.clone();
+        tokio::spawn(async move {
+            if let Some(secret) = secret
+                && let Err(e) = auth::accept(&mut socket, &secret).await
+            {
+                warn!(%addr, error = %e, "rejected result connection");
+                return;
+            }
+            let mut uploads = Uploads::default();
+            loop {
+                let mut prefix = [0u8; 5];
+                if socket.read_exact(&mut prefix).await.is_err() {
+                    break;
+                }
+                if !matches!(
+                    &prefix,
+                    b"hello" | b"reslt" | b"block" | b"flush" | b"error"
+                ) {
+                    break;
+                }
+                let Some(frame) = frame::read(&mut socket, limits).await else {
+                    break;
+                };
+                let reply: &[u8] = match frame
+                    .and_then(|(id, raw)| decode(&prefix, id, &raw, sort_key))
+                {
+                    Ok(upload) => {
+                        let mut duplicate = false;
+                        let finished = match upload {
+                            Upload::Chunk(result) => {
+                                debug!(
+                                    job = result.id.job,
+                                    partition = result.id.partition,
+                                    chunk = result.id.chunk,
+                                    kept = result.stats.kept,
+                                    "chunk result received"
+                                );
+                                let mut store = store.lock().unwrap();
+                                if store.is_duplicate(conn, result.id) {
+                                    duplicate = true;
+                                    None
+                                } else {
+                                    store.add(conn, result)
+                                }
+                            }
+                            // written outside the lock, the disk may be slow
+                            Upload::Block(id, output) => {
+                                let path = || store.lock().unwrap().run_path(conn, id);
+                                match uploads.write(id, path, &output) {
+                                    Ok(()) => None,
+                                    Err(e) => {
+                                        error!(
+                                            job = id.job,
+                                            run = id.chunk,
+                                            error = %e,
+                                            "failed to store a block"
+                                        );
+                                        let reason = format!(
+                                            "uploaded a block that could not be stored: {}",
+                                            e
+                                        );
+                                        store.lock().unwrap().fail(conn, id.job, &reason)
+                                    }
+                                }
+                            }
+                            Upload::Flushed(id) => {
+                                let runs = uploads.close(Some(id.job)).remove(&id.job);
+                                let runs = runs.unwrap_or_default();
+                                store.lock().unwrap().uploaded(conn, id, runs)
+                            }
+                            Upload::Failed(id, error) => {
+                                warn!(job = id.job, error = %error, "a processor lost output");
+                                let reason = format!("lost output: {}", error);
+                                store.lock().unwrap().fail(conn, id.job, &reason)
+                            }
+                            Upload::Hello(port) => {
+                                let node = format!("{}:{}", addr.ip(), port);
+                                store.lock().unwrap().hello(conn, node);
+                                None
+                            }
+                        };
+                        if let Some(job) = finished {
+                            let _ = done.send(job).await;
+                        }
+                        if duplicate {
+                            METRICS.results_duplicate.inc();
+                            b"DUP"
+                        } else {
+                            b"ACK"
+                        }
+                    }
+                    Err(FrameError::Checksum) => b"NAK",
+                    Err(FrameError::Invalid(e)) => {
+                        warn!(%addr, error = %e, "rejected result");
+                        b"ERR"
+                    }
+                    Err(FrameError::Protocol(e)) => {
+                        warn!(%addr, error = %e, "protocol error, closing the result connection");
+                        let _ = socket.write_all(b"ERR").await;
+                        break;
+                    }
+                };
+                if socket.write_all(reply).await.is_err() {
+                    break;
+                }
+            }
+            // runs cut short by a dropped connection are still sorted
+            for (job, runs) in uploads.close(None) {
+                let finished = store.lock().unwrap().add_runs(conn, job, runs);
+                if let Some(job) = finished {
+                    let _ = done.send(job).await;
+                }
+            }
+        });
+    }
+}
+
+/// A frame off the result port.
+enum Upload {
+    /// First frame on a connection: the processor's data port, which with
+    /// the connection's address names the processor like `connect` did. Its
+    /// payload is the column the processor sorts its runs by.
+    Hello(u64),
+    Chunk(ChunkResult),
+    /// A block of one of a processor's sorted runs; the id's chunk is the
+    /// run number.
+    Block(ChunkId, ChunkOutput),
+    /// The processor has sent all of its runs for the job, covering the
+    /// output of as many chunks as the id's chunk says.
+    Flushed(ChunkId),
+    /// The processor lost output of the job instead, for the reason given.
+    Failed(ChunkId, String),
+}
+
+fn decode(
+    prefix: &[u8; 5],
+    id: ChunkId,
+    raw: &[u8],
+    sort_key: SortKey,
+) -> Result<Upload, FrameError> {
+    let invalid = |e: bincode2::Error| FrameError::Invalid(e.to_string());
+    match prefix {
+        b"reslt" => {
+            let result: ChunkResult = bincode2::deserialize(raw).map_err(invalid)?;
+            if result.id != id {
+                return Err(FrameError::Invalid(format!(
+                    "frame for {:?} carries the result of {:?}",
+                    id, result.id
+                )));
+            }
+            Ok(Upload::Chunk(result))
+        }
+        b"block" => Ok(Upload::Block(
+            id,
+            bincode2::deserialize(raw).map_err(invalid)?,
+        )),
+        // its runs would merge out of order
+        b"hello" if raw != sort_key.column().as_bytes() => Err(FrameError::Protocol(format!(
+            "the processor sorts its runs by {:?}, this distributer's SORT_BY is {:?}",
+            String::from_utf8_lossy(raw),
+            sort_key.column()
+        ))),
+        b"hello" => Ok(Upload::Hello(id.chunk)),
+        b"error" => Ok(Upload::Failed(
+            id,
+            String::from_utf8_lossy(raw).into_owned(),
+        )),
+        _ => Ok(Upload::Flushed(id)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::record::{Column, ColumnType};
+
+    fn result(chunk: u64, rows: Vec<Vec<Value>>) -> ChunkResult {
+        let column = |name: &str, ty| Column {
+            name: name.to_string(),
+            ty,
+        };
+        ChunkResult {
+            id: ChunkId::new(7, chunk),
+            stats: ChunkStats {
+                received: 10,
+                kept: 8,
+                rejected: 2,
+            },
+            aggregates: RecordBatch {
+                schema: Schema {
+                    columns: vec![
+                        column("src", ColumnType::Text),
+                        column("count", ColumnType::Int),
+                        column("sum_bytes", ColumnType::Int),
+                    ],
+                },
+                rows,
+            },
+        }
+    }
+
+    /// Uploads an empty block of run `id.chunk` over `conn`, as its
+    /// connection would.
+    fn block(store: &mut ResultStore, uploads: &mut Uploads, conn: u64, id: ChunkId) {
+        let output = ChunkOutput::Netflow(Vec::new());
+        uploads
+            .write(id, || store.run_path(conn, id), &output)
+            .unwrap();
+    }
+
+    /// Flushes the runs of `id.job` uploaded over `conn`, as its connection
+    /// would.
+    fn flush(
+        store: &mut ResultStore,
+        uploads: &mut Uploads,
+        conn: u64,
+        id: ChunkId,
+    ) -> Option<JobResults> {
+        let runs = uploads.close(Some(id.job)).remove(&id.job);
+        store.uploaded(conn, id, runs.unwrap_or_default())
+    }
+
+    #[test]
+    fn test_merges_chunk_results() {
+        let text = |s: &str| Value::Text(s.to_string());
+        let mut store = ResultStore::new(std::env::temp_dir().join("result-store-test"));
+        let first = vec![vec![text("10.0.0.0/24"), Value::Int(2), Value::Int(150)]];
+        assert!(store.add(1, result(0, first.clone())).is_none());
+        // a resent result counts once
+        assert!(store.add(1, result(0, first)).is_none());
+        let second = vec![
+            vec![text("10.0.0.0/24"), Value::Int(1), Value::Int(50)],
+            vec![text("10.0.1.0/24"), Value::Int(3), Value::Int(30)],
+        ];
+        assert!(store.add(1, result(1, second)).is_none());
+        let run = ChunkId::new(7, 0);
+        let (mut one, mut two) = (Uploads::default(), Uploads::default());
+        store.hello(1, "10.0.0.1:6000".to_string());
+        store.hello(2, "10.0.0.2:6000".to_string());
+        block(&mut store, &mut one, 1, run);
+        block(&mut store, &mut two, 2, run);
+        let one_chunk = ChunkId::new(7, 1);
+        assert!(flush(&mut store, &mut one, 1, one_chunk).is_none());
+        // still waiting on the second processor's upload
+        let assigned = HashMap::from([
+            ("10.0.0.1:6000".to_string(), 1),
+            ("10.0.0.2:6000".to_string(), 1),
+        ]);
+        assert!(store.expect(7, 2, assigned).is_none());
+        // and on its run, even once it delivered all its chunks
+        let runs = two.close(None).remove(&7).unwrap();
+        store
+            .jobs
+            .get_mut(&7)
+            .unwrap()
+            .delivered
+            .insert("10.0.0.2:6000".to_string(), 1);
+        assert!(store.take_if_complete(7).is_none());
+
+        let mut job = store.uploaded(2, one_chunk, runs).unwrap();
+        assert_eq!(job.chunks(), 2);
+        assert_eq!(job.take_runs().len(), 2);
+        assert_eq!(
+            job.stats,
+            ChunkStats {
+                received: 20,
+                kept: 16,
+                rejected: 4,
+            }
+        );
+        let mut rows = job.aggregates().unwrap().rows;
+        rows.sort_by_key(|row| format!("{:?}", row[0]));
+        assert_eq!(
+            rows,
+            vec![
+                vec![text("10.0.0.0/24"), Value::Int(3), Value::Int(200)],
+                vec![text("10.0.1.0/24"), Value::Int(3), Value::Int(30)],
+            ]
+        );
+        // late results of a finished job are dropped
+        assert!(store.add(1, result(1, Vec::new())).is_none());
+        assert!(store.jobs.is_empty());
+
+        // only the last jobs over are remembered
+        for job in 100..100 + FINISHED_KEPT as u64 {
+            store.cancel(job);
+        }
+        assert_eq!(store.finished.len(), FINISHED_KEPT);
+        assert!(!store.finished.contains(&7));
+    }
+
+    #[test]
+    fn test_hello_names_the_sort_key() {
+        let hello = |raw: &[u8]| decode(b"hello", ChunkId::new(0, 6000), raw, SortKey::FlowId);
+        assert!(matches!(hello(b"flow_id"), Ok(Upload::Hello(6000))));
+        assert!(matches!(hello(b"start_ts"), Err(FrameError::Protocol(_))));
+        assert!(matches!(hello(b""), Err(FrameError::Protocol(_))));
+    }
+
+    #[test]
+    fn test_first_copy_of_a_chunk_wins() {
+        let mut store = ResultStore::new(std::env::temp_dir().join("result-store-dup-test"));
+        let (slow, spare) = ("10.0.0.1:6000".to_string(), "10.0.0.2:6000".to_string());
+        store.hello(1, slow.clone());
+        store.hello(2, spare.clone());
+        let id = ChunkId::new(7, 0);
+
+        assert!(!store.is_duplicate(2, id));
+        assert!(store.add(2, result(0, Vec::new())).is_none());
+        assert_eq!(
+            store.taken(7, 0).map(|(node, _)| node),
+            Some(spare.as_str())
+        );
+        // the straggler's own result comes second, a resend from the winner
+        // does not
+        assert!(store.is_duplicate(1, id));
+        assert!(!store.is_duplicate(2, id));
+
+        // unless the winner was evicted before uploading its output
+        let assigned = HashMap::from([(slow.clone(), 1), (spare.clone(), 1)]);
+        assert!(store.expect(7, 1, assigned).is_none());
+        assert!(store.evict(7, &spare));
+        assert!(!store.is_duplicate(1, id));
+        assert!(store.add(1, result(0, Vec::new())).is_none());
+        assert_eq!(store.taken(7, 0).map(|(node, _)| node), Some(slow.as_str()));
+        assert_eq!(store.jobs[&7].stats.received, 10);
+    }
+
+    #[test]
+    fn test_cancel_drops_the_job() {
+        let dir = std::env::temp_dir().join("result-store-cancel-test");
+        let mut store = ResultStore::new(dir.clone());
+        let mut first = result(0, Vec::new());
+        first.id.partition = 3;
+        assert!(store.add(1, first).is_none());
+        assert!(store.add(1, result(1, Vec::new())).is_none());
+        assert_eq!(store.processed(7), HashMap::from([(0, 1), (3, 1)]));
+        store.hello(1, "10.0.0.1:6000".to_string());
+        let mut uploads = Uploads::default();
+        block(&mut store, &mut uploads, 1, ChunkId::new(7, 0));
+
+        store.cancel(7);
+        assert!(store.processed(7).is_empty());
+        assert!(store.add(1, result(2, Vec::new())).is_none());
+        assert!(store.jobs.is_empty());
+        // the run still being written goes once its connection is done
+        let runs = uploads.close(None).remove(&7).unwrap();
+        assert!(store.add_runs(1, 7, runs).is_none());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_lost_output_fails_the_job() {
+        let mut store = ResultStore::new(std::env::temp_dir().join("result-store-fail-test"));
+        store.hello(1, "10.0.0.1:6000".to_string());
+        assert!(store.add(1, result(0, Vec::new())).is_none());
+        let Ok(Upload::Failed(id, error)) =
+            decode(b"error", ChunkId::new(7, 1), b"disk full", SortKey::FlowId)
+        else {
+            panic!("expected a failure");
+        };
+        let job = store
+            .fail(1, id.job, &format!("lost output: {}", error))
+            .unwrap();
+        assert_eq!(
+            job.failed.as_deref(),
+            Some("processor 10.0.0.1:6000 lost output: disk full")
+        );
+        // the rest of its uploads are dropped
+        assert!(store.add(1, result(1, Vec::new())).is_none());
+        assert!(store.jobs.is_empty());
+    }
+
+    #[test]
+    fn test_evicted_processor_uploads_are_dropped() {
+        let mut store = ResultStore::new(std::env::temp_dir().join("result-store-evict-test"));
+        let (lost, spare) = ("10.0.0.1:6000".to_string(), "10.0.0.2:6000".to_string());
+        store.hello(1, lost.clone());
+        store.hello(2, spare.clone());
+        for chunk in 0..3 {
+            assert!(store.add(1, result(chunk, Vec::new())).is_none());
+        }
+        let assigned = HashMap::from([(lost.clone(), 2), (spare.clone(), 1)]);
+        assert!(store.expect(7, 3, assigned).is_none());
+        let run = ChunkId::new(7, 0);
+        let (mut one, mut two) = (Uploads::default(), Uploads::default());
+        block(&mut store, &mut one, 1, run);
+
+        // the spare already delivered its chunk, only the lost one's redo
+        assert!(store.evict(7, &lost));
+        block(&mut store, &mut one, 1, run);
+        assert!(flush(&mut store, &mut one, 1, ChunkId::new(7, 2)).is_none());
+        assert!(flush(&mut store, &mut two, 2, ChunkId::new(7, 1)).is_none());
+        assert!(!store.evict(7, &spare));
+
+        store.reassign(7, HashMap::from([(spare.clone(), 3)]));
+        block(&mut store, &mut two, 2, run);
+        let mut job = flush(&mut store, &mut two, 2, ChunkId::new(7, 3)).unwrap();
+        // the lost processor's partial run was removed
+        let runs = job.take_runs();
+        assert_eq!(runs.len(), 1);
+        assert!(
+            fs::read_dir(std::env::temp_dir().join("result-store-evict-test"))
+                .unwrap()
+                .all(|entry| runs.contains(&entry.unwrap().path()))
+        );
+        fs::remove_dir_all(std::env::temp_dir().join("result-store-evict-test")).unwrap();
+    }
+}
@@ -0,0 +1,239 @@
+//! Weighted fair queueing of chunk sends across jobs. Every chunk takes a
+//! send slot first, and there are as many slots as healthy processors. While
+//! one is free a chunk goes straight out; once the pool is busy the next free
+//! slot goes to the waiting job with the lowest virtual finish tag, so jobs
+//! with chunks to send share the pool in proportion to their weights.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+use serde::Serialize;
+use tokio::sync::oneshot;
+use tokio::time::Instant;
+
+/// A send costs this much virtual time divided by the job's weight. Divisible
+/// by every weight up to 16, so those keep exact ratios.
+const SEND_COST: u64 = 720_720;
+
+/// A job's share of the processor pool as the admin API shows it.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct JobShare {
+    pub weight: u32,
+    /// Chunks sent and acknowledged.
+    pub sent: u64,
+    /// Bytes of those chunks' frames.
+    pub bytes: u64,
+    /// Time spent waiting for a send slot.
+    pub waited_ms: u64,
+}
+
+#[derive(Default)]
+struct Share {
+    share: JobShare,
+    /// Virtual finish tag of the job's last send.
+    finish: u64,
+}
+
+struct Waiter {
+    start: u64,
+    granted: oneshot::Sender<()>,
+}
+
+#[derive(Default)]
+struct State {
+    in_flight: usize,
+    capacity: usize,
+    /// Virtual time, the start tag of the last send let through.
+    now: u64,
+    /// Waiters by finish tag, ties in arrival order.
+    waiting: BTreeMap<(u64, u64), Waiter>,
+    arrivals: u64,
+    jobs: HashMap<u64, Share>,
+}
+
+impl State {
+    /// Hands out free slots to the waiters with the lowest tags.
+    fn grant(&mut self) {
+        while self.in_flight < self.capacity {
+            let Some((_, waiter)) = self.waiting.pop_first() else {
+                return;
+            };
+            // a waiter that gave up has dropped its receiver
+            if waiter.granted.send(()).is_ok() {
+                self.in_flight += 1;
+                self.now = waiter.start;
+            }
+        }
+    }
+
+    fn release(&mut self) {
+        self.in_flight -= 1;
+        self.grant();
+    }
+}
+
+#[derive(Default)]
+pub struct FairQueue {
+    state: Mutex<State>,
+}
+
+impl FairQueue {
+    /// Sets a job's weight, 1 unless set.
+    pub fn register(&self, job: u64, weight: u32) {
+        let mut state = self.state.lock().unwrap();
+        state.jobs.entry(job).or_default().share.weight = weight.max(1);
+    }
+
+    /// Waits for a send slot for one of `job`'s chunks, `capacity` being how
+    /// many sends the pool takes at once right now. The slot is given back
+    /// when the returned [`Slot`] is dropped. A job that is not registered,
+    /// or no longer, waits with weight 1 and no share is kept for it.
+    pub async fn acquire(&self, job: u64, capacity: usize) -> Slot<'_> {
+        let started = Instant::now();
+        let (granted, rx) = oneshot::channel();
+        {
+            let mut state = self.state.lock().unwrap();
+            state.capacity = capacity.max(1);
+            let now = state.now;
+            // a job that was idle starts from now rather than its old tag,
+            // it gets no credit for the time it sent nothing
+            let (start, finish) = match state.jobs.get_mut(&job) {
+                Some(share) => {
+                    let start = share.finish.max(now);
+                    share.finish = start + SEND_COST / share.share.weight as u64;
+                    (start, share.finish)
+                }
+                None => (now, now + SEND_COST),
+            };
+            let tag = (finish, state.arrivals);
+            state.arrivals += 1;
+            state.waiting.insert(tag, Waiter { start, granted });
+            state.grant();
+        }
+        let mut waiting = Waiting {
+            queue: self,
+            rx: Some(rx),
+        };
+        let _ = waiting.rx.as_mut().expect("set above").await;
+        waiting.rx = None;
+
+        let mut state = self.state.lock().unwrap();
+        if let Some(share) = state.jobs.get_mut(&job) {
+            share.share.waited_ms += started.elapsed().as_millis() as u64;
+        }
+        Slot { queue: self, job }
+    }
+
+    /// Forgets a job that ended, returning what it had of the pool.
+    pub fn unregister(&self, job: u64) -> Option<JobShare> {
+        let mut state = self.state.lock().unwrap();
+        state.jobs.remove(&job).map(|share| share.share)
+    }
+
+    /// How much of the pool a job has had so far.
+    pub fn share(&self, job: u64) -> Option<JobShare> {
+        let state = self.state.lock().unwrap();
+        state.jobs.get(&job).map(|share| share.share.clone())
+    }
+}
+
+/// A send slot, given back on drop.
+pub struct Slot<'a> {
+    queue: &'a FairQueue,
+    job: u64,
+}
+
+impl Slot<'_> {
+    /// Counts a chunk of `bytes` sent in this slot.
+    pub fn sent(&self, bytes: usize) {
+        let mut state = self.queue.state.lock().unwrap();
+        if let Some(share) = state.jobs.get_mut(&self.job) {
+            share.share.sent += 1;
+            share.share.bytes += bytes as u64;
+        }
+    }
+}
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        self.queue.state.lock().unwrap().release();
+    }
+}
+
+/// A caller waiting in [`FairQueue::acquire`]. If it is dropped after its
+/// slot was granted but before it took it, the slot goes back.
+struct Waiting<'a> {
+    queue: &'a FairQueue,
+    rx: Option<oneshot::Receiver<()>>,
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        if let Some(mut rx) = self.rx.take() {
+            rx.close();
+            if rx.try_recv().is_ok() {
+                self.queue.state.lock().unwrap().release();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_backlogged_jobs_share_by_weight() {
+        let queue = Arc::new(FairQueue::default());
+        queue.register(1, 3);
+        queue.register(2, 1);
+        let held = queue.acquire(0, 1).await;
+
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        for job in [2, 2, 2, 2, 1, 1, 1, 1, 1, 1] {
+            let queue = queue.clone();
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                let slot = queue.acquire(job, 1).await;
+                slot.sent(100);
+                tx.send(job).unwrap();
+            });
+        }
+        drop(tx);
+        // let every task take its place in the queue
+        tokio::task::yield_now().await;
+        drop(held);
+
+        let mut order = Vec::new();
+        while let Some(job) = rx.recv().await {
+            order.push(job);
+        }
+        // job 1 gets three sends for every one of job 2 while both wait
+        assert_eq!(order, [1, 1, 2, 1, 1, 1, 2, 1, 2, 2]);
+        let share = queue.share(1).unwrap();
+        assert_eq!((share.weight, share.sent, share.bytes), (3, 6, 600));
+        assert_eq!(queue.share(2).unwrap().weight, 1);
+        assert_eq!(queue.unregister(1).map(|share| share.sent), Some(6));
+        assert_eq!(queue.share(1), None);
+        // a job over is not brought back by a late send
+        drop(queue.acquire(1, 1).await);
+        assert_eq!(queue.share(1), None);
+        assert_eq!(queue.share(0), None);
+    }
+
+    #[tokio::test]
+    async fn test_abandoned_wait_gives_the_slot_back() {
+        let queue = FairQueue::default();
+        let held = queue.acquire(1, 1).await;
+        {
+            let waiting = queue.acquire(2, 1);
+            tokio::pin!(waiting);
+            assert!(futures::poll!(waiting.as_mut()).is_pending());
+            // granted to the waiter, which goes away before taking it
+            drop(held);
+        }
+        let _slot = queue.acquire(1, 1).await;
+        assert_eq!(queue.state.lock().unwrap().in_flight, 1);
+    }
+}
@@ -0,0 +1,180 @@
+use std::fmt;
+
+use async_trait::async_trait;
+use chrono::DateTime;
+use common::record::Value;
+use common::result::ChunkOutput;
+use sqlx::{Pool, Postgres};
+
+use crate::source;
+
+pub mod file;
+pub mod postgres;
+
+pub use file::{FileSink, SinkFormat};
+pub use postgres::PostgresSink;
+
+/// Somewhere a job's merged output is written, one block at a time in key
+/// order.
+#[async_trait]
+pub trait Sink: Send {
+    async fn write(&mut self, block: &ChunkOutput) -> Result<(), SinkError>;
+
+    /// Flushes what was written; the output is complete once this returns.
+    async fn finish(self: Box<Self>) -> Result<(), SinkError>;
+}
+
+#[derive(Debug)]
+pub enum SinkError {
+    Db(sqlx::Error),
+    Io(std::io::Error),
+    Encode(String),
+    /// The sink is set up wrong: an unknown `SINK`, a bad table name.
+    Config(String),
+}
+
+impl fmt::Display for SinkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SinkError::Db(e) => write!(f, "database error: {}", e),
+            SinkError::Io(e) => write!(f, "io error: {}", e),
+            SinkError::Encode(msg) => write!(f, "encode error: {}", msg),
+            SinkError::Config(msg) => write!(f, "config error: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for SinkError {}
+
+impl From<sqlx::Error> for SinkError {
+    fn from(e: sqlx::Error) -> Self {
+        SinkError::Db(e)
+    }
+}
+
+impl From<std::io::Error> for SinkError {
+    fn from(e: std::io::Error) -> Self {
+        SinkError::Io(e)
+    }
+}
+
+/// Where a job's output goes, parsed from the job's `sink` or else `SINK`:
+/// `bson:<path>` (the default, `./output/job-{job}.bson`), `ndjson:<path>`,
+/// `csv:<path>`, `chunks:<path>` or `postgres:<table>`. `{job}` in a path is
+/// replaced by the job id so every job gets its own file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SinkSpec {
+    File(SinkFormat, String),
+    Postgres(String),
+}
+
+impl SinkSpec {
+    pub fn parse(spec: &str) -> Result<Self, SinkError> {
+        let invalid = || SinkError::Config(format!("unknown SINK {:?}", spec));
+        let (kind, target) = spec.split_once(':').ok_or_else(invalid)?;
+        if kind == "postgres" {
+            if !source::is_identifier(target) {
+                return Err(SinkError::Config(format!(
+                    "invalid table name {:?}",
+                    target
+                )));
+            }
+            return Ok(SinkSpec::Postgres(target.to_string()));
+        }
+        let format = SinkFormat::from_name(kind).ok_or_else(invalid)?;
+        Ok(SinkSpec::File(format, target.to_string()))
+    }
+
+    /// The spec with `{job}` in the path replaced by `job`.
+    pub fn for_job(&self, job: u64) -> SinkSpec {
+        match self {
+            SinkSpec::File(format, path) => {
+                SinkSpec::File(*format, path.replace("{job}", &job.to_string()))
+            }
+            SinkSpec::Postgres(table) => SinkSpec::Postgres(table.clone()),
+        }
+    }
+
+    /// Opens the sink for job `job`, whose rows a database sink tags with
+    /// the job's `key`.
+    pub async fn open(
+        &self,
+        job: u64,
+        key: &str,
+        pool: &Pool<Postgres>,
+    ) -> Result<Box<dyn Sink>, SinkError> {
+        Ok(match self.for_job(job) {
+            SinkSpec::File(format, path) => Box::new(FileSink::create(&path, format, job).await?),
+            SinkSpec::Postgres(table) => Box::new(PostgresSink::new(pool.clone(), &table, key)),
+        })
+    }
+}
+
+impl Default for SinkSpec {
+    fn default() -> Self {
+        SinkSpec::File(SinkFormat::Bson, "./output/job-{job}.bson".to_string())
+    }
+}
+
+impl fmt::Display for SinkSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SinkSpec::File(format, path) => write!(f, "{}:{}", format.as_str(), path),
+            SinkSpec::Postgres(table) => write!(f, "postgres:{}", table),
+        }
+    }
+}
+
+/// A value as a CSV field Postgres `COPY ... (FORMAT csv)` reads back: null
+/// is an empty field, an empty string is quoted, timestamps use the same
+/// text form as the generator and bytes the `\x` hex form of `bytea`.
+fn csv_field(value: &Value) -> String {
+    let text = match value {
+        Value::Null => return String::new(),
+        Value::Bool(v) => v.to_string(),
+        Value::Int(v) => v.to_string(),
+        Value::Float(v) => v.to_string(),
+        Value::Text(v) => v.clone(),
+        Value::Ip(v) => v.to_string(),
+        Value::Timestamp(v) => match DateTime::from_timestamp_micros(*v) {
+            Some(ts) => ts.to_string(),
+            None => v.to_string(),
+        },
+        Value::Bytes(v) => {
+            let hex: String = v.iter().map(|b| format!("{:02x}", b)).collect();
+            format!("\\x{}", hex)
+        }
+    };
+    if text.is_empty() || text.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sink_spec() {
+        assert_eq!(
+            SinkSpec::parse("csv:/tmp/out-{job}.csv").unwrap(),
+            SinkSpec::File(SinkFormat::Csv, "/tmp/out-{job}.csv".to_string())
+        );
+        assert_eq!(
+            SinkSpec::parse("postgres:netflow_validated").unwrap(),
+            SinkSpec::Postgres("netflow_validated".to_string())
+        );
+        assert!(SinkSpec::parse("parquet:/tmp/out").is_err());
+        assert!(SinkSpec::parse("bson").is_err());
+        let default = SinkSpec::default();
+        assert_eq!(SinkSpec::parse(&default.to_string()).unwrap(), default);
+        assert_eq!(default.for_job(7).to_string(), "bson:./output/job-7.bson");
+
+        assert_eq!(csv_field(&Value::Null), "");
+        assert_eq!(csv_field(&Value::Text(String::new())), "\"\"");
+        assert_eq!(csv_field(&Value::Text("a,\"b\"".into())), "\"a,\"\"b\"\"\"");
+        assert_eq!(csv_field(&Value::Bytes(vec![0, 255])), "\\x00ff");
+    }
+}
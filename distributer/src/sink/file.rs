@@ -0,0 +1,300 @@
+use std::path::Path;
+
+use async_trait::async_trait;
+use bson::{Bson, Document};
+use common::codec::Codec;
+use common::frame::{self, ChunkId};
+use common::record::{RecordBatch, Value};
+use common::result::ChunkOutput;
+use serde_json::{Map, Value as JsonValue};
+use tokio::fs::{self, File};
+use tokio::io::{AsyncWriteExt, BufWriter};
+
+use super::{Sink, SinkError, csv_field};
+use crate::producer;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SinkFormat {
+    /// One BSON document per row, back to back, as `mongodump` writes them.
+    Bson,
+    /// One JSON object per line; netflow rows are readable by `FileSource`.
+    Ndjson,
+    /// Header plus one row per line, loadable with `COPY ... WITH (FORMAT
+    /// csv, HEADER true)`.
+    Csv,
+    /// `chunk` (netflow) or `batch` (records) frames of one block each, in
+    /// the framing `Producer::produce` uses but always lz4 and row encoded,
+    /// whatever the job's `CODEC` and `CHUNK_ENCODING`.
+    Chunks,
+}
+
+impl SinkFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "bson" => Some(SinkFormat::Bson),
+            "ndjson" => Some(SinkFormat::Ndjson),
+            "csv" => Some(SinkFormat::Csv),
+            "chunks" => Some(SinkFormat::Chunks),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SinkFormat::Bson => "bson",
+            SinkFormat::Ndjson => "ndjson",
+            SinkFormat::Csv => "csv",
+            SinkFormat::Chunks => "chunks",
+        }
+    }
+}
+
+pub struct FileSink {
+    out: BufWriter<File>,
+    format: SinkFormat,
+    job: u64,
+    blocks: u64,
+    header_written: bool,
+}
+
+impl FileSink {
+    pub async fn create(path: &str, format: SinkFormat, job: u64) -> Result<Self, SinkError> {
+        if let Some(dir) = Path::new(path).parent() {
+            fs::create_dir_all(dir).await?;
+        }
+        Ok(Self {
+            out: BufWriter::new(File::create(path).await?),
+            format,
+            job,
+            blocks: 0,
+            header_written: false,
+        })
+    }
+
+    fn encode(&mut self, block: &ChunkOutput) -> Result<Vec<u8>, SinkError> {
+        if self.format == SinkFormat::Chunks {
+            let (prefix, raw) = match block {
+                ChunkOutput::Netflow(netflows) => (b"chunk", producer::encode_chunk(netflows)),
+                ChunkOutput::Records(batch) => (b"batch", producer::encode_batch(batch, None)),
+            };
+            let id = ChunkId::new(self.job, self.blocks);
+            return Ok(frame::encode(prefix, Codec::Lz4, id, &raw));
+        }
+
+        let converted;
+        let batch = match block {
+            ChunkOutput::Netflow(netflows) => {
+                converted = RecordBatch::from_netflows(netflows);
+                &converted
+            }
+            ChunkOutput::Records(batch) => batch,
+        };
+        let mut buf = Vec::new();
+        match self.format {
+            SinkFormat::Bson => {
+                for row in &batch.rows {
+                    let document: Document = batch
+                        .schema
+                        .columns
+                        .iter()
+                        .zip(row)
+                        .map(|(column, value)| (column.name.clone(), bson_value(value)))
+                        .collect();
+                    document
+                        .to_writer(&mut buf)
+                        .map_err(|e| SinkError::Encode(e.to_string()))?;
+                }
+            }
+            SinkFormat::Ndjson => {
+                for row in &batch.rows {
+                    let object: Map<String, JsonValue> = batch
+                        .schema
+                        .columns
+                        .iter()
+                        .zip(row)
+                        .map(|(column, value)| (column.name.clone(), json_value(value)))
+                        .collect();
+                    serde_json::to_writer(&mut buf, &object)
+                        .map_err(|e| SinkError::Encode(e.to_string()))?;
+                    buf.push(b'\n');
+                }
+            }
+            SinkFormat::Csv => {
+                if !self.header_written {
+                    let names: Vec<&str> = batch
+                        .schema
+                        .columns
+                        .iter()
+                        .map(|c| c.name.as_str())
+                        .collect();
+                    buf.extend_from_slice(names.join(",").as_bytes());
+                    buf.push(b'\n');
+                    self.header_written = true;
+                }
+                for row in &batch.rows {
+                    let fields: Vec<String> = row.iter().map(csv_field).collect();
+                    buf.extend_from_slice(fields.join(",").as_bytes());
+                    buf.push(b'\n');
+                }
+            }
+            SinkFormat::Chunks => unreachable!("handled above"),
+        }
+        Ok(buf)
+    }
+}
+
+#[async_trait]
+impl Sink for FileSink {
+    async fn write(&mut self, block: &ChunkOutput) -> Result<(), SinkError> {
+        let encoded = self.encode(block)?;
+        self.out.write_all(&encoded).await?;
+        self.blocks += 1;
+        Ok(())
+    }
+
+    async fn finish(mut self: Box<Self>) -> Result<(), SinkError> {
+        self.out.flush().await?;
+        Ok(())
+    }
+}
+
+/// Timestamps become BSON dates, which only keep milliseconds.
+fn bson_value(value: &Value) -> Bson {
+    match value {
+        Value::Null => Bson::Null,
+        Value::Bool(v) => Bson::Boolean(*v),
+        Value::Int(v) => Bson::Int64(*v),
+        Value::Float(v) => Bson::Double(*v),
+        Value::Text(v) => Bson::String(v.clone()),
+        Value::Ip(v) => Bson::String(v.to_string()),
+        Value::Timestamp(v) => Bson::DateTime(bson::DateTime::from_millis(v.div_euclid(1000))),
+        Value::Bytes(v) => Bson::Binary(bson::Binary {
+            subtype: bson::spec::BinarySubtype::Generic,
+            bytes: v.clone(),
+        }),
+    }
+}
+
+/// Timestamps are written as RFC 3339 like the generator's NDJSON.
+fn json_value(value: &Value) -> JsonValue {
+    match value {
+        Value::Null => JsonValue::Null,
+        Value::Bool(v) => JsonValue::from(*v),
+        Value::Int(v) => JsonValue::from(*v),
+        Value::Float(v) => JsonValue::from(*v),
+        Value::Text(v) => JsonValue::from(v.as_str()),
+        Value::Ip(v) => JsonValue::from(v.to_string()),
+        Value::Timestamp(v) => match chrono::DateTime::from_timestamp_micros(*v) {
+            Some(ts) => JsonValue::from(ts.to_rfc3339()),
+            None => JsonValue::from(*v),
+        },
+        Value::Bytes(v) => JsonValue::from(v.clone()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use common::netflow::Netflow;
+
+    use super::*;
+    use crate::record::Chunk;
+    use crate::source::{FileFormat, FileSource, Source};
+    use common::frame::FrameLimits;
+
+    fn netflows() -> Vec<Netflow> {
+        (1..=3)
+            .map(|flow_id| Netflow {
+                flow_id,
+                src_ip: Some("10.0.0.1".parse().unwrap()),
+                dst_ip: (flow_id != 2).then(|| "2001:db8::1".parse().unwrap()),
+                src_port: Some(443),
+                dst_port: None,
+                protocol: Some(common::netflow::Protocol::Tcp),
+                bytes: Some(flow_id * 100),
+                packets: Some(1),
+                start_ts: chrono::DateTime::from_timestamp_millis(1704067419536),
+                end_ts: None,
+                src_asn: Some(64512),
+                dst_asn: None,
+            })
+            .collect()
+    }
+
+    async fn write(format: SinkFormat, name: &str) -> String {
+        let path = std::env::temp_dir()
+            .join(name)
+            .to_string_lossy()
+            .into_owned();
+        let mut sink: Box<dyn Sink> = Box::new(FileSink::create(&path, format, 4).await.unwrap());
+        let rows = netflows();
+        sink.write(&ChunkOutput::Netflow(rows[..2].to_vec()))
+            .await
+            .unwrap();
+        sink.write(&ChunkOutput::Netflow(rows[2..].to_vec()))
+            .await
+            .unwrap();
+        sink.finish().await.unwrap();
+        path
+    }
+
+    #[tokio::test]
+    async fn test_file_sinks() {
+        // csv and ndjson read back as netflow through FileSource
+        for (format, source_format) in [
+            (SinkFormat::Csv, FileFormat::Csv),
+            (SinkFormat::Ndjson, FileFormat::Ndjson),
+        ] {
+            let path = write(format, &format!("sink_test.{}", format.as_str())).await;
+            let (tx, mut rx) = tokio::sync::mpsc::channel(10);
+            FileSource::new(&path, source_format, 1)
+                .read_partition(0, tx)
+                .await
+                .unwrap();
+            let Some(Chunk::Netflow(read)) = rx.recv().await else {
+                panic!("{:?} did not read back as netflow", format);
+            };
+            let expected = netflows();
+            assert_eq!(read.len(), expected.len());
+            for (read, expected) in read.iter().zip(&expected) {
+                assert_eq!(read.flow_id, expected.flow_id);
+                assert_eq!(read.dst_ip, expected.dst_ip);
+                assert_eq!(read.protocol, expected.protocol);
+                assert_eq!(read.start_ts, expected.start_ts);
+                assert_eq!(read.dst_port, None);
+            }
+        }
+
+        let path = write(SinkFormat::Bson, "sink_test.bson").await;
+        let bytes = std::fs::read(path).unwrap();
+        let mut reader = bytes.as_slice();
+        let mut documents = Vec::new();
+        while !reader.is_empty() {
+            documents.push(Document::from_reader(&mut reader).unwrap());
+        }
+        assert_eq!(documents.len(), 3);
+        assert_eq!(documents[1].get_i64("flow_id").unwrap(), 2);
+        assert_eq!(documents[1].get("dst_ip"), Some(&Bson::Null));
+        assert_eq!(
+            documents[0]
+                .get_datetime("start_ts")
+                .unwrap()
+                .timestamp_millis(),
+            1704067419536
+        );
+
+        let path = write(SinkFormat::Chunks, "sink_test.chunks").await;
+        let bytes = std::fs::read(path).unwrap();
+        let mut reader = bytes.as_slice();
+        let mut rows = 0;
+        while let Some((prefix, rest)) = reader.split_first_chunk::<5>() {
+            assert_eq!(prefix, b"chunk");
+            reader = rest;
+            let Some(Ok((id, raw))) = frame::read(&mut reader, FrameLimits::default()).await else {
+                panic!("corrupted chunk frame");
+            };
+            assert_eq!(id.job, 4);
+            rows += bincode2::deserialize::<Vec<Netflow>>(&raw).unwrap().len();
+        }
+        assert_eq!(rows, 3);
+    }
+}
@@ -0,0 +1,83 @@
+use async_trait::async_trait;
+use common::record::RecordBatch;
+use common::result::ChunkOutput;
+use sqlx::{Pool, Postgres};
+
+use super::{Sink, SinkError, csv_field};
+use crate::db::JobWriter;
+
+/// Writes a job's output into `table` through a [`JobWriter`], so rerunning
+/// a job that failed half way, or one that already finished, leaves every
+/// row in the table once. Runs of a job are told apart by its `key`.
+pub struct PostgresSink {
+    pool: Pool<Postgres>,
+    table: String,
+    key: String,
+    /// Begun on the first block, once the columns are known.
+    writer: Option<JobWriter>,
+}
+
+impl PostgresSink {
+    /// A sink into `table`, a name [`SinkSpec::parse`](super::SinkSpec::parse)
+    /// has checked.
+    pub fn new(pool: Pool<Postgres>, table: &str, key: &str) -> Self {
+        Self {
+            pool,
+            table: table.to_string(),
+            key: key.to_string(),
+            writer: None,
+        }
+    }
+}
+
+#[async_trait]
+impl Sink for PostgresSink {
+    async fn write(&mut self, block: &ChunkOutput) -> Result<(), SinkError> {
+        let converted;
+        let batch = match block {
+            ChunkOutput::Netflow(netflows) => {
+                converted = RecordBatch::from_netflows(netflows);
+                &converted
+            }
+            ChunkOutput::Records(batch) => batch,
+        };
+        let writer = match &mut self.writer {
+            Some(writer) => writer,
+            None => {
+                let columns: Vec<&str> = batch
+                    .schema
+                    .columns
+                    .iter()
+                    .map(|c| c.name.as_str())
+                    .collect();
+                let writer = JobWriter::begin(
+                    &self.pool,
+                    &self.table,
+                    &self.key,
+                    &columns,
+                    batch.schema.is_netflow(),
+                )
+                .await?;
+                self.writer.insert(writer)
+            }
+        };
+        let mut buf = String::new();
+        for row in &batch.rows {
+            let fields: Vec<String> = row.iter().map(csv_field).collect();
+            buf.push_str(&fields.join(","));
+            buf.push('\n');
+        }
+        writer.copy_csv(buf.into_bytes()).await?;
+        Ok(())
+    }
+
+    async fn finish(self: Box<Self>) -> Result<(), SinkError> {
+        // a rerun that writes nothing still replaces the rows of the last one
+        let writer = match self.writer {
+            Some(writer) => writer,
+            None => JobWriter::begin(&self.pool, &self.table, &self.key, &[], false).await?,
+        };
+        writer.commit().await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,233 @@
+use std::fmt;
+use std::net::IpAddr;
+
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use common::record::{ColumnType, Value};
+use tokio::sync::mpsc::Sender;
+
+use crate::metrics::METRICS;
+use crate::record::Chunk;
+
+pub mod file;
+pub mod postgres;
+pub mod sqlite;
+
+pub use file::{FileFormat, FileSource};
+pub use postgres::PostgresSource;
+pub use sqlite::SqliteSource;
+
+/// Rows sent per batch on the partition channel.
+pub const BATCH_SIZE: usize = 1000;
+
+/// Somewhere records can be read from, split into independent partitions that
+/// can be read concurrently. Every record belongs to exactly one partition.
+#[async_trait]
+pub trait Source: Send + Sync {
+    fn partitions(&self) -> usize;
+
+    /// Streams partition `partition` into `tx` in chunks of at most
+    /// [`BATCH_SIZE`] rows. Returns early without error if `tx` is closed.
+    ///
+    /// Sources whose columns are exactly the `Netflow` ones send
+    /// `Chunk::Netflow`, anything else goes out as `Chunk::Records` with a
+    /// schema derived from the source's own column metadata.
+    async fn read_partition(&self, partition: usize, tx: Sender<Chunk>) -> Result<(), SourceError>;
+}
+
+/// Groups rows into [`BATCH_SIZE`] chunks on a partition channel.
+struct Batcher<T, F: Fn(Vec<T>) -> Chunk> {
+    tx: Sender<Chunk>,
+    /// Label for the rows read metric.
+    partition: String,
+    batch: Vec<T>,
+    wrap: F,
+}
+
+impl<T, F: Fn(Vec<T>) -> Chunk> Batcher<T, F> {
+    fn new(tx: Sender<Chunk>, partition: usize, wrap: F) -> Self {
+        Self {
+            tx,
+            partition: partition.to_string(),
+            batch: Vec::with_capacity(BATCH_SIZE),
+            wrap,
+        }
+    }
+
+    /// Returns `false` once the receiving side has gone away.
+    async fn push(&mut self, item: T) -> bool {
+        self.batch.push(item);
+        if self.batch.len() < BATCH_SIZE {
+            return true;
+        }
+        let full = std::mem::replace(&mut self.batch, Vec::with_capacity(BATCH_SIZE));
+        METRICS.rows_read.add(&self.partition, full.len() as u64);
+        self.tx.send((self.wrap)(full)).await.is_ok()
+    }
+
+    async fn finish(self) {
+        if !self.batch.is_empty() {
+            METRICS
+                .rows_read
+                .add(&self.partition, self.batch.len() as u64);
+            let _ = self.tx.send((self.wrap)(self.batch)).await;
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SourceError {
+    Db(sqlx::Error),
+    Io(std::io::Error),
+    Parse(String),
+}
+
+impl fmt::Display for SourceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SourceError::Db(e) => write!(f, "database error: {}", e),
+            SourceError::Io(e) => write!(f, "io error: {}", e),
+            SourceError::Parse(msg) => write!(f, "parse error: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for SourceError {}
+
+impl From<sqlx::Error> for SourceError {
+    fn from(e: sqlx::Error) -> Self {
+        SourceError::Db(e)
+    }
+}
+
+impl From<std::io::Error> for SourceError {
+    fn from(e: std::io::Error) -> Self {
+        SourceError::Io(e)
+    }
+}
+
+/// Splits the key range `min..=max` into `partitions` contiguous ranges and
+/// returns the inclusive bounds for `partition`. When there are more
+/// partitions than keys some ranges come back empty (`lo > hi`).
+pub fn partition_range(min: i64, max: i64, partition: usize, partitions: usize) -> (i64, i64) {
+    let span = max as i128 - min as i128 + 1;
+    let bound = |p: usize| min as i128 + span * p as i128 / partitions as i128;
+    (bound(partition) as i64, (bound(partition + 1) - 1) as i64)
+}
+
+/// Whether `name` is a plain, optionally schema qualified, SQL identifier
+/// that can go into a query as it is.
+pub fn is_identifier(name: &str) -> bool {
+    let parts: Vec<&str> = name.split('.').collect();
+    parts.len() <= 2
+        && parts.iter().all(|part| {
+            let mut chars = part.chars();
+            chars
+                .next()
+                .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+                && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        })
+}
+
+/// A bare identifier is read as a whole table, anything else as a query.
+fn relation(query: &str) -> String {
+    let query = query.trim();
+    if query.contains(char::is_whitespace) {
+        query.to_string()
+    } else {
+        format!("SELECT * FROM {}", query)
+    }
+}
+
+fn parse_ip(field: &str) -> Result<IpAddr, SourceError> {
+    field
+        .parse()
+        .map_err(|_| SourceError::Parse(format!("invalid ip address {:?}", field)))
+}
+
+/// Accepts RFC 3339 as well as the `YYYY-MM-DD HH:MM:SS[.fff][ UTC]` form the
+/// generator and Postgres text output use.
+fn parse_ts(field: &str) -> Result<DateTime<Utc>, SourceError> {
+    if let Ok(ts) = DateTime::parse_from_rfc3339(field) {
+        return Ok(ts.with_timezone(&Utc));
+    }
+    let naive = field.trim_end_matches(" UTC").trim_end_matches("+00");
+    NaiveDateTime::parse_from_str(naive, "%Y-%m-%d %H:%M:%S%.f")
+        .map(|ts| ts.and_utc())
+        .map_err(|_| SourceError::Parse(format!("invalid timestamp {:?}", field)))
+}
+
+/// Best guess at the type of an untyped text field, used for CSV and NDJSON
+/// columns. Empty fields carry no information and come out as text.
+fn infer_type(field: &str) -> ColumnType {
+    if field.parse::<i64>().is_ok() {
+        ColumnType::Int
+    } else if field.parse::<f64>().is_ok() {
+        ColumnType::Float
+    } else if field == "true" || field == "false" {
+        ColumnType::Bool
+    } else if field.parse::<IpAddr>().is_ok() {
+        ColumnType::Ip
+    } else if parse_ts(field).is_ok() {
+        ColumnType::Timestamp
+    } else {
+        ColumnType::Text
+    }
+}
+
+fn parse_value(field: &str, ty: ColumnType) -> Result<Value, SourceError> {
+    let invalid = || SourceError::Parse(format!("invalid {:?} value {:?}", ty, field));
+    Ok(match ty {
+        ColumnType::Bool => Value::Bool(field.parse().map_err(|_| invalid())?),
+        ColumnType::Int => Value::Int(field.parse().map_err(|_| invalid())?),
+        ColumnType::Float => Value::Float(field.parse().map_err(|_| invalid())?),
+        ColumnType::Text => Value::Text(field.to_string()),
+        ColumnType::Ip => Value::Ip(parse_ip(field)?),
+        ColumnType::Timestamp => Value::timestamp(parse_ts(field)?),
+        ColumnType::Bytes => Value::Bytes(field.as_bytes().to_vec()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_partition_range_covers_keys_once() {
+        for partitions in 1..7 {
+            let ranges: Vec<_> = (0..partitions)
+                .map(|p| partition_range(-3, 20, p, partitions))
+                .collect();
+            assert_eq!(ranges[0].0, -3);
+            assert_eq!(ranges[partitions - 1].1, 20);
+            assert!(ranges.windows(2).all(|w| w[0].1 + 1 == w[1].0));
+        }
+        assert_eq!(partition_range(i64::MIN, i64::MAX, 1, 2).1, i64::MAX);
+        let (lo, hi) = partition_range(5, 6, 0, 4);
+        assert!(lo > hi);
+    }
+
+    #[test]
+    fn test_parse_ts_formats() {
+        let expected = DateTime::from_timestamp_millis(1704067419536).unwrap();
+        assert_eq!(parse_ts("2024-01-01T00:03:39.536Z").unwrap(), expected);
+        assert_eq!(parse_ts("2024-01-01 00:03:39.536 UTC").unwrap(), expected);
+        assert_eq!(parse_ts("2024-01-01 00:03:39.536").unwrap(), expected);
+        assert!(parse_ts("yesterday").is_err());
+    }
+
+    #[test]
+    fn test_infer_type() {
+        assert_eq!(infer_type("42"), ColumnType::Int);
+        assert_eq!(infer_type("4.2"), ColumnType::Float);
+        assert_eq!(infer_type("true"), ColumnType::Bool);
+        assert_eq!(infer_type("::1"), ColumnType::Ip);
+        assert_eq!(infer_type("2024-01-01 00:00:00"), ColumnType::Timestamp);
+        assert_eq!(infer_type("tcp"), ColumnType::Text);
+        assert_eq!(
+            parse_value("2024-01-01T00:00:00.000001Z", ColumnType::Timestamp).unwrap(),
+            Value::Timestamp(1704067200000001)
+        );
+        assert!(parse_value("x", ColumnType::Int).is_err());
+    }
+}
@@ -0,0 +1,390 @@
+use std::collections::HashMap;
+use std::io::SeekFrom;
+use std::net::IpAddr;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use common::netflow::{Netflow, Protocol};
+use common::record::{Column, ColumnType, RecordBatch, Schema, Value};
+use serde::Deserialize;
+use serde_json::{Map, Value as JsonValue};
+use tokio::fs::File;
+use tokio::io::{AsyncBufReadExt, AsyncSeekExt, BufReader};
+use tokio::sync::mpsc::Sender;
+
+use super::{Batcher, Source, SourceError, infer_type, parse_ip, parse_ts, parse_value};
+use crate::record::Chunk;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileFormat {
+    /// Comma separated with a header row naming the columns, empty fields are null.
+    Csv,
+    /// One JSON object per line, as written by `netflow_gen`.
+    Ndjson,
+}
+
+/// A CSV or NDJSON file split into byte ranges. A line belongs to the
+/// partition its first byte falls in, so partitions never share a row.
+pub struct FileSource {
+    path: String,
+    format: FileFormat,
+    partitions: usize,
+}
+
+impl FileSource {
+    pub fn new(path: &str, format: FileFormat, partitions: usize) -> Self {
+        Self {
+            path: path.to_string(),
+            format,
+            partitions: partitions.max(1),
+        }
+    }
+
+    /// Column names come from the CSV header or the keys of the first JSON
+    /// object, and their types are inferred from the first data row.
+    async fn schema(&self) -> Result<Schema, SourceError> {
+        let mut reader = BufReader::new(File::open(&self.path).await?);
+        let mut first = String::new();
+        reader.read_line(&mut first).await?;
+
+        let columns = match self.format {
+            FileFormat::Csv => {
+                let mut sample = String::new();
+                reader.read_line(&mut sample).await?;
+                let sample: Vec<&str> = sample.trim_end().split(',').collect();
+                first
+                    .trim_end()
+                    .split(',')
+                    .enumerate()
+                    .map(|(i, name)| Column {
+                        name: name.trim().to_string(),
+                        ty: infer_type(sample.get(i).map(|f| f.trim()).unwrap_or("")),
+                    })
+                    .collect()
+            }
+            FileFormat::Ndjson => {
+                let object: Map<String, JsonValue> = serde_json::from_str(first.trim_end())
+                    .map_err(|e| SourceError::Parse(e.to_string()))?;
+                object
+                    .iter()
+                    .map(|(name, value)| Column {
+                        name: name.clone(),
+                        ty: match value {
+                            JsonValue::Bool(_) => ColumnType::Bool,
+                            JsonValue::Number(n) if n.is_i64() => ColumnType::Int,
+                            JsonValue::Number(_) => ColumnType::Float,
+                            JsonValue::String(s) => infer_type(s),
+                            _ => ColumnType::Text,
+                        },
+                    })
+                    .collect()
+            }
+        };
+        Ok(Schema { columns })
+    }
+
+    async fn lines(&self, partition: usize) -> Result<LineRange, SourceError> {
+        let mut file = File::open(&self.path).await?;
+        let len = file.metadata().await?.len();
+        let start = len * partition as u64 / self.partitions as u64;
+        let end = len * (partition as u64 + 1) / self.partitions as u64;
+
+        let mut line = String::new();
+        let (reader, pos) = if start == 0 {
+            let mut reader = BufReader::new(file);
+            let mut pos = 0;
+            if self.format == FileFormat::Csv {
+                pos += reader.read_line(&mut line).await? as u64;
+            }
+            (reader, pos)
+        } else {
+            // back up one byte so a line starting exactly at `start` is kept
+            file.seek(SeekFrom::Start(start - 1)).await?;
+            let mut reader = BufReader::new(file);
+            let pos = start - 1 + reader.read_line(&mut line).await? as u64;
+            (reader, pos)
+        };
+        Ok(LineRange {
+            reader,
+            pos,
+            end,
+            line,
+        })
+    }
+
+    fn parse_error(&self, lines: &LineRange, e: SourceError) -> SourceError {
+        SourceError::Parse(format!("{} at byte {}: {}", self.path, lines.pos, e))
+    }
+}
+
+/// The non-empty lines of one partition's byte range.
+struct LineRange {
+    reader: BufReader<File>,
+    pos: u64,
+    end: u64,
+    line: String,
+}
+
+impl LineRange {
+    async fn next(&mut self) -> Result<Option<&str>, SourceError> {
+        while self.pos < self.end {
+            self.line.clear();
+            let n = self.reader.read_line(&mut self.line).await?;
+            if n == 0 {
+                break;
+            }
+            self.pos += n as u64;
+            if !self.line.trim_end().is_empty() {
+                return Ok(Some(self.line.trim_end()));
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[async_trait]
+impl Source for FileSource {
+    fn partitions(&self) -> usize {
+        self.partitions
+    }
+
+    async fn read_partition(&self, partition: usize, tx: Sender<Chunk>) -> Result<(), SourceError> {
+        let schema = self.schema().await?;
+        let mut lines = self.lines(partition).await?;
+
+        if schema.is_netflow() {
+            let columns: HashMap<String, usize> = schema
+                .columns
+                .iter()
+                .enumerate()
+                .map(|(i, c)| (c.name.clone(), i))
+                .collect();
+            let mut batcher = Batcher::new(tx, partition, Chunk::Netflow);
+            while let Some(line) = lines.next().await? {
+                let netflow = match self.format {
+                    FileFormat::Csv => parse_csv(line, &columns),
+                    FileFormat::Ndjson => parse_ndjson(line),
+                };
+                let netflow = netflow.map_err(|e| self.parse_error(&lines, e))?;
+                if !batcher.push(netflow).await {
+                    return Ok(());
+                }
+            }
+            batcher.finish().await;
+            return Ok(());
+        }
+
+        let mut batcher = Batcher::new(tx, partition, |rows| {
+            Chunk::Records(RecordBatch {
+                schema: schema.clone(),
+                rows,
+            })
+        });
+        while let Some(line) = lines.next().await? {
+            let values = match self.format {
+                FileFormat::Csv => parse_csv_values(line, &schema),
+                FileFormat::Ndjson => parse_json_values(line, &schema),
+            };
+            let values = values.map_err(|e| self.parse_error(&lines, e))?;
+            if !batcher.push(values).await {
+                return Ok(());
+            }
+        }
+        batcher.finish().await;
+        Ok(())
+    }
+}
+
+fn parse_csv_values(line: &str, schema: &Schema) -> Result<Vec<Value>, SourceError> {
+    let fields: Vec<&str> = line.split(',').collect();
+    schema
+        .columns
+        .iter()
+        .enumerate()
+        .map(|(i, c)| match fields.get(i).map(|f| f.trim()) {
+            None | Some("") => Ok(Value::Null),
+            Some(field) => parse_value(field, c.ty),
+        })
+        .collect()
+}
+
+fn parse_json_values(line: &str, schema: &Schema) -> Result<Vec<Value>, SourceError> {
+    let object: Map<String, JsonValue> =
+        serde_json::from_str(line).map_err(|e| SourceError::Parse(e.to_string()))?;
+    schema
+        .columns
+        .iter()
+        .map(|c| match (object.get(&c.name), c.ty) {
+            (None | Some(JsonValue::Null), _) => Ok(Value::Null),
+            (Some(JsonValue::Bool(b)), _) => Ok(Value::Bool(*b)),
+            (Some(JsonValue::Number(n)), ColumnType::Int) if n.is_i64() => {
+                Ok(Value::Int(n.as_i64().unwrap()))
+            }
+            (Some(JsonValue::Number(n)), _) => Ok(Value::Float(n.as_f64().unwrap_or(f64::NAN))),
+            (Some(JsonValue::String(s)), ty) => parse_value(s, ty),
+            (Some(other), _) => Ok(Value::Text(other.to_string())),
+        })
+        .collect()
+}
+
+fn parse_csv(line: &str, columns: &HashMap<String, usize>) -> Result<Netflow, SourceError> {
+    let fields: Vec<&str> = line.split(',').collect();
+    let field = |name: &str| -> Option<&str> {
+        columns
+            .get(name)
+            .and_then(|&i| fields.get(i))
+            .map(|f| f.trim())
+            .filter(|f| !f.is_empty())
+    };
+    fn num<T: std::str::FromStr>(v: Option<&str>, name: &str) -> Result<Option<T>, SourceError> {
+        v.map(|v| {
+            v.parse()
+                .map_err(|_| SourceError::Parse(format!("invalid {} {:?}", name, v)))
+        })
+        .transpose()
+    }
+
+    Ok(Netflow {
+        flow_id: num(field("flow_id"), "flow_id")?
+            .ok_or_else(|| SourceError::Parse("missing flow_id".to_string()))?,
+        src_ip: field("src_ip").map(parse_ip).transpose()?,
+        dst_ip: field("dst_ip").map(parse_ip).transpose()?,
+        src_port: num(field("src_port"), "src_port")?,
+        dst_port: num(field("dst_port"), "dst_port")?,
+        protocol: num::<u8>(field("protocol"), "protocol")?.map(Protocol::from),
+        bytes: num(field("bytes"), "bytes")?,
+        packets: num(field("packets"), "packets")?,
+        start_ts: field("start_ts").map(parse_ts).transpose()?,
+        end_ts: field("end_ts").map(parse_ts).transpose()?,
+        src_asn: num(field("src_asn"), "src_asn")?,
+        dst_asn: num(field("dst_asn"), "dst_asn")?,
+    })
+}
+
+/// Line shape of the NDJSON files; unlike the wire format, timestamps are
+/// RFC 3339 strings and the protocol a plain number.
+#[derive(Deserialize)]
+struct JsonRow {
+    flow_id: i64,
+    src_ip: Option<IpAddr>,
+    dst_ip: Option<IpAddr>,
+    src_port: Option<i32>,
+    dst_port: Option<i32>,
+    protocol: Option<u8>,
+    bytes: Option<i64>,
+    packets: Option<i64>,
+    start_ts: Option<DateTime<Utc>>,
+    end_ts: Option<DateTime<Utc>>,
+    src_asn: Option<i32>,
+    dst_asn: Option<i32>,
+}
+
+fn parse_ndjson(line: &str) -> Result<Netflow, SourceError> {
+    let row: JsonRow = serde_json::from_str(line).map_err(|e| SourceError::Parse(e.to_string()))?;
+    Ok(Netflow {
+        flow_id: row.flow_id,
+        src_ip: row.src_ip,
+        dst_ip: row.dst_ip,
+        src_port: row.src_port,
+        dst_port: row.dst_port,
+        protocol: row.protocol.map(Protocol::from),
+        bytes: row.bytes,
+        packets: row.packets,
+        start_ts: row.start_ts,
+        end_ts: row.end_ts,
+        src_asn: row.src_asn,
+        dst_asn: row.dst_asn,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::netflow_gen::{self, NetflowGenConfig, OutputFormat};
+
+    async fn read_all(source: &FileSource) -> Vec<Netflow> {
+        let mut rows = Vec::new();
+        for partition in 0..source.partitions() {
+            let (tx, mut rx) = tokio::sync::mpsc::channel(100);
+            source.read_partition(partition, tx).await.unwrap();
+            while let Some(chunk) = rx.recv().await {
+                let Chunk::Netflow(batch) = chunk else {
+                    panic!("expected the netflow fast path");
+                };
+                rows.extend(batch);
+            }
+        }
+        rows
+    }
+
+    #[tokio::test]
+    async fn test_file_partitions_read_every_row_once() {
+        for format in [OutputFormat::Csv, OutputFormat::Ndjson] {
+            let path =
+                std::env::temp_dir().join(format!("source_file_test.{}", format.extension()));
+            netflow_gen::run(NetflowGenConfig {
+                rows: 2_000,
+                ipv6_ratio: 0.2,
+                output_path: path.to_string_lossy().into_owned(),
+                format,
+                ..NetflowGenConfig::default()
+            })
+            .unwrap();
+
+            let file_format = match format {
+                OutputFormat::Csv => FileFormat::Csv,
+                _ => FileFormat::Ndjson,
+            };
+            for partitions in [1, 3, 7] {
+                let source = FileSource::new(&path.to_string_lossy(), file_format, partitions);
+                let mut ids: Vec<i64> = read_all(&source).await.iter().map(|n| n.flow_id).collect();
+                ids.sort();
+                assert_eq!(ids, (1..=2_000).collect::<Vec<_>>());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_generic_csv_and_ndjson() {
+        let dir = std::env::temp_dir();
+        let csv = dir.join("source_file_generic.csv");
+        std::fs::write(
+            &csv,
+            "host,load,up,seen\nweb1,0.5,true,2024-01-01 00:00:00\ndb1,,false,\n",
+        )
+        .unwrap();
+        let ndjson = dir.join("source_file_generic.ndjson");
+        std::fs::write(
+            &ndjson,
+            "{\"host\":\"web1\",\"load\":0.5,\"up\":true,\"seen\":\"2024-01-01T00:00:00Z\"}\n\
+             {\"host\":\"db1\",\"load\":null,\"up\":false}\n",
+        )
+        .unwrap();
+
+        for (path, format) in [(csv, FileFormat::Csv), (ndjson, FileFormat::Ndjson)] {
+            let source = FileSource::new(&path.to_string_lossy(), format, 2);
+            let mut batches = Vec::new();
+            for partition in 0..source.partitions() {
+                let (tx, mut rx) = tokio::sync::mpsc::channel(10);
+                source.read_partition(partition, tx).await.unwrap();
+                while let Some(chunk) = rx.recv().await {
+                    let Chunk::Records(batch) = chunk else {
+                        panic!("expected generic records");
+                    };
+                    batches.push(batch);
+                }
+            }
+
+            let schema = batches[0].schema.clone();
+            let column = |name: &str| schema.columns.iter().position(|c| c.name == name).unwrap();
+            assert_eq!(schema.columns[column("load")].ty, ColumnType::Float);
+            assert_eq!(schema.columns[column("seen")].ty, ColumnType::Timestamp);
+            let rows: Vec<_> = batches.into_iter().flat_map(|b| b.rows).collect();
+            assert_eq!(rows.len(), 2);
+            assert_eq!(rows[0][column("host")], Value::Text("web1".into()));
+            assert_eq!(rows[0][column("seen")], Value::Timestamp(1704067200000000));
+            assert_eq!(rows[1][column("load")], Value::Null);
+            assert_eq!(rows[1][column("up")], Value::Bool(false));
+        }
+    }
+}
@@ -0,0 +1,199 @@
+use std::net::IpAddr;
+
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use common::netflow::Netflow;
+use common::record::{Column, ColumnType, RecordBatch, Schema, Value};
+use futures::TryStreamExt;
+use sqlx::postgres::PgRow;
+use sqlx::{Column as _, Executor, Pool, Postgres, Row, TypeInfo};
+use tokio::sync::mpsc::Sender;
+
+use super::{Batcher, Source, SourceError, partition_range, relation};
+use crate::record::Chunk;
+
+/// A table or query in Postgres, split into ranges of an integer
+/// `partition_column` between its current minimum and maximum.
+pub struct PostgresSource {
+    pool: Pool<Postgres>,
+    relation: String,
+    partition_column: String,
+    partitions: usize,
+}
+
+impl PostgresSource {
+    pub fn new(
+        pool: Pool<Postgres>,
+        query: &str,
+        partition_column: &str,
+        partitions: usize,
+    ) -> Self {
+        Self {
+            pool,
+            relation: relation(query),
+            partition_column: partition_column.to_string(),
+            partitions: partitions.max(1),
+        }
+    }
+}
+
+#[async_trait]
+impl Source for PostgresSource {
+    fn partitions(&self) -> usize {
+        self.partitions
+    }
+
+    async fn read_partition(&self, partition: usize, tx: Sender<Chunk>) -> Result<(), SourceError> {
+        let col = &self.partition_column;
+        let (min, max): (Option<i64>, Option<i64>) = sqlx::query_as(&format!(
+            "SELECT CAST(MIN({col}) AS BIGINT), CAST(MAX({col}) AS BIGINT) FROM ({}) AS source",
+            self.relation
+        ))
+        .fetch_one(&self.pool)
+        .await?;
+        let (Some(min), Some(max)) = (min, max) else {
+            return Ok(());
+        };
+        let (lo, hi) = partition_range(min, max, partition, self.partitions);
+
+        let sql = format!(
+            "SELECT * FROM ({}) AS source WHERE {col} BETWEEN $1 AND $2 ORDER BY {col}",
+            self.relation
+        );
+        let described = (&self.pool).describe(&sql).await?;
+        let decoders = described
+            .columns()
+            .iter()
+            .map(|c| PgDecoder::for_type(c.type_info().name()).map(|d| (c.name().to_string(), d)))
+            .collect::<Result<Vec<_>, _>>()?;
+        let schema = Schema {
+            columns: decoders
+                .iter()
+                .map(|(name, decoder)| Column {
+                    name: name.clone(),
+                    ty: decoder.column_type(),
+                })
+                .collect(),
+        };
+
+        if schema.is_netflow() {
+            let mut rows = sqlx::query_as::<_, Netflow>(&sql)
+                .bind(lo)
+                .bind(hi)
+                .fetch(&self.pool);
+            let mut batcher = Batcher::new(tx, partition, Chunk::Netflow);
+            while let Some(row) = rows.try_next().await? {
+                if !batcher.push(row).await {
+                    return Ok(());
+                }
+            }
+            batcher.finish().await;
+            return Ok(());
+        }
+
+        let mut rows = sqlx::query(&sql).bind(lo).bind(hi).fetch(&self.pool);
+        let mut batcher = Batcher::new(tx, partition, |rows| {
+            Chunk::Records(RecordBatch {
+                schema: schema.clone(),
+                rows,
+            })
+        });
+        while let Some(row) = rows.try_next().await? {
+            let values = decoders
+                .iter()
+                .enumerate()
+                .map(|(i, (_, decoder))| decoder.decode(&row, i))
+                .collect::<Result<Vec<_>, _>>()?;
+            if !batcher.push(values).await {
+                return Ok(());
+            }
+        }
+        batcher.finish().await;
+        Ok(())
+    }
+}
+
+/// How a Postgres column is read into a [`Value`], picked from its type name.
+enum PgDecoder {
+    Bool,
+    Int2,
+    Int4,
+    Int8,
+    Float4,
+    Float8,
+    Text,
+    Inet,
+    Timestamptz,
+    Timestamp,
+    Date,
+    Bytea,
+}
+
+impl PgDecoder {
+    fn for_type(name: &str) -> Result<Self, SourceError> {
+        Ok(match name {
+            "BOOL" => PgDecoder::Bool,
+            "INT2" => PgDecoder::Int2,
+            "INT4" => PgDecoder::Int4,
+            "INT8" => PgDecoder::Int8,
+            "FLOAT4" => PgDecoder::Float4,
+            "FLOAT8" => PgDecoder::Float8,
+            "TEXT" | "VARCHAR" | "BPCHAR" | "NAME" => PgDecoder::Text,
+            "INET" => PgDecoder::Inet,
+            "TIMESTAMPTZ" => PgDecoder::Timestamptz,
+            "TIMESTAMP" => PgDecoder::Timestamp,
+            "DATE" => PgDecoder::Date,
+            "BYTEA" => PgDecoder::Bytea,
+            other => {
+                return Err(SourceError::Parse(format!(
+                    "unsupported column type {}, cast it in SOURCE_QUERY",
+                    other
+                )));
+            }
+        })
+    }
+
+    fn column_type(&self) -> ColumnType {
+        match self {
+            PgDecoder::Bool => ColumnType::Bool,
+            PgDecoder::Int2 | PgDecoder::Int4 | PgDecoder::Int8 => ColumnType::Int,
+            PgDecoder::Float4 | PgDecoder::Float8 => ColumnType::Float,
+            PgDecoder::Text => ColumnType::Text,
+            PgDecoder::Inet => ColumnType::Ip,
+            PgDecoder::Timestamptz | PgDecoder::Timestamp | PgDecoder::Date => {
+                ColumnType::Timestamp
+            }
+            PgDecoder::Bytea => ColumnType::Bytes,
+        }
+    }
+
+    fn decode(&self, row: &PgRow, i: usize) -> Result<Value, sqlx::Error> {
+        fn get<'r, T: sqlx::Decode<'r, Postgres> + sqlx::Type<Postgres>>(
+            row: &'r PgRow,
+            i: usize,
+            f: impl FnOnce(T) -> Value,
+        ) -> Result<Value, sqlx::Error> {
+            Ok(row
+                .try_get::<Option<T>, _>(i)?
+                .map(f)
+                .unwrap_or(Value::Null))
+        }
+
+        match self {
+            PgDecoder::Bool => get(row, i, Value::Bool),
+            PgDecoder::Int2 => get(row, i, |v: i16| Value::Int(v as i64)),
+            PgDecoder::Int4 => get(row, i, |v: i32| Value::Int(v as i64)),
+            PgDecoder::Int8 => get(row, i, Value::Int),
+            PgDecoder::Float4 => get(row, i, |v: f32| Value::Float(v as f64)),
+            PgDecoder::Float8 => get(row, i, Value::Float),
+            PgDecoder::Text => get(row, i, Value::Text),
+            PgDecoder::Inet => get(row, i, |v: IpAddr| Value::Ip(v)),
+            PgDecoder::Timestamptz => get(row, i, |v: DateTime<Utc>| Value::timestamp(v)),
+            PgDecoder::Timestamp => get(row, i, |v: NaiveDateTime| Value::timestamp(v.and_utc())),
+            PgDecoder::Date => get(row, i, |v: NaiveDate| {
+                Value::timestamp(v.and_hms_opt(0, 0, 0).unwrap().and_utc())
+            }),
+            PgDecoder::Bytea => get(row, i, Value::Bytes),
+        }
+    }
+}
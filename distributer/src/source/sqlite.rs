@@ -0,0 +1,281 @@
+use async_trait::async_trait;
+use common::netflow::{Netflow, Protocol};
+use common::record::{Column, ColumnType, RecordBatch, Schema, Value};
+use futures::TryStreamExt;
+use sqlx::sqlite::{SqlitePool, SqlitePoolOptions, SqliteRow};
+use sqlx::{Column as _, Executor, Row, TypeInfo, ValueRef};
+use tokio::sync::mpsc::Sender;
+
+use super::{
+    Batcher, Source, SourceError, parse_ip, parse_ts, parse_value, partition_range, relation,
+};
+use crate::record::Chunk;
+
+/// A table or query in a SQLite file. SQLite has no native address or
+/// timestamp types, so IPs are read from text columns and timestamps from
+/// either text or integer epoch seconds.
+pub struct SqliteSource {
+    pool: SqlitePool,
+    relation: String,
+    partition_column: String,
+    partitions: usize,
+}
+
+impl SqliteSource {
+    pub async fn open(
+        path: &str,
+        query: &str,
+        partition_column: &str,
+        partitions: usize,
+    ) -> Result<Self, SourceError> {
+        let pool = SqlitePoolOptions::new()
+            .max_connections(partitions.max(1) as u32)
+            .connect(&format!("sqlite://{}?mode=ro", path))
+            .await?;
+        Ok(Self {
+            pool,
+            relation: relation(query),
+            partition_column: partition_column.to_string(),
+            partitions: partitions.max(1),
+        })
+    }
+}
+
+#[async_trait]
+impl Source for SqliteSource {
+    fn partitions(&self) -> usize {
+        self.partitions
+    }
+
+    async fn read_partition(&self, partition: usize, tx: Sender<Chunk>) -> Result<(), SourceError> {
+        let col = &self.partition_column;
+        let (min, max): (Option<i64>, Option<i64>) = sqlx::query_as(&format!(
+            "SELECT CAST(MIN({col}) AS INTEGER), CAST(MAX({col}) AS INTEGER) FROM ({}) AS source",
+            self.relation
+        ))
+        .fetch_one(&self.pool)
+        .await?;
+        let (Some(min), Some(max)) = (min, max) else {
+            return Ok(());
+        };
+        let (lo, hi) = partition_range(min, max, partition, self.partitions);
+
+        let sql = format!(
+            "SELECT * FROM ({}) AS source WHERE {col} BETWEEN ? AND ? ORDER BY {col}",
+            self.relation
+        );
+        let described = (&self.pool).describe(&sql).await?;
+        let schema = Schema {
+            columns: described
+                .columns()
+                .iter()
+                .map(|c| Column {
+                    name: c.name().to_string(),
+                    ty: column_type(c.type_info().name()),
+                })
+                .collect(),
+        };
+
+        let mut rows = sqlx::query(&sql).bind(lo).bind(hi).fetch(&self.pool);
+        if schema.is_netflow() {
+            let mut batcher = Batcher::new(tx, partition, Chunk::Netflow);
+            while let Some(row) = rows.try_next().await? {
+                if !batcher.push(netflow_from_row(&row)?).await {
+                    return Ok(());
+                }
+            }
+            batcher.finish().await;
+            return Ok(());
+        }
+
+        let mut batcher = Batcher::new(tx, partition, |rows| {
+            Chunk::Records(RecordBatch {
+                schema: schema.clone(),
+                rows,
+            })
+        });
+        while let Some(row) = rows.try_next().await? {
+            let values = schema
+                .columns
+                .iter()
+                .enumerate()
+                .map(|(i, c)| value_from_row(&row, i, c.ty))
+                .collect::<Result<Vec<_>, _>>()?;
+            if !batcher.push(values).await {
+                return Ok(());
+            }
+        }
+        batcher.finish().await;
+        Ok(())
+    }
+}
+
+/// SQLite only has storage classes; declared types that look like dates are
+/// read as timestamps, everything unknown as text.
+fn column_type(declared: &str) -> ColumnType {
+    match declared.to_ascii_uppercase().as_str() {
+        "INTEGER" | "INT" | "BIGINT" => ColumnType::Int,
+        "REAL" | "FLOAT" | "DOUBLE" => ColumnType::Float,
+        "BOOLEAN" => ColumnType::Bool,
+        "BLOB" => ColumnType::Bytes,
+        "DATETIME" | "TIMESTAMP" | "DATE" => ColumnType::Timestamp,
+        _ => ColumnType::Text,
+    }
+}
+
+fn value_from_row(row: &SqliteRow, i: usize, ty: ColumnType) -> Result<Value, SourceError> {
+    if row.try_get_raw(i)?.is_null() {
+        return Ok(Value::Null);
+    }
+    Ok(match ty {
+        ColumnType::Int => Value::Int(row.try_get(i)?),
+        ColumnType::Float => Value::Float(row.try_get(i)?),
+        ColumnType::Bool => Value::Bool(row.try_get(i)?),
+        ColumnType::Bytes => Value::Bytes(row.try_get(i)?),
+        ColumnType::Timestamp => match row.try_get::<i64, _>(i) {
+            Ok(secs) => Value::Timestamp(secs * 1_000_000),
+            Err(_) => parse_value(&row.try_get::<String, _>(i)?, ty)?,
+        },
+        ColumnType::Ip => Value::Ip(parse_ip(&row.try_get::<String, _>(i)?)?),
+        ColumnType::Text => Value::Text(row.try_get(i)?),
+    })
+}
+
+fn netflow_from_row(row: &SqliteRow) -> Result<Netflow, SourceError> {
+    let ip = |name: &str| -> Result<_, SourceError> {
+        row.try_get::<Option<String>, _>(name)?
+            .map(|v| parse_ip(&v))
+            .transpose()
+    };
+    let ts = |name: &str| -> Result<_, SourceError> {
+        if row.try_get_raw(name)?.is_null() {
+            return Ok(None);
+        }
+        match row.try_get::<i64, _>(name) {
+            Ok(secs) => Ok(chrono::DateTime::from_timestamp(secs, 0)),
+            Err(_) => parse_ts(&row.try_get::<String, _>(name)?).map(Some),
+        }
+    };
+
+    Ok(Netflow {
+        flow_id: row.try_get("flow_id")?,
+        src_ip: ip("src_ip")?,
+        dst_ip: ip("dst_ip")?,
+        src_port: row.try_get("src_port")?,
+        dst_port: row.try_get("dst_port")?,
+        protocol: row
+            .try_get::<Option<i64>, _>("protocol")?
+            .map(|p| u8::try_from(p).map(Protocol::from))
+            .transpose()
+            .map_err(|_| SourceError::Parse("protocol out of range".to_string()))?,
+        bytes: row.try_get("bytes")?,
+        packets: row.try_get("packets")?,
+        start_ts: ts("start_ts")?,
+        end_ts: ts("end_ts")?,
+        src_asn: row.try_get("src_asn")?,
+        dst_asn: row.try_get("dst_asn")?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_sqlite_partitions() {
+        let path = std::env::temp_dir().join("source_sqlite_test.db");
+        let _ = std::fs::remove_file(&path);
+        let pool = SqlitePoolOptions::new()
+            .connect(&format!("sqlite://{}?mode=rwc", path.display()))
+            .await
+            .unwrap();
+        sqlx::query(
+            "CREATE TABLE flows (flow_id INTEGER, src_ip TEXT, dst_ip TEXT, src_port INTEGER, \
+             dst_port INTEGER, protocol INTEGER, bytes INTEGER, packets INTEGER, start_ts TEXT, \
+             end_ts INTEGER, src_asn INTEGER, dst_asn INTEGER)",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        for id in 1..=25 {
+            sqlx::query(
+                "INSERT INTO flows VALUES (?, '10.0.0.1', '2001:db8::1', 1234, 443, 6, 100, 1, \
+                 '2024-01-01 00:00:00.250', 1704067201, 1, 2)",
+            )
+            .bind(id)
+            .execute(&pool)
+            .await
+            .unwrap();
+        }
+        pool.close().await;
+
+        let source = SqliteSource::open(&path.to_string_lossy(), "flows", "flow_id", 3)
+            .await
+            .unwrap();
+        let mut ids = Vec::new();
+        for partition in 0..source.partitions() {
+            let (tx, mut rx) = tokio::sync::mpsc::channel(10);
+            source.read_partition(partition, tx).await.unwrap();
+            while let Some(chunk) = rx.recv().await {
+                let Chunk::Netflow(batch) = chunk else {
+                    panic!("expected the netflow fast path");
+                };
+                ids.extend(batch.iter().map(|n| n.flow_id));
+                assert_eq!(batch[0].protocol, Some(Protocol::Tcp));
+                assert!(batch[0].dst_ip.unwrap().is_ipv6());
+                assert_eq!(batch[0].start_ts.unwrap().timestamp_millis(), 1704067200250);
+            }
+        }
+        assert_eq!(ids, (1..=25).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_generic_records() {
+        let path = std::env::temp_dir().join("source_sqlite_generic_test.db");
+        let _ = std::fs::remove_file(&path);
+        let pool = SqlitePoolOptions::new()
+            .connect(&format!("sqlite://{}?mode=rwc", path.display()))
+            .await
+            .unwrap();
+        sqlx::query("CREATE TABLE hosts (id INTEGER, name TEXT, load REAL, seen DATETIME)")
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query(
+            "INSERT INTO hosts VALUES (1, 'a', 0.5, '2024-01-01 00:00:00'), (2, NULL, 1.5, NULL)",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        pool.close().await;
+
+        let source = SqliteSource::open(&path.to_string_lossy(), "hosts", "id", 1)
+            .await
+            .unwrap();
+        let (tx, mut rx) = tokio::sync::mpsc::channel(10);
+        source.read_partition(0, tx).await.unwrap();
+        let Some(Chunk::Records(batch)) = rx.recv().await else {
+            panic!("expected generic records");
+        };
+        let types: Vec<_> = batch.schema.columns.iter().map(|c| c.ty).collect();
+        assert_eq!(
+            types,
+            vec![
+                ColumnType::Int,
+                ColumnType::Text,
+                ColumnType::Float,
+                ColumnType::Timestamp
+            ]
+        );
+        assert_eq!(
+            batch.rows[0],
+            vec![
+                Value::Int(1),
+                Value::Text("a".into()),
+                Value::Float(0.5),
+                Value::Timestamp(1704067200000000)
+            ]
+        );
+        assert_eq!(batch.rows[1][1], Value::Null);
+    }
+}
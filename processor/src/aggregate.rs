@@ -0,0 +1,215 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+use common::netflow::Netflow;
+use ipnet::IpNet;
+
+use common::record::{Column, ColumnType, RecordBatch, Schema, Value};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GroupKey {
+    Src,
+    Dst,
+    SrcDst,
+}
+
+impl GroupKey {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "src" => Some(GroupKey::Src),
+            "dst" => Some(GroupKey::Dst),
+            "src_dst" => Some(GroupKey::SrcDst),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct FlowStats {
+    pub flows: u64,
+    pub bytes: i64,
+    pub packets: i64,
+}
+
+/// Traffic totals grouped by subnet instead of exact address. IPv4 and IPv6
+/// addresses are truncated to their own prefix lengths, so a `/24` for v4 and
+/// a `/64` for v6 both end up as one group per customer network.
+pub struct CidrAggregator {
+    key: GroupKey,
+    v4_prefix: u8,
+    v6_prefix: u8,
+    groups: HashMap<(Option<IpNet>, Option<IpNet>), FlowStats>,
+}
+
+impl CidrAggregator {
+    pub fn new(key: GroupKey, v4_prefix: u8, v6_prefix: u8) -> Self {
+        Self {
+            key,
+            v4_prefix: v4_prefix.min(32),
+            v6_prefix: v6_prefix.min(128),
+            groups: HashMap::new(),
+        }
+    }
+
+    pub fn add(&mut self, netflow: &Netflow) {
+        let (Some(src), Some(dst)) = (netflow.src_ip, netflow.dst_ip) else {
+            return;
+        };
+        let key = match self.key {
+            GroupKey::Src => (Some(self.network(src)), None),
+            GroupKey::Dst => (None, Some(self.network(dst))),
+            GroupKey::SrcDst => (Some(self.network(src)), Some(self.network(dst))),
+        };
+
+        let stats = self.groups.entry(key).or_default();
+        stats.flows += 1;
+        stats.bytes += netflow.bytes.unwrap_or(0);
+        stats.packets += netflow.packets.unwrap_or(0);
+    }
+
+    pub fn top_by_bytes(&self, n: usize) -> Vec<(GroupLabel, &FlowStats)> {
+        let mut groups: Vec<_> = self.groups.iter().collect();
+        groups.sort_by_key(|(_, stats)| std::cmp::Reverse(stats.bytes));
+        groups
+            .into_iter()
+            .take(n)
+            .map(|((src, dst), stats)| ((label(src), label(dst)), stats))
+            .collect()
+    }
+
+    /// The groups as a record batch shaped like `RecordAggregator::to_batch`:
+    /// the grouped `src`/`dst` networks as text, `count`, `sum_bytes` and
+    /// `sum_packets`.
+    pub fn to_batch(&self) -> RecordBatch {
+        let (src, dst) = match self.key {
+            GroupKey::Src => (true, false),
+            GroupKey::Dst => (false, true),
+            GroupKey::SrcDst => (true, true),
+        };
+        let column = |name: &str, ty| Column {
+            name: name.to_string(),
+            ty,
+        };
+        let mut columns = Vec::new();
+        if src {
+            columns.push(column("src", ColumnType::Text));
+        }
+        if dst {
+            columns.push(column("dst", ColumnType::Text));
+        }
+        columns.push(column("count", ColumnType::Int));
+        columns.push(column("sum_bytes", ColumnType::Int));
+        columns.push(column("sum_packets", ColumnType::Int));
+
+        let rows = self
+            .groups
+            .iter()
+            .map(|((src_net, dst_net), stats)| {
+                let mut row = Vec::with_capacity(columns.len());
+                if src {
+                    row.push(Value::Text(label(src_net)));
+                }
+                if dst {
+                    row.push(Value::Text(label(dst_net)));
+                }
+                row.push(Value::Int(stats.flows as i64));
+                row.push(Value::Int(stats.bytes));
+                row.push(Value::Int(stats.packets));
+                row
+            })
+            .collect();
+        RecordBatch {
+            schema: Schema { columns },
+            rows,
+        }
+    }
+
+    fn network(&self, addr: IpAddr) -> IpNet {
+        let prefix = match addr {
+            IpAddr::V4(_) => self.v4_prefix,
+            IpAddr::V6(_) => self.v6_prefix,
+        };
+        IpNet::new(addr, prefix)
+            .expect("prefix clamped in new")
+            .trunc()
+    }
+}
+
+pub type GroupLabel = (String, String);
+
+fn label(net: &Option<IpNet>) -> String {
+    net.map(|n| n.to_string())
+        .unwrap_or_else(|| "*".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use common::netflow::Protocol;
+
+    use super::*;
+
+    fn flow(src: &str, dst: &str, bytes: i64) -> Netflow {
+        Netflow {
+            flow_id: 1,
+            src_ip: src.parse().ok(),
+            dst_ip: dst.parse().ok(),
+            src_port: Some(1234),
+            dst_port: Some(443),
+            protocol: Some(Protocol::Tcp),
+            bytes: Some(bytes),
+            packets: Some(1),
+            start_ts: None,
+            end_ts: None,
+            src_asn: Some(1),
+            dst_asn: Some(2),
+        }
+    }
+
+    #[test]
+    fn test_groups_by_prefix() {
+        let mut agg = CidrAggregator::new(GroupKey::Src, 24, 64);
+        agg.add(&flow("10.0.0.1", "1.1.1.1", 100));
+        agg.add(&flow("10.0.0.200", "8.8.8.8", 50));
+        agg.add(&flow("10.0.1.1", "8.8.8.8", 7));
+        agg.add(&flow("2001:db8::1", "8.8.8.8", 10));
+        agg.add(&flow("2001:db8::ffff:1", "8.8.8.8", 20));
+        agg.add(&flow("2001:db8:0:1::1", "8.8.8.8", 30));
+        agg.add(&flow("not-an-ip", "8.8.8.8", 1000));
+
+        let groups: HashMap<String, FlowStats> = agg
+            .top_by_bytes(usize::MAX)
+            .into_iter()
+            .map(|((src, _), stats)| (src, stats.clone()))
+            .collect();
+        assert_eq!(groups.len(), 4);
+        assert_eq!(
+            (groups["10.0.0.0/24"].flows, groups["10.0.0.0/24"].bytes),
+            (2, 150)
+        );
+        assert_eq!(groups["10.0.1.0/24"].bytes, 7);
+        assert_eq!(
+            (groups["2001:db8::/64"].flows, groups["2001:db8::/64"].bytes),
+            (2, 30)
+        );
+        assert_eq!(groups["2001:db8:0:1::/64"].bytes, 30);
+
+        let top = agg.top_by_bytes(1);
+        assert_eq!(top[0].0, ("10.0.0.0/24".to_string(), "*".to_string()));
+
+        let batch = agg.to_batch();
+        let names: Vec<_> = batch
+            .schema
+            .columns
+            .iter()
+            .map(|c| c.name.as_str())
+            .collect();
+        assert_eq!(names, ["src", "count", "sum_bytes", "sum_packets"]);
+        assert_eq!(batch.rows.len(), 4);
+        assert!(batch.rows.contains(&vec![
+            Value::Text("10.0.0.0/24".into()),
+            Value::Int(2),
+            Value::Int(150),
+            Value::Int(2),
+        ]));
+    }
+}
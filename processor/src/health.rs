@@ -0,0 +1,40 @@
+//! The load a processor keeps count of for the health report it answers
+//! every heartbeat with.
+
+use std::fs;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+
+use common::health::HealthReport;
+
+/// Counters the workers keep up to date for the health report.
+#[derive(Debug, Default)]
+pub struct Load {
+    pub workers: AtomicU32,
+    pub busy: AtomicU32,
+    pub records: AtomicU64,
+    pub draining: AtomicBool,
+    /// Chunks processed whose output has not been uploaded yet.
+    pub unflushed: AtomicU64,
+}
+
+impl Load {
+    pub fn report(&self, queue_depth: usize, queue_capacity: usize) -> HealthReport {
+        HealthReport {
+            queue_depth: queue_depth as u64,
+            queue_capacity: queue_capacity as u64,
+            busy_workers: self.busy.load(Ordering::Relaxed),
+            workers: self.workers.load(Ordering::Relaxed),
+            records_processed: self.records.load(Ordering::Relaxed),
+            memory_bytes: resident_bytes(),
+            draining: self.draining.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// `VmRSS` from `/proc/self/status`, so Linux only.
+fn resident_bytes() -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kib: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kib * 1024)
+}
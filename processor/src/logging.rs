@@ -0,0 +1,27 @@
+//! Log output. `RUST_LOG` picks what is logged, `info` and up unless set,
+//! and `LOG_FORMAT=json` turns every event into one JSON object carrying the
+//! fields of the spans it happened in. Chunks are logged with the `job`,
+//! `partition` and `chunk` the distributer put in their frame, matching its
+//! own logs for them.
+
+use std::env;
+
+use tracing_subscriber::EnvFilter;
+
+/// Installs the global subscriber from `RUST_LOG` and `LOG_FORMAT` (`text`,
+/// the default, or `json`).
+pub fn init() -> Result<(), String> {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+    match env::var("LOG_FORMAT").as_deref() {
+        Ok("text") | Err(_) => builder.init(),
+        Ok("json") => builder.json().with_span_list(true).init(),
+        Ok(other) => {
+            return Err(format!(
+                "unknown LOG_FORMAT {:?}, expected text or json",
+                other
+            ));
+        }
+    }
+    Ok(())
+}
@@ -1,160 +1,722 @@
-use std::{clone, process::exit, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+    path::PathBuf,
+    process::exit,
+    sync::{Arc, Mutex, atomic::Ordering},
+    time::Instant,
+};
 
 use anyhow::Result;
 use bincode2::deserialize;
-use lz4_flex::decompress_size_prepended;
+use common::auth::{self, Secret};
+use common::codec::Codec;
+use common::columnar;
+use common::frame::{self, ChunkId, FrameError, FrameLimits};
+use common::http;
+use common::netflow::Netflow;
+use common::record::{RecordBatch, TransformSpec};
+use common::result::{ChunkOutput, ChunkResult, ChunkStats};
 use rand::{Rng, SeedableRng, rngs::StdRng};
-use serde::{Deserialize, Serialize};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     net::{TcpListener, TcpStream},
 };
+use tracing::{debug, error, info, info_span, warn};
+
+use crate::aggregate::{CidrAggregator, GroupKey};
+use crate::health::Load;
+use crate::metrics::METRICS;
+use crate::record::Chunk;
+use crate::result::Upload;
+use crate::spill::{SpillBuffer, SpillConfig};
+use crate::transform::{RecordAggregator, Transform};
+
+mod aggregate;
+mod health;
+mod logging;
+mod metrics;
+mod record;
+mod result;
+mod spill;
+mod transform;
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    logging::init().map_err(anyhow::Error::msg)?;
     let mut rng = StdRng::from_os_rng();
     let port = rng.random_range(6000..9000);
-    let (tx, rx) = tokio::sync::mpsc::channel::<Vec<Netflow>>(1000);
-    tokio::spawn(listen_port(port, tx));
-    let (processed_tx, mut processed_rx) = tokio::sync::mpsc::channel::<Netflow>(1000);
+    let (tx, rx) = tokio::sync::mpsc::channel::<(ChunkId, Chunk)>(1000);
+    let secret = Secret::from_env();
+    if secret.is_none() {
+        warn!("CLUSTER_SECRET not set, the data port accepts anyone");
+    }
+    let limits = FrameLimits::from_env().map_err(anyhow::Error::msg)?;
+    let spill = SpillConfig::from_env().map_err(anyhow::Error::msg)?;
+    let (signals_tx, mut signals) = tokio::sync::mpsc::channel::<JobSignal>(100);
+    let in_flight = Arc::new(Mutex::new(InFlight::default()));
+    let load = Arc::new(Load::default());
+    let queue = tx.clone();
+    tokio::spawn(listen_port(
+        port,
+        tx,
+        signals_tx,
+        limits,
+        secret.clone(),
+        load.clone(),
+        in_flight.clone(),
+    ));
+    let (results_tx, mut results_rx) = tokio::sync::mpsc::channel::<Processed>(1000);
+    let (upstream_tx, upstream_rx) = tokio::sync::mpsc::channel::<Upload>(1000);
+    let (verdicts_tx, mut verdicts) = tokio::sync::mpsc::unbounded_channel::<(ChunkId, bool)>();
+    let results_addr = env::var("RESULTS_ADDR").unwrap_or_else(|_| "0.0.0.0:8081".to_string());
+    tokio::spawn(result::send_results(
+        results_addr,
+        secret.clone(),
+        port as u16,
+        spill.sort_key,
+        upstream_rx,
+        verdicts_tx,
+        load.clone(),
+    ));
+    let transform = Arc::new(Transform::from_env().map_err(anyhow::Error::msg)?);
+    let record_aggregator = Arc::new(RecordAggregator::from_env());
+    let group_key = env::var("GROUP_BY")
+        .ok()
+        .map(|name| GroupKey::from_name(&name).expect("unknown GROUP_BY"))
+        .unwrap_or(GroupKey::SrcDst);
 
     let cores = std::thread::available_parallelism()
         .map(|n| n.get())
         .unwrap_or(1);
     let workers = std::cmp::max(1, cores / 2);
+    load.workers.store(workers as u32, Ordering::Relaxed);
     let rx = Arc::new(tokio::sync::Mutex::new(rx));
 
     for _ in 0..workers {
         let rx = Arc::clone(&rx);
-        let processed_tx = processed_tx.clone();
+        let results_tx = results_tx.clone();
+        let transform = Arc::clone(&transform);
+        let record_aggregator = Arc::clone(&record_aggregator);
+        let load = Arc::clone(&load);
+        let in_flight = Arc::clone(&in_flight);
 
         tokio::spawn(async move {
             loop {
                 let next_chunk = { rx.lock().await.recv().await };
-                let Some(chunk) = next_chunk else { break };
+                let Some((id, chunk)) = next_chunk else { break };
+                if in_flight.lock().unwrap().skip(id) {
+                    debug!(parent: &chunk_span(id), "dropped chunk of a cancelled job or taken from another processor");
+                    continue;
+                }
 
-                validate_chunk(chunk, processed_tx.clone()).await;
+                load.busy.fetch_add(1, Ordering::Relaxed);
+                let started = Instant::now();
+                let rows = match &chunk {
+                    Chunk::Netflow(netflows) => netflows.len(),
+                    Chunk::Records(batch, _) => batch.rows.len(),
+                };
+                let span = chunk_span(id);
+                let processed = span.in_scope(|| {
+                    let processed = match chunk {
+                        Chunk::Netflow(netflows) => {
+                            let (result, output) = process_netflows(id, netflows, group_key);
+                            (result, Ok(output))
+                        }
+                        // a job's own transform over the processor's
+                        Chunk::Records(batch, job_transform) => process_records(
+                            id,
+                            batch,
+                            job_transform.as_ref().unwrap_or(transform.as_ref()),
+                            &record_aggregator,
+                        ),
+                    };
+                    debug!(
+                        kept = processed.0.stats.kept,
+                        rejected = processed.0.stats.rejected,
+                        "chunk processed"
+                    );
+                    processed
+                });
+                METRICS.chunk_seconds.observe(started.elapsed());
+                METRICS.rows_kept.add(processed.0.stats.kept);
+                METRICS.rows_rejected.add(processed.0.stats.rejected);
+                load.records.fetch_add(rows as u64, Ordering::Relaxed);
+                load.busy.fetch_sub(1, Ordering::Relaxed);
+                if let Err(err) = results_tx.send(processed).await {
+                    error!(parent: &span, error = %err, "failed to send chunk result");
+                    in_flight.lock().unwrap().done(id);
+                }
             }
         });
     }
-    let mut validated_netflows = Box::new(Vec::<Netflow>::new());
+
+    let mut aggregates = CidrAggregator::new(group_key, 24, 64);
+    let mut record_aggregates = RecordAggregator::from_env();
+    let unflushed = Arc::clone(&load);
     tokio::spawn(async move {
-        while let Some(netflow) = processed_rx.recv().await {
-            validated_netflows.push(netflow);
+        let mut jobs = HashMap::<u64, PendingJob>::new();
+        let (mut kept, mut batches) = (0, 0);
+        loop {
+            let job = tokio::select! {
+                Some((result, output)) = results_rx.recv() => {
+                    // processed before the cancel reached the workers
+                    if in_flight.lock().unwrap().done(result.id) {
+                        continue;
+                    }
+                    match &output {
+                        Ok(ChunkOutput::Netflow(netflows)) => {
+                            for netflow in netflows {
+                                aggregates.add(netflow);
+                                kept += 1;
+                                if kept % 100_000 == 0 {
+                                    for ((src, dst), stats) in aggregates.top_by_bytes(3) {
+                                        info!(%src, %dst, ?stats, "top talkers");
+                                    }
+                                }
+                            }
+                        }
+                        Ok(ChunkOutput::Records(batch)) => {
+                            if let Err(err) = record_aggregates.add(batch) {
+                                warn!(error = %err, "failed to aggregate records");
+                            }
+                            batches += 1;
+                            if batches % 100 == 0 {
+                                info!(
+                                    groups = record_aggregates.to_batch(&batch.schema).rows.len(),
+                                    "records kept"
+                                );
+                            }
+                        }
+                        Err(_) => {}
+                    }
+                    let job = result.id.job;
+                    let pending = jobs
+                        .entry(job)
+                        .or_insert_with(|| PendingJob::new(&spill, port, job));
+                    // a chunk resent after its ACK was lost only counts once,
+                    // and one taken from another processor not at all; the
+                    // output waits for the merger to take the result
+                    if pending.seen.insert(result.id.chunk) {
+                        unflushed.unflushed.fetch_add(1, Ordering::Relaxed);
+                        let held = match output {
+                            Ok(output) => pending.buffer.hold(result.id.chunk, output),
+                            Err(err) => Err(std::io::Error::other(format!(
+                                "chunk {}: {}",
+                                result.id.chunk, err
+                            ))),
+                        };
+                        if let Err(err) = held {
+                            error!(
+                                parent: &chunk_span(result.id),
+                                error = %err,
+                                "chunk output lost, failing the job"
+                            );
+                            pending.lost(err);
+                        }
+                        if let Err(err) = upstream_tx.send(Upload::Chunk(result)).await {
+                            error!(error = %err, "failed to queue chunk result");
+                        }
+                    }
+                    job
+                }
+                Some((id, kept)) = verdicts.recv() => {
+                    let Some(pending) = jobs.get_mut(&id.job) else {
+                        continue;
+                    };
+                    match pending.buffer.release(id.chunk, kept) {
+                        Ok(false) => continue,
+                        Ok(true) if !kept => debug!(
+                            parent: &chunk_span(id),
+                            "another processor's copy came first, output dropped"
+                        ),
+                        Ok(true) => {}
+                        Err(err) => {
+                            error!(
+                                parent: &chunk_span(id),
+                                error = %err,
+                                "failed to buffer chunk output"
+                            );
+                            pending.lost(err);
+                        }
+                    }
+                    id.job
+                }
+                Some(signal) = signals.recv() => match signal {
+                    JobSignal::Flush(id) => {
+                        if in_flight.lock().unwrap().is_cancelled(id.job) {
+                            continue;
+                        }
+                        let pending = jobs
+                            .entry(id.job)
+                            .or_insert_with(|| PendingJob::new(&spill, port, id.job));
+                        pending.expected = pending.expected.max(Some(id.chunk));
+                        id.job
+                    }
+                    JobSignal::Taken(id) => {
+                        if in_flight.lock().unwrap().is_cancelled(id.job) {
+                            continue;
+                        }
+                        let pending = jobs
+                            .entry(id.job)
+                            .or_insert_with(|| PendingJob::new(&spill, port, id.job));
+                        // done here as far as the job is concerned, with no
+                        // output; the queued copy is skipped
+                        if pending.seen.insert(id.chunk) {
+                            unflushed.unflushed.fetch_add(1, Ordering::Relaxed);
+                            in_flight.lock().unwrap().take(id);
+                        }
+                        id.job
+                    }
+                    JobSignal::Cancel(job) => {
+                        in_flight.lock().unwrap().cancel(job);
+                        if let Some(pending) = jobs.remove(&job) {
+                            let dropped = pending.discard();
+                            unflushed.unflushed.fetch_sub(dropped, Ordering::Relaxed);
+                            info!(job, dropped, "job cancelled, its output dropped");
+                        }
+                        if let Err(err) = upstream_tx.send(Upload::Forget(job)).await {
+                            error!(job, error = %err, "failed to forget job");
+                        }
+                        continue;
+                    }
+                    JobSignal::Close(job) => {
+                        // everything was uploaded, or the merger could not
+                        // have finished the job
+                        if let Some(pending) = jobs.remove(&job) {
+                            let dropped = pending.discard();
+                            unflushed.unflushed.fetch_sub(dropped, Ordering::Relaxed);
+                            debug!(job, "job closed");
+                        }
+                        if let Err(err) = upstream_tx.send(Upload::Forget(job)).await {
+                            error!(job, error = %err, "failed to forget job");
+                        }
+                        continue;
+                    }
+                },
+                else => break,
+            };
+
+            let pending = jobs.get_mut(&job).expect("inserted above");
+            if !pending.is_done() {
+                continue;
+            }
+            let runs = pending.take_runs(&spill, port);
+            match &runs {
+                Ok(runs) => info!(job, runs = runs.len(), "job flushed, uploading its runs"),
+                Err(err) => error!(job, error = %err, "job flushed, its output is lost"),
+            }
+            let chunks = pending.uploaded;
+            if let Err(err) = upstream_tx.send(Upload::Runs { job, runs, chunks }).await {
+                error!(job, error = %err, "failed to queue job output");
+            }
         }
     });
 
-    register_processor(port).await?;
+    {
+        // next to the data port unless told otherwise, so processors sharing
+        // a host don't collide
+        let addr =
+            env::var("METRICS_ADDR").unwrap_or_else(|_| format!("127.0.0.1:{}", port + 10000));
+        let load = Arc::clone(&load);
+        let queue = queue.clone();
+        let listener = TcpListener::bind(&addr).await?;
+        info!("serving metrics on http://{}/metrics", addr);
+        tokio::spawn(http::serve(listener, move |request| {
+            metrics::handle(request, || {
+                load.report(
+                    queue.max_capacity() - queue.capacity(),
+                    queue.max_capacity(),
+                )
+            })
+        }));
+    }
+    register_processor(port, secret.as_ref()).await?;
     tokio::signal::ctrl_c().await.unwrap();
+    // the distributer sees the flag on its next heartbeat and stops sending
+    // chunks; finish the queued ones and stay until their jobs are flushed
+    // and uploaded, unless interrupted again
+    info!("draining, interrupt again to stop now");
+    load.draining.store(true, Ordering::Relaxed);
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = async {
+            let mut interval = tokio::time::interval(std::time::Duration::from_millis(500));
+            loop {
+                interval.tick().await;
+                let drained = queue.capacity() == queue.max_capacity()
+                    && load.busy.load(Ordering::Relaxed) == 0
+                    && load.unflushed.load(Ordering::Relaxed) == 0;
+                if drained {
+                    break;
+                }
+            }
+        } => {}
+    }
+    info!("drained");
     Ok(())
 }
 
-async fn validate_chunk(
-    netflow_chunk: Vec<Netflow>,
-    processed_tx: tokio::sync::mpsc::Sender<Netflow>,
-) {
-    for netflow in netflow_chunk {
-        if !netflow.is_valid() {
-            continue;
+/// What the distributer says about a job as a whole.
+#[derive(Debug, PartialEq, Eq)]
+enum JobSignal {
+    /// It sent this processor `id.chunk` chunks of job `id.job` and none
+    /// will follow.
+    Flush(ChunkId),
+    /// Another processor's result for chunk `id.chunk` of job `id.job` was
+    /// taken: skip the copy sent here if it is still queued.
+    Taken(ChunkId),
+    /// The job was cancelled: drop its queued chunks and its output.
+    Cancel(u64),
+    /// The job's output is merged: forget it.
+    Close(u64),
+}
+
+/// Chunks taken off the data port whose result has not reached the
+/// collector yet, by job, and the ones to drop rather than process. A
+/// cancellation is only kept while the job has chunks in flight: job ids
+/// start over when the distributer restarts, and a new job with the same id
+/// must not be dropped.
+#[derive(Default)]
+struct InFlight {
+    chunks: HashMap<u64, u64>,
+    cancelled: HashSet<u64>,
+    /// Chunks another processor's copy of was taken instead, by job and
+    /// chunk.
+    taken: HashSet<(u64, u64)>,
+}
+
+impl InFlight {
+    fn queued(&mut self, id: ChunkId) {
+        *self.chunks.entry(id.job).or_default() += 1;
+    }
+
+    /// Whether a chunk about to be processed is to be dropped instead, which
+    /// is then done with.
+    fn skip(&mut self, id: ChunkId) -> bool {
+        let skip = self.cancelled.contains(&id.job) || self.taken.contains(&(id.job, id.chunk));
+        if skip {
+            self.done(id);
+        }
+        skip
+    }
+
+    /// A chunk processed or dropped, returning whether its job was
+    /// cancelled.
+    fn done(&mut self, id: ChunkId) -> bool {
+        let cancelled = self.is_cancelled(id.job);
+        self.taken.remove(&(id.job, id.chunk));
+        if let Some(chunks) = self.chunks.get_mut(&id.job) {
+            *chunks -= 1;
+            if *chunks == 0 {
+                self.chunks.remove(&id.job);
+                self.cancelled.remove(&id.job);
+            }
+        }
+        cancelled
+    }
+
+    fn is_cancelled(&self, job: u64) -> bool {
+        self.cancelled.contains(&job)
+    }
+
+    fn take(&mut self, id: ChunkId) {
+        self.taken.insert((id.job, id.chunk));
+    }
+
+    fn cancel(&mut self, job: u64) {
+        self.taken.retain(|(taken, _)| *taken != job);
+        if self.chunks.contains_key(&job) {
+            self.cancelled.insert(job);
+        }
+    }
+}
+
+/// A job's output on this processor until the distributer flushes it. The
+/// job is kept after its upload, until the distributer closes or cancels
+/// it: a processor can be handed more of its chunks when another one is
+/// evicted, and then uploads those as well.
+struct PendingJob {
+    job: u64,
+    buffer: SpillBuffer,
+    /// Chunks processed so far, or taken from another processor. Their
+    /// output is held in `buffer` until the merger takes their result, and
+    /// dropped if another processor's copy came first.
+    seen: HashSet<u64>,
+    /// Chunks the distributer sent here, known once it flushes the job and
+    /// raised by later flushes.
+    expected: Option<u64>,
+    /// Chunks covered by the uploads so far.
+    uploaded: u64,
+    /// Uploads so far, naming the next buffer's run files.
+    uploads: u64,
+    /// Why output of a chunk could not be kept, failing the next upload.
+    lost: Option<std::io::Error>,
+}
+
+impl PendingJob {
+    fn new(spill: &SpillConfig, port: i32, job: u64) -> Self {
+        Self {
+            job,
+            buffer: SpillBuffer::new(spill, format!("{}-job-{}-0", port, job)),
+            seen: HashSet::new(),
+            expected: None,
+            uploaded: 0,
+            uploads: 0,
+            lost: None,
         }
-        if let Err(err) = processed_tx.send(netflow).await {
-            eprintln!("failed to send processed netflow: {}", err);
-            break;
+    }
+
+    fn is_done(&self) -> bool {
+        let processed = self.seen.len() as u64;
+        self.expected == Some(processed) && self.uploaded < processed && !self.buffer.holding()
+    }
+
+    /// Records that output of the job was lost, keeping the first error.
+    fn lost(&mut self, err: std::io::Error) {
+        self.lost.get_or_insert(err);
+    }
+
+    /// The runs of everything processed since the last upload, leaving an
+    /// empty buffer for chunks still to come. Fails if any of that output
+    /// was lost, its runs then being removed.
+    fn take_runs(&mut self, spill: &SpillConfig, port: i32) -> std::io::Result<Vec<PathBuf>> {
+        self.uploads += 1;
+        let name = format!("{}-job-{}-{}", port, self.job, self.uploads);
+        let buffer = std::mem::replace(&mut self.buffer, SpillBuffer::new(spill, name));
+        self.uploaded = self.seen.len() as u64;
+        if let Some(err) = self.lost.take() {
+            buffer.discard();
+            return Err(err);
         }
+        buffer.finish()
+    }
+
+    /// Throws away the output not uploaded yet, returning how many chunks it
+    /// covered.
+    fn discard(self) -> u64 {
+        self.buffer.discard();
+        self.seen.len() as u64 - self.uploaded
     }
 }
 
-impl Netflow {
-    fn is_valid(&self) -> bool {
-        self.src_ip.is_some()
-            && self.dst_ip.is_some()
-            && self.protocol.is_some()
-            && self.bytes.is_some()
-            && self.packets.is_some()
-            && self.start_ts.is_some()
-            && self.end_ts.is_some()
-            && self.src_asn.is_some()
-            && self.dst_asn.is_some()
+/// Drops invalid flows from a chunk and aggregates the rest by network.
+fn process_netflows(
+    id: ChunkId,
+    netflows: Vec<Netflow>,
+    group_key: GroupKey,
+) -> (ChunkResult, ChunkOutput) {
+    let received = netflows.len() as u64;
+    let kept: Vec<Netflow> = netflows.into_iter().filter(Netflow::is_valid).collect();
+    let mut aggregates = CidrAggregator::new(group_key, 24, 64);
+    for netflow in &kept {
+        aggregates.add(netflow);
     }
+    let result = ChunkResult {
+        id,
+        stats: ChunkStats {
+            received,
+            kept: kept.len() as u64,
+            rejected: received - kept.len() as u64,
+        },
+        aggregates: aggregates.to_batch(),
+    };
+    (result, ChunkOutput::Netflow(kept))
 }
 
-async fn register_processor(port: i32) -> Result<()> {
+/// A chunk's result and its output, or why the output is lost.
+type Processed = (ChunkResult, Result<ChunkOutput, String>);
+
+/// Applies `FILTER`/`PROJECT` to a record batch and aggregates what is left.
+/// A batch the transform or the aggregates cannot handle counts as entirely
+/// rejected and has no output, which fails its job.
+fn process_records(
+    id: ChunkId,
+    batch: RecordBatch,
+    transform: &Transform,
+    aggregator: &RecordAggregator,
+) -> Processed {
+    let received = batch.rows.len() as u64;
+    let schema = batch.schema.clone();
+    let mut aggregates = aggregator.clone();
+    let output = transform
+        .apply(batch)
+        .map_err(|err| format!("failed to transform records: {}", err))
+        .and_then(|output| match aggregates.add(&output) {
+            Ok(()) => Ok(output),
+            Err(err) => Err(format!("failed to aggregate records: {}", err)),
+        });
+    let output = match output {
+        Ok(output) => output,
+        Err(err) => {
+            let result = ChunkResult {
+                id,
+                stats: ChunkStats {
+                    received,
+                    kept: 0,
+                    rejected: received,
+                },
+                aggregates: aggregator.to_batch(&schema),
+            };
+            return (result, Err(err));
+        }
+    };
+    let kept = output.rows.len() as u64;
+    let result = ChunkResult {
+        id,
+        stats: ChunkStats {
+            received,
+            kept,
+            rejected: received - kept,
+        },
+        aggregates: aggregates.to_batch(&output.schema),
+    };
+    (result, Ok(ChunkOutput::Records(output)))
+}
+
+/// Registers with the distributer, offering `colmn` frames unless
+/// `CHUNK_ENCODING=rows` asks for bincode `chunk` frames only.
+async fn register_processor(port: i32, secret: Option<&Secret>) -> Result<()> {
+    let command = match env::var("CHUNK_ENCODING").as_deref() {
+        Ok("rows") => format!("connect {}", port),
+        Ok("columnar") | Err(_) => format!("connect {} columnar", port),
+        Ok(other) => anyhow::bail!(
+            "unknown CHUNK_ENCODING {:?}, expected rows or columnar",
+            other
+        ),
+    };
     match TcpStream::connect("0.0.0.0:8080").await {
         Ok(mut stream) => {
-            stream
-                .write_all(format!("connect {}", port).as_bytes())
-                .await?;
+            if let Some(secret) = secret {
+                auth::connect(&mut stream, secret).await?;
+            }
+            stream.write_all(command.as_bytes()).await?;
         }
         Err(err) => {
-            println!("error connecting to distributor: {}", err);
+            error!(error = %err, "failed to connect to the distributer");
             exit(1);
         }
     }
     Ok(())
 }
 
-#[derive(Debug, sqlx::FromRow, Serialize, Deserialize, Clone)]
-
-pub struct Netflow {
-    pub flow_id: i64,
-    pub src_ip: Option<String>,
-    pub dst_ip: Option<String>,
-    pub src_port: Option<i32>,
-    pub dst_port: Option<i32>,
-    pub protocol: Option<i16>,
-    pub bytes: Option<i64>,
-    pub packets: Option<i64>,
-    pub start_ts: Option<i64>,
-    pub end_ts: Option<i64>,
-    pub src_asn: Option<i32>,
-    pub dst_asn: Option<i32>,
-}
-
 async fn listen_port(
     port: i32,
-    tx: tokio::sync::mpsc::Sender<Vec<Netflow>>,
+    tx: tokio::sync::mpsc::Sender<(ChunkId, Chunk)>,
+    signals: tokio::sync::mpsc::Sender<JobSignal>,
+    limits: FrameLimits,
+    secret: Option<Secret>,
+    load: Arc<Load>,
+    in_flight: Arc<Mutex<InFlight>>,
 ) -> std::io::Result<()> {
     let listener = TcpListener::bind(format!("0.0.0.0:{}", port)).await?;
     loop {
-        let (mut socket, _) = listener.accept().await?;
+        let (mut socket, addr) = listener.accept().await?;
         let tx = tx.clone();
+        let signals = signals.clone();
+        let secret = secret.clone();
+        let load = Arc::clone(&load);
+        let in_flight = Arc::clone(&in_flight);
         tokio::spawn(async move {
+            if let Some(secret) = secret
+                && let Err(err) = auth::accept(&mut socket, &secret).await
+            {
+                warn!(%addr, error = %err, "rejected connection");
+                return;
+            }
             loop {
                 let mut prefix = [0u8; 5];
                 if socket.read_exact(&mut prefix).await.is_err() {
                     break;
                 }
 
-                if &prefix == b"chunk" {
-                    let mut len_buf = [0u8; 4];
-                    if socket.read_exact(&mut len_buf).await.is_err() {
+                if &prefix == b"chunk" || &prefix == b"colmn" || &prefix == b"batch" {
+                    let Some(frame) = read_frame(&prefix, &mut socket, limits).await else {
                         break;
-                    }
-                    let len = u32::from_be_bytes(len_buf) as usize;
-                    let mut compressed = vec![0u8; len];
-                    if socket.read_exact(&mut compressed).await.is_err() {
+                    };
+                    let reply: &[u8] = match frame {
+                        Ok((id, chunk)) => {
+                            let span = chunk_span(id);
+                            let rows = match &chunk {
+                                Chunk::Netflow(items) => items.len(),
+                                Chunk::Records(batch, _) => batch.rows.len(),
+                            };
+                            let kind = String::from_utf8_lossy(&prefix);
+                            debug!(parent: &span, %addr, %kind, rows, "chunk received");
+                            METRICS.chunks_received.add(&kind, 1);
+                            in_flight.lock().unwrap().queued(id);
+                            match tx.send((id, chunk)).await {
+                                Ok(_) => b"ACK",
+                                Err(err) => {
+                                    in_flight.lock().unwrap().done(id);
+                                    error!(parent: &span, error = %err, "failed to queue chunk");
+                                    b"ERR"
+                                }
+                            }
+                        }
+                        Err(FrameError::Checksum) => {
+                            METRICS.frames_corrupted.inc();
+                            warn!(%addr, "frame failed its checksum, asking for a resend");
+                            b"NAK"
+                        }
+                        Err(FrameError::Invalid(err)) => {
+                            warn!(%addr, error = %err, "rejected frame");
+                            b"ERR"
+                        }
+                        Err(FrameError::Protocol(err)) => {
+                            // the rest of the frame is still unread, there is
+                            // no way to find the next one
+                            warn!(%addr, error = %err, "protocol error, closing the connection");
+                            let _ = socket.write_all(b"ERR").await;
+                            break;
+                        }
+                    };
+                    if socket.write_all(reply).await.is_err() {
                         break;
                     }
-
-                    match decompress_size_prepended(&compressed) {
-                        Ok(decompressed) => match deserialize::<Vec<Netflow>>(&decompressed) {
-                            Ok(items) => {
-                                println!("received {} netflow items", items.len());
-
-                                if let Err(err) = tx.send(items).await {
-                                    eprintln!("failed to send netflow chunk: {}", err);
-                                }
+                } else if &prefix == b"flush"
+                    || &prefix == b"taken"
+                    || &prefix == b"cancl"
+                    || &prefix == b"close"
+                {
+                    let reply: &[u8] = match frame::read(&mut socket, limits).await {
+                        None => break,
+                        Some(Ok((id, _))) => {
+                            let signal = match &prefix {
+                                b"flush" => JobSignal::Flush(id),
+                                b"taken" => JobSignal::Taken(id),
+                                b"close" => JobSignal::Close(id.job),
+                                _ => JobSignal::Cancel(id.job),
+                            };
+                            match signals.send(signal).await {
+                                Ok(_) => b"ACK",
+                                Err(_) => b"ERR",
                             }
-                            Err(err) => eprintln!("failed to deserialize: {}", err),
-                        },
-                        Err(err) => eprintln!("failed to decompress: {}", err),
+                        }
+                        Some(Err(FrameError::Checksum)) => b"NAK",
+                        Some(Err(FrameError::Invalid(_))) => b"ERR",
+                        Some(Err(FrameError::Protocol(err))) => {
+                            warn!(%addr, error = %err, "protocol error, closing the connection");
+                            let _ = socket.write_all(b"ERR").await;
+                            break;
+                        }
+                    };
+                    if socket.write_all(reply).await.is_err() {
+                        break;
                     }
-                } else if &prefix[..5] == b"healt" {
-                    let mut check_rest = [0u8; 6];
+                } else if &prefix == b"healt" {
+                    // the rest of `health-check`
+                    let mut check_rest = [0u8; 7];
                     if socket.read_exact(&mut check_rest).await.is_err() {
                         break;
                     }
-                    let _ = socket.write_all(b"healthy").await;
+                    let report = load.report(tx.max_capacity() - tx.capacity(), tx.max_capacity());
+                    let raw = bincode2::serialize(&report).expect("failed to encode report");
+                    let id = ChunkId::new(0, 0);
+                    let reply = frame::encode(b"healt", Codec::None, id, &raw);
+                    if socket.write_all(&reply).await.is_err() {
+                        break;
+                    }
                 } else {
                     break;
                 }
@@ -163,61 +725,620 @@ async fn listen_port(
     }
 }
 
+/// The span a chunk is logged in on this processor, named after the ids the
+/// distributer logs it with.
+fn chunk_span(id: ChunkId) -> tracing::Span {
+    info_span!(
+        "chunk",
+        job = id.job,
+        partition = id.partition,
+        chunk = id.chunk
+    )
+}
+
+/// Reads the rest of a data frame after its `prefix` and decodes the payload.
+/// `None` means the connection is done.
+async fn read_frame(
+    prefix: &[u8; 5],
+    socket: &mut TcpStream,
+    limits: FrameLimits,
+) -> Option<Result<(ChunkId, Chunk), FrameError>> {
+    let (id, raw) = match frame::read(socket, limits).await? {
+        Ok(frame) => frame,
+        Err(err) => return Some(Err(err)),
+    };
+    let decoded: Result<Chunk> = match prefix {
+        b"chunk" => deserialize::<Vec<Netflow>>(&raw)
+            .map(Chunk::Netflow)
+            .map_err(Into::into),
+        b"colmn" => columnar::decode(&raw)
+            .map(Chunk::Netflow)
+            .map_err(Into::into),
+        _ => deserialize::<(Option<TransformSpec>, RecordBatch)>(&raw)
+            .map_err(anyhow::Error::from)
+            .and_then(|(spec, batch)| {
+                batch.check_width().map_err(anyhow::Error::msg)?;
+                let transform = spec
+                    .map(|spec| Transform::parse(spec.filter.as_deref(), spec.project.as_deref()))
+                    .transpose()
+                    .map_err(anyhow::Error::msg)?;
+                Ok(Chunk::Records(batch, transform))
+            }),
+    };
+    Some(
+        decoded
+            .map(|chunk| (id, chunk))
+            .map_err(|err| FrameError::Invalid(err.to_string())),
+    )
+}
+
 #[cfg(test)]
 mod tests {
     use bincode2::serialize;
-    use lz4_flex::compress_prepend_size;
+    use chrono::DateTime;
+    use common::health::HealthReport;
+    use common::netflow::Protocol;
 
     use super::*;
 
+    const ID: ChunkId = ChunkId {
+        job: 3,
+        chunk: 14,
+        partition: 2,
+    };
+
+    fn data_frame(prefix: &[u8; 5], codec: Codec, raw: &[u8]) -> Vec<u8> {
+        frame::encode(prefix, codec, ID, raw)
+    }
+
+    async fn connect(port: i32) -> TcpStream {
+        // the listener is spawned right before this, give it a moment to bind
+        for _ in 0..50 {
+            if let Ok(stream) = TcpStream::connect(format!("0.0.0.0:{}", port)).await {
+                return stream;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        panic!("listener on {} never came up", port);
+    }
+
+    /// For tests that never flush or cancel a job.
+    fn signal_channel() -> tokio::sync::mpsc::Sender<JobSignal> {
+        tokio::sync::mpsc::channel(1).0
+    }
+
     #[tokio::test]
     async fn test_listen_port() {
         let port = 7001;
-        let (tx, _rx) = tokio::sync::mpsc::channel::<Vec<Netflow>>(100);
-        tokio::spawn(listen_port(port, tx));
+        let (tx, _rx) = tokio::sync::mpsc::channel::<(ChunkId, Chunk)>(100);
+        tokio::spawn(listen_port(
+            port,
+            tx,
+            signal_channel(),
+            FrameLimits::default(),
+            None,
+            Arc::default(),
+            Arc::default(),
+        ));
 
-        let mut stream = TcpStream::connect(format!("0.0.0.0:{}", port))
-            .await
-            .unwrap();
-        stream.write_all(b"health check").await.unwrap();
-        let mut response = [0u8; 7];
-        stream.read_exact(&mut response).await.unwrap();
-        assert_eq!(&response, b"healthy");
+        let mut stream = connect(port).await;
+        stream.write_all(b"health-check").await.unwrap();
+        let mut prefix = [0u8; 5];
+        stream.read_exact(&mut prefix).await.unwrap();
+        assert_eq!(&prefix, b"healt");
+        let Some(Ok((_, raw))) = frame::read(&mut stream, FrameLimits::default()).await else {
+            panic!("expected a health report");
+        };
+        let report: HealthReport = bincode2::deserialize(&raw).unwrap();
+        assert_eq!(report.queue_capacity, 100);
+        assert!(!report.draining);
     }
     #[tokio::test]
     async fn test_listen_chunk() {
         let port = 7002;
-        let (tx, _rx) = tokio::sync::mpsc::channel::<Vec<Netflow>>(100);
+        let (tx, mut rx) = tokio::sync::mpsc::channel::<(ChunkId, Chunk)>(100);
         tokio::spawn(async move {
-            listen_port(port, tx).await.unwrap();
-        });
-
-        let mut stream = TcpStream::connect(format!("0.0.0.0:{}", port))
+            listen_port(
+                port,
+                tx,
+                signal_channel(),
+                FrameLimits::default(),
+                None,
+                Arc::default(),
+                Arc::default(),
+            )
             .await
             .unwrap();
+        });
+
+        let mut stream = connect(port).await;
         let netflows = vec![Netflow {
             flow_id: 1,
-            src_ip: Some("192.168.1.1".into()),
-            dst_ip: Some("192.168.1.2".into()),
+            src_ip: Some("192.168.1.1".parse().unwrap()),
+            dst_ip: Some("192.168.1.2".parse().unwrap()),
             src_port: Some(80),
             dst_port: Some(443),
-            protocol: Some(6),
+            protocol: Some(Protocol::Tcp),
             bytes: Some(1024),
             packets: Some(5),
-            start_ts: Some(1678886400),
-            end_ts: Some(1678886500),
+            start_ts: DateTime::from_timestamp_millis(1678886400123),
+            end_ts: DateTime::from_timestamp_millis(1678886500456),
             src_asn: Some(12345),
             dst_asn: Some(54321),
         }];
         let serialized = serialize(&netflows).unwrap();
-        let compressed = compress_prepend_size(&serialized);
-        let decompressed = decompress_size_prepended(&compressed).unwrap();
-        let items: Vec<Netflow> = deserialize(&decompressed).unwrap();
+        let items: Vec<Netflow> = deserialize(&serialized).unwrap();
         assert_eq!(items[0].flow_id, 1);
-        let mut chunk_message = b"chunk".to_vec();
-        let len = (compressed.len() as u32).to_be_bytes();
-        chunk_message.extend_from_slice(&len);
-        chunk_message.extend_from_slice(&compressed);
-        stream.write_all(&chunk_message).await.unwrap();
+        assert_eq!(items[0].protocol, Some(Protocol::Tcp));
+        assert_eq!(items[0].start_ts, netflows[0].start_ts);
+        for codec in [Codec::None, Codec::Lz4, Codec::Zstd, Codec::Snappy] {
+            stream
+                .write_all(&data_frame(b"chunk", codec, &serialized))
+                .await
+                .unwrap();
+            let Some((id, Chunk::Netflow(received))) = rx.recv().await else {
+                panic!("expected a netflow chunk with {:?}", codec);
+            };
+            assert_eq!(id, ID);
+            assert_eq!(received[0].flow_id, 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_listen_batch() {
+        use common::record::{Column, ColumnType, Schema, Value};
+
+        let port = 7003;
+        let (tx, mut rx) = tokio::sync::mpsc::channel::<(ChunkId, Chunk)>(100);
+        tokio::spawn(listen_port(
+            port,
+            tx,
+            signal_channel(),
+            FrameLimits::default(),
+            None,
+            Arc::default(),
+            Arc::default(),
+        ));
+        let mut stream = connect(port).await;
+
+        let batch = RecordBatch {
+            schema: Schema {
+                columns: vec![Column {
+                    name: "host".into(),
+                    ty: ColumnType::Text,
+                }],
+            },
+            rows: vec![vec![Value::Text("web1".into())], vec![Value::Null]],
+        };
+        let no_transform: Option<TransformSpec> = None;
+        let raw = serialize(&(no_transform, &batch)).unwrap();
+        stream
+            .write_all(&data_frame(b"batch", Codec::Lz4, &raw))
+            .await
+            .unwrap();
+        let mut reply = [0u8; 3];
+        stream.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"ACK");
+        let Some((_, Chunk::Records(received, None))) = rx.recv().await else {
+            panic!("expected a record batch");
+        };
+        assert_eq!(received, batch);
+
+        // the job's own transform comes along, parsed
+        for (filter, expected) in [("host=web1", &b"ACK"), ("host", &b"ERR")] {
+            let spec = TransformSpec {
+                filter: Some(filter.to_string()),
+                project: None,
+            };
+            let raw = serialize(&(Some(spec), &batch)).unwrap();
+            stream
+                .write_all(&data_frame(b"batch", Codec::Lz4, &raw))
+                .await
+                .unwrap();
+            stream.read_exact(&mut reply).await.unwrap();
+            assert_eq!(&reply, *expected);
+        }
+        let Some((_, Chunk::Records(_, Some(transform)))) = rx.recv().await else {
+            panic!("expected a record batch with its transform");
+        };
+        assert_eq!(transform.apply(batch.clone()).unwrap().rows.len(), 1);
+
+        // a row short of a value is refused before anything indexes it
+        let mut short = batch;
+        short.rows.push(Vec::new());
+        let raw = serialize(&(None::<TransformSpec>, &short)).unwrap();
+        stream
+            .write_all(&data_frame(b"batch", Codec::Lz4, &raw))
+            .await
+            .unwrap();
+        stream.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"ERR");
+    }
+
+    #[tokio::test]
+    async fn test_listen_columnar() {
+        let port = 7004;
+        let (tx, mut rx) = tokio::sync::mpsc::channel::<(ChunkId, Chunk)>(100);
+        tokio::spawn(listen_port(
+            port,
+            tx,
+            signal_channel(),
+            FrameLimits::default(),
+            None,
+            Arc::default(),
+            Arc::default(),
+        ));
+        let mut stream = connect(port).await;
+
+        let netflows = vec![
+            Netflow {
+                flow_id: 41,
+                src_ip: Some("2001:db8::1".parse().unwrap()),
+                dst_ip: Some("10.0.0.1".parse().unwrap()),
+                src_port: Some(53000),
+                dst_port: Some(53),
+                protocol: Some(Protocol::Udp),
+                bytes: Some(120),
+                packets: Some(1),
+                start_ts: DateTime::from_timestamp_millis(1678886400123),
+                end_ts: DateTime::from_timestamp_millis(1678886400124),
+                src_asn: Some(64512),
+                dst_asn: None,
+            },
+            Netflow {
+                flow_id: 42,
+                src_ip: Some("10.0.0.1".parse().unwrap()),
+                dst_ip: None,
+                src_port: None,
+                dst_port: Some(443),
+                protocol: None,
+                bytes: Some(9000),
+                packets: None,
+                start_ts: None,
+                end_ts: DateTime::from_timestamp_millis(1678886400000),
+                src_asn: None,
+                dst_asn: Some(1),
+            },
+        ];
+        let message = data_frame(b"colmn", Codec::Zstd, &columnar::encode(&netflows));
+        stream.write_all(&message).await.unwrap();
+
+        let Some((_, Chunk::Netflow(received))) = rx.recv().await else {
+            panic!("expected a netflow chunk");
+        };
+        assert_eq!(format!("{:?}", received), format!("{:?}", netflows));
+    }
+
+    #[tokio::test]
+    async fn test_listen_corrupted_frame() {
+        let port = 7005;
+        let (tx, mut rx) = tokio::sync::mpsc::channel::<(ChunkId, Chunk)>(100);
+        tokio::spawn(listen_port(
+            port,
+            tx,
+            signal_channel(),
+            FrameLimits::default(),
+            None,
+            Arc::default(),
+            Arc::default(),
+        ));
+        let mut stream = connect(port).await;
+        let mut reply = [0u8; 3];
+
+        let good = data_frame(
+            b"chunk",
+            Codec::Lz4,
+            &serialize(&Vec::<Netflow>::new()).unwrap(),
+        );
+        let mut corrupted = good.clone();
+        *corrupted.last_mut().unwrap() ^= 0x01;
+        stream.write_all(&corrupted).await.unwrap();
+        stream.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"NAK");
+        assert!(rx.try_recv().is_err());
+
+        stream.write_all(&good).await.unwrap();
+        stream.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"ACK");
+        assert!(matches!(rx.recv().await, Some((_, Chunk::Netflow(_)))));
+
+        // intact on the wire but not a bincode Vec<Netflow>
+        stream
+            .write_all(&data_frame(b"chunk", Codec::None, &[0xff; 3]))
+            .await
+            .unwrap();
+        stream.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"ERR");
+    }
+
+    #[tokio::test]
+    async fn test_listen_malicious_frames() {
+        let port = 7006;
+        let (tx, mut rx) = tokio::sync::mpsc::channel::<(ChunkId, Chunk)>(100);
+        let limits = FrameLimits {
+            max_frame: 8192,
+            max_decompressed: 4096,
+        };
+        tokio::spawn(listen_port(
+            port,
+            tx,
+            signal_channel(),
+            limits,
+            None,
+            Arc::default(),
+            Arc::default(),
+        ));
+        let mut reply = [0u8; 3];
+
+        // a 4 GiB length is refused before anything is allocated for it,
+        // and the connection is dropped since the stream can't be resynced
+        let mut stream = connect(port).await;
+        let mut huge = b"chunk".to_vec();
+        huge.push(Codec::None.id());
+        huge.extend_from_slice(&0u32.to_be_bytes());
+        huge.extend_from_slice(&[0; 20]);
+        huge.extend_from_slice(&u32::MAX.to_be_bytes());
+        stream.write_all(&huge).await.unwrap();
+        stream.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"ERR");
+        assert_eq!(stream.read(&mut reply).await.unwrap(), 0);
+
+        let mut stream = connect(port).await;
+        let mut expect_err = async |frame: Vec<u8>| {
+            stream.write_all(&frame).await.unwrap();
+            stream.read_exact(&mut reply).await.unwrap();
+            assert_eq!(&reply, b"ERR");
+        };
+        // payloads claiming more than the limit, honestly or not
+        let bomb = vec![0u8; 64 << 10];
+        for codec in [Codec::Lz4, Codec::Zstd, Codec::Snappy] {
+            expect_err(data_frame(b"chunk", codec, &bomb)).await;
+        }
+        // a stored frame of the zstd body claims the body's own length; the
+        // codec byte is outside the checksum so it can be relabelled
+        let zstd = Codec::Zstd.compress(&bomb);
+        let mut forged = data_frame(b"chunk", Codec::None, &zstd[4..]);
+        forged[5] = Codec::Zstd.id();
+        expect_err(forged).await;
+        // element counts far beyond the payload
+        expect_err(data_frame(b"chunk", Codec::None, &u64::MAX.to_le_bytes())).await;
+        expect_err(data_frame(b"colmn", Codec::None, &u32::MAX.to_le_bytes())).await;
+        let mut unknown_codec = data_frame(b"batch", Codec::None, &[]);
+        unknown_codec[5] = 0x7f;
+        expect_err(unknown_codec).await;
+
+        // still usable afterwards
+        let empty = serialize(&Vec::<Netflow>::new()).unwrap();
+        stream
+            .write_all(&data_frame(b"chunk", Codec::Lz4, &empty))
+            .await
+            .unwrap();
+        stream.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"ACK");
+        assert!(matches!(rx.recv().await, Some((_, Chunk::Netflow(_)))));
+    }
+
+    #[tokio::test]
+    async fn test_listen_flush() {
+        let port = 7009;
+        let (tx, _rx) = tokio::sync::mpsc::channel::<(ChunkId, Chunk)>(100);
+        let (signals_tx, mut signals) = tokio::sync::mpsc::channel(100);
+        tokio::spawn(listen_port(
+            port,
+            tx,
+            signals_tx,
+            FrameLimits::default(),
+            None,
+            Arc::default(),
+            Arc::default(),
+        ));
+
+        let mut stream = connect(port).await;
+        stream
+            .write_all(&data_frame(b"flush", Codec::None, &[]))
+            .await
+            .unwrap();
+        let mut reply = [0u8; 3];
+        stream.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"ACK");
+        assert_eq!(signals.recv().await, Some(JobSignal::Flush(ID)));
+
+        stream
+            .write_all(&data_frame(b"taken", Codec::None, &[]))
+            .await
+            .unwrap();
+        stream.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"ACK");
+        assert_eq!(signals.recv().await, Some(JobSignal::Taken(ID)));
+
+        stream
+            .write_all(&data_frame(b"cancl", Codec::None, &[]))
+            .await
+            .unwrap();
+        stream.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"ACK");
+        assert_eq!(signals.recv().await, Some(JobSignal::Cancel(ID.job)));
+
+        stream
+            .write_all(&data_frame(b"close", Codec::None, &[]))
+            .await
+            .unwrap();
+        stream.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"ACK");
+        assert_eq!(signals.recv().await, Some(JobSignal::Close(ID.job)));
+    }
+
+    #[tokio::test]
+    async fn test_listen_requires_secret() {
+        let port = 7007;
+        let (tx, mut rx) = tokio::sync::mpsc::channel::<(ChunkId, Chunk)>(100);
+        let secret = Secret::new(b"correct horse");
+        tokio::spawn(listen_port(
+            port,
+            tx,
+            signal_channel(),
+            FrameLimits::default(),
+            Some(secret.clone()),
+            Arc::default(),
+            Arc::default(),
+        ));
+        let empty = data_frame(
+            b"chunk",
+            Codec::Lz4,
+            &serialize(&Vec::<Netflow>::new()).unwrap(),
+        );
+        let mut reply = [0u8; 3];
+
+        // skipping the handshake: the frame is taken as a bad handshake response
+        let mut stream = connect(port).await;
+        stream.write_all(&empty).await.unwrap();
+        stream.write_all(&[0u8; 48]).await.unwrap();
+        let mut nonce = [0u8; 16];
+        stream.read_exact(&mut nonce).await.unwrap();
+        // closed without a proof or a reply, possibly reset over the unread bytes
+        assert!(matches!(stream.read(&mut reply).await, Ok(0) | Err(_)));
+
+        let mut stream = connect(port).await;
+        assert!(
+            auth::connect(&mut stream, &Secret::new(b"battery staple"))
+                .await
+                .is_err()
+        );
+
+        let mut stream = connect(port).await;
+        auth::connect(&mut stream, &secret).await.unwrap();
+        stream.write_all(&empty).await.unwrap();
+        stream.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"ACK");
+        assert!(matches!(rx.recv().await, Some((_, Chunk::Netflow(_)))));
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_is_valid_addresses() {
+        let mut netflow = Netflow {
+            flow_id: 1,
+            src_ip: Some("2001:db8::1".parse().unwrap()),
+            dst_ip: Some("192.168.1.2".parse().unwrap()),
+            src_port: Some(80),
+            dst_port: Some(443),
+            protocol: Some(Protocol::from(6)),
+            bytes: Some(1024),
+            packets: Some(5),
+            start_ts: DateTime::from_timestamp_millis(1678886400000),
+            end_ts: DateTime::from_timestamp_millis(1678886400250),
+            src_asn: Some(12345),
+            dst_asn: Some(54321),
+        };
+        assert!(netflow.is_valid());
+
+        netflow.dst_ip = None;
+        assert!(!netflow.is_valid());
+        netflow.dst_ip = Some("192.168.1.2".parse().unwrap());
+        netflow.src_ip = None;
+        assert!(!netflow.is_valid());
+    }
+
+    #[test]
+    fn test_pending_job_uploads_reassigned_chunks() {
+        let spill = SpillConfig {
+            max_bytes: 1 << 20,
+            dir: std::env::temp_dir().join("processor-pending-test"),
+            sort_key: common::spill::SortKey::FlowId,
+        };
+        let mut job = PendingJob::new(&spill, 7010, 4);
+        let output = || ChunkOutput::Netflow(Vec::new());
+        for chunk in [0, 1, 1] {
+            if job.seen.insert(chunk) {
+                job.buffer.push(output()).unwrap();
+            }
+        }
+        assert!(!job.is_done());
+        job.expected = Some(2);
+        assert!(job.is_done());
+        job.take_runs(&spill, 7010).unwrap();
+        assert_eq!(job.uploaded, 2);
+        assert!(!job.is_done());
+
+        // an evicted processor's chunk arrives, then the raised total
+        job.seen.insert(9);
+        job.buffer.hold(9, output()).unwrap();
+        assert!(!job.is_done());
+        job.expected = Some(3);
+        // not until the merger has answered its result
+        assert!(!job.is_done());
+        assert!(job.buffer.release(9, true).unwrap());
+        assert!(job.is_done());
+        // cancelled before the raised total was uploaded
+        assert_eq!(job.discard(), 1);
+    }
+
+    #[test]
+    fn test_failed_transform_loses_the_chunk() {
+        use common::record::{Column, ColumnType, Schema, Value};
+
+        let batch = RecordBatch {
+            schema: Schema {
+                columns: vec![Column {
+                    name: "bytes".to_string(),
+                    ty: ColumnType::Int,
+                }],
+            },
+            rows: vec![vec![Value::Int(1)], vec![Value::Int(2)]],
+        };
+        let transform = Transform::parse(Some("missing>1"), None).unwrap();
+        let aggregator = RecordAggregator::new(Vec::new(), Vec::new());
+        let (result, output) = process_records(ID, batch, &transform, &aggregator);
+        assert_eq!(
+            result.stats,
+            ChunkStats {
+                received: 2,
+                kept: 0,
+                rejected: 2
+            }
+        );
+        assert!(output.unwrap_err().contains("missing"));
+    }
+
+    #[test]
+    fn test_lost_output_fails_the_upload() {
+        let spill = SpillConfig {
+            max_bytes: 1 << 20,
+            dir: std::env::temp_dir().join("processor-lost-test"),
+            sort_key: common::spill::SortKey::FlowId,
+        };
+        let mut job = PendingJob::new(&spill, 7011, 5);
+        job.seen.insert(0);
+        job.buffer.push(ChunkOutput::Netflow(Vec::new())).unwrap();
+        job.lost(std::io::Error::other("disk full"));
+        job.expected = Some(1);
+        assert!(job.is_done());
+        let err = job.take_runs(&spill, 7011).unwrap_err();
+        assert_eq!(err.to_string(), "disk full");
+        // the next upload starts afresh
+        assert_eq!(job.take_runs(&spill, 7011).unwrap(), Vec::<PathBuf>::new());
+    }
+
+    #[test]
+    fn test_cancellation_ends_with_the_jobs_chunks() {
+        let mut in_flight = InFlight::default();
+        let chunk = |chunk| ChunkId::new(5, chunk);
+        in_flight.queued(chunk(0));
+        in_flight.queued(chunk(1));
+        in_flight.cancel(5);
+        assert!(in_flight.done(chunk(0)));
+        assert!(in_flight.skip(chunk(1)));
+        // the next distributer's job 5 is a different job
+        in_flight.queued(chunk(0));
+        assert!(!in_flight.skip(chunk(0)));
+        assert!(!in_flight.done(chunk(0)));
+        // nothing in flight, nothing to remember
+        in_flight.cancel(5);
+        assert!(!in_flight.is_cancelled(5));
+
+        in_flight.queued(chunk(2));
+        in_flight.take(chunk(2));
+        assert!(in_flight.skip(chunk(2)));
+        assert!(in_flight.chunks.is_empty() && in_flight.taken.is_empty());
     }
 }
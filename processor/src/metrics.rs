@@ -0,0 +1,141 @@
+//! Counters and histograms kept for the whole process and served in the
+//! Prometheus text format on `/metrics`, next to the gauges of the health
+//! report.
+
+use common::frame;
+use common::health::HealthReport;
+use common::metrics::{Counter, Exposition, Family, Histogram};
+
+use crate::http::{Request, Response};
+
+pub static METRICS: Metrics = Metrics {
+    chunks_received: Family::new(),
+    frames_corrupted: Counter::new(),
+    rows_kept: Counter::new(),
+    rows_rejected: Counter::new(),
+    chunk_seconds: Histogram::new(LATENCY_BUCKETS),
+};
+
+/// Upper bounds in seconds, from a small chunk to a large batch through a
+/// heavy transform.
+const LATENCY_BUCKETS: &[f64] = &[
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+];
+
+pub struct Metrics {
+    /// Chunks taken off the data port, by frame prefix.
+    pub chunks_received: Family,
+    /// Frames answered with `NAK` for failing their checksum.
+    pub frames_corrupted: Counter,
+    /// Rows that passed validation or the transform, and those that did not.
+    pub rows_kept: Counter,
+    pub rows_rejected: Counter,
+    /// Time a worker spends on one chunk.
+    pub chunk_seconds: Histogram,
+}
+
+impl Metrics {
+    pub fn render(&self, report: &HealthReport) -> String {
+        let mut out = Exposition::default();
+        out.family(
+            "processor_chunks_received_total",
+            "Chunks received on the data port.",
+            "counter",
+            "prefix",
+            &self.chunks_received,
+        );
+        out.counter(
+            "processor_frames_corrupted_total",
+            "Frames that failed their checksum.",
+            &self.frames_corrupted,
+        );
+        out.counter(
+            "processor_frames_resent_total",
+            "Uploads resent after a NAK.",
+            &frame::METRICS.resent,
+        );
+        out.family(
+            "processor_bytes_raw_total",
+            "Received frame payload bytes, decompressed.",
+            "counter",
+            "codec",
+            &frame::METRICS.received_raw,
+        );
+        out.family(
+            "processor_bytes_compressed_total",
+            "Received frame payload bytes, as sent.",
+            "counter",
+            "codec",
+            &frame::METRICS.received_compressed,
+        );
+        out.counter(
+            "processor_rows_kept_total",
+            "Rows kept by validation or the transform.",
+            &self.rows_kept,
+        );
+        out.counter(
+            "processor_rows_rejected_total",
+            "Rows rejected by validation or the transform.",
+            &self.rows_rejected,
+        );
+        out.histogram(
+            "processor_chunk_seconds",
+            "Time spent processing one chunk.",
+            &self.chunk_seconds,
+        );
+        out.value(
+            "processor_records_processed_total",
+            "Rows processed.",
+            "counter",
+            report.records_processed,
+        );
+        out.value(
+            "processor_queue_depth",
+            "Chunks waiting for a worker.",
+            "gauge",
+            report.queue_depth,
+        );
+        out.value(
+            "processor_queue_capacity",
+            "Chunks the queue holds.",
+            "gauge",
+            report.queue_capacity,
+        );
+        out.value(
+            "processor_busy_workers",
+            "Workers processing a chunk.",
+            "gauge",
+            report.busy_workers.into(),
+        );
+        out.value(
+            "processor_workers",
+            "Workers.",
+            "gauge",
+            report.workers.into(),
+        );
+        if let Some(bytes) = report.memory_bytes {
+            out.value(
+                "processor_resident_memory_bytes",
+                "Resident set size.",
+                "gauge",
+                bytes,
+            );
+        }
+        out.value(
+            "processor_draining",
+            "1 while shutting down.",
+            "gauge",
+            report.draining.into(),
+        );
+        out.finish()
+    }
+}
+
+/// Answers `GET /metrics`, with the gauges of `report` taken at the time
+/// of the request.
+pub fn handle(request: &Request, report: impl FnOnce() -> HealthReport) -> Response {
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/metrics") => Response::text(METRICS.render(&report())),
+        _ => Response::not_found(),
+    }
+}
@@ -0,0 +1,12 @@
+use common::netflow::Netflow;
+use common::record::RecordBatch;
+
+use crate::transform::Transform;
+
+/// One decoded frame off the wire.
+#[derive(Debug, Clone)]
+pub enum Chunk {
+    Netflow(Vec<Netflow>),
+    /// Rows and the transform of their job, if it has its own.
+    Records(RecordBatch, Option<Transform>),
+}
@@ -0,0 +1,352 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use common::auth::{self, Secret};
+use common::codec::Codec;
+use common::frame::{self, ChunkId};
+use common::result::ChunkResult;
+use common::spill::{RunReader, SortKey};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::{Receiver, UnboundedSender};
+use tracing::{debug, error, warn};
+
+use crate::health::Load;
+
+/// What goes back to the merger.
+#[derive(Debug)]
+pub enum Upload {
+    Chunk(ChunkResult),
+    /// A job's sorted runs, once every chunk this processor got for it is
+    /// done. Each run goes out as `block` frames (the frame's chunk id is the
+    /// run number), followed by a `flush` frame carrying how many distinct
+    /// chunks of the job this processor has uploaded output for by now. The
+    /// files are removed once sent. If the output could not be spilled, or a
+    /// run cannot be read back, an `error` frame carrying the error goes out
+    /// instead of the `flush`, failing the job.
+    Runs {
+        job: u64,
+        runs: std::io::Result<Vec<PathBuf>>,
+        chunks: u64,
+    },
+    /// The job is over, closed or cancelled; nothing more of it is uploaded.
+    Forget(u64),
+}
+
+/// Streams results to the distributer (or whichever merger listens on
+/// `addr`) over one connection, reconnecting whenever it breaks. A frame is
+/// only dropped once the merger has rejected it; until it is acknowledged
+/// the sender keeps retrying, which backs up into the collector. Every
+/// connection opens with a `hello` frame carrying this processor's data
+/// `port`, so the merger knows whose uploads arrive on it, and the column its
+/// runs are sorted by, which the merger refuses unless it merges by the same.
+///
+/// Whether each chunk's output is to be kept goes to `verdicts` once its
+/// result is answered: not when the merger took another processor's copy of
+/// the chunk first.
+pub async fn send_results(
+    addr: String,
+    secret: Option<Secret>,
+    port: u16,
+    sort_key: SortKey,
+    mut rx: Receiver<Upload>,
+    verdicts: UnboundedSender<(ChunkId, bool)>,
+    load: Arc<Load>,
+) {
+    let mut merger = Merger {
+        addr,
+        secret,
+        port,
+        sort_key,
+        stream: None,
+    };
+    // chunks covered by the uploads so far, per job
+    let mut uploaded = HashMap::<u64, u64>::new();
+    while let Some(upload) = rx.recv().await {
+        match upload {
+            Upload::Chunk(result) => {
+                debug!(
+                    job = result.id.job,
+                    partition = result.id.partition,
+                    chunk = result.id.chunk,
+                    "sending chunk result"
+                );
+                let raw = bincode2::serialize(&result).expect("failed to encode result");
+                let kept = merger
+                    .deliver(&frame::encode(b"reslt", Codec::Lz4, result.id, &raw))
+                    .await;
+                let _ = verdicts.send((result.id, kept));
+            }
+            Upload::Runs { job, runs, chunks } => {
+                let sent = match runs {
+                    Ok(runs) => merger.send_runs(job, &runs).await,
+                    Err(err) => Err(err),
+                };
+                let id = ChunkId::new(job, chunks);
+                let frame = match sent {
+                    Ok(()) => frame::encode(b"flush", Codec::None, id, &[]),
+                    Err(err) => {
+                        error!(job, error = %err, "failed to upload job output");
+                        frame::encode(b"error", Codec::None, id, err.to_string().as_bytes())
+                    }
+                };
+                merger.deliver(&frame).await;
+                let before = uploaded.insert(job, chunks).unwrap_or(0);
+                load.unflushed
+                    .fetch_sub(chunks.saturating_sub(before), Ordering::Relaxed);
+            }
+            Upload::Forget(job) => {
+                uploaded.remove(&job);
+            }
+        }
+    }
+}
+
+/// The connection to the merger, opened on first use.
+struct Merger {
+    addr: String,
+    secret: Option<Secret>,
+    port: u16,
+    sort_key: SortKey,
+    stream: Option<TcpStream>,
+}
+
+impl Merger {
+    /// Sends a job's runs as `block` frames, removing each file once sent,
+    /// or every one left if a run cannot be read.
+    async fn send_runs(&mut self, job: u64, runs: &[PathBuf]) -> std::io::Result<()> {
+        for (run, path) in runs.iter().enumerate() {
+            let id = ChunkId::new(job, run as u64);
+            let sent = async {
+                for block in RunReader::open(path)? {
+                    let raw = bincode2::serialize(&block?).expect("failed to encode block");
+                    self.deliver(&frame::encode(b"block", Codec::Lz4, id, &raw))
+                        .await;
+                }
+                Ok::<_, std::io::Error>(())
+            }
+            .await;
+            if let Err(err) = sent {
+                for path in &runs[run..] {
+                    let _ = fs::remove_file(path);
+                }
+                return Err(std::io::Error::new(
+                    err.kind(),
+                    format!("failed to read run {}: {}", path.display(), err),
+                ));
+            }
+            let _ = fs::remove_file(path);
+        }
+        Ok(())
+    }
+
+    /// Sends `frame` until it is acknowledged or rejected, returning false if
+    /// the merger answered that it already has the chunk from elsewhere.
+    async fn deliver(&mut self, frame: &[u8]) -> bool {
+        loop {
+            if self.stream.is_none() {
+                match connect(&self.addr, self.secret.as_ref(), self.port, self.sort_key).await {
+                    Ok(connected) => self.stream = Some(connected),
+                    Err(err) if err.kind() == ErrorKind::InvalidInput => {
+                        error!(
+                            merger = %self.addr,
+                            sort_by = self.sort_key.column(),
+                            "the merger refused this processor, is its SORT_BY the same?"
+                        );
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                        continue;
+                    }
+                    Err(err) => {
+                        warn!(merger = %self.addr, error = %err, "failed to connect to the merger");
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                        continue;
+                    }
+                }
+            }
+            let connected = self.stream.as_mut().expect("connected above");
+            match frame::send(connected, frame).await {
+                Ok(_) => return true,
+                Err(err) if err.kind() == ErrorKind::AlreadyExists => return false,
+                Err(err) if err.kind() == ErrorKind::InvalidInput => {
+                    error!(
+                        frame = %String::from_utf8_lossy(&frame[..5]),
+                        error = %err,
+                        "merger rejected a frame"
+                    );
+                    return true;
+                }
+                Err(err) => {
+                    warn!(error = %err, "failed to send to the merger");
+                    self.stream = None;
+                }
+            }
+        }
+    }
+}
+
+async fn connect(
+    addr: &str,
+    secret: Option<&Secret>,
+    port: u16,
+    sort_key: SortKey,
+) -> std::io::Result<TcpStream> {
+    let mut stream = TcpStream::connect(addr).await?;
+    if let Some(secret) = secret {
+        auth::connect(&mut stream, secret).await?;
+    }
+    let hello = ChunkId::new(0, port as u64);
+    frame::send(
+        &mut stream,
+        &frame::encode(b"hello", Codec::None, hello, sort_key.column().as_bytes()),
+    )
+    .await?;
+    Ok(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::spill::{SpillBuffer, SpillConfig};
+    use common::frame::FrameLimits;
+    use common::record::{Column, ColumnType, RecordBatch, Schema, Value};
+    use common::result::{ChunkOutput, ChunkStats};
+
+    fn batch(rows: i64) -> RecordBatch {
+        RecordBatch {
+            schema: Schema {
+                columns: vec![Column {
+                    name: "flow_id".to_string(),
+                    ty: ColumnType::Int,
+                }],
+            },
+            rows: (0..rows).map(|i| vec![Value::Int(i)]).collect(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_send_results() {
+        let listener = TcpListener::bind("127.0.0.1:7008").await.unwrap();
+        let (tx, rx) = tokio::sync::mpsc::channel(10);
+        let (verdicts, mut verdict_rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(send_results(
+            "127.0.0.1:7008".to_string(),
+            None,
+            6123,
+            SortKey::FlowId,
+            rx,
+            verdicts,
+            Arc::default(),
+        ));
+        let id = ChunkId::new(2, 9);
+        let stats = ChunkStats {
+            received: 5,
+            kept: 3,
+            rejected: 2,
+        };
+        tx.send(Upload::Chunk(ChunkResult {
+            id,
+            stats,
+            aggregates: batch(0),
+        }))
+        .await
+        .unwrap();
+
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut prefix = [0u8; 5];
+        socket.read_exact(&mut prefix).await.unwrap();
+        assert_eq!(&prefix, b"hello");
+        let Some(Ok((hello, sort_by))) = frame::read(&mut socket, FrameLimits::default()).await
+        else {
+            panic!("expected a hello frame");
+        };
+        socket.write_all(b"ACK").await.unwrap();
+        assert_eq!(hello.chunk, 6123);
+        assert_eq!(sort_by, b"flow_id");
+        socket.read_exact(&mut prefix).await.unwrap();
+        assert_eq!(&prefix, b"reslt");
+        let Some(Ok((received_id, raw))) = frame::read(&mut socket, FrameLimits::default()).await
+        else {
+            panic!("expected a result frame");
+        };
+        socket.write_all(b"ACK").await.unwrap();
+        let result: ChunkResult = bincode2::deserialize(&raw).unwrap();
+        assert_eq!(received_id, id);
+        assert_eq!(result.id, id);
+        assert_eq!(result.stats, stats);
+        assert_eq!(verdict_rx.recv().await, Some((id, true)));
+
+        // another processor's copy of the chunk came first
+        tx.send(Upload::Chunk(ChunkResult {
+            id,
+            stats,
+            aggregates: batch(0),
+        }))
+        .await
+        .unwrap();
+        socket.read_exact(&mut prefix).await.unwrap();
+        assert_eq!(&prefix, b"reslt");
+        assert!(
+            frame::read(&mut socket, FrameLimits::default())
+                .await
+                .is_some()
+        );
+        socket.write_all(b"DUP").await.unwrap();
+        assert_eq!(verdict_rx.recv().await, Some((id, false)));
+
+        // a job's runs follow as blocks, then a flush with the chunk count
+        let config = SpillConfig {
+            max_bytes: 1 << 20,
+            dir: std::env::temp_dir().join("processor-upload-test"),
+            sort_key: SortKey::FlowId,
+        };
+        let mut buffer = SpillBuffer::new(&config, "job-2".to_string());
+        buffer.push(ChunkOutput::Records(batch(3))).unwrap();
+        let runs = buffer.finish().unwrap();
+        tx.send(Upload::Runs {
+            job: 2,
+            runs: Ok(runs.clone()),
+            chunks: 4,
+        })
+        .await
+        .unwrap();
+        for (expected, chunk) in [(b"block", 0), (b"flush", 4)] {
+            socket.read_exact(&mut prefix).await.unwrap();
+            assert_eq!(&prefix, expected);
+            let Some(Ok((id, raw))) = frame::read(&mut socket, FrameLimits::default()).await else {
+                panic!("expected a frame");
+            };
+            socket.write_all(b"ACK").await.unwrap();
+            assert_eq!(id, ChunkId::new(2, chunk));
+            if expected == b"block" {
+                let output: ChunkOutput = bincode2::deserialize(&raw).unwrap();
+                assert!(matches!(output, ChunkOutput::Records(b) if b.rows.len() == 3));
+            }
+        }
+        assert!(!runs[0].exists());
+
+        // a run that cannot be read fails the job rather than go missing
+        let missing = config.dir.join("job-3-missing.run");
+        tx.send(Upload::Runs {
+            job: 3,
+            runs: Ok(vec![missing]),
+            chunks: 1,
+        })
+        .await
+        .unwrap();
+        socket.read_exact(&mut prefix).await.unwrap();
+        assert_eq!(&prefix, b"error");
+        let Some(Ok((id, raw))) = frame::read(&mut socket, FrameLimits::default()).await else {
+            panic!("expected an error frame");
+        };
+        socket.write_all(b"ACK").await.unwrap();
+        assert_eq!(id, ChunkId::new(3, 1));
+        assert!(String::from_utf8_lossy(&raw).contains("job-3-missing.run"));
+    }
+}
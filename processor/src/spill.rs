@@ -0,0 +1,330 @@
+//! Bounded buffering of a job's output. Rows collect in memory until their
+//! encoded size reaches `SPILL_THRESHOLD`, then are sorted and written to a
+//! run file; once the job is flushed every run is uploaded block by block.
+//! Output held back until the merger has answered its chunk's result counts
+//! towards the same threshold; past it, each held chunk is written to a file
+//! of its own, so it can still be dropped.
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::PathBuf;
+
+use common::record::RecordBatch;
+use common::result::ChunkOutput;
+use common::spill::{BLOCK_ROWS, RunReader, RunWriter, SortKey};
+
+#[derive(Debug, Clone)]
+pub struct SpillConfig {
+    /// Encoded bytes buffered per job before a run is written.
+    pub max_bytes: usize,
+    pub dir: PathBuf,
+    pub sort_key: SortKey,
+}
+
+impl SpillConfig {
+    /// Reads `SPILL_THRESHOLD` (bytes, 64 MiB by default), `SPILL_DIR` (a
+    /// directory under the system temp dir by default) and `SORT_BY`
+    /// (`flow_id` or `start_ts`).
+    pub fn from_env() -> Result<Self, String> {
+        let max_bytes = match env::var("SPILL_THRESHOLD") {
+            Ok(value) => value
+                .parse()
+                .map_err(|_| format!("SPILL_THRESHOLD must be a size in bytes, got {:?}", value))?,
+            Err(_) => 64 << 20,
+        };
+        let dir = env::var("SPILL_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| env::temp_dir().join("processor-spill"));
+        let sort_key = match env::var("SORT_BY") {
+            Ok(name) => SortKey::from_name(&name)
+                .ok_or_else(|| format!("SORT_BY must be flow_id or start_ts, got {:?}", name))?,
+            Err(_) => SortKey::FlowId,
+        };
+        Ok(Self {
+            max_bytes,
+            dir,
+            sort_key,
+        })
+    }
+}
+
+/// A chunk's output set aside until it is known whether to keep it.
+enum Held {
+    Memory(ChunkOutput, usize),
+    Spilled(PathBuf),
+}
+
+/// The output of one job on this processor, in memory and in sorted runs.
+pub struct SpillBuffer {
+    config: SpillConfig,
+    /// Prefix of this buffer's run file names.
+    name: String,
+    pending: Option<ChunkOutput>,
+    pending_bytes: usize,
+    runs: Vec<PathBuf>,
+    /// Per chunk, output that may still be dropped.
+    held: HashMap<u64, Held>,
+    /// Encoded bytes of the held output in memory.
+    held_bytes: usize,
+}
+
+impl SpillBuffer {
+    pub fn new(config: &SpillConfig, name: String) -> Self {
+        Self {
+            config: config.clone(),
+            name,
+            pending: None,
+            pending_bytes: 0,
+            runs: Vec::new(),
+            held: HashMap::new(),
+            held_bytes: 0,
+        }
+    }
+
+    /// Sets `chunk`'s output aside until [`release`](Self::release) says
+    /// whether to keep it, in memory while the buffer is under its threshold
+    /// and in a file of its own past it.
+    pub fn hold(&mut self, chunk: u64, output: ChunkOutput) -> io::Result<()> {
+        let bytes = bincode2::serialized_size(&output).map_err(io::Error::other)? as usize;
+        if self.pending_bytes + self.held_bytes + bytes < self.config.max_bytes {
+            self.held_bytes += bytes;
+            self.held.insert(chunk, Held::Memory(output, bytes));
+            return Ok(());
+        }
+        fs::create_dir_all(&self.config.dir)?;
+        let path = self
+            .config
+            .dir
+            .join(format!("{}-held-{}.run", self.name, chunk));
+        let mut file = RunWriter::create(path)?;
+        file.write(&output)?;
+        self.held.insert(chunk, Held::Spilled(file.finish()?));
+        Ok(())
+    }
+
+    /// Buffers the output held for `chunk` if `keep`, else drops it. Returns
+    /// whether any was held.
+    pub fn release(&mut self, chunk: u64, keep: bool) -> io::Result<bool> {
+        let Some(held) = self.held.remove(&chunk) else {
+            return Ok(false);
+        };
+        match held {
+            Held::Memory(output, bytes) => {
+                self.held_bytes -= bytes;
+                if keep {
+                    self.push(output)?;
+                }
+            }
+            Held::Spilled(path) => {
+                let read = if keep {
+                    RunReader::open(&path)?.try_for_each(|block| self.push(block?))
+                } else {
+                    Ok(())
+                };
+                let _ = fs::remove_file(&path);
+                read?;
+            }
+        }
+        Ok(true)
+    }
+
+    /// Whether any output waits on [`release`](Self::release).
+    pub fn holding(&self) -> bool {
+        !self.held.is_empty()
+    }
+
+    pub fn push(&mut self, output: ChunkOutput) -> io::Result<()> {
+        self.pending_bytes +=
+            bincode2::serialized_size(&output).map_err(io::Error::other)? as usize;
+        match (&mut self.pending, output) {
+            (None, output) => self.pending = Some(output),
+            (Some(ChunkOutput::Netflow(rows)), ChunkOutput::Netflow(more)) => rows.extend(more),
+            (Some(ChunkOutput::Records(batch)), ChunkOutput::Records(more)) => {
+                batch.rows.extend(more.rows)
+            }
+            _ => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    "netflow and record output in the same job",
+                ));
+            }
+        }
+        if self.pending_bytes >= self.config.max_bytes {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    fn spill(&mut self) -> io::Result<()> {
+        let Some(mut output) = self.pending.take() else {
+            return Ok(());
+        };
+        self.pending_bytes = 0;
+        let blocks = blocks(&mut output, self.config.sort_key);
+        if blocks.is_empty() {
+            return Ok(());
+        }
+
+        fs::create_dir_all(&self.config.dir)?;
+        let path = self
+            .config
+            .dir
+            .join(format!("{}-{}.run", self.name, self.runs.len()));
+        let mut run = RunWriter::create(path)?;
+        for block in &blocks {
+            run.write(block)?;
+        }
+        self.runs.push(run.finish()?);
+        Ok(())
+    }
+
+    /// Spills whatever is still in memory and returns the run files, each
+    /// sorted by the configured key.
+    pub fn finish(mut self) -> io::Result<Vec<PathBuf>> {
+        self.spill()?;
+        Ok(self.runs)
+    }
+
+    /// Throws the output away, removing any runs already spilled.
+    pub fn discard(self) {
+        for run in self.runs {
+            let _ = fs::remove_file(run);
+        }
+        for held in self.held.into_values() {
+            if let Held::Spilled(path) = held {
+                let _ = fs::remove_file(path);
+            }
+        }
+    }
+}
+
+/// Sorts `output` and cuts it into blocks of at most [`BLOCK_ROWS`] rows.
+fn blocks(output: &mut ChunkOutput, sort_key: SortKey) -> Vec<ChunkOutput> {
+    sort_key.sort(output);
+    match output {
+        ChunkOutput::Netflow(rows) => rows
+            .chunks(BLOCK_ROWS)
+            .map(|rows| ChunkOutput::Netflow(rows.to_vec()))
+            .collect(),
+        ChunkOutput::Records(batch) => batch
+            .rows
+            .chunks(BLOCK_ROWS)
+            .map(|rows| {
+                ChunkOutput::Records(RecordBatch {
+                    schema: batch.schema.clone(),
+                    rows: rows.to_vec(),
+                })
+            })
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use common::netflow::Netflow;
+
+    use super::*;
+
+    fn netflow(flow_id: i64) -> Netflow {
+        Netflow {
+            flow_id,
+            src_ip: None,
+            dst_ip: None,
+            src_port: None,
+            dst_port: None,
+            protocol: None,
+            bytes: None,
+            packets: None,
+            start_ts: None,
+            end_ts: None,
+            src_asn: None,
+            dst_asn: None,
+        }
+    }
+
+    #[test]
+    fn test_spills_sorted_runs() {
+        let dir = env::temp_dir().join("processor-spill-test");
+        let config = SpillConfig {
+            max_bytes: 2048,
+            dir: dir.clone(),
+            sort_key: SortKey::FlowId,
+        };
+        let mut buffer = SpillBuffer::new(&config, "job-1".to_string());
+        for chunk in 0..10 {
+            let rows = (0..100).rev().map(|i| netflow(i * 10 + chunk)).collect();
+            buffer.push(ChunkOutput::Netflow(rows)).unwrap();
+        }
+        let runs = buffer.finish().unwrap();
+        assert!(runs.len() > 1);
+
+        let mut all = Vec::new();
+        for run in &runs {
+            let mut ids = Vec::new();
+            for block in RunReader::open(run).unwrap() {
+                let ChunkOutput::Netflow(rows) = block.unwrap() else {
+                    panic!("expected netflow blocks");
+                };
+                ids.extend(rows.iter().map(|n| n.flow_id));
+            }
+            assert!(ids.is_sorted());
+            all.extend(ids);
+            fs::remove_file(run).unwrap();
+        }
+        all.sort();
+        assert_eq!(all, (0..1000).collect::<Vec<_>>());
+
+        let mut buffer = SpillBuffer::new(&config, "job-2".to_string());
+        buffer.push(ChunkOutput::Netflow(vec![netflow(1)])).unwrap();
+        let records = RecordBatch {
+            schema: common::record::Schema {
+                columns: Vec::new(),
+            },
+            rows: Vec::new(),
+        };
+        assert!(buffer.push(ChunkOutput::Records(records)).is_err());
+    }
+
+    #[test]
+    fn test_held_output_counts_towards_the_threshold() {
+        let dir = env::temp_dir().join("processor-held-test");
+        let config = SpillConfig {
+            max_bytes: 2048,
+            dir: dir.clone(),
+            sort_key: SortKey::FlowId,
+        };
+        let rows =
+            |chunk: i64| ChunkOutput::Netflow((0..50).map(|i| netflow(chunk * 100 + i)).collect());
+        let mut buffer = SpillBuffer::new(&config, "job-3".to_string());
+        for chunk in 0..4 {
+            buffer.hold(chunk as u64, rows(chunk)).unwrap();
+        }
+        let held = dir.join("job-3-held-3.run");
+        assert!(held.exists());
+        assert!(buffer.holding());
+
+        assert!(buffer.release(3, true).unwrap());
+        assert!(!held.exists());
+        assert!(buffer.release(0, false).unwrap());
+        assert!(!buffer.release(0, true).unwrap());
+        buffer.release(1, true).unwrap();
+        buffer.release(2, true).unwrap();
+        assert!(!buffer.holding());
+
+        let mut ids = Vec::new();
+        for run in buffer.finish().unwrap() {
+            for block in RunReader::open(&run).unwrap() {
+                let ChunkOutput::Netflow(rows) = block.unwrap() else {
+                    panic!("expected netflow blocks");
+                };
+                ids.extend(rows.iter().map(|n| n.flow_id));
+            }
+            fs::remove_file(run).unwrap();
+        }
+        ids.sort();
+        let expected: Vec<i64> = (100..350).filter(|id| id % 100 < 50).collect();
+        assert_eq!(ids, expected);
+    }
+}
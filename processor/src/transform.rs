@@ -0,0 +1,321 @@
+use std::collections::HashMap;
+use std::env;
+
+use common::record::{Column, ColumnType, RecordBatch, Schema, Value};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+/// `column <op> literal`, with the literal parsed against the column's type
+/// once the schema of a batch is known.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Predicate {
+    pub column: String,
+    pub op: Op,
+    pub literal: String,
+}
+
+impl Predicate {
+    pub fn parse(expr: &str) -> Option<Self> {
+        const OPS: [(&str, Op); 6] = [
+            (">=", Op::Ge),
+            ("<=", Op::Le),
+            ("!=", Op::Ne),
+            ("=", Op::Eq),
+            (">", Op::Gt),
+            ("<", Op::Lt),
+        ];
+        OPS.iter().find_map(|(token, op)| {
+            let (column, literal) = expr.split_once(token)?;
+            Some(Predicate {
+                column: column.trim().to_string(),
+                op: *op,
+                literal: literal.trim().to_string(),
+            })
+        })
+    }
+
+    fn matches(&self, value: &Value, literal: &Value) -> bool {
+        let Some(ord) = value.partial_cmp(literal) else {
+            return false;
+        };
+        match self.op {
+            Op::Eq => ord.is_eq(),
+            Op::Ne => ord.is_ne(),
+            Op::Lt => ord.is_lt(),
+            Op::Le => ord.is_le(),
+            Op::Gt => ord.is_gt(),
+            Op::Ge => ord.is_ge(),
+        }
+    }
+}
+
+/// Filter then project, applied to generic record batches. Unknown columns
+/// are an error rather than silently matching nothing.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Transform {
+    pub filters: Vec<Predicate>,
+    pub projection: Option<Vec<String>>,
+}
+
+impl Transform {
+    /// `FILTER` is a comma separated list of predicates (`bytes>1000,proto=tcp`)
+    /// and `PROJECT` a comma separated list of columns to keep.
+    pub fn from_env() -> Result<Self, String> {
+        Self::parse(
+            env::var("FILTER").ok().as_deref(),
+            env::var("PROJECT").ok().as_deref(),
+        )
+    }
+
+    /// A transform from the `FILTER` and `PROJECT` syntax, as set in the
+    /// environment or sent along with a job's batches.
+    pub fn parse(filter: Option<&str>, project: Option<&str>) -> Result<Self, String> {
+        let filters = match filter {
+            Some(exprs) => exprs
+                .split(',')
+                .map(|e| Predicate::parse(e).ok_or_else(|| format!("invalid filter {:?}", e)))
+                .collect::<Result<_, _>>()?,
+            None => Vec::new(),
+        };
+        let projection =
+            project.map(|cols| cols.split(',').map(|c| c.trim().to_string()).collect());
+        Ok(Self {
+            filters,
+            projection,
+        })
+    }
+
+    pub fn apply(&self, batch: RecordBatch) -> Result<RecordBatch, String> {
+        let filters = self
+            .filters
+            .iter()
+            .map(|p| {
+                let index = column(&batch.schema, &p.column)?;
+                let ty = batch.schema.columns[index].ty;
+                let literal = Value::parse(&p.literal, ty)
+                    .ok_or_else(|| format!("{:?} is not a valid {:?}", p.literal, ty))?;
+                Ok((p, index, literal))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+
+        let rows = batch
+            .rows
+            .into_iter()
+            .filter(|row| filters.iter().all(|(p, i, lit)| p.matches(&row[*i], lit)));
+
+        let Some(projection) = &self.projection else {
+            return Ok(RecordBatch {
+                schema: batch.schema,
+                rows: rows.collect(),
+            });
+        };
+        let indices = projection
+            .iter()
+            .map(|name| column(&batch.schema, name))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(RecordBatch {
+            schema: Schema {
+                columns: indices
+                    .iter()
+                    .map(|&i| batch.schema.columns[i].clone())
+                    .collect(),
+            },
+            rows: rows
+                .map(|row| indices.iter().map(|&i| row[i].clone()).collect())
+                .collect(),
+        })
+    }
+}
+
+fn column(schema: &Schema, name: &str) -> Result<usize, String> {
+    schema
+        .index_of(name)
+        .ok_or_else(|| format!("unknown column {:?}", name))
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GroupStats {
+    pub count: u64,
+    pub sums: Vec<f64>,
+}
+
+/// Row count plus per column sums for every distinct combination of the
+/// `group_by` columns, accumulated across batches.
+#[derive(Clone)]
+pub struct RecordAggregator {
+    group_by: Vec<String>,
+    sum: Vec<String>,
+    groups: HashMap<Vec<Value>, GroupStats>,
+}
+
+impl RecordAggregator {
+    pub fn new(group_by: Vec<String>, sum: Vec<String>) -> Self {
+        Self {
+            group_by,
+            sum,
+            groups: HashMap::new(),
+        }
+    }
+
+    /// Reads `RECORD_GROUP_BY` and `RECORD_SUM`, both comma separated.
+    pub fn from_env() -> Self {
+        let list = |name: &str| -> Vec<String> {
+            env::var(name)
+                .map(|v| v.split(',').map(|c| c.trim().to_string()).collect())
+                .unwrap_or_default()
+        };
+        Self::new(list("RECORD_GROUP_BY"), list("RECORD_SUM"))
+    }
+
+    pub fn add(&mut self, batch: &RecordBatch) -> Result<(), String> {
+        let keys = self
+            .group_by
+            .iter()
+            .map(|name| column(&batch.schema, name))
+            .collect::<Result<Vec<_>, _>>()?;
+        let sums = self
+            .sum
+            .iter()
+            .map(|name| column(&batch.schema, name))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        for row in &batch.rows {
+            let key = keys.iter().map(|&i| row[i].clone()).collect();
+            let stats = self.groups.entry(key).or_insert_with(|| GroupStats {
+                count: 0,
+                sums: vec![0.0; sums.len()],
+            });
+            stats.count += 1;
+            for (total, &i) in stats.sums.iter_mut().zip(&sums) {
+                *total += row[i].as_f64().unwrap_or(0.0);
+            }
+        }
+        Ok(())
+    }
+
+    /// The aggregate as a batch of its own: the group columns, `count`, then
+    /// one `sum_<column>` per summed column.
+    pub fn to_batch(&self, input: &Schema) -> RecordBatch {
+        let mut columns: Vec<Column> = self
+            .group_by
+            .iter()
+            .filter_map(|name| input.index_of(name).map(|i| input.columns[i].clone()))
+            .collect();
+        columns.push(Column {
+            name: "count".to_string(),
+            ty: ColumnType::Int,
+        });
+        columns.extend(self.sum.iter().map(|name| Column {
+            name: format!("sum_{}", name),
+            ty: ColumnType::Float,
+        }));
+
+        let rows = self
+            .groups
+            .iter()
+            .map(|(key, stats)| {
+                let mut row = key.clone();
+                row.push(Value::Int(stats.count as i64));
+                row.extend(stats.sums.iter().map(|s| Value::Float(*s)));
+                row
+            })
+            .collect();
+        RecordBatch {
+            schema: Schema { columns },
+            rows,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn batch() -> RecordBatch {
+        let col = |name: &str, ty| Column {
+            name: name.to_string(),
+            ty,
+        };
+        RecordBatch {
+            schema: Schema {
+                columns: vec![
+                    col("host", ColumnType::Text),
+                    col("addr", ColumnType::Ip),
+                    col("bytes", ColumnType::Int),
+                ],
+            },
+            rows: vec![
+                vec![
+                    Value::Text("a".into()),
+                    Value::Ip("10.0.0.1".parse().unwrap()),
+                    Value::Int(100),
+                ],
+                vec![
+                    Value::Text("b".into()),
+                    Value::Ip("10.0.0.2".parse().unwrap()),
+                    Value::Int(5),
+                ],
+                vec![Value::Text("a".into()), Value::Null, Value::Int(50)],
+            ],
+        }
+    }
+
+    #[test]
+    fn test_filter_and_project() {
+        let transform = Transform {
+            filters: vec![Predicate::parse("bytes >= 50").unwrap()],
+            projection: Some(vec!["bytes".into(), "host".into()]),
+        };
+        assert_eq!(
+            Transform::parse(Some("bytes >= 50"), Some("bytes, host")),
+            Ok(transform.clone())
+        );
+        assert!(Transform::parse(Some("bytes"), None).is_err());
+        let out = transform.apply(batch()).unwrap();
+        assert_eq!(out.schema.columns[0].name, "bytes");
+        assert_eq!(
+            out.rows,
+            vec![
+                vec![Value::Int(100), Value::Text("a".into())],
+                vec![Value::Int(50), Value::Text("a".into())],
+            ]
+        );
+
+        let by_addr = Transform {
+            filters: vec![Predicate::parse("addr=10.0.0.2").unwrap()],
+            projection: None,
+        };
+        assert_eq!(by_addr.apply(batch()).unwrap().rows.len(), 1);
+
+        let unknown = Transform {
+            filters: vec![Predicate::parse("nope<1").unwrap()],
+            projection: None,
+        };
+        assert!(unknown.apply(batch()).is_err());
+    }
+
+    #[test]
+    fn test_aggregate() {
+        let mut agg = RecordAggregator::new(vec!["host".into()], vec!["bytes".into()]);
+        agg.add(&batch()).unwrap();
+        agg.add(&batch()).unwrap();
+        let out = agg.to_batch(&batch().schema);
+        let names: Vec<_> = out.schema.columns.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, vec!["host", "count", "sum_bytes"]);
+        let a = out
+            .rows
+            .iter()
+            .find(|r| r[0] == Value::Text("a".into()))
+            .unwrap();
+        assert_eq!(a[1], Value::Int(4));
+        assert_eq!(a[2], Value::Float(300.0));
+    }
+}